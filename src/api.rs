@@ -0,0 +1,173 @@
+use crate::bin::{generate, Opt, TagsResult};
+use anyhow::{bail, Error};
+use std::path::PathBuf;
+use structopt::StructOpt;
+use thiserror::Error;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Error
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Error)]
+enum PtagsError {
+    #[error("directory is not found ({:?})", dir)]
+    DirNotFound { dir: PathBuf },
+    #[error("unknown output format ({})", format)]
+    UnknownFormat { format: String },
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Ptags
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Library entry point to generate tags without going through the command line.
+///
+/// ```no_run
+/// use ptagslib::api::Ptags;
+///
+/// let result = Ptags::builder().dir("src").threads(4).build()?.generate()?;
+/// println!("{} tags", result.tags);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct Ptags {
+    opt: Opt,
+}
+
+impl Ptags {
+    pub fn builder() -> PtagsBuilder {
+        PtagsBuilder {
+            opt: Opt::from_iter(&["ptags"]),
+        }
+    }
+
+    pub fn from_opt(opt: Opt) -> Ptags {
+        Ptags { opt }
+    }
+
+    pub fn opt(&self) -> &Opt {
+        &self.opt
+    }
+
+    pub fn generate(&self) -> Result<TagsResult, Error> {
+        generate(&self.opt)
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// PtagsBuilder
+// ---------------------------------------------------------------------------------------------------------------------
+
+pub struct PtagsBuilder {
+    opt: Opt,
+}
+
+impl PtagsBuilder {
+    pub fn dir<T: Into<PathBuf>>(mut self, dir: T) -> Self {
+        self.opt.dir = dir.into();
+        self
+    }
+
+    pub fn output<T: Into<PathBuf>>(mut self, output: T) -> Self {
+        self.opt.output = output.into();
+        self
+    }
+
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.opt.thread = threads;
+        self
+    }
+
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.opt.jobs = jobs;
+        self
+    }
+
+    pub fn bin_ctags<T: Into<PathBuf>>(mut self, bin_ctags: T) -> Self {
+        self.opt.bin_ctags = bin_ctags.into();
+        self
+    }
+
+    pub fn bin_git<T: Into<PathBuf>>(mut self, bin_git: T) -> Self {
+        self.opt.bin_git = bin_git.into();
+        self
+    }
+
+    pub fn opt_ctags<T: Into<String>>(mut self, opt_ctags: T) -> Self {
+        self.opt.opt_ctags.push(opt_ctags.into());
+        self
+    }
+
+    pub fn exclude<T: Into<String>>(mut self, exclude: T) -> Self {
+        self.opt.exclude.push(exclude.into());
+        self
+    }
+
+    pub fn format<T: Into<String>>(mut self, format: T) -> Self {
+        self.opt.format = format.into();
+        self
+    }
+
+    pub fn unsorted(mut self, unsorted: bool) -> Self {
+        self.opt.unsorted = unsorted;
+        self
+    }
+
+    pub fn no_vcs(mut self, no_vcs: bool) -> Self {
+        self.opt.no_vcs = no_vcs;
+        self
+    }
+
+    pub fn include_untracked(mut self, include_untracked: bool) -> Self {
+        self.opt.include_untracked = include_untracked;
+        self
+    }
+
+    pub fn include_ignored(mut self, include_ignored: bool) -> Self {
+        self.opt.include_ignored = include_ignored;
+        self
+    }
+
+    pub fn include_submodule(mut self, include_submodule: bool) -> Self {
+        self.opt.include_submodule = include_submodule;
+        self
+    }
+
+    pub fn build(self) -> Result<Ptags, Error> {
+        if !self.opt.dir.is_dir() {
+            bail!(PtagsError::DirNotFound { dir: self.opt.dir });
+        }
+        if !["ctags", "json", "cscope", "gtags"].contains(&self.opt.format.as_str()) {
+            bail!(PtagsError::UnknownFormat {
+                format: self.opt.format
+            });
+        }
+        Ok(Ptags { opt: self.opt })
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::Ptags;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_builder() {
+        let ptags = Ptags::builder()
+            .dir("src")
+            .output("-")
+            .threads(3)
+            .exclude("*.rs")
+            .build()
+            .unwrap();
+        assert_eq!(ptags.opt().dir, PathBuf::from("src"));
+        assert_eq!(ptags.opt().threads(), 3);
+        assert_eq!(ptags.opt().exclude, vec![String::from("*.rs")]);
+
+        assert!(Ptags::builder().dir("not_found").build().is_err());
+        assert!(Ptags::builder().format("xml").build().is_err());
+    }
+}
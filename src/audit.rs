@@ -0,0 +1,165 @@
+use crate::bin::format_timestamp;
+use anyhow::{Context, Error};
+use serde_json::json;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process::{Command, ExitStatus, Output};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Audit
+// ---------------------------------------------------------------------------------------------------------------------
+
+static LOG: Mutex<Option<File>> = Mutex::new(None);
+
+/// Opens `--audit-log`, where records are appended so that the file keeps the trail of all runs.
+pub fn init(path: Option<&Path>) -> Result<(), Error> {
+    if let Some(path) = path {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context(format!("failed to open audit log ({:?})", path))?;
+        *LOG.lock().unwrap() = Some(file);
+    }
+    Ok(())
+}
+
+/// Returns the record of the finished command as a JSON object.
+///
+/// `status` is `null` if the command was killed by a signal or ptags, or failed to start.
+fn to_json(
+    cmd: &Command,
+    started: Instant,
+    status: Option<ExitStatus>,
+    bytes_in: u64,
+    bytes_out: u64,
+) -> serde_json::Value {
+    let mut argv = vec![cmd.get_program().to_string_lossy().into_owned()];
+    argv.extend(cmd.get_args().map(|x| x.to_string_lossy().into_owned()));
+    let cwd = std::env::current_dir().unwrap_or_default();
+    let cwd = match cmd.get_current_dir() {
+        Some(x) => cwd.join(x),
+        None => cwd,
+    };
+    let elapsed = started.elapsed();
+    let time = SystemTime::now()
+        .checked_sub(elapsed)
+        .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+        .map(|x| x.as_secs())
+        .unwrap_or(0);
+    json!({
+        "time": format_timestamp(time),
+        "argv": argv,
+        "cwd": cwd.to_string_lossy(),
+        "duration_ms": elapsed.as_millis() as u64,
+        "status": status.and_then(|x| x.code()),
+        "bytes_in": bytes_in,
+        "bytes_out": bytes_out,
+    })
+}
+
+/// Appends the record of the finished command to `--audit-log` if given.
+///
+/// `bytes_in` is bytes written to stdin, and `bytes_out` is bytes of stdout.
+pub fn record(
+    cmd: &Command,
+    started: Instant,
+    status: Option<ExitStatus>,
+    bytes_in: u64,
+    bytes_out: u64,
+) {
+    let mut log = LOG.lock().unwrap_or_else(|x| x.into_inner());
+    if let Some(ref mut file) = *log {
+        let record = to_json(cmd, started, status, bytes_in, bytes_out);
+        // A line is written at once so that records of concurrent processes are not interleaved.
+        let _ = file.write_all(format!("{}\n", record).as_bytes());
+    }
+}
+
+/// Same as `Command::output` but the command is recorded.
+pub fn output(cmd: &mut Command) -> io::Result<Output> {
+    let started = Instant::now();
+    let output = cmd.output();
+    match output {
+        Ok(ref x) => record(cmd, started, Some(x.status), 0, x.stdout.len() as u64),
+        Err(_) => record(cmd, started, None, 0, 0),
+    }
+    output
+}
+
+/// Writer or reader which counts bytes written to stdin, or read from stdout of a command.
+pub struct Counter<W> {
+    inner: W,
+    pub bytes: u64,
+}
+
+impl<W> Counter<W> {
+    pub fn new(inner: W) -> Counter<W> {
+        Counter { inner, bytes: 0 }
+    }
+}
+
+impl<W: Write> Write for Counter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.bytes += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Counter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.bytes += len as u64;
+        Ok(len)
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{to_json, Counter};
+    use std::io::{Read, Write};
+    use std::process::Command;
+    use std::time::Instant;
+
+    #[test]
+    fn test_to_json() {
+        let mut cmd = Command::new("git");
+        cmd.args(["ls-files", "-z"]).current_dir("src");
+        let output = cmd.output().unwrap();
+        let record = to_json(&cmd, Instant::now(), Some(output.status), 3, 10);
+        assert_eq!(record["argv"], serde_json::json!(["git", "ls-files", "-z"]));
+        assert!(record["cwd"].as_str().unwrap().ends_with("src"));
+        assert_eq!(record["status"], 0);
+        assert_eq!(record["bytes_in"], 3);
+        assert_eq!(record["bytes_out"], 10);
+        assert!(record["time"].as_str().unwrap().ends_with('Z'));
+
+        let record = to_json(&cmd, Instant::now(), None, 0, 0);
+        assert!(record["status"].is_null());
+    }
+
+    #[test]
+    fn test_counter() {
+        let mut counter = Counter::new(Vec::new());
+        counter.write_all(b"a.rs\n").unwrap();
+        writeln!(counter, "b.rs").unwrap();
+        assert_eq!(counter.bytes, 10);
+
+        let mut counter = Counter::new(&b"a.rs\nb.rs\n"[..]);
+        let mut buf = String::new();
+        counter.read_to_string(&mut buf).unwrap();
+        assert_eq!(counter.bytes, 10);
+    }
+}
@@ -1,34 +1,90 @@
-use crate::cmd_ctags::CmdCtags;
+use crate::audit::{self, Counter};
+use crate::cache::Cache;
+use crate::check;
+use crate::cmd_cscope::CmdCscope;
+use crate::cmd_ctags::{CmdCtags, CtagsConfig, CtagsOutput};
 use crate::cmd_git::CmdGit;
-use anyhow::{Context, Error};
+use crate::cmd_gtags::CmdGtags;
+use crate::cmd_hg::CmdHg;
+use crate::cmd_watchman::{CmdWatchman, WATCHMAN_CONFIG};
+use crate::diff;
+use crate::doctor;
+use crate::export;
+use crate::filter::{self, FileFilter};
+use crate::find;
+use crate::languages;
+use crate::lock::{lock_path, TagsLock};
+use crate::log;
+use crate::lsp;
+use crate::merge;
+use crate::outline;
+use crate::pick;
+use crate::pipeline::Pipeline;
+use crate::regenerate;
+use crate::serve::Server;
+use crate::signal;
+use crate::state::{RunStats, State};
+use crate::trace::Trace;
+use crate::walk::Walk;
+use crate::watch::DirWatcher;
+use anyhow::{bail, Context, Error};
 use dirs;
+use rayon::slice::ParallelSliceMut;
+use regex::bytes::RegexSet;
 use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::fs;
 use std::io::BufRead;
-use std::io::{stdout, BufWriter, Read, Write};
-use std::path::PathBuf;
-use std::process::Output;
+use std::io::{stdout, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
 use std::str;
+use std::time::{Duration, Instant};
 use structopt::{clap, StructOpt};
 use structopt_toml::StructOptToml;
-use time::{Duration, Instant};
+use tempfile::TempDir;
+use thiserror::Error;
 use toml;
+use tracing::{debug, trace};
 
 // ---------------------------------------------------------------------------------------------------------------------
 // Options
 // ---------------------------------------------------------------------------------------------------------------------
 
-#[derive(Debug, Deserialize, Serialize, StructOpt, StructOptToml)]
+#[derive(Clone, Debug, Deserialize, Serialize, StructOpt, StructOptToml)]
 #[serde(default)]
 #[structopt(name = "ptags")]
 #[structopt(long_version = option_env!("LONG_VERSION").unwrap_or(env!("CARGO_PKG_VERSION")))]
 #[structopt(setting = clap::AppSettings::AllowLeadingHyphen)]
 #[structopt(setting = clap::AppSettings::ColoredHelp)]
 pub struct Opt {
-    /// Number of threads
-    #[structopt(short = "t", long = "thread", default_value = "8")]
+    /// Number of threads ( 0 means the number of logical CPUs )
+    #[structopt(short = "t", long = "thread", default_value = "0")]
     pub thread: usize,
 
+    /// Number of files passed to a ctags process at once
+    #[structopt(long = "batch-size", default_value = "1000")]
+    pub batch_size: usize,
+
+    /// Number of concurrent ctags processes ( 0 means the same as --thread )
+    #[structopt(short = "j", long = "jobs", default_value = "0")]
+    pub jobs: usize,
+
+    /// Memory budget in MB, which limits concurrent ctags processes and spills tags held in memory to disk
+    #[structopt(long = "max-memory")]
+    pub max_memory: Option<u64>,
+
+    /// Balance chunks by file size instead of file count
+    #[structopt(long = "balance-by-size")]
+    pub balance_by_size: bool,
+
+    /// Call ctags for files while git lists them, and merge finished chunks while others are running
+    #[structopt(long = "pipeline")]
+    pub pipeline: bool,
+
     /// Output filename ( filename '-' means output to stdout )
     #[structopt(short = "f", long = "file", default_value = "tags", parse(from_os_str))]
     pub output: PathBuf,
@@ -37,14 +93,54 @@ pub struct Opt {
     #[structopt(name = "DIR", default_value = ".", parse(from_os_str))]
     pub dir: PathBuf,
 
+    /// Additional search directories merged into the same tags file
+    #[structopt(name = "EXTRA_DIR", parse(from_os_str))]
+    pub extra_dirs: Vec<PathBuf>,
+
+    /// Pathspecs passed to git ls-files ( ex. -- 'src/**/*.c' ':!third_party' )
+    #[structopt(name = "PATHSPEC", last = true)]
+    pub pathspecs: Vec<String>,
+
+    /// Repository tagged into the same tags file ( ex. --repo lib/foo )
+    #[structopt(long = "repo", number_of_values = 1, parse(from_os_str))]
+    pub repos: Vec<PathBuf>,
+
+    /// Root directory of paths in tags file of multiple directories [default: current directory]
+    #[structopt(long = "root", parse(from_os_str))]
+    pub root: Option<PathBuf>,
+
+    /// Tag git repositories found recursively in DIR into a tags file
+    #[structopt(long = "recurse-repos")]
+    pub recurse_repos: bool,
+
     /// Show statistics
     #[structopt(short = "s", long = "stat")]
     pub stat: bool,
 
+    /// Call ctags for each file and show the slowest files
+    #[structopt(long = "profile")]
+    pub profile: bool,
+
+    /// Number of files shown by --profile
+    #[structopt(long = "profile-top", default_value = "10")]
+    pub profile_top: usize,
+
+    /// Write the timeline of git, ctags processes and writing tags in Chrome tracing JSON format
+    #[structopt(long = "trace", parse(from_os_str))]
+    pub trace: Option<PathBuf>,
+
     /// Filename of input file list
     #[structopt(short = "L", long = "list")]
     pub list: Option<String>,
 
+    /// Version control system used to search files
+    #[structopt(long = "vcs", default_value = "git", possible_values = &["git", "hg"])]
+    pub vcs: String,
+
+    /// Search files by walking the directory instead of using version control system
+    #[structopt(long = "no-vcs")]
+    pub no_vcs: bool,
+
     /// Path to ctags binary
     #[structopt(long = "bin-ctags", default_value = "ctags", parse(from_os_str))]
     pub bin_ctags: PathBuf,
@@ -53,10 +149,138 @@ pub struct Opt {
     #[structopt(long = "bin-git", default_value = "git", parse(from_os_str))]
     pub bin_git: PathBuf,
 
+    /// Path to hg binary
+    #[structopt(long = "bin-hg", default_value = "hg", parse(from_os_str))]
+    pub bin_hg: PathBuf,
+
+    /// Path to watchman binary
+    #[structopt(long = "bin-watchman", default_value = "watchman", parse(from_os_str))]
+    pub bin_watchman: PathBuf,
+
+    /// Path to cscope binary
+    #[structopt(long = "bin-cscope", default_value = "cscope", parse(from_os_str))]
+    pub bin_cscope: PathBuf,
+
+    /// Path to gtags binary
+    #[structopt(long = "bin-gtags", default_value = "gtags", parse(from_os_str))]
+    pub bin_gtags: PathBuf,
+
+    /// Execute ctags in the container image with DIR bind-mounted ( --bin-ctags is searched in the image )
+    #[structopt(long = "ctags-container")]
+    pub ctags_container: Option<String>,
+
+    /// Path to container runtime binary used by --ctags-container ( docker or podman )
+    #[structopt(long = "bin-container", default_value = "docker", parse(from_os_str))]
+    pub bin_container: PathBuf,
+
+    /// Execute ctags on the remote host by ssh ( DIR must be mounted or synced at the same path or --remote-dir )
+    #[structopt(long = "remote")]
+    pub remote: Option<String>,
+
+    /// Path of DIR on the remote host
+    #[structopt(long = "remote-dir", parse(from_os_str))]
+    pub remote_dir: Option<PathBuf>,
+
+    /// Path to ssh binary used by --remote
+    #[structopt(long = "bin-ssh", default_value = "ssh", parse(from_os_str))]
+    pub bin_ssh: PathBuf,
+
+    /// Kill ctags process which doesn't finish in the given seconds
+    #[structopt(long = "ctags-timeout")]
+    pub ctags_timeout: Option<u64>,
+
+    /// Limit CPU time of each ctags process in seconds by RLIMIT_CPU
+    #[structopt(long = "ctags-rlimit-cpu")]
+    pub ctags_rlimit_cpu: Option<u64>,
+
+    /// Limit memory of each ctags process by RLIMIT_DATA ( ex. --ctags-rlimit-mem 2G )
+    #[structopt(long = "ctags-rlimit-mem", parse(try_from_str = parse_size))]
+    pub ctags_rlimit_mem: Option<u64>,
+
+    /// Run ctags with the lowest CPU and I/O priority for background regeneration
+    #[structopt(long = "low-priority")]
+    pub low_priority: bool,
+
+    /// Confine ctags to read DIR and write only its temporary directory by Landlock on Linux
+    #[structopt(long = "sandbox")]
+    pub sandbox: bool,
+
+    /// Retry timed out chunk file by file to skip the offending files
+    #[structopt(long = "retry-timeout")]
+    pub retry_timeout: bool,
+
+    /// Keep a ctags process for each job and send files one by one by the interactive mode of Universal Ctags ( requires --format json )
+    #[structopt(long = "persistent-workers")]
+    pub persistent_workers: bool,
+
+    /// How to pass the file list of each chunk to ctags ( tempfile: write it into a temporary file passed by -L )
+    #[structopt(long = "files-via", default_value = "stdin", possible_values = &["stdin", "tempfile"])]
+    pub files_via: String,
+
+    /// Maximum size of the pipe of the file list set by F_SETPIPE_SZ on Linux
+    #[structopt(long = "pipe-size", default_value = "1048576")]
+    pub pipe_size: usize,
+
+    /// Keep the default size of the pipe of the file list
+    #[structopt(long = "no-set-pipe-size")]
+    pub no_set_pipe_size: bool,
+
+    /// Write tags of succeeded ctags processes even if some of them failed
+    #[structopt(long = "keep-going")]
+    pub keep_going: bool,
+
+    /// Convert non-UTF-8 paths lossily instead of failing
+    #[structopt(long = "lossy-paths")]
+    pub lossy_paths: bool,
+
+    /// Minimum version of ctags ( ex. 5.9 )
+    #[structopt(long = "min-ctags-version")]
+    pub min_ctags_version: Option<String>,
+
+    /// Minimum version of git ( ex. 2.20 )
+    #[structopt(long = "min-git-version")]
+    pub min_git_version: Option<String>,
+
+    /// Languages to be tagged ( ex. C++,Rust )
+    #[structopt(long = "languages")]
+    pub languages: Option<String>,
+
+    /// Kinds to be tagged per language ( ex. Rust=fst )
+    #[structopt(long = "kinds", number_of_values = 1)]
+    pub kinds: Vec<String>,
+
+    /// Extension fields to be included ( ex. +n )
+    #[structopt(long = "fields")]
+    pub fields: Option<String>,
+
+    /// Extra tag entries to be included ( ex. +q )
+    #[structopt(long = "extras")]
+    pub extras: Option<String>,
+
+    /// Encoding of source files passed to ctags by --input-encoding ( ex. sjis )
+    #[structopt(long = "encoding")]
+    pub encoding: Option<String>,
+
+    /// Encoding of source files matching the glob pattern, which overrides --encoding ( ex. 'legacy/**=euc-jp' )
+    #[structopt(long = "encoding-for", number_of_values = 1)]
+    pub encoding_for: Vec<String>,
+
+    /// Encoding of tags passed to ctags by --output-encoding [default: utf-8 if --encoding or --encoding-for is given]
+    #[structopt(long = "output-encoding")]
+    pub output_encoding: Option<String>,
+
     /// Options passed to ctags
     #[structopt(short = "c", long = "opt-ctags", number_of_values = 1)]
     pub opt_ctags: Vec<String>,
 
+    /// Don't read option files of ctags like .ctags.d/*.ctags for reproducible runs
+    #[structopt(long = "no-user-ctags-config")]
+    pub no_user_ctags_config: bool,
+
+    /// Options of ctags per language from `[ctags.lang.<LANG>]` of config files
+    #[structopt(skip)]
+    pub ctags: CtagsConfig,
+
     /// Options passed to git
     #[structopt(short = "g", long = "opt-git", number_of_values = 1)]
     pub opt_git: Vec<String>,
@@ -65,14 +289,73 @@ pub struct Opt {
     #[structopt(long = "opt-git-lfs", number_of_values = 1)]
     pub opt_git_lfs: Vec<String>,
 
+    /// Options passed to hg
+    #[structopt(long = "opt-hg", number_of_values = 1)]
+    pub opt_hg: Vec<String>,
+
+    /// Options passed to cscope
+    #[structopt(long = "opt-cscope", number_of_values = 1)]
+    pub opt_cscope: Vec<String>,
+
+    /// Options passed to gtags
+    #[structopt(long = "opt-gtags", number_of_values = 1)]
+    pub opt_gtags: Vec<String>,
+
     /// Verbose mode
     #[structopt(short = "v", long = "verbose")]
     pub verbose: bool,
 
+    /// Suppress progress messages, warnings and logs except errors ( overrides --verbose )
+    #[structopt(short = "q", long = "quiet")]
+    pub quiet: bool,
+
+    /// How to handle warnings which ctags reports to stderr ( fail: exit with an error after writing tags )
+    #[structopt(long = "warnings", default_value = "show", possible_values = &["show", "hide", "fail"])]
+    pub warnings: String,
+
+    /// Fail if ctags reports warnings, same as --warnings fail
+    #[structopt(long = "deny-warnings")]
+    pub deny_warnings: bool,
+
+    /// Fail only by warnings matched with the regex ( ex. --deny-warnings-regex 'ignoring null tag' )
+    #[structopt(long = "deny-warnings-regex", number_of_values = 1)]
+    pub deny_warnings_regex: Vec<String>,
+
+    /// Write warnings of ctags into the file regardless of --warnings
+    #[structopt(long = "warnings-log", parse(from_os_str))]
+    pub warnings_log: Option<PathBuf>,
+
+    /// Level of logs ( default: debug with --verbose, otherwise warn )
+    #[structopt(
+        long = "log-level",
+        possible_values = &["off", "error", "warn", "info", "debug", "trace"]
+    )]
+    pub log_level: Option<String>,
+
+    /// Format of logs
+    #[structopt(long = "log-format", default_value = "text", possible_values = &["text", "json"])]
+    pub log_format: String,
+
+    /// Append logs to the file instead of stderr
+    #[structopt(long = "log-file", parse(from_os_str))]
+    pub log_file: Option<PathBuf>,
+
+    /// Append argv, cwd, duration, exit status and bytes in/out of every spawned command to the file in JSON lines
+    #[structopt(long = "audit-log", parse(from_os_str))]
+    pub audit_log: Option<PathBuf>,
+
     /// Exclude git-lfs tracked files
     #[structopt(long = "exclude-lfs")]
     pub exclude_lfs: bool,
 
+    /// Exclude files marked as linguist-vendored or linguist-generated in .gitattributes
+    #[structopt(long = "exclude-vendored")]
+    pub exclude_vendored: bool,
+
+    /// Exclude files out of sparse-checkout
+    #[structopt(long = "sparse")]
+    pub sparse: bool,
+
     /// Include untracked files
     #[structopt(long = "include-untracked")]
     pub include_untracked: bool,
@@ -89,14 +372,114 @@ pub struct Opt {
     #[structopt(long = "validate-utf8")]
     pub validate_utf8: bool,
 
+    /// Replace invalid UTF8 sequences of tags by U+FFFD and report the affected tags instead of failing by --validate-utf8
+    #[structopt(long = "lossy-utf8")]
+    pub lossy_utf8: bool,
+
+    /// Report file of tags replaced by --lossy-utf8 [default: <output>.lossy-utf8, or stderr for stdout]
+    #[structopt(long = "lossy-utf8-report", parse(from_os_str))]
+    pub lossy_utf8_report: Option<PathBuf>,
+
     /// Disable tags sort
     #[structopt(long = "unsorted")]
     pub unsorted: bool,
 
+    /// How to sort tags of chunks ( merge: streaming merge, full: parallel sort in memory, auto: full if tags are large and fit in memory )
+    #[structopt(long = "sort-mode", default_value = "merge", possible_values = &["merge", "full", "auto"])]
+    pub sort_mode: String,
+
+    /// Output format
+    #[structopt(long = "format", default_value = "ctags", possible_values = &["ctags", "json", "cscope", "gtags"])]
+    pub format: String,
+
+    /// Line ending of the tags file ( native is crlf on Windows )
+    #[structopt(long = "eol", default_value = "lf", possible_values = &["lf", "crlf", "native"])]
+    pub eol: String,
+
     /// Glob pattern of exclude file ( ex. --exclude '*.rs' )
     #[structopt(short = "e", long = "exclude", number_of_values = 1)]
     pub exclude: Vec<String>,
 
+    /// Glob pattern of files to be tagged, other files are excluded ( ex. --include '*.py' )
+    #[structopt(long = "include", number_of_values = 1)]
+    pub include: Vec<String>,
+
+    /// Regex of files to be tagged, other files are excluded ( ex. --include-regex '\.(c|h)$' )
+    #[structopt(long = "include-regex", number_of_values = 1)]
+    pub include_regex: Vec<String>,
+
+    /// Regex of files to be excluded ( ex. --exclude-regex 'gen/v[0-9]+/' )
+    #[structopt(long = "exclude-regex", number_of_values = 1)]
+    pub exclude_regex: Vec<String>,
+
+    /// Skip files larger than the size ( ex. --max-file-size 10M )
+    #[structopt(long = "max-file-size", parse(try_from_str = parse_size))]
+    pub max_file_size: Option<u64>,
+
+    /// Skip minified files like *.min.js and files with very long lines
+    #[structopt(long = "skip-minified")]
+    pub skip_minified: bool,
+
+    /// Expand symbolic links to directories, and skip dangling or duplicated links
+    #[structopt(long = "follow-symlinks")]
+    pub follow_symlinks: bool,
+
+    /// Skip symbolic links ( prior to --follow-symlinks )
+    #[structopt(long = "skip-symlinks")]
+    pub skip_symlinks: bool,
+
+    /// File of gitignore-style patterns to exclude files ( .ptagsignore in DIR is loaded too )
+    #[structopt(long = "exclude-from", number_of_values = 1, parse(from_os_str))]
+    pub exclude_from: Vec<PathBuf>,
+
+    /// Drop tags of the kind from the output ( ex. --drop-kind l )
+    #[structopt(long = "drop-kind", number_of_values = 1)]
+    pub drop_kind: Vec<String>,
+
+    /// Drop tag lines matched with the regex from the output ( ex. --filter-regex '^test_' )
+    #[structopt(long = "filter-regex", number_of_values = 1)]
+    pub filter_regex: Vec<String>,
+
+    /// Make paths relative to the directory of tags file ( yes: absolute if outside, always: use '..', no: absolute )
+    #[structopt(long = "tag-relative", possible_values = &["yes", "no", "always"])]
+    pub tag_relative: Option<String>,
+
+    /// Remove the prefix from paths in tags file
+    #[structopt(long = "strip-prefix")]
+    pub strip_prefix: Option<String>,
+
+    /// Add the prefix to paths in tags file
+    #[structopt(long = "path-prefix")]
+    pub path_prefix: Option<String>,
+
+    /// Map the path prefix in tags file ( ex. --map-path /workspace=/home/user/repo )
+    #[structopt(long = "map-path", number_of_values = 1)]
+    pub map_path: Vec<String>,
+
+    /// Rewrite backslashes to forward slashes in paths of tags file for Vim of WSL and Cygwin
+    #[structopt(long = "slash")]
+    pub slash: bool,
+
+    /// Command to filter tags through before writing the output ( ex. --filter-cmd 'sed s/foo/bar/' )
+    #[structopt(long = "filter-cmd")]
+    pub filter_cmd: Option<String>,
+
+    /// Command to stream tags into instead of writing the output file ( ex. --output-cmd 'zstd -o tags.zst' )
+    #[structopt(long = "output-cmd")]
+    pub output_cmd: Option<String>,
+
+    /// Split tags into the tags file of each directory at the given depth with paths relative to it
+    #[structopt(long = "split-by-dir")]
+    pub split_by_dir: Option<usize>,
+
+    /// Split tags into the tags file of each language ( ex. tags.rust ), ignored with --split-by-dir
+    #[structopt(long = "split-by-lang")]
+    pub split_by_lang: bool,
+
+    /// Split sorted tags into the given number of tags files ( ex. tags.0 ) by ranges of tag names with an index file
+    #[structopt(long = "shards")]
+    pub shards: Option<usize>,
+
     /// Generate shell completion file
     #[structopt(
         long = "completion",
@@ -104,9 +487,372 @@ pub struct Opt {
     )]
     pub completion: Option<String>,
 
-    /// Generate configuration sample file
+    /// Show the effective configuration merged from config files and the command line
     #[structopt(long = "config")]
+    #[serde(skip)]
     pub config: bool,
+
+    /// Config file used instead of ~/.ptags.toml
+    #[structopt(long = "config-file", parse(from_os_str))]
+    #[serde(skip)]
+    pub config_file: Option<PathBuf>,
+
+    /// Config files merged into the options
+    #[structopt(skip)]
+    #[serde(skip)]
+    pub config_sources: Vec<PathBuf>,
+
+    /// Append tags to the existing output file, replacing tags of re-tagged files
+    #[structopt(short = "a", long = "append")]
+    pub append: bool,
+
+    /// Tag files in the git revision without checking it out ( ex. --rev v1.0 )
+    #[structopt(long = "rev")]
+    pub rev: Option<String>,
+
+    /// Tag contents in the git index instead of the working tree
+    #[structopt(long = "staged", conflicts_with = "rev")]
+    pub staged: bool,
+
+    /// Retag only files changed since the revision, replacing their tags in the existing output file
+    #[structopt(long = "diff-against")]
+    pub diff_against: Option<String>,
+
+    /// Retag only files changed since the previous run
+    #[structopt(long = "incremental")]
+    pub incremental: bool,
+
+    /// Get changed files from Watchman in incremental and watch mode ( enabled if DIR has .watchmanconfig )
+    #[structopt(long = "use-watchman")]
+    pub use_watchman: bool,
+
+    /// State filename for incremental mode
+    #[structopt(
+        long = "state-file",
+        default_value = ".ptags_state",
+        parse(from_os_str)
+    )]
+    pub state_file: PathBuf,
+
+    /// Wait for another ptags writing the same tags file ( default, overrides --no-wait in config files )
+    #[structopt(long = "wait")]
+    pub wait: bool,
+
+    /// Fail immediately if another ptags is writing the same tags file
+    #[structopt(long = "no-wait")]
+    pub no_wait: bool,
+
+    /// Keep running and regenerate tags when files change
+    #[structopt(long = "watch")]
+    pub watch: bool,
+
+    /// Print git and ctags command lines and chunks of files without calling ctags or writing the output
+    #[structopt(long = "dry-run")]
+    pub dry_run: bool,
+
+    /// Generate the byte-identical tags file from the same files and options regardless of the number of threads
+    #[structopt(long = "reproducible")]
+    pub reproducible: bool,
+
+    /// Check whether the tags file is up to date without writing it, and fail if it's stale
+    #[structopt(long = "check-up-to-date")]
+    pub check_up_to_date: bool,
+
+    /// Delay[ms] to wait for further changes before regenerating in watch mode
+    #[structopt(long = "watch-delay", default_value = "200")]
+    pub watch_delay: u64,
+
+    /// Reuse ctags output of files unchanged from git index
+    #[structopt(long = "cache")]
+    pub cache: bool,
+
+    /// Cache directory ( default is ptags directory in the git directory shared by worktrees, or user cache directory )
+    #[structopt(long = "cache-dir", parse(from_os_str))]
+    pub cache_dir: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    #[serde(skip)]
+    pub cmd: Option<Cmd>,
+}
+
+impl Opt {
+    /// Returns the number of threads resolving `--thread 0` to the number of logical CPUs.
+    pub fn threads(&self) -> usize {
+        if self.thread == 0 {
+            std::thread::available_parallelism()
+                .map(|x| x.get())
+                .unwrap_or(1)
+        } else {
+            self.thread
+        }
+    }
+
+    /// Returns the number of concurrent ctags processes.
+    ///
+    /// `--thread` decides how many chunks the file list is split to, and `--jobs` bounds how many of them run at once.
+    pub fn jobs(&self) -> usize {
+        if self.jobs == 0 {
+            self.threads()
+        } else {
+            self.jobs
+        }
+    }
+
+    /// Returns true if warnings of ctags fail the run.
+    pub fn denies_warnings(&self) -> bool {
+        self.warnings == "fail" || self.deny_warnings || !self.deny_warnings_regex.is_empty()
+    }
+}
+
+#[derive(Clone, Debug, StructOpt)]
+pub enum Cmd {
+    /// Generate tags file ( default )
+    #[structopt(name = "generate")]
+    Generate,
+
+    /// Check the tags file for malformed lines, sort order and missing files
+    #[structopt(name = "check")]
+    Check,
+
+    /// Diagnose git, git-lfs, ctags and config files, and print whether ptags is ready
+    #[structopt(name = "doctor")]
+    Doctor,
+
+    /// Show how many files are parsed by each language of ctags, and skipped
+    #[structopt(name = "languages")]
+    Languages {
+        /// Show languages which no file is parsed by too
+        #[structopt(long = "all")]
+        all: bool,
+
+        /// Output format
+        #[structopt(long = "format", default_value = "text", possible_values = &["text", "json"])]
+        format: String,
+    },
+
+    /// Generate the tags file again with the options recorded in its pseudo-tags
+    #[structopt(name = "regenerate")]
+    Regenerate,
+
+    /// Merge tags files into a tags file
+    #[structopt(name = "merge")]
+    Merge {
+        /// Output filename
+        #[structopt(
+            short = "o",
+            long = "output",
+            default_value = "tags",
+            parse(from_os_str)
+        )]
+        output: PathBuf,
+
+        /// Tags files to be merged
+        #[structopt(name = "FILE", required = true, parse(from_os_str))]
+        files: Vec<PathBuf>,
+    },
+
+    /// Run as a server which keeps tags in memory and answers requests through a socket
+    #[structopt(name = "serve")]
+    Serve {
+        /// Socket filename
+        #[structopt(long = "socket", default_value = ".ptags.sock", parse(from_os_str))]
+        socket: PathBuf,
+    },
+
+    /// Run as a language server through stdio which answers symbols from tags regenerated in the background
+    #[structopt(name = "lsp")]
+    Lsp,
+
+    /// Find tags by name from the tags file
+    #[structopt(name = "find")]
+    Find {
+        /// Tag name
+        name: String,
+
+        /// Find tags whose name starts with the given name
+        #[structopt(long = "prefix")]
+        prefix: bool,
+
+        /// Ignore case of the name
+        #[structopt(long = "icase")]
+        icase: bool,
+
+        /// Find tags of the kind only ( ex. f, function )
+        #[structopt(long = "kind")]
+        kind: Option<String>,
+
+        /// Print tags in JSON lines
+        #[structopt(long = "json")]
+        json: bool,
+    },
+
+    /// Compare symbols of two tags files and show added, removed and moved symbols by file
+    #[structopt(name = "diff")]
+    Diff {
+        /// Old tags file
+        #[structopt(parse(from_os_str))]
+        old: PathBuf,
+
+        /// New tags file
+        #[structopt(parse(from_os_str))]
+        new: PathBuf,
+
+        /// Output format
+        #[structopt(long = "format", default_value = "text", possible_values = &["text", "json"])]
+        format: String,
+    },
+
+    /// Export the tags file into a database or an index
+    #[structopt(name = "export")]
+    Export {
+        /// SQLite database filename
+        #[structopt(long = "sqlite", parse(from_os_str))]
+        sqlite: Option<PathBuf>,
+
+        /// Create FTS5 trigram index `symbols_fts` for fuzzy search of symbol names
+        #[structopt(long = "fts")]
+        fts: bool,
+
+        /// LSIF dump filename for code intelligence platforms
+        #[structopt(long = "lsif", parse(from_os_str))]
+        lsif: Option<PathBuf>,
+    },
+
+    /// Pick a tag interactively by fuzzy search and print the location as `file:line`
+    #[structopt(name = "pick")]
+    Pick {
+        /// Initial query
+        query: Option<String>,
+
+        /// Open the location by $VISUAL or $EDITOR instead of printing it
+        #[structopt(long = "edit")]
+        edit: bool,
+    },
+
+    /// Call ctags for a file and show the outline of symbols by scope
+    #[structopt(name = "outline")]
+    Outline {
+        /// Source filename
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+
+        /// Output format
+        #[structopt(long = "format", default_value = "text", possible_values = &["text", "json"])]
+        format: String,
+    },
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Error
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Exit code when ptags failed.
+pub const EXIT_FAILURE: i32 = 1;
+
+/// Exit code when tags file is written but some ctags processes failed in keep-going mode.
+pub const EXIT_PARTIAL: i32 = 2;
+
+#[derive(Debug, Error)]
+enum RunError {
+    #[error("tags file is incomplete because {} ctags processes failed", failed)]
+    PartialFailure { failed: usize },
+
+    #[error(
+        "path is not valid UTF-8 ({}), use --lossy-paths to convert it lossily",
+        path
+    )]
+    InvalidPath { path: String },
+
+    #[error("{} is too old ({}), {} or later is required", bin, found, required)]
+    OldVersion {
+        bin: String,
+        found: String,
+        required: String,
+    },
+
+    #[error("command failed ({}, {})", cmd, status)]
+    FilterFailed { cmd: String, status: ExitStatus },
+
+    #[error("{} requires the output file", option)]
+    OutputRequired { option: String },
+
+    #[error("{} requires sorted tags", option)]
+    SortRequired { option: String },
+
+    #[error("ctags reported {} denied warnings{}", count, summary)]
+    Warnings { count: usize, summary: String },
+
+    #[error(
+        "tags file is not up to date ({}, {} added, {} removed)",
+        path,
+        added,
+        removed
+    )]
+    Stale {
+        path: String,
+        added: usize,
+        removed: usize,
+    },
+}
+
+pub fn exit_code(err: &Error) -> i32 {
+    match err.downcast_ref::<RunError>() {
+        Some(RunError::PartialFailure { .. }) => EXIT_PARTIAL,
+        _ => EXIT_FAILURE,
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Result
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Summary of a tags generation.
+#[derive(Debug, Default)]
+pub struct TagsResult {
+    /// Number of written tags except pseudo-tags
+    pub tags: usize,
+    /// Number of searched files including cache hits
+    pub files: usize,
+    /// Number of files whose tags were taken from the cache
+    pub cache_hits: usize,
+    /// Statistics of each worker thread
+    pub threads: Vec<ThreadStat>,
+    /// Elapsed time of each phase
+    pub timings: Timings,
+    /// Messages which ctags reported to stderr without failing, deduplicated in order of chunks
+    pub warnings: Vec<String>,
+    /// Number of warning lines before deduplication
+    pub warning_lines: usize,
+    /// ctags processes which failed in keep-going mode
+    pub failures: Vec<CtagsFailure>,
+    /// Number of files skipped by `--max-file-size`
+    pub large_files: usize,
+    /// Files and elapsed time of ctags in descending order of the time by `--profile`
+    pub slow_files: Vec<(String, Duration)>,
+}
+
+#[derive(Debug)]
+pub struct CtagsFailure {
+    pub files: Vec<String>,
+    pub stderr: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ThreadStat {
+    pub chunks: usize,
+    pub files: usize,
+    /// Total size of the source files, which is measured only with `--stat`
+    pub source_bytes: u64,
+    /// Total size of the ctags output
+    pub output_bytes: u64,
+    pub call_ctags: Duration,
+}
+
+#[derive(Debug, Default)]
+pub struct Timings {
+    pub git_files: Duration,
+    pub call_ctags: Duration,
+    pub write_tags: Duration,
 }
 
 // ---------------------------------------------------------------------------------------------------------------------
@@ -123,210 +869,3715 @@ macro_rules! watch_time (
     );
 );
 
-pub fn git_files(opt: &Opt) -> Result<Vec<String>, Error> {
-    let list = CmdGit::get_files(&opt)?;
-    let mut files = vec![String::from(""); opt.thread];
+pub fn vcs_list(opt: &Opt) -> Result<Vec<String>, Error> {
+    if opt.no_vcs {
+        return Walk::get_files(opt);
+    }
+    match opt.vcs.as_str() {
+        "hg" => CmdHg::get_files(opt),
+        _ => {
+            if CmdGit::is_work_tree(opt)? {
+                CmdGit::get_files(opt)
+            } else {
+                if opt.verbose {
+                    eprintln!("Not a git work tree, fall back to directory walk");
+                }
+                Walk::get_files(opt)
+            }
+        }
+    }
+}
 
-    for (i, f) in list.iter().enumerate() {
-        files[i % opt.thread].push_str(f);
-        files[i % opt.thread].push_str("\n");
+/// Returns directories to be tagged into a tags file, or `None` for a single `DIR`.
+///
+/// `DIR` is omitted if only `--repo` is given and `DIR` is the default.
+pub fn search_dirs(opt: &Opt) -> Result<Option<Vec<PathBuf>>, Error> {
+    if opt.extra_dirs.is_empty() && opt.repos.is_empty() && !opt.recurse_repos {
+        return Ok(None);
+    }
+    let mut ret = Vec::new();
+    if !opt.extra_dirs.is_empty() || opt.repos.is_empty() || opt.dir != Path::new(".") {
+        ret.push(opt.dir.clone());
     }
+    ret.extend(opt.extra_dirs.iter().cloned());
+    ret.extend(opt.repos.iter().cloned());
 
-    Ok(files)
+    if opt.recurse_repos {
+        let mut repos = Vec::new();
+        for dir in &ret {
+            find_repos(dir, &mut repos)
+                .context(format!("failed to find repositories ({:?})", dir))?;
+        }
+        if opt.verbose {
+            for repo in &repos {
+                eprintln!("Found repository: {}", repo.to_string_lossy());
+            }
+        }
+        ret = repos;
+    }
+    Ok(Some(ret))
 }
 
-pub fn input_files(file: &String, opt: &Opt) -> Result<Vec<String>, Error> {
-    let mut list = Vec::new();
-    if file == &String::from("-") {
-        let stdin = std::io::stdin();
-        for line in stdin.lock().lines() {
-            list.push(String::from(line?));
+/// Returns the root directory of paths in tags file of multiple directories.
+fn search_root(opt: &Opt) -> PathBuf {
+    match opt.root {
+        Some(ref x) => x.clone(),
+        None if opt.recurse_repos && opt.extra_dirs.is_empty() && opt.repos.is_empty() => {
+            opt.dir.clone()
         }
-    } else {
-        for line in fs::read_to_string(file)?.lines() {
-            list.push(String::from(line));
+        None => PathBuf::from("."),
+    }
+}
+
+/// Finds directories including `.git` recursively. Symbolic links are not followed.
+fn find_repos(dir: &Path, repos: &mut Vec<PathBuf>) -> Result<(), Error> {
+    if dir.join(".git").exists() {
+        repos.push(dir.to_path_buf());
+    }
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|x| x.file_name());
+    for entry in entries {
+        if entry.file_type()?.is_dir() && entry.file_name() != ".git" {
+            find_repos(&entry.path(), repos)?;
         }
     }
+    Ok(())
+}
 
-    let mut files = vec![String::from(""); opt.thread];
+/// Returns the path of the directory relative to the root, or the absolute path if it is outside of the root.
+fn relative_dir(opt: &Opt, dir: &Path, root: &Path) -> Result<String, Error> {
+    let dir = dir
+        .canonicalize()
+        .context(format!("failed to find directory ({:?})", dir))?;
+    let root = root
+        .canonicalize()
+        .context(format!("failed to find directory ({:?})", root))?;
+    let ret = dir.strip_prefix(&root).unwrap_or(&dir);
+    let ret = path_from_os_str(opt, ret.as_os_str())?;
+    Ok(ret.replace('\\', "/"))
+}
 
-    for (i, f) in list.iter().enumerate() {
-        files[i % opt.thread].push_str(f);
-        files[i % opt.thread].push_str("\n");
+/// Lists files in each directory with paths relative to the root.
+fn multi_list(opt: &Opt, dirs: &[PathBuf], root: &Path) -> Result<Vec<String>, Error> {
+    let mut ret = Vec::new();
+    for dir in dirs {
+        let mut dir_opt = opt.clone();
+        dir_opt.dir = dir.clone();
+        dir_opt.extra_dirs.clear();
+        dir_opt.repos.clear();
+        let list = vcs_list(&dir_opt).context(format!("failed to get file list ({:?})", dir))?;
+        let prefix = relative_dir(opt, dir, root)?;
+        for f in list {
+            if prefix.is_empty() {
+                ret.push(f);
+            } else {
+                ret.push(format!("{}/{}", prefix, f));
+            }
+        }
     }
+    Ok(ret)
+}
 
-    Ok(files)
+/// Makes the tree of the index in the directory.
+///
+/// Files whose working tree content is the same as the index are linked, and others are extracted by git.
+fn stage_files(opt: &Opt, list: &[String], dir: &Path) -> Result<(), Error> {
+    let unmodified = CmdGit::get_blob_ids(opt)?;
+    let work_tree = opt.dir.canonicalize()?;
+    let mut objects = Vec::new();
+    for f in list.iter().filter(|x| !x.contains('\n')) {
+        if unmodified.contains_key(f) {
+            let path = dir.join(f);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(work_tree.join(f), &path)?;
+            #[cfg(not(unix))]
+            fs::copy(work_tree.join(f), &path)?;
+        } else {
+            objects.push((format!(":./{}", f), f.clone()));
+        }
+    }
+    if opt.verbose {
+        eprintln!("Staged changes: {}", objects.len());
+    }
+    CmdGit::write_objects(opt, &objects, dir)
 }
 
-fn call_ctags(opt: &Opt, files: &[String]) -> Result<Vec<Output>, Error> {
-    Ok(CmdCtags::call(&opt, &files)?)
+/// Removes files larger than `--max-file-size` from the list, and returns the number of them.
+fn skip_large_files(opt: &Opt, list: &mut Vec<String>) -> usize {
+    let len = list.len();
+    list.retain(|x| !is_large_file(opt, x));
+    len - list.len()
 }
 
-fn get_tags_header(opt: &Opt) -> Result<String, Error> {
-    Ok(CmdCtags::get_tags_header(&opt).context("failed to get ctags header")?)
+/// Returns true if the file is larger than `--max-file-size`.
+pub fn is_large_file(opt: &Opt, file: &str) -> bool {
+    let max = match opt.max_file_size {
+        Some(x) => x,
+        None => return false,
+    };
+    let size = fs::metadata(opt.dir.join(file))
+        .map(|x| x.len())
+        .unwrap_or(0);
+    if size > max && opt.verbose {
+        eprintln!("Skip large file: {} ({} bytes)", file, size);
+    }
+    size > max
 }
 
-fn write_tags(opt: &Opt, outputs: &[Output]) -> Result<(), Error> {
-    let mut iters = Vec::new();
-    let mut lines = Vec::new();
-    for o in outputs {
-        let mut iter = if opt.validate_utf8 {
-            str::from_utf8(&o.stdout)?.lines()
-        } else {
-            unsafe { str::from_utf8_unchecked(&o.stdout).lines() }
-        };
-        lines.push(iter.next());
-        iters.push(iter);
+/// Returns true if the file looks minified for `--skip-minified`.
+pub fn is_minified_file(opt: &Opt, file: &str) -> bool {
+    let minified = filter::is_minified(&opt.dir, file);
+    if minified && opt.verbose {
+        eprintln!("Skip minified file: {}", file);
     }
+    minified
+}
 
-    let mut f = if opt.output.to_str().unwrap_or("") == "-" {
-        BufWriter::new(Box::new(stdout()) as Box<dyn Write>)
-    } else {
-        let f = fs::File::create(&opt.output)?;
-        BufWriter::new(Box::new(f) as Box<dyn Write>)
+/// Parses the size with an optional suffix of K, M or G ( ex. 10M ).
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (num, unit) = match s.find(|x: char| !x.is_ascii_digit()) {
+        Some(x) => s.split_at(x),
+        None => (s, ""),
     };
+    let unit = match unit.to_ascii_uppercase().trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return Err(format!("invalid size ({})", s)),
+    };
+    num.parse::<u64>()
+        .ok()
+        .and_then(|x| x.checked_mul(unit))
+        .ok_or_else(|| format!("invalid size ({})", s))
+}
 
-    f.write(get_tags_header(&opt)?.as_bytes())?;
-
-    while lines.iter().any(|x| x.is_some()) {
-        let mut min = 0;
-        for i in 1..lines.len() {
-            if opt.unsorted {
-                if !lines[i].is_none() && lines[min].is_none() {
-                    min = i;
-                }
-            } else {
-                if !lines[i].is_none()
-                    && (lines[min].is_none() || lines[i].unwrap() < lines[min].unwrap())
-                {
-                    min = i;
-                }
+/// Converts a path given by VCS to String.
+///
+/// Non-UTF-8 paths are an error unless `--lossy-paths` is specified,
+/// because ctags can't open the lossily converted path and its tags are silently lost.
+pub fn path_from_bytes(opt: &Opt, path: &[u8]) -> Result<String, Error> {
+    match str::from_utf8(path) {
+        Ok(x) => Ok(String::from(x)),
+        Err(_) if opt.lossy_paths => {
+            let ret = String::from_utf8_lossy(path).into_owned();
+            if opt.verbose {
+                eprintln!("Lossy path: {}", ret);
             }
+            Ok(ret)
         }
-        f.write(lines[min].unwrap().as_bytes())?;
-        f.write("\n".as_bytes())?;
-        lines[min] = iters[min].next();
+        Err(_) => bail!(RunError::InvalidPath {
+            path: String::from_utf8_lossy(path).into_owned(),
+        }),
     }
+}
 
-    Ok(())
+pub fn path_from_os_str(opt: &Opt, path: &std::ffi::OsStr) -> Result<String, Error> {
+    match path.to_str() {
+        Some(x) => Ok(String::from(x)),
+        None => path_from_bytes(opt, path.as_encoded_bytes()),
+    }
 }
 
-// ---------------------------------------------------------------------------------------------------------------------
-// Run
-// ---------------------------------------------------------------------------------------------------------------------
+/// Parses the first version number in the string ( ex. "git version 2.39.2" -> [2, 39, 2] ).
+pub fn parse_version(s: &str) -> Option<Vec<u64>> {
+    let token = s
+        .split(|x: char| x.is_whitespace() || x == '(' || x == ',')
+        .find(|x| x.starts_with(|x: char| x.is_ascii_digit()))?;
+    token
+        .split('.')
+        .map_while(|x| {
+            let digits: String = x.chars().take_while(|x| x.is_ascii_digit()).collect();
+            digits.parse().ok()
+        })
+        .map(Some)
+        .collect()
+}
 
-pub fn run_opt(opt: &Opt) -> Result<(), Error> {
-    if opt.config {
-        let toml = toml::to_string(&opt)?;
-        println!("{}", toml);
-        return Ok(());
+fn check_version(bin: &Path, found: &str, required: &str) -> Result<(), Error> {
+    let ok = match (parse_version(found), parse_version(required)) {
+        (Some(found), Some(required)) => found >= required,
+        _ => false,
+    };
+    if !ok {
+        bail!(RunError::OldVersion {
+            bin: bin.to_string_lossy().into_owned(),
+            found: if found.is_empty() {
+                String::from("unknown version")
+            } else {
+                String::from(found)
+            },
+            required: String::from(required),
+        });
     }
+    Ok(())
+}
 
-    match opt.completion {
-        Some(ref x) => {
-            let shell = match x.as_str() {
-                "bash" => clap::Shell::Bash,
-                "fish" => clap::Shell::Fish,
-                "zsh" => clap::Shell::Zsh,
-                "powershell" => clap::Shell::PowerShell,
-                _ => clap::Shell::Bash,
-            };
-            Opt::clap().gen_completions("ptags", shell, "./");
-            return Ok(());
-        }
-        None => {}
+/// Checks versions of ctags and git before calling them.
+///
+/// ctags and git are not probed unless a minimum version is required.
+fn check_versions(opt: &Opt) -> Result<(), Error> {
+    if let Some(ref required) = opt.min_ctags_version {
+        let found = CmdCtags::info(opt).version;
+        check_version(&opt.bin_ctags, &found, required)?;
     }
 
-    let files;
-    let time_git_files;
-    if let Some(ref list) = opt.list {
-        files = input_files(list, &opt).context("failed to get file list")?;
-        time_git_files = Duration::seconds(0);
-    } else {
+    // `git ls-files --recurse-submodules` is available from git 2.11.
+    let use_git = opt.list.is_none() && !opt.no_vcs && opt.vcs == "git";
+    let required = match opt.min_git_version {
+        Some(ref x) => Some(x.as_str()),
+        None if opt.include_submodule => Some("2.11"),
+        None => None,
+    };
+    if let (true, Some(required)) = (use_git, required) {
+        let found = CmdGit::version(opt).unwrap_or_default();
+        check_version(&opt.bin_git, &found, required)?;
+    }
+    Ok(())
+}
+
+pub fn git_files(opt: &Opt) -> Result<Vec<String>, Error> {
+    let list = CmdGit::get_files(&opt)?;
+    Ok(split_files(&list, opt))
+}
+
+pub fn input_files(file: &String, opt: &Opt) -> Result<Vec<String>, Error> {
+    let list = input_list(file, opt)?;
+    Ok(split_files(&list, opt))
+}
+
+fn input_list(file: &String, opt: &Opt) -> Result<Vec<String>, Error> {
+    let mut buf = Vec::new();
+    if file == &String::from("-") {
+        std::io::stdin().lock().read_to_end(&mut buf)?;
+    } else {
+        buf = fs::read(file)?;
+    }
+    let mut list = Vec::new();
+    for line in buf.split(|x| *x == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if !line.is_empty() {
+            list.push(path_from_bytes(opt, line)?);
+        }
+    }
+    Ok(list)
+}
+
+pub fn split_files(list: &[String], opt: &Opt) -> Vec<String> {
+    // ctags reads the file list line by line, so a path including newline can't be passed.
+    let filtered: Vec<String>;
+    let list = if list.iter().any(|x| x.contains('\n')) {
+        filtered = list
+            .iter()
+            .filter(|x| {
+                if x.contains('\n') && !opt.quiet {
+                    eprintln!("skipped a file whose name contains newline ({:?})", x);
+                }
+                !x.contains('\n')
+            })
+            .cloned()
+            .collect();
+        filtered.as_slice()
+    } else {
+        list
+    };
+
+    // Small lists are split to all threads, and large lists are split to chunks of the batch size.
+    // Each file is a chunk with `--profile` to measure the time of each file.
+    let per_thread = list.len().div_ceil(opt.threads());
+    let size = if opt.profile {
+        1
+    } else {
+        std::cmp::max(1, std::cmp::min(opt.batch_size, per_thread))
+    };
+
+    let chunks: Vec<Vec<&String>> = if opt.balance_by_size {
+        balance_by_size(list, opt, list.len().div_ceil(size))
+    } else {
+        list.chunks(size).map(|x| x.iter().collect()).collect()
+    };
+
+    let mut files = Vec::new();
+    for chunk in chunks {
+        let mut file = String::new();
+        for f in chunk {
+            file.push_str(f);
+            file.push('\n');
+        }
+        files.push(file);
+    }
+
+    files
+}
+
+/// Distributes files to chunks by longest-processing-time-first so that every chunk has roughly equal bytes.
+fn balance_by_size<'a>(list: &'a [String], opt: &Opt, count: usize) -> Vec<Vec<&'a String>> {
+    let mut sizes: Vec<(u64, &String)> = list
+        .iter()
+        .map(|x| {
+            (
+                fs::metadata(opt.dir.join(x)).map(|x| x.len()).unwrap_or(0),
+                x,
+            )
+        })
+        .collect();
+    sizes.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(b.1)));
+
+    let mut chunks = vec![Vec::new(); count];
+    let mut heap: BinaryHeap<Reverse<(u64, usize)>> = (0..count).map(|i| Reverse((0, i))).collect();
+    for (size, f) in sizes {
+        if let Some(Reverse((total, i))) = heap.pop() {
+            chunks[i].push(f);
+            heap.push(Reverse((total + size, i)));
+        }
+    }
+
+    for chunk in &mut chunks {
+        chunk.sort();
+    }
+    chunks
+}
+
+pub fn call_ctags(opt: &Opt, files: &[String]) -> Result<Vec<CtagsOutput>, Error> {
+    Ok(CmdCtags::call(&opt, &files)?)
+}
+
+/// How tags are generated within `--max-memory`.
+#[derive(Debug, PartialEq)]
+struct MemoryPlan {
+    /// Number of concurrent ctags processes
+    jobs: usize,
+    /// True if tags held in memory like the base of incremental update are spilled into temporary files
+    spill: bool,
+}
+
+/// Decides the plan from bytes of files in each chunk, and bytes of tags held in memory.
+///
+/// A ctags process is estimated to hold as many bytes as files of the chunk to sort tags.
+/// Processes are limited so that the largest chunks running at once fit in the budget, but one is always allowed.
+fn plan_memory(budget: u64, chunks: &[u64], in_memory: u64, jobs: usize) -> MemoryPlan {
+    let spill = in_memory + chunks.iter().sum::<u64>() > budget;
+    let budget = if spill { budget } else { budget - in_memory };
+
+    let mut chunks = chunks.to_vec();
+    chunks.sort_by_key(|x| Reverse(*x));
+    let mut total = 0;
+    let mut fit = 0;
+    for x in chunks.iter().take(jobs) {
+        total += x;
+        if total > budget {
+            break;
+        }
+        fit += 1;
+    }
+    MemoryPlan {
+        jobs: fit.max(1),
+        spill,
+    }
+}
+
+/// Returns bytes of files in each chunk.
+fn chunk_bytes(opt: &Opt, files: &[String]) -> Vec<u64> {
+    files
+        .iter()
+        .map(|x| {
+            x.lines()
+                .filter_map(|x| fs::metadata(opt.dir.join(x)).ok())
+                .map(|x| x.len())
+                .sum()
+        })
+        .collect()
+}
+
+/// Writes tags held in memory into a temporary file to be read by the merge.
+fn spill(buf: &[u8]) -> Result<fs::File, Error> {
+    let mut f = tempfile::tempfile()?;
+    f.write_all(buf)?;
+    f.seek(SeekFrom::Start(0))?;
+    Ok(f)
+}
+
+pub fn get_tags_header(opt: &Opt) -> Result<String, Error> {
+    let header = CmdCtags::get_tags_header(opt).context("failed to get ctags header")?;
+    Ok(patch_header(opt, &header, &provenance(opt)))
+}
+
+/// Prefix of pseudo-tags written by ptags.
+const TAG_PTAGS: &str = "TAG_PTAGS_";
+
+/// Pseudo-tag recording the command line of ptags.
+const TAG_PTAGS_INVOCATION: &str = "TAG_PTAGS_INVOCATION";
+
+/// Pseudo-tag recording options different from the default as JSON, including options from config files.
+pub const TAG_PTAGS_OPTIONS: &str = "TAG_PTAGS_OPTIONS";
+
+/// Pseudo-tag recording the version of ptags.
+pub const TAG_PTAGS_VERSION: &str = "TAG_PTAGS_VERSION";
+
+/// Pseudo-tag of ptags as `(name, value, comment)`.
+type PseudoTag = (&'static str, String, String);
+
+/// Options which don't change tags but how ptags runs, omitted from `!_TAG_PTAGS_OPTIONS` by `--reproducible`.
+const RUN_OPTIONS: &[&str] = &[
+    "thread",
+    "jobs",
+    "max_memory",
+    "balance_by_size",
+    "pipeline",
+    "persistent_workers",
+    "files_via",
+    "pipe_size",
+    "no_set_pipe_size",
+    "stat",
+    "profile",
+    "profile_top",
+    "trace",
+    "verbose",
+    "quiet",
+    "warnings_log",
+    "log_level",
+    "log_format",
+    "log_file",
+    "audit_log",
+];
+
+/// Returns pseudo-tags recording how the tags file is generated, so that stale or foreign tags files can be diagnosed.
+///
+/// The command line and the timestamp are omitted by `--reproducible`,
+/// and the recorded options exclude ones like `--thread` which don't change tags.
+fn provenance(opt: &Opt) -> Vec<PseudoTag> {
+    let version = String::from(env!("CARGO_PKG_VERSION"));
+    let mut ret = Vec::new();
+    if !opt.reproducible {
+        let args: Vec<String> = std::env::args().map(|x| shell_quote(&x)).collect();
+        ret.push((
+            TAG_PTAGS_INVOCATION,
+            args.join(" "),
+            format!("ptags {}", version),
+        ));
+    }
+    let mut options = effective_options(opt);
+    if opt.reproducible {
+        if let serde_json::Value::Object(ref mut map) = options {
+            map.retain(|key, _| !RUN_OPTIONS.contains(&key.as_str()));
+        }
+    }
+    ret.push((TAG_PTAGS_VERSION, version, String::new()));
+    ret.push((
+        TAG_PTAGS_OPTIONS,
+        options.to_string(),
+        String::from("effective options"),
+    ));
+    if opt.list.is_none() && !opt.no_vcs && opt.vcs == "git" {
+        let rev = opt.rev.as_deref().unwrap_or("HEAD");
+        if let Ok(commit) = CmdGit::rev_parse(opt, rev) {
+            ret.push(("TAG_PTAGS_GIT_COMMIT", commit, String::from(rev)));
+        }
+    }
+    if !opt.reproducible {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or(0);
+        ret.push(("TAG_PTAGS_TIMESTAMP", format_timestamp(now), String::new()));
+    }
+    ret
+}
+
+/// Returns options different from the default as a JSON object.
+fn effective_options(opt: &Opt) -> serde_json::Value {
+    let default = serde_json::to_value(Opt::from_iter(["ptags"].iter())).unwrap_or_default();
+    let mut ret = serde_json::Map::new();
+    if let serde_json::Value::Object(map) = serde_json::to_value(opt).unwrap_or_default() {
+        for (key, value) in map {
+            if value != default[&key] {
+                ret.insert(key, value);
+            }
+        }
+    }
+    serde_json::Value::Object(ret)
+}
+
+/// Formats seconds since the epoch like `2024-01-02T03:04:05Z`.
+pub fn format_timestamp(secs: u64) -> String {
+    // Converts days since the epoch to the civil date in the proleptic Gregorian calendar.
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let time = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// Patches pseudo-tags generated by ctags for an empty file to reflect the actual output of ptags.
+///
+/// `!_TAG_FILE_SORTED` follows `--unsorted`, and pseudo-tags of ptags are appended.
+/// The header is not changed if ctags doesn't output pseudo-tags.
+fn patch_header(opt: &Opt, header: &str, ptags: &[PseudoTag]) -> String {
+    if header.is_empty() {
+        return String::new();
+    }
+    let sorted = if opt.unsorted { "0" } else { "1" };
+
+    let mut ret = String::new();
+    for line in header.lines() {
+        if opt.format == "json" {
+            if let Ok(mut tag) = serde_json::from_str::<serde_json::Value>(line) {
+                if tag["_type"] == "ptag" && tag["name"] == "TAG_FILE_SORTED" {
+                    tag["path"] = serde_json::Value::from(sorted);
+                    ret.push_str(&tag.to_string());
+                    ret.push('\n');
+                    continue;
+                }
+            }
+        } else if let Some(rest) = line.strip_prefix("!_TAG_FILE_SORTED\t") {
+            let comment = rest.split_once('\t').map(|x| x.1).unwrap_or("//");
+            ret.push_str(&format!("!_TAG_FILE_SORTED\t{}\t{}\n", sorted, comment));
+            continue;
+        }
+        ret.push_str(line);
+        ret.push('\n');
+    }
+
+    for (name, value, comment) in ptags {
+        if opt.format == "json" {
+            let tag = serde_json::json!({
+                "_type": "ptag",
+                "name": name,
+                "path": value,
+                "pattern": comment,
+            });
+            ret.push_str(&tag.to_string());
+        } else {
+            ret.push_str(&format!(
+                "!_{}\t{}\t/{}/",
+                name,
+                escape_pseudo_tag(value),
+                comment
+            ));
+        }
+        ret.push('\n');
+    }
+    ret
+}
+
+/// Escapes the value of a pseudo-tag which can't contain tabs and newlines.
+fn escape_pseudo_tag(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+/// Returns the name of the tag line, or `None` if the line is a pseudo-tag.
+pub fn tag_name(opt: &Opt, line: &[u8]) -> Option<String> {
+    if opt.format == "json" {
+        let tag: serde_json::Value = serde_json::from_slice(line).ok()?;
+        if tag["_type"] != "tag" {
+            return None;
+        }
+        tag["name"].as_str().map(String::from)
+    } else {
+        if line.starts_with(b"!_") {
+            return None;
+        }
+        let name = line.split(|x| *x == b'\t').next().unwrap_or(b"");
+        Some(String::from_utf8_lossy(name).into_owned())
+    }
+}
+
+/// Returns the file of the tag line, or `None` if the line is a pseudo-tag.
+pub fn tag_file(opt: &Opt, line: &[u8]) -> Option<String> {
+    if opt.format == "json" {
+        let tag: serde_json::Value = serde_json::from_slice(line).ok()?;
+        if tag["_type"] != "tag" {
+            return None;
+        }
+        tag["path"].as_str().map(String::from)
+    } else {
+        if line.starts_with(b"!_") {
+            return None;
+        }
+        let file = line.split(|x| *x == b'\t').nth(1).unwrap_or(b"");
+        Some(String::from_utf8_lossy(file).into_owned())
+    }
+}
+
+/// Returns the kind of the tag line, or `None` if the line is a pseudo-tag or has no kind.
+pub fn tag_kind(opt: &Opt, line: &[u8]) -> Option<String> {
+    if opt.format == "json" {
+        let tag: serde_json::Value = serde_json::from_slice(line).ok()?;
+        if tag["_type"] != "tag" {
+            return None;
+        }
+        tag["kind"].as_str().map(String::from)
+    } else {
+        if line.starts_with(b"!_") {
+            return None;
+        }
+        let pos = line.windows(3).position(|x| x == b";\"\t")?;
+        let field = line[pos + 3..].split(|x| *x == b'\t').next()?;
+        let kind = field.strip_prefix(b"kind:").unwrap_or(field);
+        if kind.contains(&b':') {
+            return None;
+        }
+        Some(String::from_utf8_lossy(kind).into_owned())
+    }
+}
+
+/// Returns the language of the tag line from the `language` field, or `None` if the line has no language.
+pub fn tag_language(opt: &Opt, line: &[u8]) -> Option<String> {
+    if opt.format == "json" {
+        let tag: serde_json::Value = serde_json::from_slice(line).ok()?;
+        if tag["_type"] != "tag" {
+            return None;
+        }
+        tag["language"].as_str().map(String::from)
+    } else {
+        if line.starts_with(b"!_") {
+            return None;
+        }
+        let pos = line.windows(3).position(|x| x == b";\"\t")?;
+        line[pos + 3..]
+            .split(|x| *x == b'\t')
+            .find_map(|x| x.strip_prefix(b"language:"))
+            .map(|x| String::from_utf8_lossy(x).into_owned())
+    }
+}
+
+/// Returns the line number of the tag line, or `None` if the tag is addressed by a pattern without `line:` field.
+pub fn tag_line(opt: &Opt, line: &[u8]) -> Option<usize> {
+    if opt.format == "json" {
+        let tag: serde_json::Value = serde_json::from_slice(line).ok()?;
+        if tag["_type"] != "tag" {
+            return None;
+        }
+        tag["line"].as_u64().map(|x| x as usize)
+    } else {
+        if line.starts_with(b"!_") {
+            return None;
+        }
+        let pos = line.windows(3).position(|x| x == b";\"\t")?;
+        let address = line[..pos].split(|x| *x == b'\t').nth(2)?;
+        let field = line[pos + 3..]
+            .split(|x| *x == b'\t')
+            .find_map(|x| x.strip_prefix(b"line:"));
+        str::from_utf8(field.unwrap_or(address)).ok()?.parse().ok()
+    }
+}
+
+/// Filter of tag lines by `--drop-kind` and `--filter-regex`.
+struct TagFilter<'a> {
+    kinds: &'a [String],
+    regex: RegexSet,
+}
+
+impl<'a> TagFilter<'a> {
+    fn new(opt: &'a Opt) -> Result<Option<TagFilter<'a>>, Error> {
+        if opt.drop_kind.is_empty() && opt.filter_regex.is_empty() {
+            return Ok(None);
+        }
+        let regex = RegexSet::new(&opt.filter_regex).context("failed to parse --filter-regex")?;
+        Ok(Some(TagFilter {
+            kinds: &opt.drop_kind,
+            regex,
+        }))
+    }
+
+    /// Returns true if the line should be dropped. Pseudo-tags are always kept.
+    fn drops(&self, opt: &Opt, line: &[u8]) -> bool {
+        if tag_name(opt, line).is_none() {
+            return false;
+        }
+        if !self.kinds.is_empty() {
+            if let Some(kind) = tag_kind(opt, line) {
+                if self.kinds.contains(&kind) {
+                    return true;
+                }
+            }
+        }
+        self.regex.is_match(line)
+    }
+}
+
+/// Tags whose invalid UTF8 sequences are replaced by `--lossy-utf8`.
+struct LossyReport {
+    /// `None` means stderr
+    path: Option<PathBuf>,
+    /// File and line of affected tags, or the whole line of pseudo-tags
+    tags: Vec<(String, Option<usize>)>,
+}
+
+impl LossyReport {
+    fn new(opt: &Opt) -> Option<LossyReport> {
+        if !opt.lossy_utf8 {
+            return None;
+        }
+        Some(LossyReport {
+            path: LossyReport::path(opt),
+            tags: Vec::new(),
+        })
+    }
+
+    /// Returns the report file, or `None` if tags are written to stdout.
+    fn path(opt: &Opt) -> Option<PathBuf> {
+        if opt.lossy_utf8_report.is_some() {
+            return opt.lossy_utf8_report.clone();
+        }
+        if opt.output == Path::new("-") || opt.output_cmd.is_some() {
+            return None;
+        }
+        let mut path = opt.output.clone().into_os_string();
+        path.push(".lossy-utf8");
+        Some(PathBuf::from(path))
+    }
+
+    /// Returns the line whose invalid sequences are replaced, and records the tag if replaced.
+    fn replace<'a>(&mut self, opt: &Opt, line: &'a [u8]) -> Cow<'a, [u8]> {
+        if str::from_utf8(line).is_ok() {
+            return Cow::Borrowed(line);
+        }
+        let replaced = String::from_utf8_lossy(line).into_owned();
+        let tag = match tag_file(opt, replaced.as_bytes()) {
+            Some(file) => (file, tag_line(opt, replaced.as_bytes())),
+            None => (replaced.clone(), None),
+        };
+        self.tags.push(tag);
+        Cow::Owned(replaced.into_bytes())
+    }
+
+    /// Writes `<file>:<line>` of affected tags in order of files and lines.
+    ///
+    /// The report of the previous run is removed if no tag is affected.
+    fn write(mut self, opt: &Opt) -> Result<(), Error> {
+        if self.tags.is_empty() {
+            if let Some(ref path) = self.path {
+                let _ = fs::remove_file(path);
+            }
+            return Ok(());
+        }
+        let count = self.tags.len();
+        self.tags.sort();
+        self.tags.dedup();
+
+        let mut f: Box<dyn Write> = match self.path {
+            Some(ref path) => Box::new(BufWriter::new(
+                fs::File::create(path).context(format!("failed to create file ({:?})", path))?,
+            )),
+            None => Box::new(std::io::stderr()),
+        };
+        for (file, line) in &self.tags {
+            match line {
+                Some(line) => writeln!(f, "{}:{}", file, line)?,
+                None => writeln!(f, "{}", file)?,
+            }
+        }
+        f.flush()?;
+
+        if !opt.quiet {
+            if let Some(ref path) = self.path {
+                eprintln!(
+                    "replaced invalid UTF8 sequences of {} tags ({:?})",
+                    count, path
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Rewriter of the file column by `--tag-relative`, `--slash`, `--strip-prefix`, `--path-prefix` and `--map-path`.
+struct PathRewriter {
+    /// Absolute `DIR` and the directory of the output file which paths are made relative to
+    base: Option<(PathBuf, Option<PathBuf>)>,
+    slash: bool,
+    strip_prefix: Option<String>,
+    path_prefix: Option<String>,
+    map_path: Vec<(String, String)>,
+}
+
+impl PathRewriter {
+    fn new(opt: &Opt) -> Result<Option<PathRewriter>, Error> {
+        if opt.tag_relative.is_none()
+            && !opt.slash
+            && opt.strip_prefix.is_none()
+            && opt.path_prefix.is_none()
+            && opt.map_path.is_empty()
+        {
+            return Ok(None);
+        }
+        let mut map_path = Vec::new();
+        for x in &opt.map_path {
+            match x.split_once('=') {
+                Some((from, to)) if !from.is_empty() => {
+                    map_path.push((String::from(from), String::from(to)))
+                }
+                _ => bail!("invalid value of --map-path ({}), FROM=TO is required", x),
+            }
+        }
+        let base = match opt.tag_relative.as_deref() {
+            Some(mode) => {
+                let dir = opt
+                    .dir
+                    .canonicalize()
+                    .context(format!("failed to find directory ({:?})", opt.dir))?;
+                let output = if mode == "no" {
+                    None
+                } else if opt.output == Path::new("-") || opt.output_cmd.is_some() {
+                    Some(std::env::current_dir()?)
+                } else {
+                    let parent = match opt.output.parent() {
+                        Some(x) if !x.as_os_str().is_empty() => x.to_path_buf(),
+                        _ => PathBuf::from("."),
+                    };
+                    Some(parent.canonicalize()?)
+                };
+                Some((dir, output))
+            }
+            None => None,
+        };
+        Ok(Some(PathRewriter {
+            base,
+            slash: opt.slash,
+            strip_prefix: opt.strip_prefix.clone(),
+            path_prefix: opt.path_prefix.clone(),
+            map_path,
+        }))
+    }
+
+    fn rewrite_path(&self, opt: &Opt, path: &str) -> String {
+        let mut ret = String::from(path);
+        if let Some((ref dir, ref output)) = self.base {
+            let abs = dir.join(path);
+            ret = match output {
+                Some(output) if opt.tag_relative.as_deref() == Some("always") => {
+                    relative_path(&abs, output)
+                }
+                Some(output) => match abs.strip_prefix(output) {
+                    Ok(x) => x.to_string_lossy().into_owned(),
+                    Err(_) => abs.to_string_lossy().into_owned(),
+                },
+                None => abs.to_string_lossy().into_owned(),
+            };
+        }
+        // Separators are normalized before prefixes, which are given with forward slashes.
+        if self.slash {
+            ret = ret.replace('\\', "/");
+        }
+        if let Some(ref prefix) = self.strip_prefix {
+            if let Some(x) = ret.strip_prefix(prefix.as_str()) {
+                ret = String::from(x);
+            }
+        }
+        if let Some(ref prefix) = self.path_prefix {
+            ret = format!("{}{}", prefix, ret);
+        }
+        // The first mapping whose FROM matches with leading path components is applied.
+        for (from, to) in &self.map_path {
+            if let Some(rest) = ret.strip_prefix(from.as_str()) {
+                if rest.is_empty() || rest.starts_with('/') || from.ends_with('/') {
+                    ret = format!("{}{}", to, rest);
+                    break;
+                }
+            }
+        }
+        ret
+    }
+
+    /// Returns the tag line whose file column is rewritten. Pseudo-tags are not changed.
+    fn rewrite(&self, opt: &Opt, line: &[u8]) -> Vec<u8> {
+        if opt.format == "json" {
+            if let Ok(mut tag) = serde_json::from_slice::<serde_json::Value>(line) {
+                if let (true, Some(path)) = (tag["_type"] == "tag", tag["path"].as_str()) {
+                    tag["path"] = serde_json::Value::from(self.rewrite_path(opt, path));
+                    return tag.to_string().into_bytes();
+                }
+            }
+            return line.to_vec();
+        }
+        if line.starts_with(b"!_") {
+            return line.to_vec();
+        }
+        let mut fields = line.splitn(3, |x| *x == b'\t');
+        let (name, path, rest) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(name), Some(path), Some(rest)) => (name, path, rest),
+            _ => return line.to_vec(),
+        };
+        let path = self.rewrite_path(opt, &String::from_utf8_lossy(path));
+        let mut ret = Vec::with_capacity(line.len() + path.len());
+        ret.extend_from_slice(name);
+        ret.push(b'\t');
+        ret.extend_from_slice(path.as_bytes());
+        ret.push(b'\t');
+        ret.extend_from_slice(rest);
+        ret
+    }
+}
+
+/// Returns the path relative to the base directory using `..` if necessary. Both must be absolute.
+fn relative_path(path: &Path, base: &Path) -> String {
+    let path: Vec<_> = path.components().collect();
+    let base: Vec<_> = base.components().collect();
+    let common = path.iter().zip(&base).take_while(|(a, b)| a == b).count();
+    let mut ret = PathBuf::new();
+    for _ in common..base.len() {
+        ret.push("..");
+    }
+    for c in &path[common..] {
+        ret.push(c);
+    }
+    ret.to_string_lossy().into_owned()
+}
+
+fn read_base_tags(opt: &Opt, drop: &HashSet<&str>) -> Result<Vec<u8>, Error> {
+    let mut ret = Vec::new();
+    let base = fs::read(&opt.output)?;
+    for line in base.split(|x| *x == b'\n') {
+        if line.is_empty() {
+            continue;
+        }
+        match tag_file(opt, line) {
+            Some(ref file) if !drop.contains(file.as_str()) => (),
+            _ => continue,
+        }
+        ret.extend_from_slice(line);
+        ret.push(b'\n');
+    }
+    Ok(ret)
+}
+
+fn get_cache(opt: &Opt) -> Result<Cache, Error> {
+    let dir = match opt.cache_dir {
+        Some(ref x) => x.clone(),
+        None => default_cache_dir(opt)?,
+    };
+    if opt.verbose {
+        eprintln!("Cache: {}", dir.to_string_lossy());
+    }
+    let mut key: Vec<String> = CmdCtags::get_launcher(opt)
+        .iter()
+        .map(|x| x.to_string_lossy().into_owned())
+        .collect();
+    key.append(&mut CmdCtags::get_args(opt));
+    Ok(Cache::new(&dir, &key))
+}
+
+/// Returns the default cache directory.
+///
+/// In a git repository, the cache is placed in the common git directory so that all worktrees of the repository share it.
+fn default_cache_dir(opt: &Opt) -> Result<PathBuf, Error> {
+    if !opt.no_vcs && opt.vcs == "git" && CmdGit::is_work_tree(opt)? {
+        if opt.verbose && CmdGit::is_linked_worktree(opt)? {
+            eprintln!("Linked worktree: share cache with other worktrees");
+        }
+        return Ok(CmdGit::common_dir(opt)?.join("ptags").join("cache"));
+    }
+    match dirs::cache_dir() {
+        Some(x) => Ok(x.join("ptags")),
+        None => bail!("failed to find cache directory"),
+    }
+}
+
+/// Returns cached tags and files which are not cached.
+fn read_cache(
+    opt: &Opt,
+    cache: &Cache,
+    oids: &HashMap<String, String>,
+    list: Vec<String>,
+) -> (Vec<u8>, Vec<String>) {
+    let mut cached = Vec::new();
+    let mut miss = Vec::new();
+    for f in list {
+        match oids.get(&f).and_then(|oid| cache.get(&f, oid)) {
+            Some(mut x) => cached.append(&mut x),
+            None => miss.push(f),
+        }
+    }
+
+    if !opt.unsorted {
+        let mut lines: Vec<&[u8]> = cached
+            .split(|x| *x == b'\n')
+            .filter(|x| !x.is_empty())
+            .collect();
+        lines.sort_unstable();
+        let mut sorted = Vec::with_capacity(cached.len());
+        for line in lines {
+            sorted.extend_from_slice(line);
+            sorted.push(b'\n');
+        }
+        cached = sorted;
+    }
+
+    (cached, miss)
+}
+
+fn write_cache(
+    opt: &Opt,
+    cache: &Cache,
+    oids: &HashMap<String, String>,
+    list: &[String],
+    outputs: &[CtagsOutput],
+) -> Result<(), Error> {
+    let mut tags: HashMap<String, Vec<u8>> = HashMap::new();
+    let mut skip: HashSet<&str> = HashSet::new();
+    let mut line = Vec::new();
+    for o in outputs {
+        // Files which ctags failed or timed out on are not cached to retry them next time.
+        skip.extend(o.timed_out.iter().map(|x| x.as_str()));
+        if !o.status.success() {
+            skip.extend(o.list.lines());
+            continue;
+        }
+        let mut reader = o.reader()?;
+        while read_tag_line(opt, &mut reader, &mut line)? {
+            if let Some(file) = tag_file(opt, &line) {
+                let buf = tags.entry(file).or_default();
+                buf.extend_from_slice(&line);
+                buf.push(b'\n');
+            }
+        }
+    }
+
+    // Files without any tag are also cached to skip them next time.
+    for f in list {
+        if skip.contains(f.as_str()) {
+            continue;
+        }
+        if let Some(oid) = oids.get(f) {
+            let buf = tags.get(f).map(|x| x.as_slice()).unwrap_or(b"");
+            cache.put(f, oid, buf)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_tag_line(opt: &Opt, input: &mut dyn BufRead, buf: &mut Vec<u8>) -> Result<bool, Error> {
+    buf.clear();
+    if input.read_until(b'\n', buf)? == 0 {
+        return Ok(false);
+    }
+    if buf.last() == Some(&b'\n') {
+        buf.pop();
+        if buf.last() == Some(&b'\r') {
+            buf.pop();
+        }
+    }
+    if opt.validate_utf8 && !opt.lossy_utf8 {
+        str::from_utf8(buf)?;
+    }
+    Ok(true)
+}
+
+pub fn write_tags(
+    opt: &Opt,
+    header: &str,
+    inputs: Vec<Box<dyn BufRead + '_>>,
+) -> Result<usize, Error> {
+    if let Some(shards) = opt.shards {
+        return write_sharded_tags(opt, shards, header, inputs);
+    }
+    if opt.split_by_dir.is_some() || opt.split_by_lang {
+        return write_split_tags(opt, header, inputs);
+    }
+
+    let filter = TagFilter::new(opt)?;
+    let rewriter = PathRewriter::new(opt)?;
+    let mut lossy = LossyReport::new(opt);
+    let is_stdout = opt.output.to_str().unwrap_or("") == "-" || opt.output_cmd.is_some();
+    let mut output = if is_stdout {
+        None
+    } else {
+        Some(AtomicFile::create(&opt.output)?)
+    };
+
+    let count = if output_pipe(opt).is_some() {
+        write_tags_via_cmd(
+            opt,
+            output.as_ref(),
+            header,
+            inputs,
+            &filter,
+            &rewriter,
+            &mut lossy,
+        )?
+    } else {
+        // Rust writes bytes to stdout as is without CRLF translation even on Windows,
+        // so line endings are given only by `--eol`.
+        let f = match output {
+            Some(ref mut x) => Box::new(x) as Box<dyn Write>,
+            None => Box::new(stdout()) as Box<dyn Write>,
+        };
+        let mut f = BufWriter::new(f);
+        let count = merge_tags(opt, &mut f, header, inputs, &filter, &rewriter, &mut lossy)?;
+        f.flush()?;
+        count
+    };
+
+    if let Some(output) = output {
+        output.commit()?;
+    }
+    if let Some(lossy) = lossy {
+        lossy.write(opt)?;
+    }
+    Ok(count)
+}
+
+/// Returns the command which tags are piped into by `--filter-cmd` or `--output-cmd`.
+///
+/// `--output-cmd` receives the output of `--filter-cmd` through the pipe if both are given.
+fn output_pipe(opt: &Opt) -> Option<String> {
+    match (&opt.filter_cmd, &opt.output_cmd) {
+        (Some(x), Some(y)) => Some(format!("{} | {}", x, y)),
+        (Some(x), None) | (None, Some(x)) => Some(x.clone()),
+        (None, None) => None,
+    }
+}
+
+/// Prefix of temporary files written beside the output.
+pub const TEMP_PREFIX: &str = ".ptags";
+
+/// Returns the directory of the output, where temporary files are written.
+fn output_dir(opt: &Opt) -> PathBuf {
+    match opt.output.parent() {
+        Some(x) if !x.as_os_str().is_empty() => x.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// Tags file written into a temporary file in the same directory, and renamed into place by `commit`.
+///
+/// The temporary file is removed on errors and interrupts, so that a failed run never leaves a truncated file
+/// and the previous file is kept.
+struct AtomicFile {
+    path: PathBuf,
+    /// `None` after `commit`
+    tmp: Option<tempfile::NamedTempFile>,
+}
+
+impl AtomicFile {
+    fn create(path: &Path) -> Result<AtomicFile, Error> {
+        let dir = match path.parent() {
+            Some(x) if !x.as_os_str().is_empty() => x,
+            _ => Path::new("."),
+        };
+        let mut builder = tempfile::Builder::new();
+        builder.prefix(TEMP_PREFIX);
+        // Same permissions as `File::create` instead of 0o600 of temporary files.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            builder.permissions(fs::Permissions::from_mode(0o666));
+        }
+        let tmp = builder
+            .tempfile_in(dir)
+            .context(format!("failed to write file ({:?})", path))?;
+        signal::register_file(tmp.path());
+        Ok(AtomicFile {
+            path: path.to_path_buf(),
+            tmp: Some(tmp),
+        })
+    }
+
+    fn file(&self) -> &fs::File {
+        self.tmp.as_ref().unwrap().as_file()
+    }
+
+    fn commit(mut self) -> Result<(), Error> {
+        let tmp = self.tmp.take().unwrap();
+        let tmp_path = tmp.path().to_path_buf();
+        let ret = tmp.persist(&self.path);
+        signal::unregister_file(&tmp_path);
+        ret.map_err(|x| x.error)
+            .context(format!("failed to write file ({:?})", self.path))?;
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.tmp.as_mut().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.tmp.as_mut().unwrap().flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if let Some(ref tmp) = self.tmp {
+            signal::unregister_file(tmp.path());
+        }
+    }
+}
+
+/// Pipes the merged tags into `--filter-cmd` or `--output-cmd`.
+///
+/// The stdout of the command becomes the output, or is inherited with `--output-cmd`.
+fn write_tags_via_cmd(
+    opt: &Opt,
+    output: Option<&AtomicFile>,
+    header: &str,
+    inputs: Vec<Box<dyn BufRead + '_>>,
+    filter: &Option<TagFilter>,
+    rewriter: &Option<PathRewriter>,
+    lossy: &mut Option<LossyReport>,
+) -> Result<usize, Error> {
+    let cmd = output_pipe(opt).unwrap_or_default();
+    let cmd = cmd.as_str();
+    let stdout = match output {
+        Some(x) => Stdio::from(x.file().try_clone()?),
+        None => Stdio::inherit(),
+    };
+    let started = Instant::now();
+    let mut command = shell(cmd);
+    // Commands like `sort` in the pipe order lines by the locale.
+    if opt.reproducible {
+        command.env("LC_ALL", "C");
+    }
+    let mut child = signal::isolate(&mut command)
+        .stdin(Stdio::piped())
+        .stdout(stdout)
+        .spawn()
+        .context(format!("failed to call command ({})", cmd))?;
+    let pid = child.id();
+    signal::register_child(pid);
+
+    let (count, bytes_in) = {
+        let mut f = BufWriter::new(Counter::new(child.stdin.take().unwrap()));
+        let count =
+            merge_tags(opt, &mut f, header, inputs, filter, rewriter, lossy).and_then(|x| {
+                f.flush()?;
+                Ok(x)
+            });
+        (count, f.get_ref().bytes)
+    };
+    let status = child.wait();
+    signal::unregister_child(pid);
+    let bytes_out = output
+        .and_then(|x| x.file().metadata().ok())
+        .map(|x| x.len())
+        .unwrap_or(0);
+    audit::record(
+        &command,
+        started,
+        status.as_ref().ok().copied(),
+        bytes_in,
+        bytes_out,
+    );
+
+    let status = status?;
+    if !status.success() {
+        bail!(RunError::FilterFailed {
+            cmd: String::from(cmd),
+            status,
+        });
+    }
+    count
+}
+
+/// Writes tags into multiple tags files by `--split-by-dir` or `--split-by-lang`.
+///
+/// Tags are merged into a temporary file once, and then each tag line is routed to `<directory>/<output>` in `DIR`
+/// with the path relative to the directory, or `<output>.<language>`. So each tags file keeps the sort order.
+/// Tags which match no directory or language are written into the output.
+/// Pseudo-tags are copied into all tags files.
+fn write_split_tags(
+    opt: &Opt,
+    header: &str,
+    inputs: Vec<Box<dyn BufRead + '_>>,
+) -> Result<usize, Error> {
+    let option = if opt.split_by_dir.is_some() {
+        "--split-by-dir"
+    } else {
+        "--split-by-lang"
+    };
+    if opt.output.to_str().unwrap_or("") == "-" || opt.output_cmd.is_some() {
+        bail!(RunError::OutputRequired {
+            option: String::from(option),
+        });
+    }
+    let base = match opt.output.parent() {
+        Some(x) if !x.as_os_str().is_empty() => x.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let name = opt.output.file_name().unwrap_or_default();
+
+    let tmp = tempfile::Builder::new()
+        .prefix(TEMP_PREFIX)
+        .tempfile_in(&base)?;
+    let mut merged_opt = opt.clone();
+    merged_opt.output = tmp.path().to_path_buf();
+    merged_opt.split_by_dir = None;
+    merged_opt.split_by_lang = false;
+    merged_opt.eol = String::from("lf");
+    merged_opt.lossy_utf8_report = LossyReport::path(opt);
+    let count = write_tags(&merged_opt, header, inputs)?;
+    let newline = eol(opt);
+
+    // All tags files are renamed into place after every file is written.
+    let mut pseudo = Vec::new();
+    let mut outputs: HashMap<String, (BufWriter<AtomicFile>, Option<PathRewriter>)> =
+        HashMap::new();
+    let mut root = BufWriter::new(AtomicFile::create(&opt.output)?);
+    // The temporary file is replaced by the merged tags, so it's opened by the path.
+    for line in std::io::BufReader::new(fs::File::open(tmp.path())?).split(b'\n') {
+        let line = line?;
+        let file = match tag_file(opt, &line) {
+            Some(x) => x,
+            None => {
+                if outputs.is_empty() {
+                    root.write_all(&line)?;
+                    root.write_all(newline)?;
+                    pseudo.push(line);
+                }
+                continue;
+            }
+        };
+
+        let key = match opt.split_by_dir {
+            Some(depth) => {
+                let dirs: Vec<&str> = file.split('/').collect();
+                if dirs.len() <= depth || dirs[..depth].iter().any(|x| x.is_empty() || *x == "..") {
+                    None
+                } else {
+                    Some(dirs[..depth].join("/"))
+                }
+            }
+            None => tag_language(opt, &line).map(|x| x.to_lowercase()),
+        };
+        let key = match key {
+            Some(x) => x,
+            None => {
+                root.write_all(&line)?;
+                root.write_all(newline)?;
+                continue;
+            }
+        };
+
+        if !outputs.contains_key(&key) {
+            let (path, rewriter) = if opt.split_by_dir.is_some() {
+                let rewriter = PathRewriter {
+                    base: None,
+                    slash: false,
+                    strip_prefix: Some(format!("{}/", key)),
+                    path_prefix: None,
+                    map_path: Vec::new(),
+                };
+                (opt.dir.join(&key).join(name), Some(rewriter))
+            } else {
+                let mut path = opt.output.clone().into_os_string();
+                path.push(".");
+                path.push(&key);
+                (PathBuf::from(path), None)
+            };
+            let mut f = BufWriter::new(AtomicFile::create(&path)?);
+            for x in &pseudo {
+                f.write_all(x)?;
+                f.write_all(newline)?;
+            }
+            outputs.insert(key.clone(), (f, rewriter));
+        }
+        let (f, rewriter) = outputs.get_mut(&key).unwrap();
+        match rewriter {
+            Some(x) => f.write_all(&x.rewrite(opt, &line))?,
+            None => f.write_all(&line)?,
+        }
+        f.write_all(newline)?;
+    }
+    let split = outputs.len();
+    let mut files = vec![root];
+    files.extend(outputs.into_values().map(|x| x.0));
+    commit_files(files)?;
+    if opt.verbose {
+        eprintln!("Split tags files: {}", split);
+    }
+    Ok(count)
+}
+
+/// Flushes the files, and renames them into place only if all of them are written.
+fn commit_files(files: Vec<BufWriter<AtomicFile>>) -> Result<(), Error> {
+    let mut written = Vec::new();
+    for f in files {
+        written.push(f.into_inner().map_err(|x| x.into_error())?);
+    }
+    for f in written {
+        f.commit()?;
+    }
+    Ok(())
+}
+
+/// Suffix of the index file of `--shards`.
+const SHARD_INDEX: &str = "index";
+
+/// Writes sorted tags into `<output>.0` .. `<output>.<shards - 1>` by `--shards`.
+///
+/// Each shard has a contiguous range of tag names and about the same number of tags.
+/// Tags of the same name are kept in a shard so that a binary search of a shard finds all of them.
+/// `<output>.index` lists `<shard>\t<first name>\t<last name>` of non-empty shards.
+fn write_sharded_tags(
+    opt: &Opt,
+    shards: usize,
+    header: &str,
+    inputs: Vec<Box<dyn BufRead + '_>>,
+) -> Result<usize, Error> {
+    if opt.output.to_str().unwrap_or("") == "-" || opt.output_cmd.is_some() {
+        bail!(RunError::OutputRequired {
+            option: String::from("--shards"),
+        });
+    }
+    if opt.unsorted {
+        bail!(RunError::SortRequired {
+            option: String::from("--shards"),
+        });
+    }
+    let shards = shards.max(1);
+    let base = match opt.output.parent() {
+        Some(x) if !x.as_os_str().is_empty() => x.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let shard_path = |suffix: &str| {
+        let mut x = opt.output.clone().into_os_string();
+        x.push(".");
+        x.push(suffix);
+        PathBuf::from(x)
+    };
+
+    let tmp = tempfile::Builder::new()
+        .prefix(TEMP_PREFIX)
+        .tempfile_in(&base)?;
+    let mut merged_opt = opt.clone();
+    merged_opt.output = tmp.path().to_path_buf();
+    merged_opt.shards = None;
+    merged_opt.eol = String::from("lf");
+    merged_opt.lossy_utf8_report = LossyReport::path(opt);
+    let count = write_tags(&merged_opt, header, inputs)?;
+    let newline = eol(opt);
+    let per_shard = count.div_ceil(shards).max(1);
+
+    let mut outputs = Vec::new();
+    for i in 0..shards {
+        outputs.push(BufWriter::new(AtomicFile::create(&shard_path(
+            &i.to_string(),
+        ))?));
+    }
+    let mut ranges: Vec<Option<(String, String)>> = vec![None; shards];
+    let mut shard = 0;
+    let mut written = 0;
+    // The temporary file is replaced by the merged tags, so it's opened by the path.
+    for line in std::io::BufReader::new(fs::File::open(tmp.path())?).split(b'\n') {
+        let line = line?;
+        let name = match tag_name(opt, &line) {
+            Some(x) => x,
+            None => {
+                if written == 0 {
+                    for f in &mut outputs {
+                        f.write_all(&line)?;
+                        f.write_all(newline)?;
+                    }
+                }
+                continue;
+            }
+        };
+        // Move to the next shard if it's full, but not in the middle of tags of the same name.
+        if let Some((_, ref last)) = ranges[shard] {
+            if written >= per_shard * (shard + 1) && shard + 1 < shards && *last != name {
+                shard += 1;
+            }
+        }
+        match ranges[shard] {
+            Some((_, ref mut last)) => *last = name,
+            None => ranges[shard] = Some((name.clone(), name)),
+        }
+        outputs[shard].write_all(&line)?;
+        outputs[shard].write_all(newline)?;
+        written += 1;
+    }
+    let mut index = BufWriter::new(AtomicFile::create(&shard_path(SHARD_INDEX))?);
+    for (i, range) in ranges.iter().enumerate() {
+        if let Some((first, last)) = range {
+            let path = shard_path(&i.to_string());
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            writeln!(index, "{}\t{}\t{}", name, first, last)?;
+        }
+    }
+    outputs.push(index);
+    commit_files(outputs)?;
+    Ok(count)
+}
+
+/// Returns the command to execute the command line by the shell.
+pub fn shell(cmd: &str) -> Command {
+    if cfg!(windows) {
+        let mut ret = Command::new("cmd");
+        ret.arg("/C").arg(cmd);
+        ret
+    } else {
+        let mut ret = Command::new("sh");
+        ret.arg("-c").arg(cmd);
+        ret
+    }
+}
+
+/// Quotes the string for POSIX shell if it contains any special character.
+pub fn shell_quote(s: &str) -> String {
+    let safe = |x: char| x.is_ascii_alphanumeric() || "-_./:=+,@%".contains(x);
+    if !s.is_empty() && s.chars().all(safe) {
+        String::from(s)
+    } else {
+        format!("'{}'", s.replace('\'', "'\\''"))
+    }
+}
+
+/// Returns the line ending of tags files by `--eol`.
+fn eol(opt: &Opt) -> &'static [u8] {
+    match opt.eol.as_str() {
+        "crlf" => b"\r\n",
+        "native" if cfg!(windows) => b"\r\n",
+        _ => b"\n",
+    }
+}
+
+/// Number of tags between progress logs of merging.
+const MERGE_PROGRESS: usize = 100000;
+
+fn merge_tags(
+    opt: &Opt,
+    f: &mut dyn Write,
+    header: &str,
+    mut inputs: Vec<Box<dyn BufRead + '_>>,
+    filter: &Option<TagFilter>,
+    rewriter: &Option<PathRewriter>,
+    lossy: &mut Option<LossyReport>,
+) -> Result<usize, Error> {
+    let newline = eol(opt);
+    if newline == b"\n" {
+        f.write_all(header.as_bytes())?;
+    } else {
+        f.write_all(header.replace('\n', "\r\n").as_bytes())?;
+    }
+    debug!(
+        inputs = inputs.len(),
+        sorted = !opt.unsorted,
+        "merge started"
+    );
+
+    let mut count = 0;
+    let mut emit = |line: &[u8]| -> Result<(), Error> {
+        if filter.as_ref().map(|x| x.drops(opt, line)).unwrap_or(false) {
+            return Ok(());
+        }
+        let line = match lossy {
+            Some(lossy) => lossy.replace(opt, line),
+            None => Cow::Borrowed(line),
+        };
+        let line = line.as_ref();
+        match rewriter {
+            Some(rewriter) => f.write_all(&rewriter.rewrite(opt, line))?,
+            None => f.write_all(line)?,
+        }
+        f.write_all(newline)?;
+        count += 1;
+        if count % MERGE_PROGRESS == 0 {
+            trace!(tags = count, "merge progress");
+        }
+        Ok(())
+    };
+
+    if opt.unsorted {
+        let mut line = Vec::new();
+        for input in &mut inputs {
+            while read_tag_line(opt, input, &mut line)? {
+                emit(&line)?;
+            }
+        }
+    } else if opt.sort_mode == "merge" {
+        merge_sorted(opt, inputs, &mut emit)?;
+    } else {
+        let limit = if opt.sort_mode == "auto" {
+            Some(
+                opt.max_memory
+                    .map(|x| x * 1024 * 1024)
+                    .unwrap_or(SORT_FULL_MAX),
+            )
+        } else {
+            None
+        };
+        let mut loaded = load_tags(opt, &mut inputs, limit)?;
+        let full = opt.sort_mode == "full"
+            || (!loaded.overflow && loaded.buf.len() as u64 >= SORT_FULL_MIN && opt.threads() > 1);
+        debug!(bytes = loaded.buf.len(), full, "sort mode selected");
+        if full {
+            loaded.sort(opt.threads())?;
+            for &(start, end) in &loaded.lines {
+                emit(&loaded.buf[start..end])?;
+            }
+        } else {
+            // Loaded lines are put back before the rest of each input.
+            let mut rest: Vec<Box<dyn BufRead + '_>> = Vec::with_capacity(inputs.len());
+            for (i, input) in inputs.into_iter().enumerate() {
+                match loaded.input(i) {
+                    Some(x) => rest.push(Box::new(x.chain(input))),
+                    None => rest.push(input),
+                }
+            }
+            merge_sorted(opt, rest, &mut emit)?;
+        }
+    }
+    debug!(tags = count, "merge finished");
+    Ok(count)
+}
+
+/// k-way merge of sorted inputs. Equal lines are taken in the order of inputs.
+fn merge_sorted(
+    opt: &Opt,
+    mut inputs: Vec<Box<dyn BufRead + '_>>,
+    emit: &mut dyn FnMut(&[u8]) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut line = Vec::new();
+    let mut heap = BinaryHeap::with_capacity(inputs.len());
+    for (i, input) in inputs.iter_mut().enumerate() {
+        if read_tag_line(opt, input, &mut line)? {
+            heap.push(Reverse((line.clone(), i)));
+        }
+    }
+    while let Some(Reverse((mut line, i))) = heap.pop() {
+        emit(&line)?;
+        if read_tag_line(opt, &mut inputs[i], &mut line)? {
+            heap.push(Reverse((line, i)));
+        }
+    }
+    Ok(())
+}
+
+/// Tags smaller than this are merged by `--sort-mode auto` because sorting in parallel doesn't pay.
+const SORT_FULL_MIN: u64 = 16 * 1024 * 1024;
+
+/// Tags larger than this are merged by `--sort-mode auto` unless `--max-memory` is given.
+const SORT_FULL_MAX: u64 = 1024 * 1024 * 1024;
+
+/// Tags loaded into memory to be sorted by `--sort-mode full` or `auto`.
+#[derive(Debug, Default)]
+struct LoadedTags {
+    /// Lines of inputs, each followed by a newline
+    buf: Vec<u8>,
+    /// Ranges of lines in `buf` without the newline
+    lines: Vec<(usize, usize)>,
+    /// End offsets in `buf` of each input loaded
+    ends: Vec<usize>,
+    /// Loading is stopped in the middle of the last input because tags exceed the limit
+    overflow: bool,
+}
+
+impl LoadedTags {
+    /// Returns lines loaded from the input, or `None` if the input isn't read.
+    fn input(&self, i: usize) -> Option<&[u8]> {
+        let end = *self.ends.get(i)?;
+        let start = if i == 0 { 0 } else { self.ends[i - 1] };
+        Some(&self.buf[start..end])
+    }
+
+    /// Sorts lines in bytes in parallel.
+    ///
+    /// The unstable sort is enough because lines equal in bytes are not distinguished in the output.
+    fn sort(&mut self, threads: usize) -> Result<(), Error> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()?;
+        let buf = &self.buf;
+        let lines = &mut self.lines;
+        pool.install(|| lines.par_sort_unstable_by(|a, b| buf[a.0..a.1].cmp(&buf[b.0..b.1])));
+        Ok(())
+    }
+}
+
+/// Loads lines of inputs into memory up to `limit` bytes.
+fn load_tags(
+    opt: &Opt,
+    inputs: &mut [Box<dyn BufRead + '_>],
+    limit: Option<u64>,
+) -> Result<LoadedTags, Error> {
+    let mut ret = LoadedTags::default();
+    let mut line = Vec::new();
+    'inputs: for input in inputs.iter_mut() {
+        while read_tag_line(opt, input, &mut line)? {
+            let start = ret.buf.len();
+            ret.buf.extend_from_slice(&line);
+            ret.lines.push((start, start + line.len()));
+            ret.buf.push(b'\n');
+            if limit.map(|x| ret.buf.len() as u64 > x).unwrap_or(false) {
+                ret.overflow = true;
+                ret.ends.push(ret.buf.len());
+                break 'inputs;
+            }
+        }
+        ret.ends.push(ret.buf.len());
+    }
+    Ok(ret)
+}
+
+/// Merges tags of the inputs as is without the header, `--drop-kind`, `--filter-regex`, `--lossy-utf8` and
+/// rewriting of paths, which are applied when the result is merged again by `write_tags`.
+pub fn merge_raw_tags(
+    opt: &Opt,
+    f: &mut dyn Write,
+    inputs: Vec<Box<dyn BufRead + '_>>,
+) -> Result<usize, Error> {
+    merge_tags(opt, f, "", inputs, &None, &None, &mut None)
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Run
+// ---------------------------------------------------------------------------------------------------------------------
+
+pub fn run_opt(opt: &Opt) -> Result<(), Error> {
+    if opt.config {
+        for path in &opt.config_sources {
+            println!("# {}", path.to_string_lossy());
+        }
+        let toml = toml::to_string(&opt)?;
+        println!("{}", toml);
+        return Ok(());
+    }
+
+    match opt.completion {
+        Some(ref x) => {
+            let shell = match x.as_str() {
+                "bash" => clap::Shell::Bash,
+                "fish" => clap::Shell::Fish,
+                "zsh" => clap::Shell::Zsh,
+                "powershell" => clap::Shell::PowerShell,
+                _ => clap::Shell::Bash,
+            };
+            Opt::clap().gen_completions("ptags", shell, "./");
+            return Ok(());
+        }
+        None => {}
+    }
+
+    match opt.cmd {
+        Some(Cmd::Check) => {
+            return check::run(opt);
+        }
+        Some(Cmd::Doctor) => {
+            return doctor::run(opt);
+        }
+        Some(Cmd::Languages { all, ref format }) => {
+            return languages::run(opt, all, format);
+        }
+        Some(Cmd::Regenerate) => {
+            return regenerate::run(opt);
+        }
+        Some(Cmd::Merge {
+            ref output,
+            ref files,
+        }) => {
+            merge::run(opt, output, files)?;
+            return Ok(());
+        }
+        Some(Cmd::Serve { ref socket }) => {
+            let mut server = Server::new(opt)?;
+            server.regenerate()?;
+            return server.serve(socket);
+        }
+        Some(Cmd::Lsp) => {
+            return lsp::run(opt);
+        }
+        Some(Cmd::Find {
+            ref name,
+            prefix,
+            icase,
+            ref kind,
+            json,
+        }) => {
+            let query = find::Query {
+                name: name.clone(),
+                prefix,
+                icase,
+                kind: kind.clone(),
+            };
+            return find::run(opt, &query, json);
+        }
+        Some(Cmd::Diff {
+            ref old,
+            ref new,
+            ref format,
+        }) => {
+            return diff::run(opt, old, new, format);
+        }
+        Some(Cmd::Export {
+            ref sqlite,
+            fts,
+            ref lsif,
+        }) => {
+            return export::run(opt, sqlite.as_deref(), fts, lsif.as_deref());
+        }
+        Some(Cmd::Pick { ref query, edit }) => {
+            return pick::run(opt, &opt.output, query.as_deref().unwrap_or(""), edit);
+        }
+        Some(Cmd::Outline {
+            ref file,
+            ref format,
+        }) => {
+            return outline::run(opt, file, format);
+        }
+        Some(Cmd::Generate) | None => {}
+    }
+
+    if opt.dry_run {
+        generate(opt)?;
+        return Ok(());
+    }
+
+    if opt.check_up_to_date {
+        return check_up_to_date(opt);
+    }
+
+    if opt.watch {
+        return watch(opt);
+    }
+
+    let deny = WarningDenial::new(opt)?;
+    let result = generate(opt)?;
+    report(opt, &result);
+    if !result.failures.is_empty() {
+        bail!(RunError::PartialFailure {
+            failed: result.failures.len(),
+        });
+    }
+    if let Some(deny) = deny {
+        deny.check(&result.warnings)?;
+    }
+    Ok(())
+}
+
+/// Prints the ctags command line and the number of files in each chunk by `--dry-run`.
+///
+/// git commands are printed when they are called to search files.
+fn print_dry_run(opt: &Opt, files: &[String]) {
+    println!("ctags: {}", CmdCtags::get_command_line(opt));
+    for (i, chunk) in files.iter().enumerate() {
+        println!("chunk {}: {} files", i, chunk.lines().count());
+    }
+}
+
+/// Maximum number of differences printed by `--check-up-to-date`.
+const MAX_STALE_LINES: usize = 10;
+
+/// Generates tags into a temporary file and compares them with the output.
+fn check_up_to_date(opt: &Opt) -> Result<(), Error> {
+    if opt.output.to_str().unwrap_or("") == "-" || opt.output_cmd.is_some() {
+        bail!(RunError::OutputRequired {
+            option: String::from("--check-up-to-date"),
+        });
+    }
+    // The temporary file is placed beside the output so that relative paths are the same.
+    let base = match opt.output.parent() {
+        Some(x) if !x.as_os_str().is_empty() => x.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let tmp = tempfile::NamedTempFile::new_in(&base)?;
+    let mut new_opt = opt.clone();
+    new_opt.output = tmp.path().to_path_buf();
+    new_opt.check_up_to_date = false;
+    new_opt.incremental = false;
+    new_opt.append = false;
+    new_opt.diff_against = None;
+
+    let result = generate(&new_opt)?;
+    if !result.failures.is_empty() {
+        bail!(RunError::PartialFailure {
+            failed: result.failures.len(),
+        });
+    }
+    let old = fs::read(&opt.output).unwrap_or_default();
+    let new = fs::read(tmp.path())?;
+    let (added, removed) = diff_lines(&old, &new);
+    if added.is_empty() && removed.is_empty() {
+        return Ok(());
+    }
+
+    for (mark, lines) in [("-", &removed), ("+", &added)] {
+        for line in lines.iter().take(MAX_STALE_LINES) {
+            eprintln!("{} {}", mark, String::from_utf8_lossy(line));
+        }
+        if lines.len() > MAX_STALE_LINES {
+            eprintln!("{} ... and {} more", mark, lines.len() - MAX_STALE_LINES);
+        }
+    }
+    bail!(RunError::Stale {
+        path: opt.output.to_string_lossy().into_owned(),
+        added: added.len(),
+        removed: removed.len(),
+    });
+}
+
+/// Returns true if the line is a pseudo-tag of ptags like `!_TAG_PTAGS_INVOCATION` of ctags format or JSON format.
+fn is_provenance(line: &[u8]) -> bool {
+    if line.starts_with(format!("!_{}", TAG_PTAGS).as_bytes()) {
+        return true;
+    }
+    if !line.starts_with(b"{") {
+        return false;
+    }
+    match serde_json::from_slice::<serde_json::Value>(line) {
+        Ok(x) => {
+            x["_type"] == "ptag" && x["name"].as_str().is_some_and(|x| x.starts_with(TAG_PTAGS))
+        }
+        Err(_) => false,
+    }
+}
+
+/// Returns lines added to `new` and removed from `old` regardless of the order.
+///
+/// Pseudo-tags of ptags are ignored because they change with the command line and the time.
+fn diff_lines<'a>(old: &'a [u8], new: &'a [u8]) -> (Vec<&'a [u8]>, Vec<&'a [u8]>) {
+    let lines = |data: &'a [u8]| {
+        let mut ret: Vec<&[u8]> = data
+            .split(|x| *x == b'\n')
+            .filter(|x| !x.is_empty() && !is_provenance(x))
+            .collect();
+        ret.sort();
+        ret
+    };
+    let old = lines(old);
+    let new = lines(new);
+
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if j == new.len() || (i < old.len() && old[i] < new[j]) {
+            removed.push(old[i]);
+            i += 1;
+        } else if i == old.len() || new[j] < old[i] {
+            added.push(new[j]);
+            j += 1;
+        } else {
+            i += 1;
+            j += 1;
+        }
+    }
+    (added, removed)
+}
+
+fn watch(opt: &Opt) -> Result<(), Error> {
+    let result = generate(opt)?;
+    report(opt, &result);
+
+    let watcher = dir_watcher(opt)?;
+    loop {
+        watcher.wait()?;
+        if opt.verbose {
+            eprintln!("Regenerate tags");
+        }
+        match generate(opt) {
+            Ok(result) => report(opt, &result),
+            Err(x) => {
+                eprintln!("{}", x);
+                for x in x.chain().skip(1) {
+                    eprintln!("{}", x);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the watcher of `DIR` for `--watch` and `ptags lsp`.
+///
+/// Files written by each run like the lock file and temporary files beside the output are ignored,
+/// so that the run doesn't trigger itself.
+pub fn dir_watcher(opt: &Opt) -> Result<DirWatcher, Error> {
+    let ignore = vec![
+        opt.output.clone(),
+        lock_path(&opt.output),
+        opt.state_file.clone(),
+    ];
+    let temp_dirs = vec![output_dir(opt)];
+    let delay = Duration::from_millis(opt.watch_delay);
+    DirWatcher::new(&opt.dir, &ignore, &temp_dirs, delay)
+}
+
+/// Returns the state of files in the list.
+///
+/// With Watchman, only files changed since the clock of the last run are checked.
+/// If Watchman is not available or restarted, all files are checked.
+fn get_state(opt: &Opt, list: &[String], old: Option<&State>) -> State {
+    let explicit = opt.use_watchman;
+    if !explicit && !opt.dir.join(WATCHMAN_CONFIG).exists() {
+        return State::from_files(&opt.dir, list);
+    }
+    let warn = |x: Error| {
+        if explicit || opt.verbose {
+            eprintln!("Failed to query watchman, so all files are checked: {}", x);
+        }
+    };
+
+    if let Some((old, clock)) = old.and_then(|x| x.watchman_clock.as_ref().map(|y| (x, y))) {
+        match CmdWatchman::since(opt, clock) {
+            Ok(since) if !since.fresh => {
+                if opt.verbose {
+                    eprintln!("Watchman changed files: {}", since.files.len());
+                }
+                let changed = since.files.into_iter().collect();
+                let mut state = State::from_files_since(&opt.dir, list, old, &changed);
+                state.watchman_clock = Some(since.clock);
+                return state;
+            }
+            Ok(_) => (),
+            Err(x) => {
+                warn(x);
+                return State::from_files(&opt.dir, list);
+            }
+        }
+    }
+
+    // The clock is taken before checking files so that changes while checking are found at the next run.
+    let clock = match CmdWatchman::clock(opt) {
+        Ok(x) => Some(x),
+        Err(x) => {
+            warn(x);
+            None
+        }
+    };
+    let mut state = State::from_files(&opt.dir, list);
+    state.watchman_clock = clock;
+    state
+}
+
+/// Generates the tags file and returns the summary.
+pub fn generate(opt: &Opt) -> Result<TagsResult, Error> {
+    check_versions(opt)?;
+    let _lock = TagsLock::acquire(opt)?;
+    let time_begin = Instant::now();
+
+    // Languages are probed before ctags is called so that every ctags process gets the adapted arguments.
+    let (adapted, language_warnings) = CmdCtags::adapt_languages(opt);
+    let opt = &adapted;
+
+    // `--pipeline` calls ctags before the whole file list is known, so options which need the list disable it.
+    let mut pipeline = None;
+    if opt.pipeline {
+        match Pipeline::conflict(opt) {
+            Some(x) if opt.verbose => eprintln!("--pipeline is disabled by {}", x),
+            Some(_) => (),
+            None if CmdGit::is_work_tree(opt)? => pipeline = Some(Pipeline::start(opt)?),
+            None => (),
+        }
+    }
+
+    let mut list;
+    let time_git_files;
+    let mut large_files = 0;
+    let mut chunks = None;
+    let mut root_opt = None;
+    let mut _tmp_dir = None;
+    if let Some(ref file) = opt.list {
+        list = input_list(file, opt).context("failed to get file list")?;
+        time_git_files = Duration::ZERO;
+    } else if let Some(ref dirs) = search_dirs(opt)? {
+        let root = search_root(opt);
+        time_git_files = watch_time!({
+            list = multi_list(opt, dirs, &root)?;
+        });
+        // ctags is called in the root because paths are relative to it.
+        root_opt = Some(Opt {
+            dir: root,
+            ..opt.clone()
+        });
+    } else if let Some(ref rev) = opt.rev {
+        // Files in the revision are extracted to a temporary directory, and ctags is called in it.
+        let dir = TempDir::new()?;
+        time_git_files = watch_time!({
+            list = CmdGit::ls_tree(opt, rev).context("failed to get file list")?;
+            let objects: Vec<(String, String)> = list
+                .iter()
+                .filter(|x| !x.contains('\n'))
+                .map(|x| (format!("{}:./{}", rev, x), x.clone()))
+                .collect();
+            CmdGit::write_objects(opt, &objects, dir.path()).context("failed to extract files")?;
+        });
+        root_opt = Some(Opt {
+            dir: dir.path().to_path_buf(),
+            ..opt.clone()
+        });
+        _tmp_dir = Some(dir);
+    } else if opt.staged {
+        let dir = TempDir::new()?;
         time_git_files = watch_time!({
-            files = git_files(&opt).context("failed to get file list")?;
+            list = CmdGit::ls_stage(opt).context("failed to get file list")?;
+            stage_files(opt, &list, dir.path()).context("failed to extract files")?;
+        });
+        root_opt = Some(Opt {
+            dir: dir.path().to_path_buf(),
+            ..opt.clone()
+        });
+        _tmp_dir = Some(dir);
+    } else if let Some(ref mut pipeline) = pipeline {
+        // Filters are applied to each file by the pipeline.
+        time_git_files = watch_time!({
+            let listed = pipeline.wait_list().context("failed to get file list")?;
+            list = listed.list;
+            large_files = listed.large_files;
+            chunks = Some(listed.chunks);
+        });
+    } else {
+        time_git_files = watch_time!({
+            list = vcs_list(opt).context("failed to get file list")?;
+        });
+    }
+    if pipeline.is_none() {
+        list = FileFilter::new(opt)?.apply(opt, list);
+    }
+    // Paths in tags file are relative to DIR even if files are extracted to a temporary directory.
+    let tags_opt = if opt.rev.is_some() || opt.staged {
+        opt
+    } else {
+        root_opt.as_ref().unwrap_or(opt)
+    };
+    let opt = root_opt.as_ref().unwrap_or(opt);
+    if pipeline.is_none() {
+        if opt.skip_symlinks {
+            list.retain(|x| !filter::is_symlink(&opt.dir, x));
+        } else if opt.follow_symlinks {
+            list = filter::follow_symlinks(&opt.dir, list);
+        }
+        large_files = skip_large_files(opt, &mut list);
+        if opt.skip_minified {
+            list.retain(|x| !is_minified_file(opt, x));
+        }
+    }
+
+    if opt.format == "cscope" || opt.format == "gtags" {
+        if opt.dry_run {
+            println!("{}: {} files", opt.format, list.len());
+            return Ok(TagsResult::default());
+        }
+        let time_call = watch_time!({
+            if opt.format == "cscope" {
+                CmdCscope::call(opt, &list).context("failed to call cscope")?;
+            } else {
+                CmdGtags::call(opt, &list).context("failed to call gtags")?;
+            }
+        });
+        return Ok(TagsResult {
+            files: list.len(),
+            threads: vec![ThreadStat::default(); opt.jobs()],
+            timings: Timings {
+                git_files: time_git_files,
+                call_ctags: time_call,
+                ..Default::default()
+            },
+            large_files,
+            ..Default::default()
+        });
+    }
+
+    // The output can't be the base of incremental update if it's not a complete tags file.
+    let is_stdout = opt.output.to_str().unwrap_or("") == "-"
+        || opt.output_cmd.is_some()
+        || opt.split_by_dir.is_some()
+        || opt.split_by_lang
+        || opt.shards.is_some();
+    let mut base = None;
+    let mut state = None;
+    if (opt.incremental || opt.watch) && !is_stdout {
+        let old_state = State::load(&opt.state_file)?;
+        let mut new_state = get_state(opt, &list, old_state.as_ref());
+        if let Some(old_state) = old_state {
+            new_state.stats = old_state.stats.clone();
+            if opt.output.exists() {
+                let (changed, removed) = old_state.diff(&new_state);
+                if opt.verbose {
+                    eprintln!("Changed: {}, Removed: {}", changed.len(), removed.len());
+                }
+                let drop: HashSet<&str> = changed
+                    .iter()
+                    .chain(removed.iter())
+                    .map(|x| x.as_str())
+                    .collect();
+                base = Some(
+                    read_base_tags(opt, &drop)
+                        .context(format!("failed to read file ({:?})", &opt.output))?,
+                );
+                list = changed;
+            }
+        }
+        state = Some(new_state);
+    }
+
+    if let Some(ref rev) = opt.diff_against {
+        let diff = CmdGit::diff_files(opt, rev).context("failed to get changed files")?;
+        if opt.verbose {
+            eprintln!("Changed: {}", diff.len());
+        }
+        let diff: HashSet<&str> = diff.iter().map(|x| x.as_str()).collect();
+        if base.is_none() && !is_stdout && opt.output.exists() {
+            base = Some(
+                read_base_tags(opt, &diff)
+                    .context(format!("failed to read file ({:?})", &opt.output))?,
+            );
+        }
+        // Deleted files are dropped from tags because they are not listed.
+        list.retain(|x| diff.contains(x.as_str()));
+    }
+
+    if opt.append && base.is_none() && !is_stdout && opt.output.exists() {
+        let drop: HashSet<&str> = list.iter().map(|x| x.as_str()).collect();
+        base = Some(
+            read_base_tags(opt, &drop)
+                .context(format!("failed to read file ({:?})", &opt.output))?,
+        );
+    }
+
+    let mut cached = None;
+    let mut cache_hits = 0;
+    let mut cache_oids = None;
+    let use_cache = opt.cache && opt.list.is_none() && !opt.no_vcs && opt.vcs == "git";
+    if use_cache && CmdGit::is_work_tree(opt)? {
+        let cache = get_cache(opt)?;
+        let oids = CmdGit::get_blob_ids(opt).context("failed to get blob ids")?;
+        let len = list.len();
+        let (tags, miss) = read_cache(opt, &cache, &oids, list);
+        cache_hits = len - miss.len();
+        if opt.verbose {
+            eprintln!("Cache hits: {}", cache_hits);
+        }
+        list = miss;
+        cached = Some(tags);
+        cache_oids = Some((cache, oids));
+    }
+
+    let files = match chunks {
+        Some(x) => x,
+        None => split_files(&list, opt),
+    };
+    if opt.dry_run {
+        print_dry_run(opt, &files);
+        return Ok(TagsResult::default());
+    }
+
+    let mut spilled = Vec::new();
+    let memory_opt;
+    let opt = if let Some(max) = opt.max_memory {
+        let in_memory = base.as_ref().map(|x| x.len()).unwrap_or(0)
+            + cached.as_ref().map(|x| x.len()).unwrap_or(0);
+        let plan = plan_memory(
+            max * 1024 * 1024,
+            &chunk_bytes(opt, &files),
+            in_memory as u64,
+            opt.jobs(),
+        );
+        if opt.verbose {
+            eprintln!("Memory plan: {} jobs, spill: {}", plan.jobs, plan.spill);
+        }
+        // The order of inputs is kept so that equal lines are merged in the same order.
+        if plan.spill {
+            for buf in [base.take(), cached.take()].iter().flatten() {
+                spilled.push(spill(buf)?);
+            }
+        }
+        memory_opt = Opt {
+            jobs: plan.jobs,
+            ..opt.clone()
+        };
+        &memory_opt
+    } else {
+        opt
+    };
+
+    let outputs;
+    let mut merged = Vec::new();
+    // ctags has been running since the pipeline was started.
+    let call_begin = if pipeline.is_some() {
+        time_begin
+    } else {
+        Instant::now()
+    };
+    match pipeline {
+        Some(x) => {
+            let generated = x.finish().context("failed to call ctags")?;
+            outputs = generated.outputs;
+            merged = generated.merged;
+        }
+        None => outputs = call_ctags(opt, &files).context("failed to call ctags")?,
+    }
+    let time_call_ctags = call_begin.elapsed();
+
+    if let Some((ref cache, ref oids)) = cache_oids {
+        write_cache(opt, cache, oids, &list, &outputs).context("failed to write cache")?;
+    }
+
+    let tags;
+    let write_begin = Instant::now();
+    let time_write_tags = watch_time!({
+        let mut inputs: Vec<Box<dyn BufRead>> = Vec::new();
+        if let Some(ref base) = base {
+            inputs.push(Box::new(base.as_slice()));
+        }
+        if let Some(ref cached) = cached {
+            inputs.push(Box::new(cached.as_slice()));
+        }
+        for f in spilled.iter().chain(merged.iter()) {
+            inputs.push(Box::new(BufReader::new(f)));
+        }
+        for o in outputs.iter().filter(|x| x.status.success() && !x.merged) {
+            inputs.push(Box::new(o.reader()?));
+        }
+        let header = get_tags_header(opt)?;
+        tags = write_tags(tags_opt, &header, inputs)
+            .context(format!("failed to write file ({:?})", &opt.output))?;
+    });
+
+    if let Some(ref path) = opt.trace {
+        let mut trace = Trace::new(time_begin);
+        trace.span(
+            "git_files",
+            0,
+            time_begin,
+            time_git_files,
+            json!({ "files": list.len() }),
+        );
+        trace.span(
+            "call_ctags",
+            0,
+            call_begin,
+            time_call_ctags,
+            json!({ "chunks": files.len() }),
+        );
+        for o in &outputs {
+            let args = json!({ "files": o.files, "status": o.status.code() });
+            trace.span("ctags", o.thread + 1, o.started, o.elapsed, args);
+        }
+        trace.span(
+            "write_tags",
+            0,
+            write_begin,
+            time_write_tags,
+            json!({ "tags": tags }),
+        );
+        trace.write(path)?;
+    }
+
+    let failures: Vec<CtagsFailure> = outputs
+        .iter()
+        .filter(|x| !x.status.success())
+        .map(|x| CtagsFailure {
+            files: x.list.lines().map(String::from).collect(),
+            stderr: String::from_utf8_lossy(&x.stderr).into_owned(),
+        })
+        .collect();
+
+    // The state is not saved if some files are not tagged so that they are retried next time.
+    if let Some(state) = state {
+        if failures.is_empty() {
+            state.save(&opt.state_file)?;
+        }
+    }
+
+    let mut threads = vec![ThreadStat::default(); opt.jobs()];
+    let mut warnings = language_warnings;
+    let mut warning_lines = warnings.len();
+    let mut seen = HashSet::new();
+    for o in &outputs {
+        threads[o.thread].chunks += 1;
+        threads[o.thread].files += o.files;
+        threads[o.thread].call_ctags += o.elapsed;
+        threads[o.thread].output_bytes += o.stdout.metadata().map(|x| x.len()).unwrap_or(0);
+        if opt.stat {
+            threads[o.thread].source_bytes += o
+                .list
+                .lines()
+                .filter_map(|x| fs::metadata(opt.dir.join(x)).ok())
+                .map(|x| x.len())
+                .sum::<u64>();
+        }
+        if o.status.success() {
+            for line in String::from_utf8_lossy(&o.stderr).lines() {
+                // The same warning is repeated by each ctags process like unknown options in `.ctags.d`.
+                warning_lines += 1;
+                if seen.insert(String::from(line)) {
+                    warnings.push(String::from(line));
+                }
+            }
+        }
+        for f in &o.timed_out {
+            warning_lines += 1;
+            warnings.push(format!("ctags timed out and the file is skipped ({})", f));
+        }
+    }
+
+    if let Some(ref path) = opt.warnings_log {
+        let mut log = String::new();
+        for w in &warnings {
+            log.push_str(w);
+            log.push('\n');
+        }
+        fs::write(path, log).context(format!("failed to write file ({:?})", path))?;
+    }
+
+    let mut slow_files = Vec::new();
+    if opt.profile {
+        slow_files = outputs
+            .iter()
+            .map(|x| (String::from(x.list.trim_end()), x.elapsed))
+            .collect();
+        slow_files.sort_by_key(|x| Reverse(x.1));
+        slow_files.truncate(opt.profile_top);
+    }
+
+    Ok(TagsResult {
+        tags,
+        files: files.iter().map(|x| x.lines().count()).sum::<usize>() + cache_hits,
+        cache_hits,
+        threads,
+        timings: Timings {
+            git_files: time_git_files,
+            call_ctags: time_call_ctags,
+            write_tags: time_write_tags,
+        },
+        warnings,
+        warning_lines,
+        failures,
+        large_files,
+        slow_files,
+    })
+}
+
+/// Maximum number of warnings listed in the error by denied warnings.
+const MAX_DENIED_WARNINGS: usize = 10;
+
+/// Warnings which fail the run by `--deny-warnings`, or only ones matched with `--deny-warnings-regex`.
+struct WarningDenial {
+    regex: Option<regex::RegexSet>,
+}
+
+impl WarningDenial {
+    fn new(opt: &Opt) -> Result<Option<WarningDenial>, Error> {
+        if !opt.denies_warnings() {
+            return Ok(None);
+        }
+        let regex = if opt.deny_warnings_regex.is_empty() {
+            None
+        } else {
+            Some(
+                regex::RegexSet::new(&opt.deny_warnings_regex)
+                    .context("failed to parse --deny-warnings-regex")?,
+            )
+        };
+        Ok(Some(WarningDenial { regex }))
+    }
+
+    /// Fails with the summary of denied warnings if any.
+    fn check(&self, warnings: &[String]) -> Result<(), Error> {
+        let denied: Vec<&String> = warnings
+            .iter()
+            .filter(|x| self.regex.as_ref().is_none_or(|r| r.is_match(x)))
+            .collect();
+        if denied.is_empty() {
+            return Ok(());
+        }
+        let mut summary = String::new();
+        for w in denied.iter().take(MAX_DENIED_WARNINGS) {
+            summary.push_str(&format!("\n  {}", w));
+        }
+        if denied.len() > MAX_DENIED_WARNINGS {
+            summary.push_str(&format!(
+                "\n  ... and {} more",
+                denied.len() - MAX_DENIED_WARNINGS
+            ));
+        }
+        bail!(RunError::Warnings {
+            count: denied.len(),
+            summary,
+        })
+    }
+}
+
+fn report(opt: &Opt, result: &TagsResult) {
+    // Denied warnings are errors, so they are shown even in quiet mode.
+    let show = if opt.denies_warnings() {
+        true
+    } else {
+        opt.warnings == "show" && !opt.quiet
+    };
+    if show {
+        for w in &result.warnings {
+            eprintln!("{}", w);
+        }
+    }
+    for (i, f) in result.failures.iter().enumerate() {
+        eprintln!("Failed chunk {} ({} files)", i, f.files.len());
+        for file in &f.files {
+            eprintln!("    {}", file);
+        }
+        for line in f.stderr.lines() {
+            eprintln!("  {}", line);
+        }
+    }
+    if opt.stat {
+        let prev = save_stats(opt, result);
+        print_stat(opt, result, prev.as_ref());
+    }
+    if opt.profile {
+        print_profile(result);
+    }
+}
+
+/// Saves statistics of the run into the state file, and returns statistics of the last run.
+fn save_stats(opt: &Opt, result: &TagsResult) -> Option<RunStats> {
+    let mut state = match State::load(&opt.state_file) {
+        Ok(x) => x.unwrap_or_default(),
+        Err(_) => State::default(),
+    };
+    let prev = state.stats.take();
+    state.stats = Some(RunStats {
+        files: result.files as u64,
+        tags: result.tags as u64,
+        git_files_ms: result.timings.git_files.as_millis() as u64,
+        call_ctags_ms: result.timings.call_ctags.as_millis() as u64,
+        write_tags_ms: result.timings.write_tags.as_millis() as u64,
+        warnings: result.warnings.len() as u64,
+    });
+    if let Err(x) = state.save(&opt.state_file) {
+        eprintln!("{}", x);
+    }
+    prev
+}
+
+/// Returns the difference from the last run like ` (+3)`, or an empty string if there is no last run.
+fn delta(now: u64, prev: Option<u64>) -> String {
+    match prev {
+        Some(prev) if now >= prev => format!(" (+{})", now - prev),
+        Some(prev) => format!(" (-{})", prev - now),
+        None => String::new(),
+    }
+}
+
+fn print_stat(opt: &Opt, result: &TagsResult, prev: Option<&RunStats>) {
+    eprintln!("\nStatistics");
+    eprintln!("- Options");
+    if opt.thread == 0 {
+        eprintln!("    thread    : {} (auto)", opt.threads());
+    } else {
+        eprintln!("    thread    : {}", opt.threads());
+    }
+    eprintln!("    jobs      : {}", opt.jobs());
+    eprintln!("    ctags     : {}\n", CmdCtags::info(opt).version);
+
+    eprintln!("- Threads");
+    eprintln!("    thread  chunks  files     source[B]     output[B]     call_ctags[ms]");
+    for (i, t) in result.threads.iter().enumerate() {
+        eprintln!(
+            "    {:<6}  {:<6}  {:<8}  {:<12}  {:<12}  {}",
+            i,
+            t.chunks,
+            t.files,
+            t.source_bytes,
+            t.output_bytes,
+            t.call_ctags.as_millis()
+        );
+    }
+    eprintln!();
+
+    eprintln!("- Searched files");
+    eprintln!(
+        "    total     : {}{}",
+        result.files,
+        delta(result.files as u64, prev.map(|x| x.files))
+    );
+    if let Some(size) = opt.max_file_size {
+        eprintln!(
+            "    skipped   : {} (larger than {} bytes)",
+            result.large_files, size
+        );
+    }
+    if opt.cache {
+        eprintln!("    cache hits: {}", result.cache_hits);
+    }
+    eprintln!();
+
+    eprintln!("- Tags");
+    eprintln!(
+        "    total     : {}{}\n",
+        result.tags,
+        delta(result.tags as u64, prev.map(|x| x.tags))
+    );
+
+    eprintln!("- Warnings");
+    eprintln!(
+        "    unique    : {}{}",
+        result.warnings.len(),
+        delta(result.warnings.len() as u64, prev.map(|x| x.warnings))
+    );
+    eprintln!("    total     : {}\n", result.warning_lines);
+
+    // Deltas are shown against the last run which `--stat` saved into the state file.
+    let timings = [
+        (
+            "git_files ",
+            result.timings.git_files,
+            prev.map(|x| x.git_files_ms),
+        ),
+        (
+            "call_ctags",
+            result.timings.call_ctags,
+            prev.map(|x| x.call_ctags_ms),
+        ),
+        (
+            "write_tags",
+            result.timings.write_tags,
+            prev.map(|x| x.write_tags_ms),
+        ),
+    ];
+    eprintln!("- Elapsed time[ms]");
+    for (name, time, prev) in timings {
+        let time = time.as_millis() as u64;
+        eprintln!("    {}: {}{}", name, time, delta(time, prev));
+    }
+}
+
+fn print_profile(result: &TagsResult) {
+    eprintln!("\nSlowest files");
+    eprintln!("    elapsed[ms]  file");
+    for (file, elapsed) in &result.slow_files {
+        eprintln!("    {:<11}  {}", elapsed.as_millis(), file);
+    }
+}
+
+/// Filename of both the user config in the home directory and the project config.
+const CONFIG_FILE: &str = ".ptags.toml";
+
+pub fn read_config(path: &Path) -> Result<toml::Table, Error> {
+    let s = fs::read_to_string(path).context(format!("failed to open file ({:?})", path))?;
+    s.parse::<toml::Table>()
+        .context(format!("failed to parse toml ({:?})", path))
+}
+
+/// Searches `.ptags.toml` from the directory to the root.
+fn find_project_config(dir: &Path, user: Option<&Path>) -> Option<PathBuf> {
+    let dir = dir.canonicalize().ok()?;
+    dir.ancestors()
+        .map(|x| x.join(CONFIG_FILE))
+        .filter(|x| Some(x.as_path()) != user)
+        .find(|x| x.is_file())
+}
+
+/// Overwrites values of `base` by `over`. Tables are merged recursively.
+fn merge_config(base: &mut toml::Table, over: toml::Table) {
+    for (key, value) in over {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(over)) => merge_config(base, over),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Parses the command line with configs.
+///
+/// The precedence is the command line, `.ptags.toml` found upward from `DIR`, then `~/.ptags.toml`.
+/// `--config-file` is used instead of `~/.ptags.toml` if specified.
+fn parse_opt() -> Result<Opt, Error> {
+    let user = match Opt::from_args().config_file {
+        Some(path) => Some(path),
+        None => dirs::home_dir()
+            .map(|x| x.join(CONFIG_FILE))
+            .filter(|x| x.exists()),
+    };
+
+    let mut sources = Vec::new();
+    let mut config = match user {
+        Some(ref path) => {
+            sources.push(path.clone());
+            read_config(path)?
+        }
+        None => toml::Table::new(),
+    };
+    let mut opt = Opt::from_args_with_toml(&config.to_string()).context(format!(
+        "failed to parse toml ({:?})",
+        user.clone().unwrap_or_default()
+    ))?;
+
+    let user = user.and_then(|x| x.canonicalize().ok());
+    if let Some(path) = find_project_config(&opt.dir, user.as_deref()) {
+        merge_config(&mut config, read_config(&path)?);
+        opt = Opt::from_args_with_toml(&config.to_string())
+            .context(format!("failed to parse toml ({:?})", path))?;
+        sources.push(path);
+    }
+    opt.config_sources = sources;
+    // Progress messages are printed only in verbose mode, so quiet mode simply disables it.
+    if opt.quiet {
+        opt.verbose = false;
+    }
+    Ok(opt)
+}
+
+#[cfg_attr(tarpaulin, skip)]
+pub fn run() -> Result<(), Error> {
+    let opt = parse_opt()?;
+    log::init(&opt)?;
+    audit::init(opt.audit_log.as_deref())?;
+    signal::install()?;
+    run_opt(&opt)
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use crate::cmd_ctags::fake_ctags;
+    use std::path::Path;
+
+    #[test]
+    fn test_run() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let output = dir.path().join("tags");
+        let args = vec!["ptags", "-f", output.to_str().unwrap()];
+        let opt = Opt::from_iter(args.iter());
+        let ret = run_opt(&opt);
+        assert!(ret.is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_reproducible() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let script = "case \"$*\" in\n\
+                      *--version*) echo 'Universal Ctags 6.0.0'; exit;;\n\
+                      *--extras=+p*) printf '!_TAG_FILE_SORTED\\t1\\t//\\n'; exit;;\n\
+                      esac\n\
+                      while read f; do printf 'z%s\\t%s\\t/^$/;\"\\tf\\n' \"${f%.rs}\" \"$f\"; done | sort\n";
+        let bin = fake_ctags(&dir, script);
+        let list = dir.path().join("list");
+        let files: Vec<String> = (0..20).map(|x| format!("{}.rs\n", 19 - x)).collect();
+        fs::write(&list, files.concat()).unwrap();
+        let output = dir.path().join("tags");
+
+        let tags = |thread: &str| {
+            let args = vec![
+                "ptags",
+                "--reproducible",
+                "-t",
+                thread,
+                "--bin-ctags",
+                &bin,
+                "-L",
+                list.to_str().unwrap(),
+                "-f",
+                output.to_str().unwrap(),
+            ];
+            let opt = Opt::from_iter(args.iter());
+            run_opt(&opt).unwrap();
+            fs::read(&output).unwrap()
+        };
+        let tags1 = tags("1");
+        assert_eq!(tags1, tags("4"));
+        let tags1 = String::from_utf8(tags1).unwrap();
+        assert!(tags1.contains("!_TAG_PTAGS_OPTIONS\t"));
+        assert!(!tags1.contains(TAG_PTAGS_INVOCATION));
+        assert!(!tags1.contains("\"thread\""));
+        assert_eq!(tags1.lines().filter(|x| !x.starts_with("!_")).count(), 20);
+    }
+
+    #[test]
+    fn test_run_opt() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let output = dir.path().join("tags");
+        let args = vec![
+            "ptags",
+            "-s",
+            "-v",
+            "--validate-utf8",
+            "--unsorted",
+            "-f",
+            output.to_str().unwrap(),
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let ret = run_opt(&opt);
+        assert!(ret.is_ok());
+    }
+
+    #[test]
+    fn test_run_incremental() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let state = dir.path().join("state");
+        let output = dir.path().join("tags");
+        let args = vec![
+            "ptags",
+            "--incremental",
+            "--state-file",
+            state.to_str().unwrap(),
+            "-f",
+            output.to_str().unwrap(),
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let ret = run_opt(&opt);
+        assert!(ret.is_ok());
+        let ret = run_opt(&opt);
+        assert!(ret.is_ok());
+        assert!(state.exists());
+    }
+
+    #[test]
+    fn test_dir_watcher() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let output = dir.path().join("tags");
+        let args = vec![
+            "ptags",
+            "-f",
+            output.to_str().unwrap(),
+            "--watch-delay",
+            "50",
+            dir.path().to_str().unwrap(),
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let watcher = dir_watcher(&opt).unwrap();
+
+        // Writing tags inside the watched directory doesn't trigger the next run.
+        let path = dir.path().join("a.rs");
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            let lock = TagsLock::acquire(&opt).unwrap();
+            let input: &[u8] = b"a\ta.rs\t/^a$/;\"\tf\n";
+            write_tags(&opt, "", vec![Box::new(input)]).unwrap();
+            drop(lock);
+            std::thread::sleep(Duration::from_millis(100));
+            fs::write(&path, "fn a() {}").unwrap();
         });
+        assert!(watcher.wait().is_ok());
+        assert!(dir.path().join("a.rs").exists());
+    }
+
+    #[test]
+    fn test_split_files() {
+        let list: Vec<String> = (0..10).map(|x| format!("{}", x)).collect();
+        let args = vec!["ptags", "-t", "4"];
+        let opt = Opt::from_iter(args.iter());
+        let files = split_files(&list, &opt);
+        assert_eq!(files, vec!["0\n1\n2\n", "3\n4\n5\n", "6\n7\n8\n", "9\n"]);
+
+        let args = vec!["ptags", "-t", "2", "--batch-size", "4"];
+        let opt = Opt::from_iter(args.iter());
+        let files = split_files(&list, &opt);
+        assert_eq!(files, vec!["0\n1\n2\n3\n", "4\n5\n6\n7\n", "8\n9\n"]);
+
+        let args = vec!["ptags", "-t", "2", "--profile"];
+        let opt = Opt::from_iter(args.iter());
+        let files = split_files(&list, &opt);
+        assert_eq!(files.len(), 10);
+        assert_eq!(files[9], "9\n");
+    }
+
+    #[test]
+    fn test_threads() {
+        let args = vec!["ptags", "-t", "3"];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(opt.threads(), 3);
+
+        let args = vec!["ptags", "-t", "0"];
+        let opt = Opt::from_iter(args.iter());
+        assert!(opt.threads() >= 1);
+
+        let args = vec!["ptags", "-t", "16", "-j", "2"];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(opt.threads(), 16);
+        assert_eq!(opt.jobs(), 2);
+
+        let args = vec!["ptags", "-t", "16"];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(opt.jobs(), 16);
+    }
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("git version 2.39.2"), Some(vec![2, 39, 2]));
+        assert_eq!(
+            parse_version("Universal Ctags 6.0.0(p6.0.20221218)"),
+            Some(vec![6, 0, 0])
+        );
+        assert_eq!(parse_version("Exuberant Ctags 5.8"), Some(vec![5, 8]));
+        assert_eq!(parse_version("ctags"), None);
+
+        assert!(check_version(Path::new("git"), "git version 2.39.2", "2.11").is_ok());
+        assert!(check_version(Path::new("git"), "git version 2.9.5", "2.11").is_err());
+        assert_eq!(
+            format!(
+                "{}",
+                check_version(Path::new("ctags"), "Exuberant Ctags 5.8", "5.9").unwrap_err()
+            ),
+            "ctags is too old (Exuberant Ctags 5.8), 5.9 or later is required"
+        );
+    }
+
+    #[test]
+    fn test_path_from_bytes() {
+        let args = vec!["ptags"];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(path_from_bytes(&opt, "ü.rs".as_bytes()).unwrap(), "ü.rs");
+        assert!(path_from_bytes(&opt, b"\xfc.rs").is_err());
+
+        let args = vec!["ptags", "--lossy-paths"];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(path_from_bytes(&opt, b"\xfc.rs").unwrap(), "\u{fffd}.rs");
     }
 
-    let outputs;
-    let time_call_ctags = watch_time!({
-        outputs = call_ctags(&opt, &files).context("failed to call ctags")?;
-    });
+    #[test]
+    fn test_write_tags_filter() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let output = dir.path().join("tags");
+        let args = vec![
+            "ptags",
+            "-f",
+            output.to_str().unwrap(),
+            "--drop-kind",
+            "l",
+            "--filter-regex",
+            "^test_",
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let header = "!_TAG_FILE_SORTED\t1\t//\n";
+        let input: &[u8] = b"a\ta.rs\t/^a$/;\"\tf\n\
+                             b\ta.rs\t/^b$/;\"\tkind:l\tline:2\n\
+                             c\ta.rs\t/^c$/;\"\tl\n\
+                             test_d\ta.rs\t/^d$/;\"\tf\n";
+        let count = write_tags(&opt, header, vec![Box::new(input)]).unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(
+            fs::read_to_string(&output).unwrap(),
+            "!_TAG_FILE_SORTED\t1\t//\na\ta.rs\t/^a$/;\"\tf\n"
+        );
 
-    let time_write_tags = watch_time!({
-        let _ = write_tags(&opt, &outputs)
-            .context(format!("failed to write file ({:?})", &opt.output))?;
-    });
+        let args = vec!["ptags", "--filter-regex", "("];
+        let opt = Opt::from_iter(args.iter());
+        assert!(write_tags(&opt, "", Vec::new()).is_err());
+    }
 
-    if opt.stat {
-        let sum: usize = files.iter().map(|x| x.lines().count()).sum();
+    #[cfg(unix)]
+    #[test]
+    fn test_write_tags_filter_cmd() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let output = dir.path().join("tags");
+        let args = vec![
+            "ptags",
+            "-f",
+            output.to_str().unwrap(),
+            "--filter-cmd",
+            "sed s/a.rs/b.rs/",
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let input: &[u8] = b"a\ta.rs\t/^a$/;\"\tf\n";
+        assert_eq!(write_tags(&opt, "", vec![Box::new(input)]).unwrap(), 1);
+        assert_eq!(
+            fs::read_to_string(&output).unwrap(),
+            "a\tb.rs\t/^a$/;\"\tf\n"
+        );
 
-        eprintln!("\nStatistics");
-        eprintln!("- Options");
-        eprintln!("    thread    : {}\n", opt.thread);
+        let args = vec![
+            "ptags",
+            "-f",
+            output.to_str().unwrap(),
+            "--filter-cmd",
+            "false",
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let input: &[u8] = b"a\ta.rs\t/^a$/;\"\tf\n";
+        assert!(write_tags(&opt, "", vec![Box::new(input)]).is_err());
+        // The previous tags file is kept, and the temporary file is removed.
+        assert_eq!(
+            fs::read_to_string(&output).unwrap(),
+            "a\tb.rs\t/^a$/;\"\tf\n"
+        );
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
 
-        eprintln!("- Searched files");
-        eprintln!("    total     : {}\n", sum);
+    #[cfg(unix)]
+    #[test]
+    fn test_write_tags_output_cmd() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let output = dir.path().join("tags");
+        let piped = dir.path().join("piped");
+        let cmd = format!("cat > {}", piped.to_str().unwrap());
+        let args = vec![
+            "ptags",
+            "-f",
+            output.to_str().unwrap(),
+            "--filter-cmd",
+            "sed s/a.rs/b.rs/",
+            "--output-cmd",
+            &cmd,
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let input: &[u8] = b"a\ta.rs\t/^a$/;\"\tf\n";
+        assert_eq!(write_tags(&opt, "", vec![Box::new(input)]).unwrap(), 1);
+        assert_eq!(
+            fs::read_to_string(&piped).unwrap(),
+            "a\tb.rs\t/^a$/;\"\tf\n"
+        );
+        assert!(!output.exists());
 
-        eprintln!("- Elapsed time[ms]");
-        eprintln!("    git_files : {}", time_git_files.whole_milliseconds());
-        eprintln!("    call_ctags: {}", time_call_ctags.whole_milliseconds());
-        eprintln!("    write_tags: {}", time_write_tags.whole_milliseconds());
+        let args = vec!["ptags", "--output-cmd", "exit 3"];
+        let opt = Opt::from_iter(args.iter());
+        let input: &[u8] = b"a\ta.rs\t/^a$/;\"\tf\n";
+        assert!(write_tags(&opt, "", vec![Box::new(input)]).is_err());
     }
 
-    Ok(())
-}
+    #[test]
+    fn test_write_tags_split_by_dir() {
+        let dir = tempfile::TempDir::new().unwrap();
+        fs::create_dir_all(dir.path().join("lib/a")).unwrap();
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+        let output = dir.path().join("tags");
+        let args = vec![
+            "ptags",
+            "-f",
+            output.to_str().unwrap(),
+            "--split-by-dir",
+            "1",
+            dir.path().to_str().unwrap(),
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let header = "!_TAG_FILE_SORTED\t1\t//\n";
+        let input: &[u8] = b"a\tsrc/a.rs\t/^a$/;\"\tf\n\
+                              b\tlib/a/b.rs\t/^b$/;\"\tf\n\
+                              c\tbuild.rs\t/^c$/;\"\tf\n\
+                              d\tsrc/d.rs\t/^d$/;\"\tf\n";
+        assert_eq!(write_tags(&opt, header, vec![Box::new(input)]).unwrap(), 4);
+        assert_eq!(
+            fs::read_to_string(&output).unwrap(),
+            "!_TAG_FILE_SORTED\t1\t//\nc\tbuild.rs\t/^c$/;\"\tf\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("src/tags")).unwrap(),
+            "!_TAG_FILE_SORTED\t1\t//\na\ta.rs\t/^a$/;\"\tf\nd\td.rs\t/^d$/;\"\tf\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("lib/tags")).unwrap(),
+            "!_TAG_FILE_SORTED\t1\t//\nb\ta/b.rs\t/^b$/;\"\tf\n"
+        );
 
-#[cfg_attr(tarpaulin, skip)]
-pub fn run() -> Result<(), Error> {
-    let cfg_path = match dirs::home_dir() {
-        Some(mut path) => {
-            path.push(".ptags.toml");
-            if path.exists() {
-                Some(path)
-            } else {
-                None
+        // The previous tags files are kept if the merge fails.
+        let args = vec![
+            "ptags",
+            "-f",
+            output.to_str().unwrap(),
+            "--split-by-dir",
+            "1",
+            "--validate-utf8",
+            dir.path().to_str().unwrap(),
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let invalid: &[u8] = b"a\tsrc/a.rs\t/^\xff$/;\"\tf\n";
+        assert!(write_tags(&opt, header, vec![Box::new(invalid)]).is_err());
+        assert_eq!(
+            fs::read_to_string(dir.path().join("src/tags")).unwrap(),
+            "!_TAG_FILE_SORTED\t1\t//\na\ta.rs\t/^a$/;\"\tf\nd\td.rs\t/^d$/;\"\tf\n"
+        );
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+
+        let args = vec!["ptags", "-f", "-", "--split-by-dir", "1"];
+        let opt = Opt::from_iter(args.iter());
+        assert!(write_tags(&opt, header, vec![Box::new(input)]).is_err());
+    }
+
+    #[test]
+    fn test_write_tags_split_by_dir_outside() {
+        // Tags files of directories are written into `DIR` even if the output is not in it.
+        let dir = tempfile::TempDir::new().unwrap();
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+        let out = tempfile::TempDir::new().unwrap();
+        let output = out.path().join("tags");
+        let args = vec![
+            "ptags",
+            "-f",
+            output.to_str().unwrap(),
+            "--split-by-dir",
+            "1",
+            dir.path().to_str().unwrap(),
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let header = "!_TAG_FILE_SORTED\t1\t//\n";
+        let input: &[u8] = b"a\tsrc/a.rs\t/^a$/;\"\tf\nc\tbuild.rs\t/^c$/;\"\tf\n";
+        assert_eq!(write_tags(&opt, header, vec![Box::new(input)]).unwrap(), 2);
+        assert_eq!(
+            fs::read_to_string(&output).unwrap(),
+            "!_TAG_FILE_SORTED\t1\t//\nc\tbuild.rs\t/^c$/;\"\tf\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("src/tags")).unwrap(),
+            "!_TAG_FILE_SORTED\t1\t//\na\ta.rs\t/^a$/;\"\tf\n"
+        );
+        assert!(!out.path().join("src").exists());
+    }
+
+    #[test]
+    fn test_write_tags_split_by_lang() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let output = dir.path().join("tags");
+        let args = vec!["ptags", "-f", output.to_str().unwrap(), "--split-by-lang"];
+        let opt = Opt::from_iter(args.iter());
+        let header = "!_TAG_FILE_SORTED\t1\t//\n";
+        let input: &[u8] = b"a\ta.rs\t/^a$/;\"\tf\tlanguage:Rust\n\
+                              b\tb.py\t/^b$/;\"\tf\tline:2\tlanguage:Python\n\
+                              c\tc.txt\t/^c$/;\"\tf\n\
+                              d\td.rs\t/^d$/;\"\tf\tlanguage:Rust\n";
+        assert_eq!(write_tags(&opt, header, vec![Box::new(input)]).unwrap(), 4);
+        assert_eq!(
+            fs::read_to_string(&output).unwrap(),
+            "!_TAG_FILE_SORTED\t1\t//\nc\tc.txt\t/^c$/;\"\tf\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("tags.rust")).unwrap(),
+            "!_TAG_FILE_SORTED\t1\t//\n\
+             a\ta.rs\t/^a$/;\"\tf\tlanguage:Rust\n\
+             d\td.rs\t/^d$/;\"\tf\tlanguage:Rust\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("tags.python")).unwrap(),
+            "!_TAG_FILE_SORTED\t1\t//\nb\tb.py\t/^b$/;\"\tf\tline:2\tlanguage:Python\n"
+        );
+    }
+
+    #[test]
+    fn test_write_tags_eol() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let output = dir.path().join("tags");
+        let args = vec!["ptags", "-f", output.to_str().unwrap(), "--eol", "crlf"];
+        let opt = Opt::from_iter(args.iter());
+        let header = "!_TAG_FILE_SORTED\t1\t//\n";
+        let input: &[u8] = b"a\ta.rs\t/^a$/;\"\tf\r\nb\tb.py\t/^b$/;\"\tf\tlanguage:Python\n";
+        assert_eq!(write_tags(&opt, header, vec![Box::new(input)]).unwrap(), 2);
+        let expected = "!_TAG_FILE_SORTED\t1\t//\r\n\
+                        a\ta.rs\t/^a$/;\"\tf\r\n\
+                        b\tb.py\t/^b$/;\"\tf\tlanguage:Python\r\n";
+        assert_eq!(fs::read_to_string(&output).unwrap(), expected);
+
+        // Tags are routed by the language field without CR.
+        let args = vec![
+            "ptags",
+            "-f",
+            output.to_str().unwrap(),
+            "--eol",
+            "crlf",
+            "--split-by-lang",
+        ];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(write_tags(&opt, header, vec![Box::new(input)]).unwrap(), 2);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("tags.python")).unwrap(),
+            "!_TAG_FILE_SORTED\t1\t//\r\nb\tb.py\t/^b$/;\"\tf\tlanguage:Python\r\n"
+        );
+    }
+
+    #[test]
+    fn test_write_tags_shards() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let output = dir.path().join("tags");
+        let args = vec!["ptags", "-f", output.to_str().unwrap(), "--shards", "3"];
+        let opt = Opt::from_iter(args.iter());
+        let header = "!_TAG_FILE_SORTED\t1\t//\n";
+        let input: &[u8] = b"a\ta.rs\t/^a$/;\"\tf\n\
+                              b\ta.rs\t/^b$/;\"\tf\n\
+                              b\tb.rs\t/^b$/;\"\tf\n\
+                              c\ta.rs\t/^c$/;\"\tf\n";
+        assert_eq!(write_tags(&opt, header, vec![Box::new(input)]).unwrap(), 4);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("tags.0")).unwrap(),
+            "!_TAG_FILE_SORTED\t1\t//\n\
+             a\ta.rs\t/^a$/;\"\tf\n\
+             b\ta.rs\t/^b$/;\"\tf\n\
+             b\tb.rs\t/^b$/;\"\tf\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("tags.1")).unwrap(),
+            "!_TAG_FILE_SORTED\t1\t//\nc\ta.rs\t/^c$/;\"\tf\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("tags.2")).unwrap(),
+            "!_TAG_FILE_SORTED\t1\t//\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("tags.index")).unwrap(),
+            "tags.0\ta\tb\ntags.1\tc\tc\n"
+        );
+
+        let args = vec!["ptags", "--shards", "3", "--unsorted"];
+        let opt = Opt::from_iter(args.iter());
+        assert!(write_tags(&opt, header, vec![Box::new(input)]).is_err());
+    }
+
+    #[test]
+    fn test_patch_header() {
+        let header = "!_TAG_FILE_FORMAT\t2\t/extended format/\n\
+                      !_TAG_FILE_SORTED\t1\t/0=unsorted, 1=sorted, 2=foldcase/\n";
+        let args = vec!["ptags", "--unsorted"];
+        let opt = Opt::from_iter(args.iter());
+        let invocation = |x: &str| {
+            vec![(
+                TAG_PTAGS_INVOCATION,
+                String::from(x),
+                format!("ptags {}", env!("CARGO_PKG_VERSION")),
+            )]
+        };
+        assert_eq!(
+            patch_header(
+                &opt,
+                header,
+                &invocation("ptags --unsorted -c '--regex-c=/a\tb/'")
+            ),
+            format!(
+                "!_TAG_FILE_FORMAT\t2\t/extended format/\n\
+                 !_TAG_FILE_SORTED\t0\t/0=unsorted, 1=sorted, 2=foldcase/\n\
+                 !_TAG_PTAGS_INVOCATION\tptags --unsorted -c '--regex-c=/a\\tb/'\t/ptags {}/\n",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+        assert_eq!(patch_header(&opt, "", &invocation("ptags")), "");
+
+        let header = "{\"_type\": \"ptag\", \"name\": \"TAG_FILE_SORTED\", \"path\": \"0\"}\n";
+        let args = vec!["ptags", "--format", "json"];
+        let opt = Opt::from_iter(args.iter());
+        let patched = patch_header(&opt, header, &invocation("ptags"));
+        let lines: Vec<serde_json::Value> = patched
+            .lines()
+            .map(|x| serde_json::from_str(x).unwrap())
+            .collect();
+        assert_eq!(lines[0]["path"], "1");
+        assert_eq!(lines[1]["name"], "TAG_PTAGS_INVOCATION");
+        assert_eq!(lines[1]["path"], "ptags");
+    }
+
+    #[test]
+    fn test_provenance() {
+        let args = vec!["ptags", "--reproducible", "-t", "2", "-v", "--no-vcs"];
+        let opt = Opt::from_iter(args.iter());
+        let ptags = provenance(&opt);
+        let names: Vec<_> = ptags.iter().map(|x| x.0).collect();
+        assert_eq!(names, vec!["TAG_PTAGS_VERSION", "TAG_PTAGS_OPTIONS"]);
+        assert_eq!(ptags[1].1, r#"{"no_vcs":true,"reproducible":true}"#);
+
+        let args = vec!["ptags", "-t", "2"];
+        let opt = Opt::from_iter(args.iter());
+        let ptags = provenance(&opt);
+        assert_eq!(ptags[0].0, "TAG_PTAGS_INVOCATION");
+        assert_eq!(ptags[2].1, r#"{"thread":2}"#);
+        assert_eq!(ptags.last().unwrap().0, "TAG_PTAGS_TIMESTAMP");
+
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_timestamp(1792236645), "2026-10-17T11:30:45Z");
+    }
+
+    #[test]
+    fn test_delta() {
+        assert_eq!(delta(10, Some(7)), " (+3)");
+        assert_eq!(delta(7, Some(10)), " (-3)");
+        assert_eq!(delta(7, Some(7)), " (+0)");
+        assert_eq!(delta(7, None), "");
+    }
+
+    #[test]
+    fn test_diff_lines() {
+        let old = b"!_TAG_PTAGS_INVOCATION\tptags\t//\n\
+                    !_TAG_PTAGS_TIMESTAMP\t2024-01-02T03:04:05Z\t//\n\
+                    a\ta.rs\t/^a$/;\"\tf\n\
+                    b\ta.rs\t/^b$/;\"\tf\n";
+        let new = b"!_TAG_PTAGS_INVOCATION\tptags -v\t//\n\
+                    b\ta.rs\t/^b$/;\"\tf\n\
+                    c\tc.rs\t/^c$/;\"\tf\n";
+        let (added, removed) = diff_lines(old, new);
+        assert_eq!(added, vec![&b"c\tc.rs\t/^c$/;\"\tf"[..]]);
+        assert_eq!(removed, vec![&b"a\ta.rs\t/^a$/;\"\tf"[..]]);
+
+        let (added, removed) = diff_lines(new, new);
+        assert!(added.is_empty() && removed.is_empty());
+
+        let json = br#"{"_type":"ptag","name":"TAG_PTAGS_INVOCATION","path":"ptags"}"#;
+        assert!(is_provenance(json));
+        assert!(!is_provenance(
+            b"TAG_PTAGS_INVOCATION\tsrc/bin.rs\t/^a$/;\"\tc"
+        ));
+    }
+
+    #[test]
+    fn test_project_config() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let sub = dir.path().join("a/b");
+        fs::create_dir_all(&sub).unwrap();
+        assert_eq!(find_project_config(&sub, None), None);
+
+        let path = dir.path().join("a").join(CONFIG_FILE);
+        fs::write(&path, "thread = 4\n").unwrap();
+        let path = path.canonicalize().unwrap();
+        assert_eq!(find_project_config(&sub, None), Some(path.clone()));
+        assert_eq!(find_project_config(&sub, Some(&path)), None);
+
+        let mut config: toml::Table = "thread = 2\nbin_ctags = \"ctags2\"\n".parse().unwrap();
+        merge_config(&mut config, read_config(&path).unwrap());
+        let opt = Opt::from_iter_with_toml(&config.to_string(), &["ptags"]).unwrap();
+        assert_eq!(opt.thread, 4);
+        assert_eq!(opt.bin_ctags, PathBuf::from("ctags2"));
+        let opt = Opt::from_iter_with_toml(&config.to_string(), &["ptags", "-t", "8"]).unwrap();
+        assert_eq!(opt.thread, 8);
+
+        let toml = toml::to_string(&opt).unwrap();
+        assert!(toml.contains("thread = 8"));
+        assert!(!toml.contains("\nconfig ="));
+        assert!(!toml.contains("config_file"));
+    }
+
+    #[test]
+    fn test_multi_list() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        fs::create_dir_all(a.join("src")).unwrap();
+        fs::create_dir_all(&b).unwrap();
+        fs::write(a.join("src/x.rs"), "").unwrap();
+        fs::write(b.join("y.rs"), "").unwrap();
+
+        let args = vec![
+            "ptags",
+            "--no-vcs",
+            a.to_str().unwrap(),
+            b.to_str().unwrap(),
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let dirs = search_dirs(&opt).unwrap().unwrap();
+        assert_eq!(dirs, vec![a.clone(), b.clone()]);
+        let mut list = multi_list(&opt, &dirs, dir.path()).unwrap();
+        list.sort();
+        assert_eq!(list, vec!["a/src/x.rs", "b/y.rs"]);
+
+        let args = vec!["ptags", "--no-vcs", "--repo", "b"];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(search_dirs(&opt).unwrap(), Some(vec![PathBuf::from("b")]));
+
+        let args = vec!["ptags", "src"];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(search_dirs(&opt).unwrap(), None);
+
+        fs::create_dir_all(a.join(".git")).unwrap();
+        fs::create_dir_all(b.join("c")).unwrap();
+        fs::write(b.join("c/.git"), "gitdir: ../.git/modules/c").unwrap();
+        let args = vec!["ptags", "--recurse-repos", dir.path().to_str().unwrap()];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(
+            search_dirs(&opt).unwrap(),
+            Some(vec![a.clone(), b.join("c")])
+        );
+        assert_eq!(search_root(&opt), dir.path());
+    }
+
+    #[test]
+    fn test_plan_memory() {
+        // The largest chunks running at once fit in the budget.
+        assert_eq!(
+            plan_memory(80, &[10, 40, 30, 20], 0, 4),
+            MemoryPlan {
+                jobs: 2,
+                spill: true
             }
-        }
-        None => None,
-    };
+        );
+        assert_eq!(
+            plan_memory(100, &[10, 20, 30], 30, 8),
+            MemoryPlan {
+                jobs: 3,
+                spill: false
+            }
+        );
+        assert_eq!(
+            plan_memory(100, &[10, 20, 30], 50, 8),
+            MemoryPlan {
+                jobs: 3,
+                spill: true
+            }
+        );
+        assert_eq!(
+            plan_memory(100, &[200], 0, 4),
+            MemoryPlan {
+                jobs: 1,
+                spill: true
+            }
+        );
+    }
 
-    let opt = match cfg_path {
-        Some(path) => {
-            let mut f =
-                fs::File::open(&path).context(format!("failed to open file ({:?})", path))?;
-            let mut s = String::new();
-            let _ = f.read_to_string(&mut s);
-            Opt::from_args_with_toml(&s).context(format!("failed to parse toml ({:?})", path))?
-        }
-        None => Opt::from_args(),
-    };
-    run_opt(&opt)
-}
+    #[test]
+    fn test_max_file_size() {
+        assert_eq!(parse_size("100"), Ok(100));
+        assert_eq!(parse_size("10K"), Ok(10 * 1024));
+        assert_eq!(parse_size("2mb"), Ok(2 * 1024 * 1024));
+        assert!(parse_size("1T").is_err());
+        assert!(parse_size("M").is_err());
 
-// ---------------------------------------------------------------------------------------------------------------------
-// Test
-// ---------------------------------------------------------------------------------------------------------------------
+        let dir = tempfile::TempDir::new().unwrap();
+        fs::write(dir.path().join("a.rs"), vec![b'a'; 100]).unwrap();
+        fs::write(dir.path().join("b.rs"), vec![b'b'; 101]).unwrap();
+        let args = vec![
+            "ptags",
+            "--max-file-size",
+            "100",
+            dir.path().to_str().unwrap(),
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let mut list = vec![String::from("a.rs"), String::from("b.rs")];
+        assert_eq!(skip_large_files(&opt, &mut list), 1);
+        assert_eq!(list, vec!["a.rs"]);
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::path::Path;
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("--fields=+n"), "--fields=+n");
+        assert_eq!(shell_quote("-L -"), "'-L -'");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    }
 
     #[test]
-    fn test_run() {
-        let args = vec!["ptags"];
+    fn test_path_rewriter() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let src = root.join("src");
+        let out = root.join("out");
+        fs::create_dir_all(&src).unwrap();
+        fs::create_dir_all(&out).unwrap();
+        let line = b"a\tlib/a.rs\t/^a$/;\"\tf";
+
+        let rewrite = |args: &[&str]| {
+            let mut args: Vec<&str> = args.to_vec();
+            args.insert(0, "ptags");
+            let opt = Opt::from_iter(args.iter());
+            let rewriter = PathRewriter::new(&opt).unwrap().unwrap();
+            String::from_utf8(rewriter.rewrite(&opt, line)).unwrap()
+        };
+        let output = root.join("tags");
+        let output = output.to_str().unwrap();
+        let output_sub = out.join("tags");
+        let output_sub = output_sub.to_str().unwrap();
+        let src = src.to_str().unwrap();
+
+        assert_eq!(
+            rewrite(&["--tag-relative", "yes", "-f", output, src]),
+            "a\tsrc/lib/a.rs\t/^a$/;\"\tf"
+        );
+        assert_eq!(
+            rewrite(&["--tag-relative", "yes", "-f", output_sub, src]),
+            format!("a\t{}/lib/a.rs\t/^a$/;\"\tf", src)
+        );
+        assert_eq!(
+            rewrite(&["--tag-relative", "always", "-f", output_sub, src]),
+            "a\t../src/lib/a.rs\t/^a$/;\"\tf"
+        );
+        assert_eq!(
+            rewrite(&["--tag-relative", "no", src]),
+            format!("a\t{}/lib/a.rs\t/^a$/;\"\tf", src)
+        );
+        assert_eq!(
+            rewrite(&["--strip-prefix", "lib/", "--path-prefix", "/work/"]),
+            "a\t/work/a.rs\t/^a$/;\"\tf"
+        );
+
+        assert_eq!(
+            rewrite(&[
+                "--path-prefix",
+                "/workspace/",
+                "--map-path",
+                "/work=/x",
+                "--map-path",
+                "/workspace=/home/a/repo",
+            ]),
+            "a\t/home/a/repo/lib/a.rs\t/^a$/;\"\tf"
+        );
+        let args = vec!["ptags", "--map-path", "/workspace"];
         let opt = Opt::from_iter(args.iter());
-        let ret = run_opt(&opt);
-        assert!(ret.is_ok());
+        assert!(PathRewriter::new(&opt).is_err());
+
+        let args = vec!["ptags", "--path-prefix", "x/"];
+        let opt = Opt::from_iter(args.iter());
+        let rewriter = PathRewriter::new(&opt).unwrap().unwrap();
+        assert_eq!(
+            rewriter.rewrite(&opt, b"!_TAG_FILE_SORTED\t1\t//"),
+            b"!_TAG_FILE_SORTED\t1\t//".to_vec()
+        );
+
+        let args = vec!["ptags", "--slash", "--strip-prefix", "src/"];
+        let opt = Opt::from_iter(args.iter());
+        let rewriter = PathRewriter::new(&opt).unwrap().unwrap();
+        assert_eq!(
+            rewriter.rewrite(&opt, b"a\tsrc\\lib\\a.rs\t/^a\\b$/;\"\tf"),
+            b"a\tlib/a.rs\t/^a\\b$/;\"\tf".to_vec()
+        );
     }
 
     #[test]
-    fn test_run_opt() {
-        let args = vec!["ptags", "-s", "-v", "--validate-utf8", "--unsorted"];
+    fn test_split_files_newline() {
+        let args = vec!["ptags", "-t", "1"];
         let opt = Opt::from_iter(args.iter());
-        let ret = run_opt(&opt);
+        let list = vec![
+            String::from("a\tb.rs"),
+            String::from("c\nd.rs"),
+            String::from("e.rs"),
+        ];
+        assert_eq!(split_files(&list, &opt), vec!["a\tb.rs\ne.rs\n"]);
+    }
+
+    #[test]
+    fn test_split_files_balance_by_size() {
+        let dir = tempfile::TempDir::new().unwrap();
+        for (f, size) in &[("a", 10), ("b", 1), ("c", 7), ("d", 3), ("e", 2)] {
+            fs::write(dir.path().join(f), vec![b'x'; *size]).unwrap();
+        }
+        let list: Vec<String> = vec!["a", "b", "c", "d", "e"]
+            .iter()
+            .map(|x| String::from(*x))
+            .collect();
+        let dir = dir.path().to_string_lossy();
+        let args = vec!["ptags", "-t", "2", "--balance-by-size", &dir];
+        let opt = Opt::from_iter(args.iter());
+        let files = split_files(&list, &opt);
+        assert_eq!(files, vec!["a\ne\n", "b\nc\nd\n"]);
+    }
+
+    #[test]
+    fn test_write_tags() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let output = dir.path().join("tags");
+        let args = vec!["ptags", "-f", output.to_str().unwrap()];
+        let opt = Opt::from_iter(args.iter());
+        let outputs: Vec<&[u8]> = vec![b"a\nd\ne\n", b"b\nd\n", b"", b"c\nf\n"];
+        let inputs = outputs.iter().map(|x| Box::new(*x) as Box<dyn BufRead>);
+        let ret = write_tags(&opt, "!_TAG\n", inputs.collect());
+        assert!(ret.is_ok());
+        let tags = fs::read_to_string(&output).unwrap();
+        assert_eq!(tags, "!_TAG\na\nb\nc\nd\nd\ne\nf\n");
+
+        let args = vec!["ptags", "-f", output.to_str().unwrap(), "--unsorted"];
+        let opt = Opt::from_iter(args.iter());
+        let inputs = outputs.iter().map(|x| Box::new(*x) as Box<dyn BufRead>);
+        let ret = write_tags(&opt, "", inputs.collect());
         assert!(ret.is_ok());
+        let tags = fs::read_to_string(&output).unwrap();
+        assert_eq!(tags, "a\nd\ne\nb\nd\nc\nf\n");
+    }
+
+    #[test]
+    fn test_write_tags_sort_mode() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let output = dir.path().join("tags");
+        let outputs: Vec<&[u8]> = vec![b"a\nd\ne\n", b"b\nd\n", b"", b"c\r\nf\n"];
+        for mode in &["merge", "full", "auto"] {
+            let args = vec!["ptags", "-f", output.to_str().unwrap(), "--sort-mode", mode];
+            let opt = Opt::from_iter(args.iter());
+            let inputs = outputs.iter().map(|x| Box::new(*x) as Box<dyn BufRead>);
+            assert_eq!(write_tags(&opt, "!_TAG\n", inputs.collect()).unwrap(), 7);
+            let tags = fs::read_to_string(&output).unwrap();
+            assert_eq!(tags, "!_TAG\na\nb\nc\nd\nd\ne\nf\n");
+        }
+    }
+
+    #[test]
+    fn test_write_tags_lossy_utf8() {
+        let args = vec![
+            "ptags",
+            "-f",
+            "test_write_tags_lossy_utf8.tags",
+            "--validate-utf8",
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let input: &[u8] =
+            b"a\ta.c\t3;\"\tf\nb\xff\tb.c\t/^b\x82\xa0$/;\"\tv\tline:7\nc\x82\tc.c\t/^c$/;\"\tf\n";
+        assert!(write_tags(&opt, "", vec![Box::new(input)]).is_err());
+
+        let args = vec![
+            "ptags",
+            "-f",
+            "test_write_tags_lossy_utf8.tags",
+            "--validate-utf8",
+            "--lossy-utf8",
+        ];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(write_tags(&opt, "", vec![Box::new(input)]).unwrap(), 3);
+        let tags = fs::read_to_string("test_write_tags_lossy_utf8.tags").unwrap();
+        assert_eq!(
+            tags,
+            "a\ta.c\t3;\"\tf\nb\u{fffd}\tb.c\t/^b\u{fffd}\u{fffd}$/;\"\tv\tline:7\nc\u{fffd}\tc.c\t/^c$/;\"\tf\n"
+        );
+        let report = fs::read_to_string("test_write_tags_lossy_utf8.tags.lossy-utf8").unwrap();
+        assert_eq!(report, "b.c:7\nc.c\n");
+
+        // The stale report is removed if no tag is affected.
+        let input: &[u8] = b"a\ta.c\t3;\"\tf\n";
+        assert_eq!(write_tags(&opt, "", vec![Box::new(input)]).unwrap(), 1);
+        assert!(!Path::new("test_write_tags_lossy_utf8.tags.lossy-utf8").exists());
+        let _ = fs::remove_file("test_write_tags_lossy_utf8.tags");
+    }
+
+    #[test]
+    fn test_load_tags() {
+        let opt = Opt::from_iter(vec!["ptags"].iter());
+        let outputs: Vec<&[u8]> = vec![b"a\nd\n", b"b\ne\ng\n", b"c\n"];
+        let mut inputs: Vec<Box<dyn BufRead>> = outputs
+            .iter()
+            .map(|x| Box::new(*x) as Box<dyn BufRead>)
+            .collect();
+        let mut loaded = load_tags(&opt, &mut inputs, None).unwrap();
+        assert!(!loaded.overflow);
+        assert_eq!(loaded.input(1), Some(&b"b\ne\ng\n"[..]));
+        loaded.sort(2).unwrap();
+        let lines: Vec<&[u8]> = loaded.lines.iter().map(|x| &loaded.buf[x.0..x.1]).collect();
+        assert_eq!(lines, vec![&b"a"[..], b"b", b"c", b"d", b"e", b"g"]);
+
+        // Loading stops at the line exceeding the limit, and the rest is left in the input.
+        let mut inputs: Vec<Box<dyn BufRead>> = outputs
+            .iter()
+            .map(|x| Box::new(*x) as Box<dyn BufRead>)
+            .collect();
+        let loaded = load_tags(&opt, &mut inputs, Some(5)).unwrap();
+        assert!(loaded.overflow);
+        assert_eq!(loaded.input(0), Some(&b"a\nd\n"[..]));
+        assert_eq!(loaded.input(1), Some(&b"b\n"[..]));
+        assert_eq!(loaded.input(2), None);
+        let mut rest = String::new();
+        inputs[1].read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "e\ng\n");
+    }
+
+    #[test]
+    fn test_read_base_tags() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let output = dir.path().join("tags");
+        let args = vec!["ptags", "-a", "-f", output.to_str().unwrap()];
+        let opt = Opt::from_iter(args.iter());
+        let tags =
+            "!_TAG_FILE_SORTED\t1\t//\na\ta.rs\t1;\"\tf\nb\tb.rs\t1;\"\tf\nc\ta.rs\t2;\"\tf\n";
+        fs::write(&output, tags).unwrap();
+        let mut drop = HashSet::new();
+        drop.insert("a.rs");
+        let base = read_base_tags(&opt, &drop).unwrap();
+        assert_eq!(str::from_utf8(&base).unwrap(), "b\tb.rs\t1;\"\tf\n");
     }
 
     #[test]
@@ -340,6 +4591,137 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_run_fail_hg() {
+        let args = vec!["ptags", "--vcs", "hg", "--bin-hg", "aaa"];
+        let opt = Opt::from_iter(args.iter());
+        let ret = run_opt(&opt);
+        assert_eq!(
+            &format!("{:?}", ret)[0..42],
+            "Err(failed to get file list\n\nCaused by:\n  "
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_warnings() {
+        let dir = TempDir::new().unwrap();
+        let bin = fake_ctags(
+            &dir,
+            "[ \"$1\" = --version ] && echo 'Universal Ctags 6.0.0' && exit 0\n\
+             cat > /dev/null\n\
+             echo 'ctags: Warning: Unknown language' >&2\n",
+        );
+        let output = dir.path().join("tags");
+        let output = output.to_string_lossy();
+
+        let args = vec![
+            "ptags",
+            "--bin-ctags",
+            &bin,
+            "-f",
+            &output,
+            "--warnings",
+            "hide",
+        ];
+        let opt = Opt::from_iter(args.iter());
+        assert!(run_opt(&opt).is_ok());
+
+        // Each of chunks reports the same warning.
+        let log = dir.path().join("warnings.log");
+        let log = log.to_string_lossy();
+        let args = vec![
+            "ptags",
+            "--bin-ctags",
+            &bin,
+            "-f",
+            &output,
+            "-t",
+            "2",
+            "--warnings-log",
+            &log,
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let result = generate(&opt).unwrap();
+        assert_eq!(result.warnings, vec!["ctags: Warning: Unknown language"]);
+        assert_eq!(result.warning_lines, 2);
+        assert_eq!(
+            fs::read_to_string(&*log).unwrap(),
+            "ctags: Warning: Unknown language\n"
+        );
+
+        let args = vec![
+            "ptags",
+            "--bin-ctags",
+            &bin,
+            "-f",
+            &output,
+            "--warnings",
+            "fail",
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let ret = run_opt(&opt).unwrap_err();
+        assert!(matches!(
+            ret.downcast_ref::<RunError>(),
+            Some(RunError::Warnings { .. })
+        ));
+        assert!(Path::new(&*output).exists());
+
+        let args = vec![
+            "ptags",
+            "--bin-ctags",
+            &bin,
+            "-f",
+            &output,
+            "--deny-warnings-regex",
+            "null tag",
+        ];
+        let opt = Opt::from_iter(args.iter());
+        assert!(run_opt(&opt).is_ok());
+        let args = vec![
+            "ptags",
+            "--bin-ctags",
+            &bin,
+            "-f",
+            &output,
+            "--deny-warnings-regex",
+            "Unknown",
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let ret = run_opt(&opt).unwrap_err();
+        assert_eq!(
+            ret.to_string(),
+            "ctags reported 1 denied warnings\n  ctags: Warning: Unknown language"
+        );
+        let args = vec!["ptags", "--deny-warnings-regex", "("];
+        let opt = Opt::from_iter(args.iter());
+        assert!(run_opt(&opt).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_dry_run() {
+        let dir = TempDir::new().unwrap();
+        let called = dir.path().join("called");
+        let bin = fake_ctags(
+            &dir,
+            &format!(
+                "[ \"$1\" = --version ] && echo 'Universal Ctags 6.0.0' && exit 0\n\
+                 touch {}\n",
+                called.to_string_lossy()
+            ),
+        );
+        let output = dir.path().join("tags");
+        let output = output.to_string_lossy();
+
+        let args = vec!["ptags", "--bin-ctags", &bin, "-f", &output, "--dry-run"];
+        let opt = Opt::from_iter(args.iter());
+        assert!(run_opt(&opt).is_ok());
+        assert!(!called.exists());
+        assert!(!Path::new(&*output).exists());
+        assert!(!crate::lock::lock_path(Path::new(&*output)).exists());
+    }
+
     #[test]
     fn test_run_completion() {
         let args = vec!["ptags", "--completion", "bash"];
@@ -0,0 +1,97 @@
+use anyhow::{Context, Error};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Cache
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Stores ctags output of each file keyed by its git blob id.
+///
+/// The output depends on the path ( language detection and file column ) and ctags options too,
+/// so they are mixed into the key.
+pub struct Cache {
+    dir: PathBuf,
+    salt: u64,
+}
+
+impl Cache {
+    pub fn new(dir: &Path, args: &[String]) -> Cache {
+        Cache {
+            dir: dir.to_path_buf(),
+            salt: fnv1a(args.join("\0").as_bytes(), FNV_OFFSET),
+        }
+    }
+
+    pub fn get(&self, path: &str, oid: &str) -> Option<Vec<u8>> {
+        fs::read(self.entry(path, oid)?).ok()
+    }
+
+    pub fn put(&self, path: &str, oid: &str, tags: &[u8]) -> Result<(), Error> {
+        let entry = match self.entry(path, oid) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let dir = entry.parent().unwrap_or(&self.dir);
+        fs::create_dir_all(dir).context(format!("failed to create directory ({:?})", dir))?;
+
+        // Write to a temporary file and rename it so that concurrent readers never see a partial entry.
+        let mut tmp = NamedTempFile::new_in(dir)?;
+        tmp.write_all(tags)?;
+        tmp.persist(&entry)
+            .context(format!("failed to write file ({:?})", entry))?;
+        Ok(())
+    }
+
+    fn entry(&self, path: &str, oid: &str) -> Option<PathBuf> {
+        if oid.len() < 3 || !oid.bytes().all(|x| x.is_ascii_hexdigit()) {
+            return None;
+        }
+        let key = fnv1a(path.as_bytes(), self.salt);
+        Some(
+            self.dir
+                .join(&oid[0..2])
+                .join(format!("{}-{:016x}", &oid[2..], key)),
+        )
+    }
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+// A stable hash is required because keys are persisted across ptags versions.
+fn fnv1a(bytes: &[u8], seed: u64) -> u64 {
+    let mut hash = seed;
+    for b in bytes {
+        hash ^= u64::from(*b);
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::Cache;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_get_put() {
+        let dir = TempDir::new().unwrap();
+        let oid = "0123456789abcdef0123456789abcdef01234567";
+        let cache = Cache::new(dir.path(), &[String::from("--sort=no")]);
+        assert_eq!(cache.get("a.rs", oid), None);
+        cache.put("a.rs", oid, b"a\ta.rs\t1;\"\tf\n").unwrap();
+        assert_eq!(cache.get("a.rs", oid), Some(b"a\ta.rs\t1;\"\tf\n".to_vec()));
+        assert_eq!(cache.get("b.rs", oid), None);
+
+        let cache = Cache::new(dir.path(), &[]);
+        assert_eq!(cache.get("a.rs", oid), None);
+        assert!(cache.put("a.rs", "", b"").is_ok());
+    }
+}
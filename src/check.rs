@@ -0,0 +1,152 @@
+use crate::bin::{tag_file, tag_name, Opt};
+use anyhow::{bail, Context, Error};
+use std::collections::HashSet;
+use std::fs;
+use thiserror::Error;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Error
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Error)]
+enum CheckError {
+    #[error("tags file has {} problems ({})", count, path)]
+    Invalid { count: usize, path: String },
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Check
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Maximum number of problems to be printed.
+const MAX_PROBLEMS: usize = 20;
+
+/// Returns problems of the tags file as `(line number, message)`.
+///
+/// The file is checked for malformed lines, pseudo-tags after tags, sort order declared by `!_TAG_FILE_SORTED`
+/// and tags of files which don't exist in `DIR`.
+pub fn check(opt: &Opt, data: &[u8]) -> Vec<(usize, String)> {
+    let json = opt.format == "json";
+    let mut ret = Vec::new();
+    let mut sorted = false;
+    let mut in_header = true;
+    let mut prev: Option<String> = None;
+    let mut exists = HashSet::new();
+
+    for (i, line) in data.split(|x| *x == b'\n').enumerate() {
+        let num = i + 1;
+        if line.is_empty() {
+            continue;
+        }
+
+        let name = if json {
+            match serde_json::from_slice::<serde_json::Value>(line) {
+                Ok(_) => tag_name(opt, line),
+                Err(_) => {
+                    ret.push((num, String::from("malformed JSON")));
+                    continue;
+                }
+            }
+        } else {
+            tag_name(opt, line)
+        };
+
+        let name = match name {
+            Some(x) => x,
+            None => {
+                if !in_header {
+                    ret.push((num, String::from("pseudo-tag after tags")));
+                }
+                if line.starts_with(b"!_TAG_FILE_SORTED\t1\t") {
+                    sorted = true;
+                }
+                continue;
+            }
+        };
+        in_header = false;
+
+        if !json && line.split(|x| *x == b'\t').count() < 3 {
+            ret.push((num, String::from("malformed tag line")));
+            continue;
+        }
+
+        if sorted {
+            if let Some(ref prev) = prev {
+                if prev.as_bytes() > name.as_bytes() {
+                    ret.push((num, format!("not sorted ({} after {})", name, prev)));
+                }
+            }
+        }
+        prev = Some(name);
+
+        if let Some(file) = tag_file(opt, line) {
+            if !exists.contains(&file) {
+                if opt.dir.join(&file).exists() {
+                    exists.insert(file);
+                } else {
+                    ret.push((num, format!("file is not found ({})", file)));
+                }
+            }
+        }
+    }
+    ret
+}
+
+pub fn run(opt: &Opt) -> Result<(), Error> {
+    let data = fs::read(&opt.output).context(format!("failed to read file ({:?})", opt.output))?;
+    let problems = check(opt, &data);
+    for (num, msg) in problems.iter().take(MAX_PROBLEMS) {
+        eprintln!("{}:{}: {}", opt.output.to_string_lossy(), num, msg);
+    }
+    if problems.len() > MAX_PROBLEMS {
+        eprintln!("... and {} more", problems.len() - MAX_PROBLEMS);
+    }
+    if !problems.is_empty() {
+        bail!(CheckError::Invalid {
+            count: problems.len(),
+            path: opt.output.to_string_lossy().into_owned(),
+        });
+    }
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::check;
+    use crate::bin::Opt;
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_check() {
+        let args = vec!["ptags"];
+        let opt = Opt::from_iter(args.iter());
+
+        let tags = b"!_TAG_FILE_SORTED\t1\t//\n\
+                     a\tsrc/bin.rs\t/^a$/;\"\tf\n\
+                     b\tsrc/bin.rs\t/^b$/;\"\tf\n";
+        assert!(check(&opt, tags).is_empty());
+
+        let tags = b"!_TAG_FILE_SORTED\t1\t//\n\
+                     b\tsrc/bin.rs\t/^b$/;\"\tf\n\
+                     a\tsrc/bin.rs\t/^a$/;\"\tf\n\
+                     !_TAG_PROGRAM_NAME\tctags\t//\n\
+                     c\tnot_found.rs\t/^c$/;\"\tf\n\
+                     d\n";
+        assert_eq!(
+            check(&opt, tags),
+            vec![
+                (3, String::from("not sorted (a after b)")),
+                (4, String::from("pseudo-tag after tags")),
+                (5, String::from("file is not found (not_found.rs)")),
+                (6, String::from("malformed tag line")),
+            ]
+        );
+
+        let tags = b"b\tsrc/bin.rs\t/^b$/;\"\tf\na\tsrc/bin.rs\t/^a$/;\"\tf\n";
+        assert!(check(&opt, tags).is_empty());
+    }
+}
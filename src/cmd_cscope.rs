@@ -0,0 +1,162 @@
+use crate::audit::{self, Counter};
+use crate::bin::{shell_quote, Opt};
+use crate::signal;
+use anyhow::{bail, Context, Error};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Instant;
+use thiserror::Error;
+use tracing::debug;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Error
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Error)]
+enum CscopeError {
+    #[error("failed to execute cscope command ({})\n{}", cmd, err)]
+    ExecFailed { cmd: String, err: String },
+
+    #[error("failed to call cscope command ({})", cmd)]
+    CallFailed { cmd: String },
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// CmdCscope
+// ---------------------------------------------------------------------------------------------------------------------
+
+pub struct CmdCscope;
+
+impl CmdCscope {
+    /// Builds the cscope cross-reference of the files.
+    ///
+    /// cscope can't merge databases built separately, so all files are passed to a cscope process.
+    pub fn call(opt: &Opt, files: &[String]) -> Result<(), Error> {
+        let args = CmdCscope::get_args(opt);
+        let cmd = CmdCscope::get_cmd(opt, &args);
+        debug!(cmd = %cmd, "call cscope");
+
+        let started = Instant::now();
+        let mut command = Command::new(&opt.bin_cscope);
+        let mut child = signal::isolate(&mut command)
+            .args(&args)
+            .current_dir(&opt.dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context(CscopeError::CallFailed { cmd: cmd.clone() })?;
+
+        let pid = child.id();
+        signal::register_child(pid);
+        let bytes_in = {
+            let mut stdin = Counter::new(child.stdin.take().unwrap());
+            for f in files {
+                let _ = stdin.write_all(CmdCscope::quote(f).as_bytes());
+                let _ = stdin.write_all(b"\n");
+            }
+            stdin.bytes
+        };
+        let output = child.wait_with_output();
+        signal::unregister_child(pid);
+        let status = output.as_ref().ok().map(|x| x.status);
+        audit::record(&command, started, status, bytes_in, 0);
+        let output = output?;
+
+        if !output.status.success() {
+            bail!(CscopeError::ExecFailed {
+                cmd,
+                err: String::from_utf8_lossy(&output.stderr).into_owned(),
+            });
+        }
+        Ok(())
+    }
+
+    fn get_args(opt: &Opt) -> Vec<String> {
+        let mut args = vec![
+            String::from("-b"),
+            String::from("-u"),
+            String::from("-i"),
+            String::from("-"),
+            String::from("-f"),
+        ];
+        args.push(String::from(CmdCscope::output(opt).to_string_lossy()));
+        args.append(&mut opt.opt_cscope.clone());
+        args
+    }
+
+    /// Returns the output filename which is `cscope.out` instead of the default `tags`.
+    pub fn output(opt: &Opt) -> PathBuf {
+        if opt.output == Path::new("tags") {
+            PathBuf::from("cscope.out")
+        } else {
+            opt.output.clone()
+        }
+    }
+
+    /// Quotes the filename including spaces as cscope namefile requires.
+    fn quote(file: &str) -> String {
+        if file.contains(' ') || file.contains('"') {
+            format!("\"{}\"", file.replace('\\', "\\\\").replace('"', "\\\""))
+        } else {
+            String::from(file)
+        }
+    }
+
+    fn get_cmd(opt: &Opt, args: &[String]) -> String {
+        let mut cmd = format!(
+            "cd {}; {}",
+            shell_quote(&opt.dir.to_string_lossy()),
+            shell_quote(&opt.bin_cscope.to_string_lossy())
+        );
+        for arg in args {
+            cmd = format!("{} {}", cmd, shell_quote(arg));
+        }
+        cmd
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::CmdCscope;
+    use crate::bin::Opt;
+    use std::path::PathBuf;
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_get_args() {
+        let args = vec!["ptags", "--format", "cscope", "--opt-cscope=-q"];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(
+            CmdCscope::get_args(&opt),
+            vec!["-b", "-u", "-i", "-", "-f", "cscope.out", "-q"]
+        );
+
+        let args = vec!["ptags", "--format", "cscope", "-f", "db.out"];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(CmdCscope::output(&opt), PathBuf::from("db.out"));
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(CmdCscope::quote("a.c"), "a.c");
+        assert_eq!(CmdCscope::quote("a b.c"), "\"a b.c\"");
+        assert_eq!(CmdCscope::quote("a \"b\".c"), "\"a \\\"b\\\".c\"");
+    }
+
+    #[test]
+    fn test_command_fail() {
+        let args = vec!["ptags", "--format", "cscope", "--bin-cscope", "aaa"];
+        let opt = Opt::from_iter(args.iter());
+        let ret = CmdCscope::call(&opt, &[String::from("a.c")]);
+        assert_eq!(
+            &format!("{:?}", ret)[0..44],
+            "Err(failed to call cscope command (cd .; aaa"
+        );
+    }
+}
@@ -1,19 +1,27 @@
-use crate::bin::Opt;
+use crate::audit;
+use crate::bin::{shell_quote, Opt};
+use crate::sandbox::Sandbox;
+use crate::signal;
 use anyhow::{bail, Context, Error};
+use globset::{Glob, GlobMatcher};
 #[cfg(target_os = "linux")]
 use nix::fcntl::{fcntl, FcntlArg};
-use std::fs;
-use std::fs::File;
-use std::io::{BufReader, Read, Write};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
 #[cfg(target_os = "linux")]
 use std::os::unix::io::AsRawFd;
-use std::path::PathBuf;
-use std::process::{ChildStdin, Command, Output, Stdio};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, ExitStatus, Stdio};
 use std::str;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
 use std::thread;
-use tempfile::NamedTempFile;
+use std::time::{Duration, Instant};
 use thiserror::Error;
+use tracing::debug;
+use wait_timeout::ChildExt;
 
 // ---------------------------------------------------------------------------------------------------------------------
 // Error
@@ -24,227 +32,2032 @@ enum CtagsError {
     #[error("failed to execute ctags command ({})\n{}", cmd, err)]
     ExecFailed { cmd: String, err: String },
 
+    #[error("failed to execute ctags command on {} ({})\n{}", file, cmd, err)]
+    FileFailed {
+        file: String,
+        cmd: String,
+        err: String,
+    },
+
     #[error("failed to call ctags command ({})", cmd)]
     CallFailed { cmd: String },
 
-    #[error("failed to convert to UTF-8 ({:?})", s)]
-    ConvFailed { s: Vec<u8> },
+    #[error(
+        "failed to call ctags command by the limit of processes or open files ({})",
+        cmd
+    )]
+    ResourceExhausted { cmd: String },
+
+    #[error("ctags command timed out after {} seconds ({})", secs, cmd)]
+    TimedOut { cmd: String, secs: u64 },
+
+    #[error(
+        "{} is not supported by {}, Universal Ctags is required",
+        option,
+        version
+    )]
+    Unsupported { option: String, version: String },
+
+    #[error("invalid value of {} ({})", option, value)]
+    InvalidOption { option: String, value: String },
+
+    #[error("invalid {} in config ({})", key, reason)]
+    InvalidConfig { key: String, reason: String },
+
+    #[error("{} requires {}", option, required)]
+    Requires { option: String, required: String },
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Config
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// `[ctags]` section of config files.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CtagsConfig {
+    /// `[ctags.lang.<LANG>]` sections
+    pub lang: BTreeMap<String, LangConfig>,
+    /// `[ctags.regex.<LANG>]` sections
+    pub regex: BTreeMap<String, RegexConfig>,
+}
+
+/// Options of a language translated into ctags options.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LangConfig {
+    /// Passed as `--kinds-<LANG>=<kinds>` ( ex. "+fm" )
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kinds: Option<String>,
+    /// Passed as `--langmap=<LANG>:<langmap>` ( ex. "+.rs.in" )
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub langmap: Option<String>,
+    /// Passed as `--regex-<LANG>=<regex>` for each
+    pub regex: Vec<String>,
+}
+
+/// Regex parser of a language, which is defined by `--langdef` if it is not a builtin language.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RegexConfig {
+    /// Defines the language by `--langdef=<LANG>`
+    pub langdef: bool,
+    /// `[[ctags.regex.<LANG>.patterns]]` entries
+    pub patterns: Vec<RegexPattern>,
+}
+
+/// Pattern passed as `--regex-<LANG>=/<regex>/<name>/<kind>/<flags>`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RegexPattern {
+    pub regex: String,
+    /// Tag name with back references ( ex. "\\1" )
+    pub name: String,
+    /// Kind letter with optional name and description ( ex. "f,function" )
+    pub kind: String,
+    /// Flags of ctags ( ex. "{scope=push}" )
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flags: Option<String>,
+    /// Passed as `--mline-regex-<LANG>` to match across lines, which requires `{mgroup=N}` of flags
+    pub multiline: bool,
+}
+
+impl RegexPattern {
+    fn get_arg(&self, lang: &str) -> String {
+        format!(
+            "--{}regex-{}=/{}/{}/{}/{}",
+            if self.multiline { "mline-" } else { "" },
+            lang,
+            escape_delimiter(&self.regex),
+            escape_delimiter(&self.name),
+            self.kind,
+            self.flags.as_deref().unwrap_or("")
+        )
+    }
+
+    /// Returns the key and the reason of the first problem.
+    fn validate(&self) -> Option<(&'static str, String)> {
+        if self.regex.is_empty() {
+            return Some(("regex", String::from("empty")));
+        }
+        if let Err(x) = regex::Regex::new(&self.regex) {
+            return Some(("regex", x.to_string()));
+        }
+        if !self.multiline && self.regex.contains('\n') {
+            return Some((
+                "regex",
+                String::from("newline matches only with multiline = true"),
+            ));
+        }
+        let letter = self.kind.split(',').next().unwrap_or("");
+        if letter.chars().count() != 1 || !letter.chars().all(|x| x.is_ascii_alphabetic()) {
+            return Some((
+                "kind",
+                String::from("must start with a kind letter like \"f,function\""),
+            ));
+        }
+        if self.kind.contains(['/', '\n']) {
+            return Some(("kind", String::from("'/' and newline are not allowed")));
+        }
+        let flags = self.flags.as_deref().unwrap_or("");
+        if flags.contains(['/', '\n']) {
+            return Some(("flags", String::from("'/' and newline are not allowed")));
+        }
+        if self.multiline && !flags.contains("{mgroup=") {
+            return Some((
+                "flags",
+                String::from("{mgroup=N} is required by multiline = true"),
+            ));
+        }
+        None
+    }
+}
+
+/// Escapes `/` which is not escaped yet, because it is the delimiter of `--regex-<LANG>`.
+fn escape_delimiter(s: &str) -> String {
+    let mut ret = String::new();
+    let mut escaped = false;
+    for c in s.chars() {
+        if c == '/' && !escaped {
+            ret.push('\\');
+        }
+        escaped = c == '\\' && !escaped;
+        ret.push(c);
+    }
+    ret
+}
+
+fn is_valid_lang(lang: &str) -> bool {
+    !lang.is_empty() && !lang.contains(|x: char| x.is_whitespace() || x == '=' || x == ':')
+}
+
+impl CtagsConfig {
+    /// Returns ctags options translated from `[ctags.regex.<LANG>]` and `[ctags.lang.<LANG>]` sections.
+    ///
+    /// Languages defined by `--langdef` are placed first so that other options can refer to them.
+    pub fn get_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        for (lang, config) in &self.regex {
+            if config.langdef {
+                args.push(format!("--langdef={}", lang));
+            }
+            for pattern in &config.patterns {
+                args.push(pattern.get_arg(lang));
+            }
+        }
+        for (lang, config) in &self.lang {
+            if let Some(ref x) = config.langmap {
+                args.push(format!("--langmap={}:{}", lang, x));
+            }
+            if let Some(ref x) = config.kinds {
+                args.push(format!("--kinds-{}={}", lang, x));
+            }
+            for x in &config.regex {
+                args.push(format!("--regex-{}={}", lang, x));
+            }
+        }
+        args
+    }
+
+    /// Checks values which ctags would reject, and returns the error pointing at the key.
+    pub fn check(&self) -> Result<(), Error> {
+        for (lang, config) in &self.lang {
+            let invalid = |key: &str, value: &str| CtagsError::InvalidOption {
+                option: format!("ctags.lang.{}{}", lang, key),
+                value: String::from(value),
+            };
+            if !is_valid_lang(lang) {
+                bail!(invalid("", lang));
+            }
+            if let Some(ref x) = config.kinds {
+                if x.is_empty() || x.contains(char::is_whitespace) {
+                    bail!(invalid(".kinds", x));
+                }
+            }
+            if let Some(ref x) = config.langmap {
+                if x.is_empty() || x.contains(char::is_whitespace) {
+                    bail!(invalid(".langmap", x));
+                }
+            }
+        }
+        for (lang, config) in &self.regex {
+            if !is_valid_lang(lang) {
+                bail!(CtagsError::InvalidConfig {
+                    key: format!("ctags.regex.{}", lang),
+                    reason: String::from("invalid language name"),
+                });
+            }
+            for (i, pattern) in config.patterns.iter().enumerate() {
+                if let Some((key, reason)) = pattern.validate() {
+                    bail!(CtagsError::InvalidConfig {
+                        key: format!("ctags.regex.{}.patterns[{}].{}", lang, i, key),
+                        reason,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if any pattern requires `--mline-regex-<LANG>` of Universal Ctags.
+    fn has_multiline(&self) -> bool {
+        self.regex
+            .values()
+            .flat_map(|x| &x.patterns)
+            .any(|x| x.multiline)
+    }
 }
 
 // ---------------------------------------------------------------------------------------------------------------------
 // CmdCtags
 // ---------------------------------------------------------------------------------------------------------------------
 
+/// How the file list of a chunk is passed to ctags.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FileList {
+    /// `-L -` through the pipe whose size is set up to the given bytes, or left as the default of the system
+    Stdin { pipe_size: Option<usize> },
+    /// `-L <file>` of a temporary file
+    TempFile,
+}
+
+/// Limits of each ctags process.
+#[derive(Clone, Debug, Default)]
+struct Limits {
+    /// Duration after which ptags kills the process
+    timeout: Option<Duration>,
+    /// RLIMIT_CPU in seconds
+    cpu: Option<u64>,
+    /// RLIMIT_DATA in bytes
+    mem: Option<u64>,
+    /// Lowest CPU and I/O priority
+    low_priority: bool,
+    /// Confinement by `--sandbox`
+    sandbox: Option<Arc<Sandbox>>,
+    /// C locale by `--reproducible`, so that ctags sorts tags in bytes regardless of the environment
+    c_locale: bool,
+}
+
+/// Initial interval of retries when spawning ctags fails by the limit of processes or open files.
+const SPAWN_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Maximum interval of the retries, after which the last thread gives up.
+const SPAWN_BACKOFF_MAX: Duration = Duration::from_millis(3200);
+
+/// Chunks waiting for ctags, which are taken by worker threads.
+#[derive(Debug)]
+struct ChunkQueue {
+    /// Index, encoding group and file list of each chunk
+    chunks: VecDeque<(usize, usize, String)>,
+    /// Number of threads which still take chunks
+    threads: usize,
+    /// No more chunk is sent
+    closed: bool,
+}
+
+/// `ChunkQueue` shared by worker threads and `ChunkSender`.
+#[derive(Debug)]
+struct SharedQueue {
+    inner: Mutex<ChunkQueue>,
+    /// Notified when a chunk is pushed or the queue is closed
+    ready: Condvar,
+}
+
+impl SharedQueue {
+    /// Takes the next chunk, or leaves the queue if it is empty and closed.
+    ///
+    /// Threads leave under the same lock as chunks are returned by failed threads,
+    /// so that a returned chunk is always taken by a remaining thread.
+    fn pop(&self) -> Option<(usize, usize, String)> {
+        let mut queue = self.inner.lock().unwrap();
+        loop {
+            if let Some(x) = queue.chunks.pop_front() {
+                return Some(x);
+            }
+            if queue.closed {
+                queue.threads -= 1;
+                return None;
+            }
+            queue = self.ready.wait(queue).unwrap();
+        }
+    }
+}
+
+/// Sender of chunks to worker threads started by `CmdCtags::start`.
+///
+/// The queue is closed when the sender is dropped, and the threads finish after the remaining chunks.
+pub struct ChunkSender {
+    queue: Arc<SharedQueue>,
+    next: usize,
+}
+
+impl ChunkSender {
+    pub fn send(&mut self, chunk: String) {
+        self.send_group(0, chunk);
+    }
+
+    /// Sends the chunk called with `--input-encoding` of the group, which is 0 for `--encoding`
+    /// and `i + 1` for the `i`-th pattern of `--encoding-for`.
+    fn send_group(&mut self, group: usize, chunk: String) {
+        let mut queue = self.queue.inner.lock().unwrap();
+        queue.chunks.push_back((self.next, group, chunk));
+        self.next += 1;
+        self.queue.ready.notify_one();
+    }
+}
+
+impl Drop for ChunkSender {
+    fn drop(&mut self) {
+        let mut queue = self.queue.inner.lock().unwrap_or_else(|x| x.into_inner());
+        queue.closed = true;
+        self.queue.ready.notify_all();
+    }
+}
+
+/// Worker threads started by `CmdCtags::start`, whose outputs are received as each chunk is finished.
+pub struct CtagsRun {
+    rx: mpsc::Receiver<(usize, Result<Vec<CtagsOutput>, Error>)>,
+    cmd: String,
+    keep_going: bool,
+}
+
+impl CtagsRun {
+    /// Returns the index and outputs of the next finished chunk, or `None` after all chunks are finished.
+    pub fn recv(&self) -> Option<(usize, Result<Vec<CtagsOutput>, Error>)> {
+        self.rx.recv().ok()
+    }
+
+    /// Returns outputs in the order of chunks from the received results.
+    pub fn collect(
+        self,
+        mut results: Vec<(usize, Result<Vec<CtagsOutput>, Error>)>,
+    ) -> Result<Vec<CtagsOutput>, Error> {
+        results.sort_by_key(|x| x.0);
+
+        let cmd = self.cmd;
+        let mut outputs = Vec::new();
+        for (_, result) in results {
+            for output in result? {
+                // Failed outputs are returned as is in keep-going mode and skipped by the caller.
+                if !output.status.success() && !self.keep_going {
+                    let err = String::from_utf8_lossy(&output.stderr).into_owned();
+                    if output.files == 1 {
+                        bail!(CtagsError::FileFailed {
+                            file: String::from(output.list.trim_end()),
+                            cmd,
+                            err,
+                        });
+                    }
+                    bail!(CtagsError::ExecFailed { cmd, err });
+                }
+
+                outputs.push(output);
+            }
+        }
+
+        Ok(outputs)
+    }
+}
+
+/// Output of a ctags process whose tags are spilled into a temporary file.
+#[derive(Debug)]
+pub struct CtagsOutput {
+    pub status: ExitStatus,
+    pub stdout: File,
+    pub stderr: Vec<u8>,
+    /// File list passed to ctags
+    pub list: String,
+    pub thread: usize,
+    pub files: usize,
+    pub started: Instant,
+    pub elapsed: Duration,
+    /// Files skipped because ctags timed out on them
+    pub timed_out: Vec<String>,
+    /// Tags are already merged into another input by `--pipeline`
+    pub merged: bool,
+}
+
+impl CtagsOutput {
+    pub fn reader(&self) -> Result<BufReader<File>, Error> {
+        let mut f = self.stdout.try_clone()?;
+        f.seek(SeekFrom::Start(0))?;
+        Ok(BufReader::new(f))
+    }
+
+    pub fn read(&self) -> Result<Vec<u8>, Error> {
+        let mut ret = Vec::new();
+        self.reader()?.read_to_end(&mut ret)?;
+        Ok(ret)
+    }
+
+    /// Returns the output of files skipped because ctags timed out on them.
+    fn timed_out(list: &str, timeout: Option<Duration>) -> Result<CtagsOutput, Error> {
+        Ok(CtagsOutput {
+            status: ExitStatus::default(),
+            stdout: tempfile::tempfile()?,
+            stderr: Vec::new(),
+            list: String::from(list),
+            thread: 0,
+            files: list.lines().count(),
+            started: Instant::now() - timeout.unwrap_or_default(),
+            elapsed: timeout.unwrap_or_default(),
+            timed_out: list.lines().map(String::from).collect(),
+            merged: false,
+        })
+    }
+
+    /// Returns a failed output of files on which ctags couldn't be executed, with the error as stderr.
+    fn failed(list: &str, err: &Error) -> Result<CtagsOutput, Error> {
+        Ok(CtagsOutput {
+            status: failed_status(),
+            stdout: tempfile::tempfile()?,
+            stderr: format!("{:#}\n", err).into_bytes(),
+            list: String::from(list),
+            thread: 0,
+            files: list.lines().count(),
+            started: Instant::now(),
+            elapsed: Duration::default(),
+            timed_out: Vec::new(),
+            merged: false,
+        })
+    }
+}
+
+/// Returns the exit status of a process which exited with 1.
+#[cfg(unix)]
+fn failed_status() -> ExitStatus {
+    std::os::unix::process::ExitStatusExt::from_raw(1 << 8)
+}
+
+#[cfg(windows)]
+fn failed_status() -> ExitStatus {
+    std::os::windows::process::ExitStatusExt::from_raw(1)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CtagsFlavor {
+    Universal,
+    Exuberant,
+    Unknown,
+}
+
+#[derive(Clone, Debug)]
+pub struct CtagsInfo {
+    pub flavor: CtagsFlavor,
+    /// Name and version printed by `ctags --version`
+    pub version: String,
+}
+
+static CTAGS_INFO: Mutex<BTreeMap<Vec<OsString>, CtagsInfo>> = Mutex::new(BTreeMap::new());
+
 pub struct CmdCtags;
 
 impl CmdCtags {
-    pub fn call(opt: &Opt, files: &[String]) -> Result<Vec<Output>, Error> {
-        let mut args = Vec::new();
-        args.push(String::from("-L -"));
-        args.push(String::from("-f -"));
-        if opt.unsorted {
-            args.push(String::from("--sort=no"));
+    pub fn call(opt: &Opt, files: &[String]) -> Result<Vec<CtagsOutput>, Error> {
+        let (mut sender, run) = CmdCtags::start(opt)?;
+        if opt.encoding_for.is_empty() {
+            for f in files {
+                sender.send(f.clone());
+            }
+        } else {
+            CmdCtags::send_by_encoding(opt, &mut sender, files)?;
         }
-        for e in &opt.exclude {
-            args.push(String::from(format!("--exclude={}", e)));
+        drop(sender);
+        let results = std::iter::from_fn(|| run.recv()).collect();
+        run.collect(results)
+    }
+
+    /// Sends chunks split into the groups by `--encoding-for` because `--input-encoding` applies to all files of a process.
+    ///
+    /// Each chunk is split keeping the order of files, and chunks of all groups share the queue of worker threads.
+    fn send_by_encoding(
+        opt: &Opt,
+        sender: &mut ChunkSender,
+        files: &[String],
+    ) -> Result<(), Error> {
+        let overrides = CmdCtags::encoding_overrides(opt)?;
+        for chunk in files {
+            // Files matching no pattern are in the first group.
+            let mut split = vec![String::new(); overrides.len() + 1];
+            for f in chunk.split_terminator('\n') {
+                let i = overrides
+                    .iter()
+                    .position(|x| x.0.is_match(f))
+                    .map_or(0, |x| x + 1);
+                split[i].push_str(f);
+                split[i].push('\n');
+            }
+            for (group, x) in split.into_iter().enumerate() {
+                if !x.is_empty() {
+                    sender.send_group(group, x);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns glob patterns and encodings of `--encoding-for`. The first matching pattern is taken.
+    fn encoding_overrides(opt: &Opt) -> Result<Vec<(GlobMatcher, String)>, Error> {
+        let mut ret = Vec::new();
+        for x in &opt.encoding_for {
+            match x.rsplit_once('=') {
+                Some((glob, encoding)) if !glob.is_empty() && !encoding.is_empty() => {
+                    let glob = Glob::new(glob)
+                        .context(format!("failed to parse --encoding-for ({})", x))?;
+                    ret.push((glob.compile_matcher(), String::from(encoding)));
+                }
+                _ => bail!(CtagsError::InvalidOption {
+                    option: String::from("--encoding-for"),
+                    value: x.clone(),
+                }),
+            }
         }
-        args.append(&mut opt.opt_ctags.clone());
+        Ok(ret)
+    }
+
+    /// Starts worker threads which call ctags for each chunk sent by the returned sender.
+    pub fn start(opt: &Opt) -> Result<(ChunkSender, CtagsRun), Error> {
+        CmdCtags::check(opt)?;
+        let workers = opt.persistent_workers && CmdCtags::supports_interactive(opt);
+        if opt.persistent_workers && !workers && !opt.quiet {
+            eprintln!(
+                "{} doesn't support --_interactive=sandbox, ctags is called for each chunk",
+                CmdCtags::info(opt).version
+            );
+        }
+        let launcher = CmdCtags::get_launcher(opt);
 
-        let cmd = CmdCtags::get_cmd(&opt, &args);
+        // Arguments and the command line of each encoding group, whose index is sent with chunks.
+        let mut encodings = vec![opt.encoding.clone()];
+        for (_, x) in CmdCtags::encoding_overrides(opt)? {
+            encodings.push(Some(x));
+        }
+        let mut commands = Vec::new();
+        for encoding in encodings {
+            let opt = Opt {
+                encoding,
+                ..opt.clone()
+            };
+            let args = if workers {
+                CmdCtags::get_worker_args(&opt)
+            } else {
+                CmdCtags::get_args(&opt)
+            };
+            let args = CmdCtags::quote_args(&opt, &args);
+            let cmd = CmdCtags::get_cmd(&opt, &launcher, &args);
+            commands.push((args, cmd));
+        }
+        let commands = Arc::new(commands);
 
-        let (tx, rx) = mpsc::channel::<Result<Output, Error>>();
+        // Each worker thread pulls the next chunk from the shared queue when its ctags process is finished,
+        // so that a thread which receives heavy files doesn't become the long pole.
+        // The number of workers bounds the number of concurrent ctags processes independently of the number of chunks.
+        // If spawning ctags fails by the limit of processes or open files, the thread returns the chunk and leaves,
+        // so the run continues with fewer concurrent processes. The last thread retries with backoff instead.
+        let queue = ChunkQueue {
+            chunks: VecDeque::new(),
+            threads: opt.jobs(),
+            closed: false,
+        };
+        let queue = Arc::new(SharedQueue {
+            inner: Mutex::new(queue),
+            ready: Condvar::new(),
+        });
 
-        for i in 0..opt.thread {
+        let (tx, rx) = mpsc::channel::<(usize, Result<Vec<CtagsOutput>, Error>)>();
+
+        let sandbox = Sandbox::new(opt)?.map(Arc::new);
+        for t in 0..opt.jobs() {
             let tx = tx.clone();
-            let file = files[i].clone();
+            let queue = queue.clone();
             let dir = opt.dir.clone();
-            let bin_ctags = opt.bin_ctags.clone();
-            let args = args.clone();
-            let cmd = cmd.clone();
-
-            if opt.verbose {
-                eprintln!("Call : {}", cmd);
-            }
+            let launcher = launcher.clone();
+            let commands = commands.clone();
+            let limits = CmdCtags::limits(opt, &sandbox);
+            let retry = opt.retry_timeout;
+            let keep_going = opt.keep_going;
+            let list = CmdCtags::file_list(opt);
+            let sorted = !opt.unsorted;
 
             thread::spawn(move || {
-                let child = Command::new(bin_ctags.clone())
-                    .args(args)
-                    .current_dir(dir)
-                    .stdin(Stdio::piped())
-                    .stdout(Stdio::piped())
-                    //.stderr(Stdio::piped()) // Stdio::piped is x2 slow to wait_with_output() completion
-                    .stderr(Stdio::null())
-                    .spawn();
-                match child {
-                    Ok(mut x) => {
-                        {
-                            let stdin = x.stdin.as_mut().unwrap();
-                            let pipe_size = std::cmp::min(file.len() as i32, 1048576);
-                            let _ = CmdCtags::set_pipe_size(&stdin, pipe_size)
-                                .or_else(|x| tx.send(Err(x.into())));
-                            let _ = stdin.write_all(file.as_bytes());
-                        }
-                        match x.wait_with_output() {
-                            Ok(x) => {
-                                let _ = tx.send(Ok(x));
+                // The worker process of each encoding group is spawned by the first chunk of the group,
+                // and kept until the queue is empty.
+                let mut worker: Vec<Option<Worker>> = commands.iter().map(|_| None).collect();
+                let mut backoff = SPAWN_BACKOFF;
+                while let Some((i, group, file)) = queue.pop() {
+                    let (args, cmd) = &commands[group];
+                    debug!(cmd = %cmd, chunk = i, thread = t, files = file.lines().count(), "call ctags");
+                    let output = if workers {
+                        let ctx = WorkerContext {
+                            launcher: &launcher,
+                            args,
+                            dir: &dir,
+                            cmd,
+                            limits: &limits,
+                        };
+                        CmdCtags::exec_worker(&mut worker[group], &ctx, &file, sorted)
+                    } else {
+                        match CmdCtags::exec(&launcher, args, &dir, &file, cmd, &limits, list) {
+                            Err(x) if retry && CmdCtags::is_timed_out(&x) => {
+                                CmdCtags::retry(&launcher, args, &dir, &file, cmd, &limits, list)
                             }
-                            Err(x) => {
-                                let _ = tx.send(Err(x.into()));
+                            // Files are isolated only if tags of the rest are written by `--keep-going`.
+                            Ok(x) if keep_going && !x.status.success() && x.files > 1 => {
+                                CmdCtags::bisect(&launcher, args, &dir, x, cmd, &limits, list)
                             }
+                            x => x.map(|x| vec![x]),
+                        }
+                    };
+                    if output.as_ref().is_err_and(CmdCtags::is_resource_exhausted) {
+                        let mut inner = queue.inner.lock().unwrap();
+                        if inner.threads > 1 {
+                            inner.threads -= 1;
+                            inner.chunks.push_front((i, group, file));
+                            queue.ready.notify_one();
+                            debug!(
+                                chunk = i,
+                                thread = t,
+                                jobs = inner.threads,
+                                "reduce ctags jobs"
+                            );
+                            break;
+                        }
+                        if backoff <= SPAWN_BACKOFF_MAX {
+                            inner.chunks.push_front((i, group, file));
+                            drop(inner);
+                            debug!(
+                                chunk = i,
+                                thread = t,
+                                backoff_ms = backoff.as_millis() as u64,
+                                "retry ctags"
+                            );
+                            thread::sleep(backoff);
+                            backoff *= 2;
+                            continue;
                         }
                     }
-                    Err(_) => {
-                        let _ = tx.send(Err(CtagsError::CallFailed { cmd }.into()));
+                    // After giving up, the following chunks fail without the retries.
+                    if output.is_ok() {
+                        backoff = SPAWN_BACKOFF;
                     }
+                    // A chunk which times out or can't be executed is reported as a failed output and skipped
+                    // in keep-going mode, so that tags of the other chunks are written.
+                    let output = match output {
+                        Err(x) if keep_going => CtagsOutput::failed(&file, &x).map(|x| vec![x]),
+                        x => x,
+                    };
+                    let output = output.map(|mut x| {
+                        for o in &mut x {
+                            o.thread = t;
+                            debug!(
+                                chunk = i,
+                                thread = t,
+                                files = o.files,
+                                elapsed_ms = o.elapsed.as_millis() as u64,
+                                status = %o.status,
+                                "ctags finished"
+                            );
+                        }
+                        x
+                    });
+                    if let Err(ref x) = output {
+                        debug!(chunk = i, thread = t, error = %x, "ctags failed");
+                    }
+                    let _ = tx.send((i, output));
                 }
             });
         }
+        drop(tx);
 
-        let mut children = Vec::new();
-        for _ in 0..opt.thread {
-            children.push(rx.recv());
-        }
+        let sender = ChunkSender { queue, next: 0 };
+        let run = CtagsRun {
+            rx,
+            cmd: commands[0].1.clone(),
+            keep_going: opt.keep_going,
+        };
+        Ok((sender, run))
+    }
 
-        let mut outputs = Vec::new();
-        for child in children {
-            let output = child??;
-
-            if !output.status.success() {
-                bail!(CtagsError::ExecFailed {
-                    cmd: cmd,
-                    err: String::from(str::from_utf8(&output.stderr).context(
-                        CtagsError::ConvFailed {
-                            s: output.stderr.to_vec(),
-                        }
-                    )?)
+    fn exec(
+        launcher: &[OsString],
+        args: &[String],
+        dir: &Path,
+        file: &str,
+        cmd: &str,
+        limits: &Limits,
+        list: FileList,
+    ) -> Result<CtagsOutput, Error> {
+        let beg = Instant::now();
+
+        // With `--files-via tempfile`, the file list is passed by `-L <file>` instead of stdin,
+        // and the file is removed when the list is dropped after ctags is finished.
+        let temp = if list == FileList::TempFile {
+            // ctags in the sandbox can read only the temporary directory of the sandbox.
+            let mut builder = tempfile::Builder::new();
+            builder.prefix("ptags-list");
+            let mut temp = match limits.sandbox {
+                Some(ref x) => builder.tempfile_in(x.dir())?,
+                None => builder.tempfile()?,
+            };
+            temp.write_all(file.as_bytes())?;
+            temp.flush()?;
+            Some(temp)
+        } else {
+            None
+        };
+
+        // ctags writes tags into the spill file directly to avoid buffering them in memory.
+        // stderr is drained by a dedicated thread instead of wait_with_output(), which is x2 slow to completion.
+        // The thread keeps ctags from blocking on the full pipe while stdin is written.
+        let spill = tempfile::tempfile()?;
+        let mut command = CmdCtags::command(launcher);
+        signal::isolate(&mut command).args(args).current_dir(dir);
+        CmdCtags::set_limits(&mut command, limits);
+        match temp {
+            Some(ref x) => command.arg("-L").arg(x.path()).stdin(Stdio::null()),
+            None => command.stdin(Stdio::piped()),
+        };
+        let child = command
+            .stdout(Stdio::from(spill.try_clone()?))
+            .stderr(Stdio::piped())
+            .spawn();
+        let mut child = match child {
+            Ok(x) => x,
+            Err(x) => {
+                audit::record(&command, beg, None, 0, 0);
+                if CmdCtags::is_resource_errno(&x) {
+                    bail!(CtagsError::ResourceExhausted {
+                        cmd: String::from(cmd),
+                    });
+                }
+                bail!(CtagsError::CallFailed {
+                    cmd: String::from(cmd),
                 });
             }
+        };
+        let mut pipe = child.stderr.take().unwrap();
+        let reader = thread::spawn(move || {
+            let mut ret = Vec::new();
+            let _ = pipe.read_to_end(&mut ret);
+            ret
+        });
 
-            outputs.push(output);
-        }
+        let pid = child.id();
+        signal::register_child(pid);
+        let status = (|| -> Result<ExitStatus, Error> {
+            if let Some(stdin) = child.stdin.as_mut() {
+                if let FileList::Stdin { pipe_size: Some(x) } = list {
+                    CmdCtags::set_pipe_size(stdin, std::cmp::min(file.len(), x));
+                }
+                let _ = stdin.write_all(file.as_bytes());
+            }
+            drop(child.stdin.take());
+            match limits.timeout {
+                Some(timeout) => match child.wait_timeout(timeout)? {
+                    Some(x) => Ok(x),
+                    None => {
+                        signal::kill_group(pid);
+                        let _ = child.kill();
+                        child.wait()?;
+                        bail!(CtagsError::TimedOut {
+                            cmd: String::from(cmd),
+                            secs: timeout.as_secs(),
+                        })
+                    }
+                },
+                None => Ok(child.wait()?),
+            }
+        })();
+        signal::unregister_child(pid);
+        let stderr = reader.join().unwrap_or_default();
+        let bytes_in = if temp.is_none() { file.len() as u64 } else { 0 };
+        let bytes_out = spill.metadata().map(|x| x.len()).unwrap_or(0);
+        audit::record(
+            &command,
+            beg,
+            status.as_ref().ok().copied(),
+            bytes_in,
+            bytes_out,
+        );
+        let status = status?;
 
-        Ok(outputs)
+        Ok(CtagsOutput {
+            status,
+            stdout: spill,
+            stderr,
+            list: String::from(file),
+            thread: 0,
+            files: file.lines().count(),
+            started: beg,
+            elapsed: beg.elapsed(),
+            timed_out: Vec::new(),
+            merged: false,
+        })
     }
 
-    pub fn get_tags_header(opt: &Opt) -> Result<String, Error> {
-        let tmp_empty = NamedTempFile::new()?;
-        let tmp_tags = NamedTempFile::new()?;
-        let tmp_tags_path: PathBuf = tmp_tags.path().into();
-        // In windiws environment, write access by ctags to the opened tmp_tags fails.
-        // So the tmp_tags must be closed and deleted.
-        tmp_tags.close()?;
-
-        let _ = Command::new(&opt.bin_ctags)
-            .arg(format!("-L {}", tmp_empty.path().to_string_lossy()))
-            .arg(format!("-f {}", tmp_tags_path.to_string_lossy()))
-            .args(&opt.opt_ctags)
-            .current_dir(&opt.dir)
-            .status();
-        let mut f = BufReader::new(File::open(&tmp_tags_path)?);
-        let mut s = String::new();
-        f.read_to_string(&mut s)?;
-
-        fs::remove_file(&tmp_tags_path)?;
-
-        Ok(s)
-    }
-
-    fn get_cmd(opt: &Opt, args: &[String]) -> String {
-        let mut cmd = format!(
-            "cd {}; {}",
-            opt.dir.to_string_lossy(),
-            opt.bin_ctags.to_string_lossy()
-        );
-        for arg in args {
-            cmd = format!("{} {}", cmd, arg);
+    /// Calls ctags for each file of the timed out chunk to isolate the offending files.
+    fn retry(
+        launcher: &[OsString],
+        args: &[String],
+        dir: &Path,
+        file: &str,
+        cmd: &str,
+        limits: &Limits,
+        list: FileList,
+    ) -> Result<Vec<CtagsOutput>, Error> {
+        let timeout = limits.timeout;
+        let mut ret = Vec::new();
+        for f in file.lines() {
+            let f = format!("{}\n", f);
+            match CmdCtags::exec(launcher, args, dir, &f, cmd, limits, list) {
+                Ok(x) => ret.push(x),
+                Err(x) if CmdCtags::is_timed_out(&x) => {
+                    ret.push(CtagsOutput::timed_out(&f, timeout)?)
+                }
+                Err(x) => return Err(x),
+            }
         }
-        cmd
+        Ok(ret)
     }
 
-    #[allow(dead_code)]
-    fn is_exuberant_ctags(opt: &Opt) -> Result<bool, Error> {
-        let output = Command::new(&opt.bin_ctags)
-            .arg("--version")
-            .current_dir(&opt.dir)
-            .output()?;
-        Ok(str::from_utf8(&output.stdout)?.starts_with("Exuberant Ctags"))
+    /// Bisects the file list of the failed chunk to isolate files which fail ctags.
+    ///
+    /// Returns outputs of succeeded parts and a failed output for each offending file.
+    /// If ctags fails without any file, the failure isn't caused by files, so the output is returned as is.
+    fn bisect(
+        launcher: &[OsString],
+        args: &[String],
+        dir: &Path,
+        failed: CtagsOutput,
+        cmd: &str,
+        limits: &Limits,
+        list: FileList,
+    ) -> Result<Vec<CtagsOutput>, Error> {
+        match CmdCtags::exec(launcher, args, dir, "", cmd, limits, list) {
+            Ok(x) if x.status.success() => (),
+            _ => return Ok(vec![failed]),
+        }
+        debug!(files = failed.files, "bisect failed chunk");
+
+        let mut ret = Vec::new();
+        let mut stack = vec![failed];
+        while let Some(output) = stack.pop() {
+            if output.status.success() || output.files <= 1 {
+                if !output.status.success() {
+                    debug!(file = output.list.trim_end(), "ctags failed on file");
+                }
+                ret.push(output);
+                continue;
+            }
+            let files: Vec<&str> = output.list.lines().collect();
+            let (head, tail) = files.split_at(files.len() / 2);
+            // The tail is pushed first so that outputs are returned in the order of files.
+            for half in [tail, head] {
+                let file: String = half.iter().map(|x| format!("{}\n", x)).collect();
+                // A half which can't be executed is kept as a failed output so that the other halves survive.
+                let output = match CmdCtags::exec(launcher, args, dir, &file, cmd, limits, list) {
+                    Ok(x) => x,
+                    Err(x) if CmdCtags::is_timed_out(&x) => {
+                        CtagsOutput::timed_out(&file, limits.timeout)?
+                    }
+                    Err(x) => CtagsOutput::failed(&file, &x)?,
+                };
+                stack.push(output);
+            }
+        }
+        Ok(ret)
     }
 
-    #[cfg(target_os = "linux")]
-    fn set_pipe_size(stdin: &ChildStdin, len: i32) -> Result<(), Error> {
-        fcntl(stdin.as_raw_fd(), FcntlArg::F_SETPIPE_SZ(len))?;
-        Ok(())
+    /// Generates tags of the chunk by the worker process of `--persistent-workers`, which is spawned if none.
+    ///
+    /// If the worker exits or times out on a file, the file is returned as a separate failed output or skipped,
+    /// and the next file is processed by a new worker.
+    fn exec_worker(
+        worker: &mut Option<Worker>,
+        ctx: &WorkerContext,
+        file: &str,
+        sorted: bool,
+    ) -> Result<Vec<CtagsOutput>, Error> {
+        let beg = Instant::now();
+        let mut ret = Vec::new();
+        let mut tags = Vec::new();
+        let mut stderr = Vec::new();
+        let mut timed_out = Vec::new();
+        for f in file.lines() {
+            let content = match fs::read(ctx.dir.join(f)) {
+                Ok(x) => x,
+                Err(x) => {
+                    let warning =
+                        format!("ctags: Warning: cannot open input file \"{}\" : {}\n", f, x);
+                    stderr.extend_from_slice(warning.as_bytes());
+                    continue;
+                }
+            };
+            let w = match worker {
+                Some(x) => x,
+                None => worker.insert(Worker::spawn(ctx)?),
+            };
+            let file_beg = Instant::now();
+            let generated = w.generate(f, &content, ctx.limits.timeout);
+            stderr.append(&mut w.take_stderr());
+            match generated {
+                Generated::Tags(mut x) => tags.append(&mut x),
+                Generated::TimedOut => {
+                    timed_out.push(String::from(f));
+                    *worker = None;
+                }
+                Generated::Failed(status) => {
+                    ret.push(CtagsOutput {
+                        status,
+                        stdout: tempfile::tempfile()?,
+                        stderr: std::mem::take(&mut stderr),
+                        list: format!("{}\n", f),
+                        thread: 0,
+                        files: 1,
+                        started: file_beg,
+                        elapsed: file_beg.elapsed(),
+                        timed_out: Vec::new(),
+                        merged: false,
+                    });
+                    *worker = None;
+                }
+            }
+        }
+
+        // Responses are in the order of files, so tags are sorted here as ctags sorts them for each chunk.
+        if sorted {
+            tags.sort_unstable();
+        }
+        let mut spill = tempfile::tempfile()?;
+        {
+            let mut w = std::io::BufWriter::new(&mut spill);
+            for t in &tags {
+                writeln!(w, "{}", t)?;
+            }
+            w.flush()?;
+        }
+        ret.insert(
+            0,
+            CtagsOutput {
+                status: ExitStatus::default(),
+                stdout: spill,
+                stderr,
+                list: String::from(file),
+                thread: 0,
+                files: file.lines().count(),
+                started: beg,
+                elapsed: beg.elapsed(),
+                timed_out,
+                merged: false,
+            },
+        );
+        Ok(ret)
     }
 
-    #[cfg(not(target_os = "linux"))]
-    fn set_pipe_size(_stdin: &ChildStdin, _len: i32) -> Result<(), Error> {
-        Ok(())
+    fn file_list(opt: &Opt) -> FileList {
+        if opt.files_via == "tempfile" {
+            FileList::TempFile
+        } else if opt.no_set_pipe_size {
+            FileList::Stdin { pipe_size: None }
+        } else {
+            FileList::Stdin {
+                pipe_size: Some(opt.pipe_size),
+            }
+        }
     }
-}
 
-// ---------------------------------------------------------------------------------------------------------------------
-// Test
-// ---------------------------------------------------------------------------------------------------------------------
+    fn limits(opt: &Opt, sandbox: &Option<Arc<Sandbox>>) -> Limits {
+        Limits {
+            timeout: opt.ctags_timeout.map(Duration::from_secs),
+            cpu: opt.ctags_rlimit_cpu,
+            mem: opt.ctags_rlimit_mem,
+            low_priority: opt.low_priority,
+            sandbox: sandbox.clone(),
+            c_locale: opt.reproducible,
+        }
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::super::bin::{git_files, Opt};
-    use super::CmdCtags;
-    use std::str;
-    use structopt::StructOpt;
+    /// Applies resource limits and the priority to the ctags process before exec,
+    /// so that ctags running away on pathological inputs is stopped by the kernel instead of exhausting the host.
+    #[cfg(unix)]
+    fn set_limits(cmd: &mut Command, limits: &Limits) {
+        use nix::sys::resource::{setrlimit, Resource};
+        use std::os::unix::process::CommandExt;
 
-    #[test]
-    fn test_call() {
-        let args = vec!["ptags", "-t", "1", "--exclude=README.md"];
-        let opt = Opt::from_iter(args.iter());
-        let files = git_files(&opt).unwrap();
-        let outputs = CmdCtags::call(&opt, &files).unwrap();
-        let mut iter = str::from_utf8(&outputs[0].stdout).unwrap().lines();
-        assert_eq!(
-            iter.next().unwrap_or(""),
-            "BIN_NAME\tMakefile\t/^BIN_NAME = ptags$/;\"\tm"
-        );
+        if let Some(ref sandbox) = limits.sandbox {
+            sandbox.apply(cmd);
+        }
+        if limits.c_locale {
+            cmd.env("LC_ALL", "C");
+        }
+        if limits.cpu.is_none() && limits.mem.is_none() && !limits.low_priority {
+            return;
+        }
+        let (cpu, mem, low_priority) = (limits.cpu, limits.mem, limits.low_priority);
+        // The closure runs between fork and exec, so it only calls async-signal-safe functions.
+        unsafe {
+            cmd.pre_exec(move || {
+                if let Some(x) = cpu {
+                    setrlimit(Resource::RLIMIT_CPU, x as _, x as _)?;
+                }
+                // RLIMIT_DATA is available on all unix, and covers memory allocated by mmap too since Linux 4.7.
+                if let Some(x) = mem {
+                    setrlimit(Resource::RLIMIT_DATA, x as _, x as _)?;
+                }
+                if low_priority {
+                    // The priority is best effort, so failures are ignored.
+                    nix::libc::nice(19);
+                    // ioprio_set(IOPRIO_WHO_PROCESS, self, IOPRIO_CLASS_IDLE)
+                    #[cfg(target_os = "linux")]
+                    nix::libc::syscall(nix::libc::SYS_ioprio_set, 1, 0, 3 << 13);
+                }
+                Ok(())
+            });
+        }
     }
 
-    #[test]
-    fn test_call_with_opt() {
-        let args = vec!["ptags", "-t", "1", "--opt-ctags=-u"];
-        let opt = Opt::from_iter(args.iter());
-        let files = git_files(&opt).unwrap();
-        let outputs = CmdCtags::call(&opt, &files).unwrap();
-        let mut iter = str::from_utf8(&outputs[0].stdout).unwrap().lines();
-        if cfg!(target_os = "linux") {
-            assert_eq!(
-                iter.next().unwrap_or(""),
-                "VERSION\tMakefile\t/^VERSION = $(patsubst \"%\",%, $(word 3, $(shell grep version Cargo.toml)))$/;\"\tm"
-            );
+    #[cfg(not(unix))]
+    fn set_limits(cmd: &mut Command, limits: &Limits) {
+        if let Some(ref sandbox) = limits.sandbox {
+            sandbox.apply(cmd);
         }
-        if cfg!(target_os = "macos") {
-            assert_eq!(
-                iter.next().unwrap_or(""),
-                "package\tCargo.toml\t/^[package]$/;\"\tt"
-            );
+        if limits.c_locale {
+            cmd.env("LC_ALL", "C");
         }
     }
 
-    #[test]
-    fn test_call_exclude() {
-        let args = vec![
-            "ptags",
-            "-t",
-            "1",
-            "--exclude=Make*",
-            "--exclude=README.md",
-            "-v",
+    fn is_timed_out(err: &Error) -> bool {
+        matches!(
+            err.downcast_ref::<CtagsError>(),
+            Some(CtagsError::TimedOut { .. })
+        )
+    }
+
+    /// Returns whether ctags failed to be spawned by the limit of processes or open files like `ulimit -n`.
+    ///
+    /// Temporary files of tags and file lists may fail to be created by the limit too.
+    fn is_resource_exhausted(err: &Error) -> bool {
+        if let Some(CtagsError::ResourceExhausted { .. }) = err.downcast_ref::<CtagsError>() {
+            return true;
+        }
+        err.downcast_ref::<std::io::Error>()
+            .is_some_and(CmdCtags::is_resource_errno)
+    }
+
+    #[cfg(unix)]
+    fn is_resource_errno(err: &std::io::Error) -> bool {
+        use nix::libc::{EAGAIN, EMFILE, ENFILE};
+        err.raw_os_error()
+            .is_some_and(|x| x == EMFILE || x == ENFILE || x == EAGAIN)
+    }
+
+    #[cfg(not(unix))]
+    fn is_resource_errno(_err: &std::io::Error) -> bool {
+        false
+    }
+
+    pub fn get_args(opt: &Opt) -> Vec<String> {
+        let flavor = CmdCtags::info(opt).flavor;
+        let mut args = CmdCtags::get_config_args(opt, flavor);
+        // `-L <file>` is added for each chunk by `exec` with `--files-via tempfile`.
+        if opt.files_via != "tempfile" {
+            args.push(String::from("-L -"));
+        }
+        args.push(String::from("-f -"));
+        if opt.unsorted {
+            args.push(String::from("--sort=no"));
+        }
+        if opt.format == "json" {
+            args.push(String::from("--output-format=json"));
+        }
+        for e in &opt.exclude {
+            args.push(format!("--exclude={}", e));
+        }
+        args.append(&mut CmdCtags::get_option_args(opt, flavor));
+        // Tags are sorted in bytes so that merging chunks doesn't depend on how files are split,
+        // even if `--sort=foldcase` is given by the options.
+        if opt.reproducible && !opt.unsorted {
+            args.push(String::from("--sort=yes"));
+        }
+        args
+    }
+
+    /// Returns arguments of worker processes of `--persistent-workers`.
+    ///
+    /// Files are sent by requests and tags are returned by responses instead of `-L -` and `-f -`.
+    fn get_worker_args(opt: &Opt) -> Vec<String> {
+        let mut args: Vec<String> = CmdCtags::get_args(opt)
+            .into_iter()
+            .filter(|x| x != "-L -" && x != "-f -")
+            .collect();
+        args.push(String::from("--_interactive=sandbox"));
+        args
+    }
+
+    /// Returns `--options` which make ctags read the same option files in every invocation.
+    ///
+    /// Universal Ctags reads option files like `.ctags.d/*.ctags` implicitly relative to the current directory,
+    /// so they are disabled by `--options=NONE`, which must be the first option, and passed explicitly.
+    /// Option files in the home directory are passed only if ctags is executed locally.
+    fn get_config_args(opt: &Opt, flavor: CtagsFlavor) -> Vec<String> {
+        if flavor != CtagsFlavor::Universal {
+            return Vec::new();
+        }
+        let mut args = vec![String::from("--options=NONE")];
+        if opt.no_user_ctags_config {
+            return args;
+        }
+        if opt.remote.is_none() && opt.ctags_container.is_none() {
+            for dir in CmdCtags::user_option_dirs() {
+                for file in CmdCtags::find_option_files(&dir) {
+                    args.push(format!("--options={}", file.to_string_lossy()));
+                }
+            }
+        }
+        for dir in [".ctags.d", "ctags.d"] {
+            for file in CmdCtags::find_option_files(&opt.dir.join(dir)) {
+                if let Some(name) = file.file_name() {
+                    args.push(format!("--options={}/{}", dir, name.to_string_lossy()));
+                }
+            }
+        }
+        args
+    }
+
+    /// Returns directories of option files in the home directory in the order read by Universal Ctags.
+    pub fn user_option_dirs() -> Vec<PathBuf> {
+        let mut ret = Vec::new();
+        let home = dirs::home_dir();
+        match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(x) if !x.is_empty() => ret.push(PathBuf::from(x).join("ctags")),
+            _ => ret.extend(home.iter().map(|x| x.join(".config").join("ctags"))),
+        }
+        ret.extend(home.iter().map(|x| x.join(".ctags.d")));
+        ret
+    }
+
+    /// Returns `*.ctags` in the directory in alphabetical order.
+    fn find_option_files(dir: &Path) -> Vec<PathBuf> {
+        let mut ret: Vec<PathBuf> = match fs::read_dir(dir) {
+            Ok(x) => x
+                .filter_map(|x| x.ok())
+                .map(|x| x.path())
+                .filter(|x| x.is_file() && x.extension().is_some_and(|x| x == "ctags"))
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        ret.sort();
+        ret
+    }
+
+    /// Returns ctags options from the selection options, `[ctags]` of config files, then `--opt-ctags`.
+    ///
+    /// The options are adapted to the flavor of ctags.
+    fn get_option_args(opt: &Opt, flavor: CtagsFlavor) -> Vec<String> {
+        CmdCtags::get_selection_args(opt)
+            .iter()
+            .chain(&opt.ctags.get_args())
+            .chain(&opt.opt_ctags)
+            .map(|x| CmdCtags::adapt_arg(flavor, x))
+            .collect()
+    }
+
+    /// Returns ctags options from `--languages`, `--kinds`, `--fields`, `--extras`, `--split-by-lang` and encodings.
+    ///
+    /// These are placed before `[ctags]` of config files and `--opt-ctags` so that they can override them.
+    fn get_selection_args(opt: &Opt) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(ref x) = opt.languages {
+            args.push(format!("--languages={}", x));
+        }
+        for k in &opt.kinds {
+            if let Some((lang, kinds)) = k.split_once('=') {
+                args.push(format!("--kinds-{}={}", lang, kinds));
+            }
+        }
+        if let Some(ref x) = opt.fields {
+            args.push(format!("--fields={}", x));
+        }
+        if let Some(ref x) = opt.extras {
+            args.push(format!("--extras={}", x));
+        }
+        // `--split-by-lang` routes tags by the language field.
+        if opt.split_by_lang {
+            args.push(String::from("--fields=+l"));
+        }
+        if let Some(ref x) = opt.encoding {
+            args.push(format!("--input-encoding={}", x));
+        }
+        // The output encoding is given to every ctags process and the header even if `--encoding-for` leaves
+        // some files in the default encoding, so that all tags are written in the same encoding.
+        if opt.encoding.is_some() || !opt.encoding_for.is_empty() || opt.output_encoding.is_some() {
+            let x = opt.output_encoding.as_deref().unwrap_or("utf-8");
+            args.push(format!("--output-encoding={}", x));
+        }
+        args
+    }
+
+    fn check_selection(opt: &Opt) -> Result<(), Error> {
+        let invalid = |option: &str, value: &str| CtagsError::InvalidOption {
+            option: String::from(option),
+            value: String::from(value),
+        };
+        if let Some(ref x) = opt.languages {
+            if x.split(',')
+                .any(|x| x.trim_start_matches(['+', '-']).is_empty())
+            {
+                bail!(invalid("--languages", x));
+            }
+        }
+        for k in &opt.kinds {
+            match k.split_once('=') {
+                Some((lang, _)) if !lang.is_empty() && !lang.contains(char::is_whitespace) => (),
+                _ => bail!(invalid("--kinds", k)),
+            }
+        }
+        for (option, value) in [
+            ("--fields", &opt.fields),
+            ("--extras", &opt.extras),
+            ("--encoding", &opt.encoding),
+            ("--output-encoding", &opt.output_encoding),
+        ] {
+            if let Some(ref x) = value {
+                if x.is_empty() || x.contains(char::is_whitespace) {
+                    bail!(invalid(option, x));
+                }
+            }
+        }
+        CmdCtags::encoding_overrides(opt)?;
+        Ok(())
+    }
+
+    /// Returns pseudo-tags of the tags file generated by ctags.
+    ///
+    /// Universal Ctags prints pseudo-tags to stdout only if `--extras=+p` is given explicitly,
+    /// so ctags is called for the empty file list without temporary files.
+    /// `--extras=+p` is placed first so that `--extras` of the options can disable pseudo-tags.
+    /// Exuberant Ctags doesn't support it, so the header is synthesized from the detected version.
+    pub fn get_tags_header(opt: &Opt) -> Result<String, Error> {
+        let info = CmdCtags::info(opt);
+        if info.flavor == CtagsFlavor::Exuberant {
+            return Ok(CmdCtags::get_exuberant_header(opt, &info));
+        }
+
+        let mut args = CmdCtags::get_config_args(opt, info.flavor);
+        args.push(String::from("-L -"));
+        args.push(String::from("-f -"));
+        args.push(String::from("--extras=+p"));
+        if opt.format == "json" {
+            args.push(String::from("--output-format=json"));
+        }
+        args.append(&mut CmdCtags::get_option_args(opt, info.flavor));
+
+        let output = audit::output(
+            CmdCtags::command(&CmdCtags::get_launcher(opt))
+                .args(CmdCtags::quote_args(opt, &args))
+                .current_dir(&opt.dir)
+                .stdin(Stdio::null())
+                .stderr(Stdio::null()),
+        )?;
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// Returns pseudo-tags written by Exuberant Ctags.
+    fn get_exuberant_header(opt: &Opt, info: &CtagsInfo) -> String {
+        let version = info.version.trim_start_matches("Exuberant Ctags").trim();
+        format!(
+            "!_TAG_FILE_FORMAT\t2\t/extended format; --format=1 will not append ;\" to lines/\n\
+             !_TAG_FILE_SORTED\t{}\t/0=unsorted, 1=sorted, 2=foldcase/\n\
+             !_TAG_PROGRAM_AUTHOR\tDarren Hiebert\t/dhiebert@users.sourceforge.net/\n\
+             !_TAG_PROGRAM_NAME\tExuberant Ctags\t//\n\
+             !_TAG_PROGRAM_URL\thttp://ctags.sourceforge.net\t/official site/\n\
+             !_TAG_PROGRAM_VERSION\t{}\t//\n",
+            if opt.unsorted { 0 } else { 1 },
+            version
+        )
+    }
+
+    /// Returns the program and the leading arguments to execute ctags.
+    ///
+    /// With `--ctags-container`, ctags is executed by `docker run` or `podman run`.
+    /// `DIR` is bind-mounted to the same path in the container,
+    /// so that the file list and the paths in tags need no translation.
+    ///
+    /// With `--remote`, the command line is executed in `DIR` of the remote host by ssh.
+    /// Arguments following the launcher must be quoted by `quote_args`.
+    pub fn get_launcher(opt: &Opt) -> Vec<OsString> {
+        let dir = match opt.remote_dir {
+            Some(ref x) if opt.remote.is_some() => x.clone(),
+            _ => opt.dir.canonicalize().unwrap_or_else(|_| opt.dir.clone()),
+        };
+
+        let mut ret: Vec<OsString> = Vec::new();
+        if let Some(ref image) = opt.ctags_container {
+            ret.push(opt.bin_container.clone().into_os_string());
+            ret.push("run".into());
+            ret.push("--rm".into());
+            ret.push("-i".into());
+            let mut volume = dir.clone().into_os_string();
+            volume.push(":");
+            volume.push(&dir);
+            volume.push(":ro");
+            ret.push("-v".into());
+            ret.push(volume);
+            ret.push("-w".into());
+            ret.push(dir.clone().into_os_string());
+            ret.push(image.into());
+        }
+        ret.push(opt.bin_ctags.clone().into_os_string());
+
+        if let Some(ref host) = opt.remote {
+            let mut script = format!("cd {} && exec", shell_quote(&dir.to_string_lossy()));
+            for x in &ret {
+                script = format!("{} {}", script, shell_quote(&x.to_string_lossy()));
+            }
+            ret = vec![
+                opt.bin_ssh.clone().into_os_string(),
+                host.into(),
+                script.into(),
+            ];
+        }
+        ret
+    }
+
+    /// Quotes arguments passed to the remote shell with `--remote`.
+    fn quote_args(opt: &Opt, args: &[String]) -> Vec<String> {
+        if opt.remote.is_some() {
+            args.iter().map(|x| shell_quote(x)).collect()
+        } else {
+            args.to_vec()
+        }
+    }
+
+    fn command(launcher: &[OsString]) -> Command {
+        let mut cmd = Command::new(&launcher[0]);
+        cmd.args(&launcher[1..]);
+        cmd
+    }
+
+    /// Returns the command line which `call` executes for each chunk.
+    pub fn get_command_line(opt: &Opt) -> String {
+        let args = CmdCtags::quote_args(opt, &CmdCtags::get_args(opt));
+        CmdCtags::get_cmd(opt, &CmdCtags::get_launcher(opt), &args)
+    }
+
+    /// Returns the command line which reproduces the call when pasted into POSIX shell with the file list in stdin,
+    /// or in the file of `$LIST` with `--files-via tempfile`.
+    ///
+    /// Each argument is quoted as passed to the process, so arguments quoted for ssh by `--remote` are quoted again.
+    fn get_cmd(opt: &Opt, launcher: &[OsString], args: &[String]) -> String {
+        let mut cmd = format!("cd {};", shell_quote(&opt.dir.to_string_lossy()));
+        for arg in launcher {
+            cmd = format!("{} {}", cmd, shell_quote(&arg.to_string_lossy()));
+        }
+        for arg in args {
+            cmd = format!("{} {}", cmd, shell_quote(arg));
+        }
+        if opt.files_via == "tempfile" {
+            cmd = format!("{} -L \"$LIST\"", cmd);
+        }
+        cmd
+    }
+
+    /// Returns the flavor and the version of ctags.
+    ///
+    /// ctags is probed once for each binary and the result is reused.
+    pub fn info(opt: &Opt) -> CtagsInfo {
+        let launcher = CmdCtags::get_launcher(opt);
+        let mut cache = CTAGS_INFO.lock().unwrap();
+        if let Some(x) = cache.get(&launcher) {
+            return x.clone();
+        }
+        let info = CmdCtags::probe(opt);
+        if opt.verbose {
+            eprintln!("ctags: {}", info.version);
+        }
+        cache.insert(launcher, info.clone());
+        info
+    }
+
+    fn probe(opt: &Opt) -> CtagsInfo {
+        let output = audit::output(
+            CmdCtags::command(&CmdCtags::get_launcher(opt))
+                .arg("--version")
+                .current_dir(&opt.dir),
+        );
+        let version = match output {
+            // ex. "Universal Ctags 6.0.0(p6.0.20221218), Copyright (C) 2015-2022 Universal Ctags Team"
+            Ok(ref x) => String::from_utf8_lossy(&x.stdout)
+                .lines()
+                .next()
+                .and_then(|x| x.split(", Copyright").next())
+                .unwrap_or("")
+                .to_string(),
+            Err(_) => String::new(),
+        };
+        let flavor = if version.starts_with("Universal Ctags") {
+            CtagsFlavor::Universal
+        } else if version.starts_with("Exuberant Ctags") {
+            CtagsFlavor::Exuberant
+        } else {
+            CtagsFlavor::Unknown
+        };
+        CtagsInfo { flavor, version }
+    }
+
+    /// Checks whether the options are supported by the detected ctags.
+    pub fn check(opt: &Opt) -> Result<(), Error> {
+        CmdCtags::check_selection(opt)?;
+        opt.ctags.check()?;
+
+        let info = CmdCtags::info(opt);
+        if info.flavor == CtagsFlavor::Exuberant && opt.format == "json" {
+            bail!(CtagsError::Unsupported {
+                option: String::from("--format json"),
+                version: info.version,
+            });
+        }
+        if info.flavor == CtagsFlavor::Exuberant
+            && (opt.encoding.is_some()
+                || !opt.encoding_for.is_empty()
+                || opt.output_encoding.is_some())
+        {
+            bail!(CtagsError::Unsupported {
+                option: String::from("--encoding"),
+                version: info.version,
+            });
+        }
+        if info.flavor == CtagsFlavor::Exuberant && opt.no_user_ctags_config {
+            bail!(CtagsError::Unsupported {
+                option: String::from("--no-user-ctags-config"),
+                version: info.version,
+            });
+        }
+        // The temporary file of the file list is not visible from the remote host or the container.
+        if opt.files_via == "tempfile" && (opt.remote.is_some() || opt.ctags_container.is_some()) {
+            bail!(CtagsError::InvalidOption {
+                option: String::from("--files-via"),
+                value: String::from("tempfile with --remote or --ctags-container"),
+            });
+        }
+        // Responses of the interactive mode are always JSON.
+        if opt.persistent_workers && opt.format != "json" {
+            bail!(CtagsError::Requires {
+                option: String::from("--persistent-workers"),
+                required: String::from("--format json"),
+            });
+        }
+        if info.flavor == CtagsFlavor::Exuberant && opt.ctags.has_multiline() {
+            bail!(CtagsError::Unsupported {
+                option: String::from("multiline of [ctags.regex]"),
+                version: info.version,
+            });
+        }
+        Ok(())
+    }
+
+    /// Drops languages of `--languages` which the detected ctags doesn't support, and returns warnings for them.
+    ///
+    /// They are reported with warnings of ctags, so `--quiet` and `--warnings` apply to them.
+    /// A list which has no supported language is kept as is so that ctags reports it.
+    pub fn adapt_languages(opt: &Opt) -> (Opt, Vec<String>) {
+        let mut ret = opt.clone();
+        let mut warnings = Vec::new();
+        if opt.languages.is_none() && !opt.opt_ctags.iter().any(|x| x.starts_with("--languages=")) {
+            return (ret, warnings);
+        }
+        let list = match CmdCtags::list_languages(opt) {
+            Ok(x) => x,
+            Err(x) => {
+                warnings.push(format!("failed to list languages of ctags ({:#})", x));
+                return (ret, warnings);
+            }
+        };
+
+        let mut unsupported = Vec::new();
+        let mut retain = |languages: &str| {
+            let (kept, dropped): (Vec<&str>, Vec<&str>) = languages.split(',').partition(|x| {
+                let x = x.trim_start_matches(['+', '-']);
+                x.is_empty()
+                    || x.eq_ignore_ascii_case("all")
+                    || list.iter().any(|y| y.eq_ignore_ascii_case(x))
+            });
+            unsupported.extend(
+                dropped
+                    .iter()
+                    .map(|x| String::from(x.trim_start_matches(['+', '-']))),
+            );
+            if kept.is_empty() {
+                String::from(languages)
+            } else {
+                kept.join(",")
+            }
+        };
+        ret.languages = opt.languages.as_deref().map(&mut retain);
+        for x in &mut ret.opt_ctags {
+            if let Some(languages) = x.strip_prefix("--languages=") {
+                *x = format!("--languages={}", retain(languages));
+            }
+        }
+
+        let version = CmdCtags::info(opt).version;
+        for l in unsupported {
+            warnings.push(format!("{} doesn't support language ({})", version, l));
+        }
+        (ret, warnings)
+    }
+
+    /// Returns languages printed by `ctags --list-languages` including disabled ones.
+    pub fn list_languages(opt: &Opt) -> Result<Vec<String>, Error> {
+        let mut args = CmdCtags::get_config_args(opt, CmdCtags::info(opt).flavor);
+        args.push(String::from("--list-languages"));
+        let args = CmdCtags::quote_args(opt, &args);
+        let launcher = CmdCtags::get_launcher(opt);
+
+        let output = audit::output(
+            CmdCtags::command(&launcher)
+                .args(&args)
+                .current_dir(&opt.dir),
+        )?;
+        if !output.status.success() {
+            bail!(CtagsError::ExecFailed {
+                cmd: CmdCtags::get_cmd(opt, &launcher, &args),
+                err: String::from_utf8_lossy(&output.stderr).into_owned(),
+            });
+        }
+        let list = String::from_utf8_lossy(&output.stdout);
+        Ok(list
+            .lines()
+            .filter_map(|x| x.split_whitespace().next())
+            .map(String::from)
+            .collect())
+    }
+
+    /// Returns features printed by `ctags --list-features`.
+    pub fn list_features(opt: &Opt) -> Result<Vec<String>, Error> {
+        let output = audit::output(
+            CmdCtags::command(&CmdCtags::get_launcher(opt))
+                .arg("--list-features")
+                .current_dir(&opt.dir)
+                .stdin(Stdio::null()),
+        )?;
+        let list = String::from_utf8_lossy(&output.stdout);
+        Ok(list
+            .lines()
+            .filter(|x| !x.starts_with('#'))
+            .filter_map(|x| x.split_whitespace().next())
+            .map(String::from)
+            .collect())
+    }
+
+    /// Returns whether ctags supports `--_interactive=sandbox`, which requires the seccomp sandbox of Universal Ctags.
+    pub fn supports_interactive(opt: &Opt) -> bool {
+        if CmdCtags::info(opt).flavor != CtagsFlavor::Universal {
+            return false;
+        }
+        match CmdCtags::list_features(opt) {
+            Ok(x) => x.iter().any(|x| x == "interactive") && x.iter().any(|x| x == "sandbox"),
+            Err(_) => false,
+        }
+    }
+
+    /// Returns file patterns of each language printed by `ctags --list-maps` with `--langmap` and other options.
+    pub fn list_maps(opt: &Opt) -> Result<Vec<(String, Vec<String>)>, Error> {
+        let info = CmdCtags::info(opt);
+        let mut args = CmdCtags::get_config_args(opt, info.flavor);
+        args.append(&mut CmdCtags::get_option_args(opt, info.flavor));
+        args.push(String::from("--list-maps"));
+        let args = CmdCtags::quote_args(opt, &args);
+        let launcher = CmdCtags::get_launcher(opt);
+
+        let output = audit::output(
+            CmdCtags::command(&launcher)
+                .args(&args)
+                .current_dir(&opt.dir)
+                .stdin(Stdio::null()),
+        )
+        .map_err(|_| CtagsError::CallFailed {
+            cmd: CmdCtags::get_cmd(opt, &launcher, &args),
+        })?;
+        if !output.status.success() {
+            bail!(CtagsError::ExecFailed {
+                cmd: CmdCtags::get_cmd(opt, &launcher, &args),
+                err: String::from_utf8_lossy(&output.stderr).into_owned(),
+            });
+        }
+        Ok(CmdCtags::parse_maps(&String::from_utf8_lossy(
+            &output.stdout,
+        )))
+    }
+
+    /// Parses lines like `C  *.c *.h`. Extensions of Exuberant Ctags like `.c` are converted to patterns like `*.c`.
+    fn parse_maps(s: &str) -> Vec<(String, Vec<String>)> {
+        s.lines()
+            .filter_map(|line| {
+                let mut iter = line.split_whitespace();
+                let lang = iter.next().filter(|x| !x.starts_with('#'))?;
+                let patterns = iter
+                    .map(|x| {
+                        if x.starts_with('.') {
+                            format!("*{}", x)
+                        } else {
+                            String::from(x)
+                        }
+                    })
+                    .collect();
+                Some((String::from(lang), patterns))
+            })
+            .collect()
+    }
+
+    /// Maps options of Universal Ctags to Exuberant Ctags.
+    fn adapt_arg(flavor: CtagsFlavor, arg: &str) -> String {
+        if flavor != CtagsFlavor::Exuberant {
+            return String::from(arg);
+        }
+        if let Some(x) = arg.strip_prefix("--extras=") {
+            return format!("--extra={}", x);
+        }
+        if let Some((lang, kinds)) = arg.strip_prefix("--kinds-").and_then(|x| x.split_once('=')) {
+            return format!("--{}-kinds={}", lang, kinds);
+        }
+        String::from(arg)
+    }
+
+    #[cfg(target_os = "linux")]
+    /// Enlarges the pipe to write the file list at once.
+    ///
+    /// The size beyond `/proc/sys/fs/pipe-max-size` is refused for unprivileged users,
+    /// so the default size is kept on failure.
+    fn set_pipe_size(stdin: &ChildStdin, len: usize) {
+        match fcntl(stdin.as_raw_fd(), FcntlArg::F_SETPIPE_SZ(len as i32)) {
+            Ok(x) => debug!(requested = len, effective = x, "set pipe size"),
+            Err(x) => debug!(requested = len, error = %x, "failed to set pipe size"),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn set_pipe_size(_stdin: &ChildStdin, _len: usize) {}
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Worker
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Command line shared by worker processes of `--persistent-workers`.
+struct WorkerContext<'a> {
+    launcher: &'a [OsString],
+    args: &'a [String],
+    dir: &'a Path,
+    cmd: &'a str,
+    limits: &'a Limits,
+}
+
+/// Result of a request to the worker process.
+#[derive(Debug)]
+enum Generated {
+    /// Tags in JSON lines
+    Tags(Vec<String>),
+    /// The worker process exited before the response was completed
+    Failed(ExitStatus),
+    /// The worker process was killed by `--ctags-timeout`
+    TimedOut,
+}
+
+/// Line of the JSON protocol of the interactive mode.
+#[derive(Debug, PartialEq, Eq)]
+enum Response {
+    Tag,
+    Completed,
+    Error(String),
+    Other,
+}
+
+impl Response {
+    fn parse(line: &str) -> Response {
+        let value: serde_json::Value = match serde_json::from_str(line) {
+            Ok(x) => x,
+            Err(_) => return Response::Other,
+        };
+        match value["_type"].as_str() {
+            Some("tag") => Response::Tag,
+            Some("completed") => Response::Completed,
+            Some("error") => {
+                Response::Error(String::from(value["message"].as_str().unwrap_or_default()))
+            }
+            _ => Response::Other,
+        }
+    }
+}
+
+/// Long-lived ctags process of `--_interactive=sandbox`, which generates tags of a file for each request.
+///
+/// The content of the file is sent with the request, so the process doesn't open any file.
+struct Worker {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    /// Lines of stdout read by a dedicated thread, so that each response can be waited with the timeout
+    lines: mpsc::Receiver<String>,
+    stderr: Arc<Mutex<Vec<u8>>>,
+    stderr_reader: Option<thread::JoinHandle<()>>,
+    command: Command,
+    started: Instant,
+    bytes_in: u64,
+    bytes_out: u64,
+    finished: bool,
+}
+
+impl Worker {
+    fn spawn(ctx: &WorkerContext) -> Result<Worker, Error> {
+        let started = Instant::now();
+        let mut command = CmdCtags::command(ctx.launcher);
+        signal::isolate(&mut command)
+            .args(ctx.args)
+            .current_dir(ctx.dir);
+        CmdCtags::set_limits(&mut command, ctx.limits);
+        let child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn();
+        let mut child = match child {
+            Ok(x) => x,
+            Err(x) => {
+                audit::record(&command, started, None, 0, 0);
+                if CmdCtags::is_resource_errno(&x) {
+                    bail!(CtagsError::ResourceExhausted {
+                        cmd: String::from(ctx.cmd),
+                    });
+                }
+                bail!(CtagsError::CallFailed {
+                    cmd: String::from(ctx.cmd),
+                });
+            }
+        };
+        signal::register_child(child.id());
+
+        let stdout = child.stdout.take().unwrap();
+        let (tx, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut pipe = child.stderr.take().unwrap();
+        let stderr = Arc::new(Mutex::new(Vec::new()));
+        let buf = stderr.clone();
+        let stderr_reader = thread::spawn(move || {
+            let mut chunk = [0; 4096];
+            while let Ok(n) = pipe.read(&mut chunk) {
+                if n == 0 {
+                    break;
+                }
+                buf.lock().unwrap().extend_from_slice(&chunk[..n]);
+            }
+        });
+
+        let mut worker = Worker {
+            stdin: child.stdin.take(),
+            child,
+            lines,
+            stderr,
+            stderr_reader: Some(stderr_reader),
+            command,
+            started,
+            bytes_in: 0,
+            bytes_out: 0,
+            finished: false,
+        };
+
+        // ctags prints `{"_type": "program", ...}` first when the interactive mode is started.
+        let first = match ctx.limits.timeout {
+            Some(x) => worker.lines.recv_timeout(x).ok(),
+            None => worker.lines.recv().ok(),
+        };
+        if let Some(ref x) = first {
+            worker.bytes_out += x.len() as u64 + 1;
+        }
+        if !first.is_some_and(|x| x.contains("\"program\"")) {
+            worker.kill();
+            bail!(CtagsError::ExecFailed {
+                cmd: String::from(ctx.cmd),
+                err: String::from_utf8_lossy(&worker.take_stderr()).into_owned(),
+            });
+        }
+        Ok(worker)
+    }
+
+    /// Sends `generate-tags` request of the file, and collects tags until the response is completed.
+    fn generate(&mut self, file: &str, content: &[u8], timeout: Option<Duration>) -> Generated {
+        let request = serde_json::json!({
+            "command": "generate-tags",
+            "filename": file,
+            "size": content.len(),
+        });
+        let request = format!("{}\n", request);
+        let sent = match self.stdin {
+            Some(ref mut x) => x
+                .write_all(request.as_bytes())
+                .and_then(|_| x.write_all(content))
+                .and_then(|_| x.flush()),
+            None => Ok(()),
+        };
+        if sent.is_err() {
+            return Generated::Failed(self.finish());
+        }
+        self.bytes_in += (request.len() + content.len()) as u64;
+
+        let deadline = timeout.map(|x| Instant::now() + x);
+        let mut tags = Vec::new();
+        loop {
+            let line = match deadline {
+                Some(x) => match self
+                    .lines
+                    .recv_timeout(x.saturating_duration_since(Instant::now()))
+                {
+                    Ok(x) => x,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        self.kill();
+                        return Generated::TimedOut;
+                    }
+                    Err(mpsc::RecvTimeoutError::Disconnected) => {
+                        return Generated::Failed(self.finish())
+                    }
+                },
+                None => match self.lines.recv() {
+                    Ok(x) => x,
+                    Err(_) => return Generated::Failed(self.finish()),
+                },
+            };
+            self.bytes_out += line.len() as u64 + 1;
+            match Response::parse(&line) {
+                Response::Tag => tags.push(line),
+                Response::Completed => return Generated::Tags(tags),
+                // An error of the request ends the response, and is reported as a warning of ctags.
+                Response::Error(x) => {
+                    let warning = format!("ctags: Warning: {} ({})\n", x, file);
+                    self.stderr
+                        .lock()
+                        .unwrap()
+                        .extend_from_slice(warning.as_bytes());
+                    return Generated::Tags(tags);
+                }
+                Response::Other => (),
+            }
+        }
+    }
+
+    fn take_stderr(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.stderr.lock().unwrap())
+    }
+
+    fn kill(&mut self) {
+        signal::kill_group(self.child.id());
+        let _ = self.child.kill();
+        self.finish();
+    }
+
+    /// Closes stdin so that ctags exits, and waits it.
+    fn finish(&mut self) -> ExitStatus {
+        drop(self.stdin.take());
+        let status = self.child.wait();
+        // stderr is read until the end so that the message of the exited process is returned by `take_stderr`.
+        if let Some(x) = self.stderr_reader.take() {
+            let _ = x.join();
+        }
+        if !self.finished {
+            self.finished = true;
+            signal::unregister_child(self.child.id());
+            audit::record(
+                &self.command,
+                self.started,
+                status.as_ref().ok().copied(),
+                self.bytes_in,
+                self.bytes_out,
+            );
+        }
+        status.unwrap_or_default()
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Writes the shell script of the body as ctags into the directory, and returns the path.
+#[cfg(all(test, unix))]
+pub fn fake_ctags(dir: &tempfile::TempDir, body: &str) -> String {
+    use std::os::unix::fs::PermissionsExt;
+
+    let bin = dir.path().join("ctags");
+    fs::write(&bin, format!("#!/bin/sh\n{}", body)).unwrap();
+    fs::set_permissions(&bin, fs::Permissions::from_mode(0o755)).unwrap();
+    bin.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::bin::{git_files, shell_quote, Opt};
+    #[cfg(unix)]
+    use super::fake_ctags;
+    use super::{
+        ChunkQueue, ChunkSender, CmdCtags, CtagsFlavor, CtagsInfo, FileList, Response, SharedQueue,
+    };
+    use std::collections::VecDeque;
+    use std::str;
+    use std::sync::{Arc, Condvar, Mutex};
+    use structopt::StructOpt;
+    use structopt_toml::StructOptToml;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_call() {
+        let args = vec!["ptags", "-t", "1", "--exclude=README.md"];
+        let opt = Opt::from_iter(args.iter());
+        let files = git_files(&opt).unwrap();
+        let outputs = CmdCtags::call(&opt, &files).unwrap();
+        let output = outputs[0].read().unwrap();
+        let mut iter = str::from_utf8(&output).unwrap().lines();
+        assert_eq!(
+            iter.next().unwrap_or(""),
+            "BIN_NAME\tMakefile\t/^BIN_NAME = ptags$/;\"\tm"
+        );
+    }
+
+    #[test]
+    fn test_call_files_via_tempfile() {
+        let args = vec![
+            "ptags",
+            "-t",
+            "1",
+            "--exclude=README.md",
+            "--files-via",
+            "tempfile",
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let files = git_files(&opt).unwrap();
+        let outputs = CmdCtags::call(&opt, &files).unwrap();
+        let output = outputs[0].read().unwrap();
+        let mut iter = str::from_utf8(&output).unwrap().lines();
+        assert_eq!(
+            iter.next().unwrap_or(""),
+            "BIN_NAME\tMakefile\t/^BIN_NAME = ptags$/;\"\tm"
+        );
+
+        let cmd = CmdCtags::get_command_line(&opt);
+        assert!(!cmd.contains("'-L -'"));
+        assert!(cmd.ends_with(" -L \"$LIST\""));
+    }
+
+    #[test]
+    fn test_call_with_opt() {
+        let args = vec!["ptags", "-t", "1", "--opt-ctags=-u"];
+        let opt = Opt::from_iter(args.iter());
+        let files = git_files(&opt).unwrap();
+        let outputs = CmdCtags::call(&opt, &files).unwrap();
+        let output = outputs[0].read().unwrap();
+        let mut iter = str::from_utf8(&output).unwrap().lines();
+        if cfg!(target_os = "linux") {
+            assert_eq!(
+                iter.next().unwrap_or(""),
+                "VERSION\tMakefile\t/^VERSION = $(patsubst \"%\",%, $(word 3, $(shell grep version Cargo.toml)))$/;\"\tm"
+            );
+        }
+        if cfg!(target_os = "macos") {
+            assert_eq!(
+                iter.next().unwrap_or(""),
+                "package\tCargo.toml\t/^[package]$/;\"\tt"
+            );
+        }
+    }
+
+    #[test]
+    fn test_call_exclude() {
+        let args = vec![
+            "ptags",
+            "-t",
+            "1",
+            "--exclude=Make*",
+            "--exclude=README.md",
+            "-v",
         ];
         let opt = Opt::from_iter(args.iter());
         let files = git_files(&opt).unwrap();
         let outputs = CmdCtags::call(&opt, &files).unwrap();
-        let mut iter = str::from_utf8(&outputs[0].stdout).unwrap().lines();
+        let output = outputs[0].read().unwrap();
+        let mut iter = str::from_utf8(&output).unwrap().lines();
 
         // Exuberant Ctags doesn't support Rust ( *.rs ).
         // So the result becomes empty when 'Makefile' is excluded.
-        if CmdCtags::is_exuberant_ctags(&opt).unwrap() {
+        if CmdCtags::info(&opt).flavor == CtagsFlavor::Exuberant {
             assert_eq!(iter.next().unwrap_or(""), "");
         } else {
             assert_eq!(
@@ -254,6 +2067,27 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_call_json() {
+        let args = vec![
+            "ptags",
+            "-t",
+            "1",
+            "--format",
+            "json",
+            "--exclude=README.md",
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let files = git_files(&opt).unwrap();
+        let outputs = CmdCtags::call(&opt, &files).unwrap();
+        let output = outputs[0].read().unwrap();
+        let mut iter = str::from_utf8(&output).unwrap().lines();
+        assert_eq!(
+            &iter.next().unwrap_or("")[0..35],
+            "{\"_type\": \"tag\", \"name\": \"BIN_NAME\""
+        );
+    }
+
     #[test]
     fn test_command_fail() {
         let args = vec!["ptags", "--bin-ctags", "aaa"];
@@ -262,7 +2096,7 @@ mod tests {
         let outputs = CmdCtags::call(&opt, &files);
         assert_eq!(
             &format!("{:?}", outputs),
-            "Err(failed to call ctags command (cd .; aaa -L - -f -))"
+            "Err(failed to call ctags command (cd .; aaa '-L -' '-f -'))"
         );
     }
 
@@ -272,10 +2106,757 @@ mod tests {
         let opt = Opt::from_iter(args.iter());
         let files = git_files(&opt).unwrap();
         let outputs = CmdCtags::call(&opt, &files);
+        let options: String = CmdCtags::get_config_args(&opt, CmdCtags::info(&opt).flavor)
+            .iter()
+            .map(|x| format!("{} ", shell_quote(x)))
+            .collect();
+        let expected = format!(
+            "Err(failed to execute ctags command (cd .; ctags {}'-L -' '-f -' -",
+            options
+        );
+        assert_eq!(&format!("{:?}", outputs)[0..expected.len()], expected);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_adapt_languages() {
+        let dir = TempDir::new().unwrap();
+        let bin = fake_ctags(
+            &dir,
+            "for x; do case \"$x\" in\n\
+             --version) echo 'Universal Ctags 6.0.0'; exit;;\n\
+             --list-languages) echo Rust; echo Python; exit;;\n\
+             esac; done\n",
+        );
+
+        let args = vec!["ptags", "--bin-ctags", &bin];
+        let opt = Opt::from_iter(args.iter());
+        assert!(CmdCtags::adapt_languages(&opt).1.is_empty());
+
+        let args = vec![
+            "ptags",
+            "--bin-ctags",
+            &bin,
+            "--languages",
+            "rust,+Zig",
+            "--opt-ctags=--languages=-python",
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let (adapted, warnings) = CmdCtags::adapt_languages(&opt);
+        assert_eq!(
+            warnings,
+            vec!["Universal Ctags 6.0.0 doesn't support language (Zig)"]
+        );
+        assert_eq!(adapted.languages.as_deref(), Some("rust"));
+        assert_eq!(adapted.opt_ctags, vec!["--languages=-python"]);
+
+        // ctags reports the list which has no supported language.
+        let args = vec!["ptags", "--bin-ctags", &bin, "--languages", "Zig"];
+        let opt = Opt::from_iter(args.iter());
+        let (adapted, warnings) = CmdCtags::adapt_languages(&opt);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(adapted.languages.as_deref(), Some("Zig"));
+
+        // A failing probe is reported as a warning.
+        let bin = fake_ctags(&dir, "exit 1\n");
+        let args = vec!["ptags", "--bin-ctags", &bin, "--languages", "rust"];
+        let opt = Opt::from_iter(args.iter());
+        let (adapted, warnings) = CmdCtags::adapt_languages(&opt);
+        assert!(warnings[0].starts_with("failed to list languages of ctags"));
+        assert_eq!(adapted.languages.as_deref(), Some("rust"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_call_timeout() {
+        let dir = TempDir::new().unwrap();
+        let bin = fake_ctags(&dir, "exec sleep 10\n");
+        let files = vec![String::from("a.rs\nb.rs\n")];
+
+        let args = vec!["ptags", "--bin-ctags", &bin, "--ctags-timeout", "1"];
+        let opt = Opt::from_iter(args.iter());
+        let outputs = CmdCtags::call(&opt, &files);
+        assert!(format!("{:?}", outputs).contains("timed out after 1 seconds"));
+
+        let args = vec![
+            "ptags",
+            "--bin-ctags",
+            &bin,
+            "--ctags-timeout",
+            "1",
+            "--retry-timeout",
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let outputs = CmdCtags::call(&opt, &files).unwrap();
+        let timed_out: Vec<_> = outputs.iter().flat_map(|x| x.timed_out.iter()).collect();
+        assert_eq!(timed_out, vec!["a.rs", "b.rs"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_call_by_encoding() {
+        let dir = TempDir::new().unwrap();
+        let bin = fake_ctags(
+            &dir,
+            "enc=default\nfor a in \"$@\"; do case \"$a\" in --input-encoding=*) enc=\"${a#*=}\";; esac; done\n\
+             while read -r f; do printf '%s\\t%s\\t1;\"\\tf\\n' \"$enc\" \"$f\"; done\n",
+        );
+        let files = vec![
+            String::from("a.rs\nlegacy/b.c\n"),
+            String::from("legacy/c.c\nd.rs\nold/e.c\n"),
+        ];
+
+        let args = vec![
+            "ptags",
+            "--bin-ctags",
+            &bin,
+            "--encoding-for",
+            "legacy/*=sjis",
+            "--encoding-for",
+            "old/*=euc-jp",
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let outputs = CmdCtags::call(&opt, &files).unwrap();
+        let tags: Vec<String> = outputs
+            .iter()
+            .map(|x| String::from_utf8(x.read().unwrap()).unwrap())
+            .collect();
+        assert_eq!(
+            tags,
+            vec![
+                "default\ta.rs\t1;\"\tf\n",
+                "sjis\tlegacy/b.c\t1;\"\tf\n",
+                "default\td.rs\t1;\"\tf\n",
+                "sjis\tlegacy/c.c\t1;\"\tf\n",
+                "euc-jp\told/e.c\t1;\"\tf\n",
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_call_limits() {
+        let dir = TempDir::new().unwrap();
+        let bin = fake_ctags(
+            &dir,
+            "echo \"cpu=$(ulimit -t) data=$(ulimit -d) nice=$(nice)\" >&2\nexit 1\n",
+        );
+        let files = vec![String::from("a.rs\n")];
+
+        let args = vec![
+            "ptags",
+            "--bin-ctags",
+            &bin,
+            "--ctags-rlimit-cpu",
+            "5",
+            "--ctags-rlimit-mem",
+            "1G",
+            "--low-priority",
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let outputs = CmdCtags::call(&opt, &files);
+        assert!(format!("{:?}", outputs).contains("cpu=5 data=1048576 nice=19"));
+
+        // ctags runs with the C locale by `--reproducible`.
+        let bin = fake_ctags(&dir, "echo \"locale=$LC_ALL;\" >&2\nexit 1\n");
+        let args = vec!["ptags", "--bin-ctags", &bin, "--reproducible"];
+        let opt = Opt::from_iter(args.iter());
+        let outputs = CmdCtags::call(&opt, &files);
+        assert!(format!("{:?}", outputs).contains("locale=C;"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_call_keep_going() {
+        let dir = TempDir::new().unwrap();
+        let bin = fake_ctags(&dir, "echo error >&2\nexit 1\n");
+        let files = vec![String::from("a.rs\n")];
+
+        let args = vec!["ptags", "--bin-ctags", &bin];
+        let opt = Opt::from_iter(args.iter());
+        let outputs = CmdCtags::call(&opt, &files);
+        assert!(format!("{:?}", outputs).contains("failed to execute ctags command"));
+
+        let args = vec!["ptags", "--bin-ctags", &bin, "--keep-going"];
+        let opt = Opt::from_iter(args.iter());
+        let outputs = CmdCtags::call(&opt, &files).unwrap();
+        assert!(!outputs[0].status.success());
+        assert_eq!(outputs[0].stderr, b"error\n");
+        assert_eq!(outputs[0].list, "a.rs\n");
+
+        // A chunk which times out is skipped as a failed output instead of failing the run.
+        let bin = fake_ctags(&dir, "case \"$(cat)\" in *slow.rs*) exec sleep 10;; esac\n");
+        let files = vec![String::from("slow.rs\n"), String::from("b.rs\n")];
+        let args = vec![
+            "ptags",
+            "--bin-ctags",
+            &bin,
+            "--keep-going",
+            "--ctags-timeout",
+            "1",
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let outputs = CmdCtags::call(&opt, &files).unwrap();
+        assert!(!outputs[0].status.success());
+        assert!(String::from_utf8_lossy(&outputs[0].stderr).contains("timed out after 1 seconds"));
+        assert_eq!(outputs[0].list, "slow.rs\n");
+        assert!(outputs[1].status.success());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_call_bisect() {
+        let dir = TempDir::new().unwrap();
+        let bin = fake_ctags(
+            &dir,
+            "case \"$(cat)\" in *bad.rs*) echo crashed >&2; exit 1;; *slow.rs*) exec sleep 10;; esac\n",
+        );
+        let files = vec![String::from("a.rs\nb.rs\nbad.rs\nc.rs\n")];
+
+        // The chunk isn't bisected without `--keep-going` because the run fails anyway.
+        let args = vec!["ptags", "--bin-ctags", &bin];
+        let opt = Opt::from_iter(args.iter());
+        let outputs = CmdCtags::call(&opt, &files);
+        assert!(
+            format!("{}", outputs.unwrap_err()).starts_with("failed to execute ctags command (")
+        );
+
+        let args = vec!["ptags", "--bin-ctags", &bin, "--keep-going"];
+        let opt = Opt::from_iter(args.iter());
+        let outputs = CmdCtags::call(&opt, &files).unwrap();
+        let lists: Vec<(&str, bool)> = outputs
+            .iter()
+            .map(|x| (x.list.as_str(), x.status.success()))
+            .collect();
+        assert_eq!(
+            lists,
+            vec![
+                ("a.rs\nb.rs\n", true),
+                ("bad.rs\n", false),
+                ("c.rs\n", true)
+            ]
+        );
+        assert_eq!(outputs[1].stderr, b"crashed\n");
+
+        // A half which times out is skipped, and the other half is still bisected.
+        let files = vec![String::from("a.rs\nslow.rs\nbad.rs\nc.rs\n")];
+        let args = vec![
+            "ptags",
+            "--bin-ctags",
+            &bin,
+            "--keep-going",
+            "--ctags-timeout",
+            "1",
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let outputs = CmdCtags::call(&opt, &files).unwrap();
+        let lists: Vec<(&str, bool)> = outputs
+            .iter()
+            .map(|x| (x.list.as_str(), x.status.success()))
+            .collect();
+        assert_eq!(
+            lists,
+            vec![
+                ("a.rs\nslow.rs\n", true),
+                ("bad.rs\n", false),
+                ("c.rs\n", true)
+            ]
+        );
+        assert_eq!(outputs[0].timed_out, vec!["a.rs", "slow.rs"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_call_persistent_workers() {
+        let dir = TempDir::new().unwrap();
+        let script = r##"for a in "$@"; do
+    case "$a" in
+        --version) echo "Universal Ctags 6.0.0"; exit 0;;
+        --list-features) printf "#NAME DESCRIPTION\ninteractive x\nsandbox x\n"; exit 0;;
+    esac
+done
+echo '{"_type": "program", "name": "Universal Ctags"}'
+while read -r req; do
+    size=${req##*\"size\":}
+    size=${size%\}}
+    dd bs=1 count="$size" > /dev/null 2>&1
+    case "$req" in *main.rs*) echo crashed >&2; exit 2;; esac
+    echo "{\"_type\": \"tag\", \"name\": \"$size\"}"
+    echo '{"_type": "completed", "command": "generate-tags"}'
+done
+"##;
+        let bin = fake_ctags(&dir, script);
+        let files = vec![String::from("Makefile\nsrc/main.rs\nCargo.toml\n")];
+
+        let args = vec!["ptags", "--bin-ctags", &bin, "--persistent-workers"];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(
+            CmdCtags::call(&opt, &files).unwrap_err().to_string(),
+            "--persistent-workers requires --format json"
+        );
+
+        let args = vec![
+            "ptags",
+            "--bin-ctags",
+            &bin,
+            "--persistent-workers",
+            "--format",
+            "json",
+            "--keep-going",
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let outputs = CmdCtags::call(&opt, &files).unwrap();
+        assert_eq!(outputs.len(), 2);
+
+        // The worker crashed by src/main.rs is spawned again for Cargo.toml.
+        let sizes: Vec<String> = ["Makefile", "Cargo.toml"]
+            .iter()
+            .map(|x| std::fs::metadata(x).unwrap().len().to_string())
+            .collect();
+        let mut expected: Vec<String> = sizes
+            .iter()
+            .map(|x| format!("{{\"_type\": \"tag\", \"name\": \"{}\"}}\n", x))
+            .collect();
+        expected.sort();
+        assert!(outputs[0].status.success());
+        assert_eq!(
+            str::from_utf8(&outputs[0].read().unwrap()).unwrap(),
+            expected.concat()
+        );
+        assert_eq!(outputs[0].files, 3);
+        assert!(!outputs[1].status.success());
+        assert_eq!(outputs[1].stderr, b"crashed\n");
+        assert_eq!(outputs[1].list, "src/main.rs\n");
+    }
+
+    #[test]
+    fn test_adapt_arg() {
+        let exuberant = CtagsFlavor::Exuberant;
+        let universal = CtagsFlavor::Universal;
+        assert_eq!(CmdCtags::adapt_arg(exuberant, "--extras=+q"), "--extra=+q");
+        assert_eq!(
+            CmdCtags::adapt_arg(exuberant, "--kinds-C=+p"),
+            "--C-kinds=+p"
+        );
+        assert_eq!(CmdCtags::adapt_arg(exuberant, "--fields=+n"), "--fields=+n");
+        assert_eq!(CmdCtags::adapt_arg(universal, "--extras=+q"), "--extras=+q");
+    }
+
+    #[test]
+    fn test_get_selection_args() {
+        let args = vec![
+            "ptags",
+            "--languages",
+            "C++,Rust",
+            "--kinds",
+            "Rust=fst",
+            "--kinds",
+            "C++=+p",
+            "--fields=+n",
+            "--extras=+q",
+        ];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(
+            CmdCtags::get_selection_args(&opt),
+            vec![
+                "--languages=C++,Rust",
+                "--kinds-Rust=fst",
+                "--kinds-C++=+p",
+                "--fields=+n",
+                "--extras=+q"
+            ]
+        );
+        assert!(CmdCtags::check_selection(&opt).is_ok());
+
+        let args = vec!["ptags", "--kinds", "fst"];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(
+            format!("{}", CmdCtags::check_selection(&opt).unwrap_err()),
+            "invalid value of --kinds (fst)"
+        );
+
+        let args = vec!["ptags", "--languages", "C,,Rust"];
+        let opt = Opt::from_iter(args.iter());
+        assert!(CmdCtags::check_selection(&opt).is_err());
+    }
+
+    #[test]
+    fn test_get_encoding_args() {
+        let args = vec!["ptags", "--encoding", "sjis"];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(
+            CmdCtags::get_selection_args(&opt),
+            vec!["--input-encoding=sjis", "--output-encoding=utf-8"]
+        );
+
+        let args = vec!["ptags", "--encoding-for", "legacy/**=euc-jp"];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(
+            CmdCtags::get_selection_args(&opt),
+            vec!["--output-encoding=utf-8"]
+        );
+        assert!(CmdCtags::check_selection(&opt).is_ok());
+
+        let args = vec!["ptags", "--output-encoding", "cp932"];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(
+            CmdCtags::get_selection_args(&opt),
+            vec!["--output-encoding=cp932"]
+        );
+
+        let args = vec!["ptags", "--encoding-for", "euc-jp"];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(
+            format!("{}", CmdCtags::check_selection(&opt).unwrap_err()),
+            "invalid value of --encoding-for (euc-jp)"
+        );
+    }
+
+    #[test]
+    fn test_get_option_args() {
+        let config = "[ctags.lang.Rust]\n\
+                      kinds = \"+fm\"\n\
+                      langmap = \"+.rs.in\"\n\
+                      regex = [\"/^macro (\\\\w+)/\\\\1/m,macro/\"]\n";
+        let args = vec!["ptags", "--kinds", "Rust=fst", "-c", "--kinds-Rust=-m"];
+        let opt = Opt::from_iter_with_toml(config, args.iter()).unwrap();
+        assert_eq!(
+            CmdCtags::get_option_args(&opt, CtagsFlavor::Universal),
+            vec![
+                "--kinds-Rust=fst",
+                "--langmap=Rust:+.rs.in",
+                "--kinds-Rust=+fm",
+                "--regex-Rust=/^macro (\\w+)/\\1/m,macro/",
+                "--kinds-Rust=-m"
+            ]
+        );
+        assert_eq!(
+            CmdCtags::get_option_args(&opt, CtagsFlavor::Exuberant)[2],
+            "--Rust-kinds=+fm"
+        );
+        assert!(opt.ctags.check().is_ok());
+
+        let config = "[ctags.lang.Rust]\nkinds = \"+ fm\"\n";
+        let opt = Opt::from_iter_with_toml(config, vec!["ptags"].iter()).unwrap();
+        assert_eq!(
+            format!("{}", opt.ctags.check().unwrap_err()),
+            "invalid value of ctags.lang.Rust.kinds (+ fm)"
+        );
+    }
+
+    #[test]
+    fn test_regex_config() {
+        let config = r#"
+            [ctags.regex.Foo]
+            langdef = true
+            [[ctags.regex.Foo.patterns]]
+            regex = '^def (\w+)/'
+            name = '\1'
+            kind = 'd,definition'
+            [[ctags.regex.Foo.patterns]]
+            regex = '''
+^class (\w+)
+\{'''
+            name = '\1'
+            kind = 'c,class'
+            flags = '{mgroup=1}'
+            multiline = true
+            [ctags.lang.Foo]
+            langmap = '.foo'
+        "#;
+        let opt = Opt::from_iter_with_toml(config, vec!["ptags"].iter()).unwrap();
+        assert!(opt.ctags.check().is_ok());
+        assert_eq!(
+            opt.ctags.get_args(),
+            vec![
+                "--langdef=Foo",
+                "--regex-Foo=/^def (\\w+)\\//\\1/d,definition/",
+                "--mline-regex-Foo=/^class (\\w+)\n\\{/\\1/c,class/{mgroup=1}",
+                "--langmap=Foo:.foo",
+            ]
+        );
+
+        let mut ctags = opt.ctags.clone();
+        ctags.regex.get_mut("Foo").unwrap().patterns[1].flags = None;
+        assert_eq!(
+            format!("{}", ctags.check().unwrap_err()),
+            "invalid ctags.regex.Foo.patterns[1].flags in config ({mgroup=N} is required by multiline = true)"
+        );
+        ctags.regex.get_mut("Foo").unwrap().patterns[0].regex = String::from("(a");
+        assert!(format!("{}", ctags.check().unwrap_err())
+            .starts_with("invalid ctags.regex.Foo.patterns[0].regex in config"));
+        ctags.regex.get_mut("Foo").unwrap().patterns[0].regex = String::from("a\nb");
+        assert!(format!("{}", ctags.check().unwrap_err())
+            .ends_with("(newline matches only with multiline = true)"));
+        ctags.regex.get_mut("Foo").unwrap().patterns[0].regex = String::from("a");
+        ctags.regex.get_mut("Foo").unwrap().patterns[0].kind = String::from("def");
+        assert!(format!("{}", ctags.check().unwrap_err())
+            .starts_with("invalid ctags.regex.Foo.patterns[0].kind in config"));
+    }
+
+    #[test]
+    fn test_get_args_reproducible() {
+        let args = vec!["ptags", "--reproducible", "-c", "--sort=foldcase"];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(CmdCtags::get_args(&opt).last().unwrap(), "--sort=yes");
+
+        let args = vec!["ptags", "--reproducible", "--unsorted"];
+        let opt = Opt::from_iter(args.iter());
+        assert!(!CmdCtags::get_args(&opt).contains(&String::from("--sort=yes")));
+    }
+
+    #[test]
+    fn test_is_resource_exhausted() {
+        let err = anyhow::Error::from(super::CtagsError::ResourceExhausted {
+            cmd: String::from("ctags"),
+        });
+        assert!(CmdCtags::is_resource_exhausted(&err));
+        let err = anyhow::Error::from(super::CtagsError::CallFailed {
+            cmd: String::from("ctags"),
+        });
+        assert!(!CmdCtags::is_resource_exhausted(&err));
+        #[cfg(unix)]
+        {
+            let err = anyhow::Error::from(std::io::Error::from_raw_os_error(nix::libc::EMFILE));
+            assert!(CmdCtags::is_resource_exhausted(&err));
+            let err = anyhow::Error::from(std::io::Error::from_raw_os_error(nix::libc::ENOENT));
+            assert!(!CmdCtags::is_resource_exhausted(&err));
+        }
+    }
+
+    #[test]
+    fn test_chunk_queue() {
+        let queue = ChunkQueue {
+            chunks: VecDeque::new(),
+            threads: 2,
+            closed: false,
+        };
+        let queue = Arc::new(SharedQueue {
+            inner: Mutex::new(queue),
+            ready: Condvar::new(),
+        });
+        let mut sender = ChunkSender {
+            queue: queue.clone(),
+            next: 0,
+        };
+        let popped = {
+            let queue = queue.clone();
+            std::thread::spawn(move || (queue.pop(), queue.pop(), queue.pop()))
+        };
+        sender.send(String::from("a.rs\n"));
+        sender.send_group(1, String::from("b.c\n"));
+        drop(sender);
+        assert_eq!(
+            popped.join().unwrap(),
+            (
+                Some((0, 0, String::from("a.rs\n"))),
+                Some((1, 1, String::from("b.c\n"))),
+                None
+            )
+        );
+        assert_eq!(queue.inner.lock().unwrap().threads, 1);
+    }
+
+    #[test]
+    fn test_get_worker_args() {
+        let args = vec!["ptags", "--format", "json", "--persistent-workers"];
+        let opt = Opt::from_iter(args.iter());
+        let args = CmdCtags::get_worker_args(&opt);
+        assert!(!args.contains(&String::from("-L -")));
+        assert!(!args.contains(&String::from("-f -")));
+        assert!(args.contains(&String::from("--output-format=json")));
+        assert_eq!(args.last().unwrap(), "--_interactive=sandbox");
+    }
+
+    #[test]
+    fn test_response() {
+        assert_eq!(
+            Response::parse(r#"{"_type": "tag", "name": "main", "path": "src/main.rs"}"#),
+            Response::Tag
+        );
+        assert_eq!(
+            Response::parse(r#"{"_type": "completed", "command": "generate-tags"}"#),
+            Response::Completed
+        );
+        assert_eq!(
+            Response::parse(r#"{"_type": "error", "message": "invalid request", "fatal": false}"#),
+            Response::Error(String::from("invalid request"))
+        );
+        assert_eq!(Response::parse(r#"{"_type": "program"}"#), Response::Other);
+        assert_eq!(Response::parse("ctags: Warning: x"), Response::Other);
+    }
+
+    #[test]
+    fn test_get_config_args() {
+        let dir = TempDir::new().unwrap();
+        std::fs::create_dir(dir.path().join(".ctags.d")).unwrap();
+        for name in ["b.ctags", "a.ctags", "c.txt"] {
+            std::fs::write(dir.path().join(".ctags.d").join(name), "--fields=+n\n").unwrap();
+        }
+        let args = vec!["ptags", dir.path().to_str().unwrap()];
+        let opt = Opt::from_iter(args.iter());
+        let universal = CmdCtags::get_config_args(&opt, CtagsFlavor::Universal);
+        assert_eq!(universal[0], "--options=NONE");
+        assert!(universal.ends_with(&[
+            String::from("--options=.ctags.d/a.ctags"),
+            String::from("--options=.ctags.d/b.ctags")
+        ]));
+        assert!(CmdCtags::get_config_args(&opt, CtagsFlavor::Exuberant).is_empty());
+
+        let args = vec![
+            "ptags",
+            "--no-user-ctags-config",
+            dir.path().to_str().unwrap(),
+        ];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(
+            CmdCtags::get_config_args(&opt, CtagsFlavor::Universal),
+            vec!["--options=NONE"]
+        );
+    }
+
+    #[test]
+    fn test_parse_maps() {
+        let maps = CmdCtags::parse_maps("C        *.c *.h\nMake     .mak [Mm]akefile\n");
+        assert_eq!(
+            maps,
+            vec![
+                (
+                    String::from("C"),
+                    vec![String::from("*.c"), String::from("*.h")]
+                ),
+                (
+                    String::from("Make"),
+                    vec![String::from("*.mak"), String::from("[Mm]akefile")]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_cmd() {
+        let args = vec!["ptags", "--exclude", "*.rs", "my repo"];
+        let opt = Opt::from_iter(args.iter());
+        let cmd = CmdCtags::get_command_line(&opt);
+        assert!(cmd.starts_with("cd 'my repo'; ctags "));
+        assert!(cmd.contains(" '-L -' '-f -' '--exclude=*.rs'"));
+
+        // Arguments quoted for ssh are quoted again as passed to ssh.
+        let args = vec!["ptags", "--remote", "build1", "--remote-dir", "/mnt"];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(
+            CmdCtags::get_cmd(
+                &opt,
+                &CmdCtags::get_launcher(&opt),
+                &[String::from("'-L -'")]
+            ),
+            "cd .; ssh build1 'cd /mnt && exec ctags' ''\\''-L -'\\'''"
+        );
+    }
+
+    #[test]
+    fn test_file_list() {
+        let opt = Opt::from_iter(vec!["ptags"].iter());
+        assert_eq!(
+            CmdCtags::file_list(&opt),
+            FileList::Stdin {
+                pipe_size: Some(1048576)
+            }
+        );
+        let opt = Opt::from_iter(vec!["ptags", "--pipe-size", "65536"].iter());
+        assert_eq!(
+            CmdCtags::file_list(&opt),
+            FileList::Stdin {
+                pipe_size: Some(65536)
+            }
+        );
+        let opt = Opt::from_iter(vec!["ptags", "--no-set-pipe-size"].iter());
+        assert_eq!(
+            CmdCtags::file_list(&opt),
+            FileList::Stdin { pipe_size: None }
+        );
+        let opt = Opt::from_iter(vec!["ptags", "--files-via", "tempfile"].iter());
+        assert_eq!(CmdCtags::file_list(&opt), FileList::TempFile);
+    }
+
+    #[test]
+    fn test_get_launcher() {
+        let args = vec!["ptags"];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(CmdCtags::get_launcher(&opt), vec!["ctags"]);
+
+        let dir = TempDir::new().unwrap();
+        let args = vec![
+            "ptags",
+            "--ctags-container",
+            "ctags:6.0",
+            "--bin-container",
+            "podman",
+            dir.path().to_str().unwrap(),
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let dir = dir.path().canonicalize().unwrap();
+        let dir = dir.to_str().unwrap();
+        let launcher: Vec<String> = CmdCtags::get_launcher(&opt)
+            .iter()
+            .map(|x| x.to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(
+            launcher,
+            vec![
+                String::from("podman"),
+                String::from("run"),
+                String::from("--rm"),
+                String::from("-i"),
+                String::from("-v"),
+                format!("{}:{}:ro", dir, dir),
+                String::from("-w"),
+                String::from(dir),
+                String::from("ctags:6.0"),
+                String::from("ctags"),
+            ]
+        );
+
+        let args = vec![
+            "ptags",
+            "--remote",
+            "build1",
+            "--remote-dir",
+            "/mnt/my repo",
+            "--ctags-container",
+            "ctags:6.0",
+        ];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(
+            CmdCtags::get_launcher(&opt),
+            vec![
+                "ssh",
+                "build1",
+                "cd '/mnt/my repo' && exec docker run --rm -i -v '/mnt/my repo:/mnt/my repo:ro' -w '/mnt/my repo' ctags:6.0 ctags"
+            ]
+        );
+        assert_eq!(
+            CmdCtags::quote_args(&opt, &[String::from("-L -"), String::from("--fields=+n")]),
+            vec!["'-L -'", "--fields=+n"]
+        );
+    }
+
+    #[test]
+    fn test_get_exuberant_header() {
+        let args = vec!["ptags", "--unsorted"];
+        let opt = Opt::from_iter(args.iter());
+        let info = CtagsInfo {
+            flavor: CtagsFlavor::Exuberant,
+            version: String::from("Exuberant Ctags 5.8"),
+        };
+        let header = CmdCtags::get_exuberant_header(&opt, &info);
+        let lines: Vec<&str> = header.lines().collect();
+        assert_eq!(lines.len(), 6);
         assert_eq!(
-            &format!("{:?}", outputs)[0..60],
-            "Err(failed to execute ctags command (cd .; ctags -L - -f - -"
+            lines[1],
+            "!_TAG_FILE_SORTED\t0\t/0=unsorted, 1=sorted, 2=foldcase/"
         );
+        assert_eq!(lines[5], "!_TAG_PROGRAM_VERSION\t5.8\t//");
     }
 
     #[test]
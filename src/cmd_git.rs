@@ -1,8 +1,17 @@
-use crate::bin::Opt;
+use crate::audit::{self, Counter};
+use crate::bin::{path_from_bytes, shell_quote, Opt};
+use crate::signal;
 use anyhow::{bail, Context, Error};
-use std::process::{Command, Output};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
 use std::str;
+use std::thread;
+use std::time::Instant;
 use thiserror::Error;
+use tracing::debug;
 
 // ---------------------------------------------------------------------------------------------------------------------
 // Error
@@ -18,6 +27,9 @@ enum GitError {
 
     #[error("failed to convert to UTF-8 ({:?})", s)]
     ConvFailed { s: Vec<u8> },
+
+    #[error("git object is not found ({})", spec)]
+    MissingObject { spec: String },
 }
 
 // ---------------------------------------------------------------------------------------------------------------------
@@ -28,7 +40,7 @@ pub struct CmdGit;
 
 impl CmdGit {
     pub fn get_files(opt: &Opt) -> Result<Vec<String>, Error> {
-        let mut list = CmdGit::ls_files(&opt)?;
+        let mut list = CmdGit::dedup(opt, CmdGit::ls_files(opt)?);
         if opt.exclude_lfs {
             let lfs_list = CmdGit::lfs_ls_files(&opt)?;
             let mut new_list = Vec::new();
@@ -39,39 +51,462 @@ impl CmdGit {
             }
             list = new_list;
         }
+        if opt.exclude_vendored {
+            let vendored = CmdGit::vendored_files(opt, &list)?;
+            if opt.verbose {
+                eprintln!("Vendored files: {}", vendored.len());
+            }
+            list.retain(|x| !vendored.contains(x));
+        }
+        if opt.sparse {
+            let skipped = CmdGit::skip_worktree_files(opt)?;
+            if opt.verbose {
+                eprintln!("Skip-worktree files: {}", skipped.len());
+            }
+            list.retain(|x| !skipped.contains(x));
+        }
         Ok(list)
     }
 
+    /// Removes duplicated paths keeping the first one.
+    ///
+    /// `git ls-files` lists a file twice if `--opt-git` combines overlapping sources like `--cached --others`,
+    /// or the file is unmerged.
+    fn dedup(opt: &Opt, list: Vec<String>) -> Vec<String> {
+        let len = list.len();
+        let mut seen = HashSet::new();
+        let ret: Vec<String> = list
+            .into_iter()
+            .filter(|x| seen.insert(x.clone()))
+            .collect();
+        if opt.verbose && ret.len() != len {
+            eprintln!("Duplicated files: {}", len - ret.len());
+        }
+        ret
+    }
+
+    /// Returns files marked as `linguist-vendored` or `linguist-generated` by `.gitattributes`.
+    fn vendored_files(opt: &Opt, list: &[String]) -> Result<HashSet<String>, Error> {
+        let args = vec![
+            String::from("check-attr"),
+            String::from("-z"),
+            String::from("--stdin"),
+            String::from("linguist-vendored"),
+            String::from("linguist-generated"),
+        ];
+        let mut input = Vec::new();
+        for f in list {
+            input.extend_from_slice(f.as_bytes());
+            input.push(0);
+        }
+        let output = CmdGit::call_with_input(opt, &args, input)?;
+
+        // <path> NUL <attribute> NUL <info> NUL
+        let mut ret = HashSet::new();
+        let fields: Vec<&[u8]> = output.stdout.split(|x| *x == 0).collect();
+        for x in fields.chunks_exact(3) {
+            if x[2] == b"set" || x[2] == b"true" {
+                ret.insert(path_from_bytes(opt, x[0])?);
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Returns files which are not checked out by sparse-checkout.
+    fn skip_worktree_files(opt: &Opt) -> Result<HashSet<String>, Error> {
+        let args = vec![
+            String::from("ls-files"),
+            String::from("-t"),
+            String::from("-z"),
+        ];
+        let output = CmdGit::call(opt, &args)?;
+
+        let mut ret = HashSet::new();
+        for l in CmdGit::entries(&output.stdout) {
+            // <tag> SP <file>, the tag of skip-worktree files is 'S'
+            if let Some(path) = l.strip_prefix(b"S ") {
+                ret.insert(path_from_bytes(opt, path)?);
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Returns files changed between the revision and the working tree.
+    pub fn diff_files(opt: &Opt, rev: &str) -> Result<Vec<String>, Error> {
+        let args = vec![
+            String::from("diff"),
+            String::from("--name-only"),
+            String::from("--relative"),
+            String::from("-z"),
+            String::from(rev),
+            String::from("--"),
+        ];
+        let output = CmdGit::call(opt, &args)?;
+
+        let mut ret = CmdGit::paths(opt, &output.stdout)?;
+        ret.sort();
+        Ok(ret)
+    }
+
+    /// Returns blob ids of files whose working tree content is the same as the index.
+    pub fn get_blob_ids(opt: &Opt) -> Result<HashMap<String, String>, Error> {
+        let args = vec![
+            String::from("ls-files"),
+            String::from("--stage"),
+            String::from("-z"),
+        ];
+        let output = CmdGit::call(opt, &args)?;
+
+        let mut ret = HashMap::new();
+        for l in CmdGit::entries(&output.stdout) {
+            // <mode> SP <object> SP <stage> TAB <file>
+            let mut iter = l.splitn(2, |x| *x == b'\t');
+            let info = String::from_utf8_lossy(iter.next().unwrap_or(b""));
+            let info: Vec<&str> = info.split(' ').collect();
+            let path = path_from_bytes(opt, iter.next().unwrap_or(b""))?;
+            if info.len() == 3 && info[2] == "0" {
+                ret.insert(path, String::from(info[1]));
+            }
+        }
+
+        let args = vec![
+            String::from("ls-files"),
+            String::from("--modified"),
+            String::from("-z"),
+        ];
+        let output = CmdGit::call(opt, &args)?;
+        for l in CmdGit::paths(opt, &output.stdout)? {
+            ret.remove(&l);
+        }
+
+        Ok(ret)
+    }
+
+    /// Returns files in the index. Unmerged entries, submodules and symbolic links are skipped.
+    pub fn ls_stage(opt: &Opt) -> Result<Vec<String>, Error> {
+        let args = vec![
+            String::from("ls-files"),
+            String::from("--stage"),
+            String::from("-z"),
+        ];
+        let output = CmdGit::call(opt, &args)?;
+
+        let mut ret = Vec::new();
+        for l in CmdGit::entries(&output.stdout) {
+            // <mode> SP <object> SP <stage> TAB <file>
+            let mut iter = l.splitn(2, |x| *x == b'\t');
+            let info = String::from_utf8_lossy(iter.next().unwrap_or(b""));
+            let info: Vec<&str> = info.split(' ').collect();
+            if info.len() == 3 && info[2] == "0" && info[0].starts_with("100") {
+                ret.push(path_from_bytes(opt, iter.next().unwrap_or(b""))?);
+            }
+        }
+        ret.sort();
+        ret.dedup();
+
+        if opt.verbose {
+            eprintln!("Files: {}", ret.len());
+        }
+
+        Ok(ret)
+    }
+
+    /// Returns files in the revision. Submodules and symbolic links are skipped.
+    pub fn ls_tree(opt: &Opt, rev: &str) -> Result<Vec<String>, Error> {
+        let args = vec![
+            String::from("ls-tree"),
+            String::from("-r"),
+            String::from("-z"),
+            String::from(rev),
+        ];
+        let output = CmdGit::call(opt, &args)?;
+
+        let mut ret = Vec::new();
+        for l in CmdGit::entries(&output.stdout) {
+            // <mode> SP <type> SP <object> TAB <file>
+            let mut iter = l.splitn(2, |x| *x == b'\t');
+            let info = String::from_utf8_lossy(iter.next().unwrap_or(b""));
+            let info: Vec<&str> = info.split(' ').collect();
+            if info.len() == 3 && info[1] == "blob" && info[0] != "120000" {
+                ret.push(path_from_bytes(opt, iter.next().unwrap_or(b""))?);
+            }
+        }
+        ret.sort();
+
+        if opt.verbose {
+            eprintln!("Files: {}", ret.len());
+        }
+
+        Ok(ret)
+    }
+
+    /// Writes contents of git objects ( ex. "HEAD:./src/main.rs" ) to the files in the directory.
+    ///
+    /// All objects are read by a `git cat-file --batch` process.
+    pub fn write_objects(opt: &Opt, objects: &[(String, String)], dir: &Path) -> Result<(), Error> {
+        let args = vec![String::from("cat-file"), String::from("--batch")];
+        let cmd = CmdGit::get_cmd(opt, &args);
+        debug!(cmd = %cmd, "call git");
+        if opt.dry_run {
+            println!("git: {}", cmd);
+        }
+
+        let started = Instant::now();
+        let mut command = Command::new(&opt.bin_git);
+        let mut child = signal::isolate(&mut command)
+            .args(&args)
+            .current_dir(&opt.dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context(GitError::CallFailed { cmd: cmd.clone() })?;
+        let pid = child.id();
+        signal::register_child(pid);
+
+        // Objects are requested by another thread to avoid deadlock of pipes.
+        let mut stdin = child.stdin.take().unwrap();
+        let specs: Vec<String> = objects.iter().map(|x| x.0.clone()).collect();
+        let bytes_in: usize = specs.iter().map(|x| x.len() + 1).sum();
+        let writer = thread::spawn(move || {
+            for spec in specs {
+                writeln!(stdin, "{}", spec)?;
+            }
+            Ok::<(), std::io::Error>(())
+        });
+
+        let mut stdout = Counter::new(child.stdout.take().unwrap());
+        let ret = CmdGit::read_objects(&mut stdout, objects, dir);
+        let _ = writer.join();
+        let output = child.wait_with_output();
+        signal::unregister_child(pid);
+        let status = output.as_ref().ok().map(|x| x.status);
+        audit::record(&command, started, status, bytes_in as u64, stdout.bytes);
+        let output = output?;
+
+        if !output.status.success() {
+            bail!(GitError::ExecFailed {
+                cmd,
+                err: String::from_utf8_lossy(&output.stderr).into_owned(),
+            });
+        }
+        ret
+    }
+
+    fn read_objects(
+        stdout: impl Read,
+        objects: &[(String, String)],
+        dir: &Path,
+    ) -> Result<(), Error> {
+        let mut reader = BufReader::new(stdout);
+        let mut header = String::new();
+        for (spec, path) in objects {
+            header.clear();
+            reader.read_line(&mut header)?;
+            // <object> SP <type> SP <size> LF <contents> LF, or <spec> SP missing LF
+            let info: Vec<&str> = header.trim_end().split(' ').collect();
+            let size = match info.last().and_then(|x| x.parse::<u64>().ok()) {
+                Some(x) if info.len() == 3 => x,
+                _ => bail!(GitError::MissingObject { spec: spec.clone() }),
+            };
+            let mut contents = Vec::with_capacity(size as usize);
+            (&mut reader).take(size).read_to_end(&mut contents)?;
+            reader.read_exact(&mut [0])?;
+
+            let path = dir.join(path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&path, contents)?;
+        }
+        Ok(())
+    }
+
+    pub fn is_work_tree(opt: &Opt) -> Result<bool, Error> {
+        let args = vec![
+            String::from("rev-parse"),
+            String::from("--is-inside-work-tree"),
+        ];
+        let cmd = CmdGit::get_cmd(opt, &args);
+
+        let output = signal::output(Command::new(&opt.bin_git).args(&args).current_dir(&opt.dir))
+            .context(GitError::CallFailed { cmd })?;
+
+        Ok(output.status.success() && str::from_utf8(&output.stdout)?.trim() == "true")
+    }
+
     fn call(opt: &Opt, args: &[String]) -> Result<Output, Error> {
         let cmd = CmdGit::get_cmd(&opt, &args);
-        if opt.verbose {
-            eprintln!("Call : {}", cmd);
+        debug!(cmd = %cmd, "call git");
+        if opt.dry_run {
+            println!("git: {}", cmd);
         }
 
-        let output = Command::new(&opt.bin_git)
+        let output = signal::output(Command::new(&opt.bin_git).args(args).current_dir(&opt.dir))
+            .context(GitError::CallFailed { cmd: cmd.clone() })?;
+
+        if !output.status.success() {
+            bail!(GitError::ExecFailed {
+                cmd,
+                err: String::from_utf8_lossy(&output.stderr).into_owned(),
+            });
+        }
+
+        Ok(output)
+    }
+
+    /// Same as `call` but the input is written to stdin of git.
+    fn call_with_input(opt: &Opt, args: &[String], input: Vec<u8>) -> Result<Output, Error> {
+        let cmd = CmdGit::get_cmd(opt, args);
+        debug!(cmd = %cmd, "call git");
+        if opt.dry_run {
+            println!("git: {}", cmd);
+        }
+
+        let started = Instant::now();
+        let mut command = Command::new(&opt.bin_git);
+        let mut child = signal::isolate(&mut command)
             .args(args)
             .current_dir(&opt.dir)
-            .output()
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
             .context(GitError::CallFailed { cmd: cmd.clone() })?;
+        let pid = child.id();
+        signal::register_child(pid);
+
+        // The input is written by another thread to avoid deadlock of pipes.
+        let mut stdin = child.stdin.take().unwrap();
+        let bytes_in = input.len() as u64;
+        let writer = thread::spawn(move || stdin.write_all(&input));
+        let output = child.wait_with_output();
+        let _ = writer.join();
+        signal::unregister_child(pid);
+        match output {
+            Ok(ref x) => audit::record(
+                &command,
+                started,
+                Some(x.status),
+                bytes_in,
+                x.stdout.len() as u64,
+            ),
+            Err(_) => audit::record(&command, started, None, bytes_in, 0),
+        }
+        let output = output?;
 
         if !output.status.success() {
             bail!(GitError::ExecFailed {
-                cmd: cmd,
-                err: String::from(str::from_utf8(&output.stderr).context(
-                    GitError::ConvFailed {
-                        s: output.stderr.to_vec(),
-                    }
-                )?)
+                cmd,
+                err: String::from_utf8_lossy(&output.stderr).into_owned(),
             });
         }
-
         Ok(output)
     }
 
+    /// Same as `get_files` but each path is passed to `f` as soon as `git ls-files` prints it.
+    ///
+    /// Options which need the whole list like `--exclude-lfs` are not applied. Paths are not sorted.
+    pub fn stream_files(
+        opt: &Opt,
+        mut f: impl FnMut(String) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let args = CmdGit::ls_files_args(opt);
+        let cmd = CmdGit::get_cmd(opt, &args);
+        debug!(cmd = %cmd, "call git");
+
+        let started = Instant::now();
+        let mut command = Command::new(&opt.bin_git);
+        let child = signal::isolate(&mut command)
+            .args(&args)
+            .current_dir(&opt.dir)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn();
+        let mut child = match child {
+            Ok(x) => x,
+            Err(_) => {
+                audit::record(&command, started, None, 0, 0);
+                bail!(GitError::CallFailed { cmd });
+            }
+        };
+        let pid = child.id();
+        signal::register_child(pid);
+        let mut pipe = child.stderr.take().unwrap();
+        let reader = thread::spawn(move || {
+            let mut ret = Vec::new();
+            let _ = pipe.read_to_end(&mut ret);
+            ret
+        });
+
+        let mut stdout = BufReader::new(Counter::new(child.stdout.take().unwrap()));
+        let mut seen = HashSet::new();
+        let mut count = 0;
+        let ret = (|| -> Result<(), Error> {
+            let mut buf = Vec::new();
+            while stdout.read_until(0, &mut buf)? != 0 {
+                let entry = buf.strip_suffix(&[0]).unwrap_or(&buf);
+                if !entry.is_empty() {
+                    let path = path_from_bytes(opt, entry)?;
+                    // Paths are deduplicated as `get_files`.
+                    if seen.insert(path.clone()) {
+                        count += 1;
+                        f(path)?;
+                    }
+                }
+                buf.clear();
+            }
+            Ok(())
+        })();
+        if ret.is_err() {
+            signal::kill_group(pid);
+            let _ = child.kill();
+        }
+        let status = child.wait();
+        signal::unregister_child(pid);
+        let err = reader.join().unwrap_or_default();
+        audit::record(
+            &command,
+            started,
+            status.as_ref().ok().copied(),
+            0,
+            stdout.get_ref().bytes,
+        );
+        ret?;
+
+        if !status?.success() {
+            bail!(GitError::ExecFailed {
+                cmd,
+                err: String::from_utf8_lossy(&err).into_owned(),
+            });
+        }
+        if opt.verbose {
+            eprintln!("Files: {}", count);
+        }
+        Ok(())
+    }
+
     fn ls_files(opt: &Opt) -> Result<Vec<String>, Error> {
+        let args = CmdGit::ls_files_args(opt);
+        let output = CmdGit::call(opt, &args)?;
+
+        // Paths are NUL-terminated without quoting so that tabs, newlines and non-ASCII characters are kept as is.
+        let mut ret = CmdGit::paths(opt, &output.stdout)?;
+        ret.sort();
+
+        if opt.verbose {
+            eprintln!("Files: {}", ret.len());
+        }
+
+        Ok(ret)
+    }
+
+    fn ls_files_args(opt: &Opt) -> Vec<String> {
         let mut args = vec![String::from("ls-files")];
         args.push(String::from("--cached"));
         args.push(String::from("--exclude-standard"));
+        args.push(String::from("-z"));
         if opt.include_submodule {
             args.push(String::from("--recurse-submodules"));
         } else if opt.include_untracked {
@@ -81,25 +516,11 @@ impl CmdGit {
             args.push(String::from("--other"));
         }
         args.append(&mut opt.opt_git.clone());
-
-        let output = CmdGit::call(&opt, &args)?;
-
-        let list = str::from_utf8(&output.stdout)
-            .context(GitError::ConvFailed {
-                s: output.stdout.to_vec(),
-            })?
-            .lines();
-        let mut ret = Vec::new();
-        for l in list {
-            ret.push(String::from(l));
+        if !opt.pathspecs.is_empty() {
+            args.push(String::from("--"));
+            args.append(&mut opt.pathspecs.clone());
         }
-        ret.sort();
-
-        if opt.verbose {
-            eprintln!("Files: {}", ret.len());
-        }
-
-        Ok(ret)
+        args
     }
 
     fn lfs_ls_files(opt: &Opt) -> Result<Vec<String>, Error> {
@@ -130,6 +551,65 @@ impl CmdGit {
         Ok(ret)
     }
 
+    /// Splits NUL-terminated output of `-z` option.
+    fn entries(list: &[u8]) -> impl Iterator<Item = &[u8]> {
+        list.split(|x| *x == 0).filter(|x| !x.is_empty())
+    }
+
+    fn paths(opt: &Opt, list: &[u8]) -> Result<Vec<String>, Error> {
+        CmdGit::entries(list)
+            .map(|x| path_from_bytes(opt, x))
+            .collect()
+    }
+
+    /// Returns the output of `git --version` ( ex. "git version 2.39.2" ).
+    pub fn version(opt: &Opt) -> Result<String, Error> {
+        let args = vec![String::from("--version")];
+        let output = CmdGit::call(opt, &args)?;
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    /// Returns the commit id of the revision like `HEAD`.
+    pub fn rev_parse(opt: &Opt, rev: &str) -> Result<String, Error> {
+        let args = vec![
+            String::from("rev-parse"),
+            String::from("--verify"),
+            format!("{}^{{commit}}", rev),
+        ];
+        let output = CmdGit::call(opt, &args)?;
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    /// Returns the version of git-lfs like `git-lfs/3.4.0`.
+    pub fn lfs_version(opt: &Opt) -> Result<String, Error> {
+        let args = vec![String::from("lfs"), String::from("version")];
+        let output = CmdGit::call(opt, &args)?;
+        let version = String::from_utf8_lossy(&output.stdout);
+        Ok(version.split_whitespace().next().unwrap_or("").to_string())
+    }
+
+    /// Returns the git directory shared by all worktrees of the repository.
+    pub fn common_dir(opt: &Opt) -> Result<PathBuf, Error> {
+        CmdGit::rev_parse_path(opt, "--git-common-dir")
+    }
+
+    /// Returns true if `DIR` is in a worktree added by `git worktree add`.
+    pub fn is_linked_worktree(opt: &Opt) -> Result<bool, Error> {
+        let git_dir = CmdGit::rev_parse_path(opt, "--git-dir")?;
+        let common_dir = CmdGit::common_dir(opt)?;
+        Ok(git_dir.canonicalize()? != common_dir.canonicalize()?)
+    }
+
+    /// Returns the path given by `git rev-parse`, which may be relative to `DIR`.
+    fn rev_parse_path(opt: &Opt, arg: &str) -> Result<PathBuf, Error> {
+        let args = vec![String::from("rev-parse"), String::from(arg)];
+        let output = CmdGit::call(opt, &args)?;
+        let path = String::from_utf8_lossy(&output.stdout)
+            .trim_end()
+            .to_string();
+        Ok(opt.dir.join(path))
+    }
+
     fn show_cdup(opt: &Opt) -> Result<String, Error> {
         let args = vec![String::from("rev-parse"), String::from("--show-cdup")];
 
@@ -156,14 +636,15 @@ impl CmdGit {
         Ok(String::from(list.next().unwrap_or("")))
     }
 
+    /// Returns the command line which reproduces the call when pasted into POSIX shell.
     fn get_cmd(opt: &Opt, args: &[String]) -> String {
         let mut cmd = format!(
             "cd {}; {}",
-            opt.dir.to_string_lossy(),
-            opt.bin_git.to_string_lossy()
+            shell_quote(&opt.dir.to_string_lossy()),
+            shell_quote(&opt.bin_git.to_string_lossy())
         );
         for arg in args {
-            cmd = format!("{} {}", cmd, arg);
+            cmd = format!("{} {}", cmd, shell_quote(arg));
         }
         cmd
     }
@@ -180,8 +661,9 @@ mod tests {
     use std::fs;
     use std::io::{BufWriter, Write};
     use structopt::StructOpt;
+    use tempfile::TempDir;
 
-    static TRACKED_FILES: [&'static str; 23] = [
+    static TRACKED_FILES: [&'static str; 52] = [
         ".cargo/config",
         ".gitattributes",
         ".github/FUNDING.yml",
@@ -198,11 +680,40 @@ mod tests {
         "Makefile",
         "README.md",
         "benches/ptags_bench.rs",
+        "src/api.rs",
+        "src/audit.rs",
         "src/bin.rs",
+        "src/cache.rs",
+        "src/check.rs",
+        "src/cmd_cscope.rs",
         "src/cmd_ctags.rs",
         "src/cmd_git.rs",
+        "src/cmd_gtags.rs",
+        "src/cmd_hg.rs",
+        "src/cmd_watchman.rs",
+        "src/diff.rs",
+        "src/doctor.rs",
+        "src/export.rs",
+        "src/filter.rs",
+        "src/find.rs",
+        "src/languages.rs",
         "src/lib.rs",
+        "src/lock.rs",
+        "src/log.rs",
+        "src/lsp.rs",
         "src/main.rs",
+        "src/merge.rs",
+        "src/outline.rs",
+        "src/pick.rs",
+        "src/pipeline.rs",
+        "src/regenerate.rs",
+        "src/sandbox.rs",
+        "src/serve.rs",
+        "src/signal.rs",
+        "src/state.rs",
+        "src/trace.rs",
+        "src/walk.rs",
+        "src/watch.rs",
         "test/lfs.txt",
         "test/ptags_test",
     ];
@@ -236,7 +747,42 @@ mod tests {
         let files = CmdGit::get_files(&opt).unwrap();
         assert_eq!(
             files,
-            vec!["bin.rs", "cmd_ctags.rs", "cmd_git.rs", "lib.rs", "main.rs"]
+            vec![
+                "api.rs",
+                "audit.rs",
+                "bin.rs",
+                "cache.rs",
+                "check.rs",
+                "cmd_cscope.rs",
+                "cmd_ctags.rs",
+                "cmd_git.rs",
+                "cmd_gtags.rs",
+                "cmd_hg.rs",
+                "cmd_watchman.rs",
+                "diff.rs",
+                "doctor.rs",
+                "export.rs",
+                "filter.rs",
+                "find.rs",
+                "languages.rs",
+                "lib.rs",
+                "lock.rs",
+                "log.rs",
+                "lsp.rs",
+                "main.rs",
+                "merge.rs",
+                "outline.rs",
+                "pick.rs",
+                "pipeline.rs",
+                "regenerate.rs",
+                "sandbox.rs",
+                "serve.rs",
+                "signal.rs",
+                "state.rs",
+                "trace.rs",
+                "walk.rs",
+                "watch.rs"
+            ]
         );
     }
 
@@ -257,7 +803,6 @@ mod tests {
 
         let mut expect_files = Vec::new();
         expect_files.push("ignored.gz");
-        expect_files.push("tags");
 
         assert_eq!(files, expect_files,);
     }
@@ -295,6 +840,35 @@ mod tests {
         assert_eq!(files, expect_files,);
     }
 
+    #[test]
+    fn test_diff_files() {
+        let args = vec!["ptags"];
+        let opt = Opt::from_iter(args.iter());
+        assert!(CmdGit::diff_files(&opt, "HEAD").is_ok());
+        assert!(CmdGit::diff_files(&opt, "aaa").is_err());
+    }
+
+    #[test]
+    fn test_get_blob_ids() {
+        let args = vec!["ptags"];
+        let opt = Opt::from_iter(args.iter());
+        let ids = CmdGit::get_blob_ids(&opt).unwrap();
+        assert_eq!(ids.get("LICENSE").map(|x| x.len()), Some(40));
+    }
+
+    #[test]
+    fn test_is_work_tree() {
+        let args = vec!["ptags"];
+        let opt = Opt::from_iter(args.iter());
+        assert!(CmdGit::is_work_tree(&opt).unwrap());
+
+        let dir = TempDir::new().unwrap();
+        let dir = dir.path().to_string_lossy();
+        let args = vec!["ptags", &dir];
+        let opt = Opt::from_iter(args.iter());
+        assert!(!CmdGit::is_work_tree(&opt).unwrap());
+    }
+
     #[test]
     fn test_command_fail() {
         let args = vec!["ptags", "--bin-git", "aaa"];
@@ -306,6 +880,136 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_ls_tree() {
+        let args = vec!["ptags"];
+        let opt = Opt::from_iter(args.iter());
+        let files = CmdGit::ls_tree(&opt, "HEAD").unwrap();
+        assert!(files.contains(&String::from("Cargo.toml")));
+        assert!(files.contains(&String::from("src/cmd_git.rs")));
+        assert!(CmdGit::ls_stage(&opt)
+            .unwrap()
+            .contains(&String::from("src/cmd_git.rs")));
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let objects = vec![(String::from("HEAD:./Cargo.toml"), String::from("a/b.toml"))];
+        CmdGit::write_objects(&opt, &objects, dir.path()).unwrap();
+        let contents = std::fs::read_to_string(dir.path().join("a/b.toml")).unwrap();
+        assert!(contents.starts_with("[package]"));
+
+        let objects = vec![(String::from("HEAD:./not_found"), String::from("a"))];
+        let ret = CmdGit::write_objects(&opt, &objects, dir.path());
+        assert_eq!(
+            format!("{}", ret.unwrap_err()),
+            "git object is not found (HEAD:./not_found)"
+        );
+    }
+
+    #[test]
+    fn test_common_dir() {
+        let args = vec!["ptags", "src"];
+        let opt = Opt::from_iter(args.iter());
+        let dir = CmdGit::common_dir(&opt).unwrap();
+        assert_eq!(
+            dir.canonicalize().unwrap(),
+            std::path::Path::new(".git").canonicalize().unwrap()
+        );
+        assert!(!CmdGit::is_linked_worktree(&opt).unwrap());
+    }
+
+    #[test]
+    fn test_get_files_sparse() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let git = |args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .args(args)
+                .current_dir(dir.path())
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        git(&["init", "-q"]);
+        std::fs::write(dir.path().join("a.rs"), "").unwrap();
+        std::fs::write(dir.path().join("b.rs"), "").unwrap();
+        git(&["add", "a.rs", "b.rs"]);
+        git(&["update-index", "--skip-worktree", "b.rs"]);
+        std::fs::remove_file(dir.path().join("b.rs")).unwrap();
+
+        let args = vec!["ptags", dir.path().to_str().unwrap()];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(CmdGit::get_files(&opt).unwrap(), vec!["a.rs", "b.rs"]);
+
+        let args = vec!["ptags", "--sparse", dir.path().to_str().unwrap()];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(CmdGit::get_files(&opt).unwrap(), vec!["a.rs"]);
+    }
+
+    #[test]
+    fn test_get_files_pathspec() {
+        let args = vec!["ptags", "--", "src/cmd_*.rs", ":!src/cmd_hg.rs"];
+        let opt = Opt::from_iter(args.iter());
+        let files = CmdGit::get_files(&opt).unwrap();
+        assert!(files.contains(&String::from("src/cmd_git.rs")));
+        assert!(!files.contains(&String::from("src/cmd_hg.rs")));
+        assert!(!files.contains(&String::from("src/bin.rs")));
+    }
+
+    #[test]
+    fn test_get_files_dedup() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let git = |args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .args(["-c", "user.name=a", "-c", "user.email=a@a"])
+                .args(args)
+                .current_dir(dir.path())
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        git(&["init", "-q"]);
+        std::fs::write(dir.path().join("a.rs"), "").unwrap();
+        git(&["add", "a.rs"]);
+        git(&["commit", "-q", "-m", "a"]);
+        std::fs::write(dir.path().join("a.rs"), "fn a() {}").unwrap();
+
+        // a.rs is listed twice by `--cached --modified`.
+        let args = vec![
+            "ptags",
+            "--opt-git=--modified",
+            dir.path().to_str().unwrap(),
+        ];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(CmdGit::get_files(&opt).unwrap(), vec!["a.rs"]);
+    }
+
+    #[test]
+    fn test_get_files_exclude_vendored() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let status = std::process::Command::new("git")
+            .args(["init", "-q"])
+            .current_dir(dir.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        std::fs::write(
+            dir.path().join(".gitattributes"),
+            "vendor/** linguist-vendored\n*.pb.rs linguist-generated=true\n",
+        )
+        .unwrap();
+        let files = vec![
+            String::from("a.rs"),
+            String::from("a.pb.rs"),
+            String::from("vendor/b.rs"),
+        ];
+
+        let args = vec!["ptags", dir.path().to_str().unwrap()];
+        let opt = Opt::from_iter(args.iter());
+        let vendored = CmdGit::vendored_files(&opt, &files).unwrap();
+        let mut vendored: Vec<_> = vendored.into_iter().collect();
+        vendored.sort();
+        assert_eq!(vendored, vec!["a.pb.rs", "vendor/b.rs"]);
+    }
+
     #[test]
     fn test_git_fail() {
         let args = vec!["ptags", "--opt-git=-aaa"];
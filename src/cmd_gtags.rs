@@ -0,0 +1,150 @@
+use crate::audit::{self, Counter};
+use crate::bin::{shell_quote, Opt};
+use crate::signal;
+use anyhow::{bail, Context, Error};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Instant;
+use thiserror::Error;
+use tracing::debug;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Error
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Error)]
+enum GtagsError {
+    #[error("failed to execute gtags command ({})\n{}", cmd, err)]
+    ExecFailed { cmd: String, err: String },
+
+    #[error("failed to call gtags command ({})", cmd)]
+    CallFailed { cmd: String },
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// CmdGtags
+// ---------------------------------------------------------------------------------------------------------------------
+
+pub struct CmdGtags;
+
+impl CmdGtags {
+    /// Builds GNU GLOBAL databases ( GTAGS, GRTAGS and GPATH ) of the files.
+    ///
+    /// gtags can't merge databases built separately, so all files are passed to a gtags process.
+    /// ptags still selects the files in the same way as ctags format.
+    pub fn call(opt: &Opt, files: &[String]) -> Result<(), Error> {
+        let args = CmdGtags::get_args(opt);
+        let cmd = CmdGtags::get_cmd(opt, &args);
+        debug!(cmd = %cmd, "call gtags");
+
+        let started = Instant::now();
+        let mut command = Command::new(&opt.bin_gtags);
+        let mut child = signal::isolate(&mut command)
+            .args(&args)
+            .current_dir(&opt.dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context(GtagsError::CallFailed { cmd: cmd.clone() })?;
+
+        let pid = child.id();
+        signal::register_child(pid);
+        let bytes_in = {
+            let mut stdin = Counter::new(child.stdin.take().unwrap());
+            for f in files {
+                let _ = stdin.write_all(f.as_bytes());
+                let _ = stdin.write_all(b"\n");
+            }
+            stdin.bytes
+        };
+        let output = child.wait_with_output();
+        signal::unregister_child(pid);
+        let status = output.as_ref().ok().map(|x| x.status);
+        audit::record(&command, started, status, bytes_in, 0);
+        let output = output?;
+
+        if !output.status.success() {
+            bail!(GtagsError::ExecFailed {
+                cmd,
+                err: String::from_utf8_lossy(&output.stderr).into_owned(),
+            });
+        }
+        Ok(())
+    }
+
+    fn get_args(opt: &Opt) -> Vec<String> {
+        let mut args = vec![String::from("--file"), String::from("-")];
+        if opt.incremental {
+            args.push(String::from("--incremental"));
+        }
+        args.append(&mut opt.opt_gtags.clone());
+        if let Some(dbpath) = CmdGtags::dbpath(opt) {
+            args.push(String::from(dbpath.to_string_lossy()));
+        }
+        args
+    }
+
+    /// Returns the directory of databases given by `--file`, or `None` for the default `tags`.
+    pub fn dbpath(opt: &Opt) -> Option<PathBuf> {
+        if opt.output == Path::new("tags") {
+            None
+        } else {
+            Some(opt.output.clone())
+        }
+    }
+
+    fn get_cmd(opt: &Opt, args: &[String]) -> String {
+        let mut cmd = format!(
+            "cd {}; {}",
+            shell_quote(&opt.dir.to_string_lossy()),
+            shell_quote(&opt.bin_gtags.to_string_lossy())
+        );
+        for arg in args {
+            cmd = format!("{} {}", cmd, shell_quote(arg));
+        }
+        cmd
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::CmdGtags;
+    use crate::bin::Opt;
+    use std::path::PathBuf;
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_get_args() {
+        let args = vec!["ptags", "--format", "gtags", "--opt-gtags=--skip-symlink"];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(
+            CmdGtags::get_args(&opt),
+            vec!["--file", "-", "--skip-symlink"]
+        );
+
+        let args = vec!["ptags", "--format", "gtags", "--incremental", "-f", "db"];
+        let opt = Opt::from_iter(args.iter());
+        assert_eq!(
+            CmdGtags::get_args(&opt),
+            vec!["--file", "-", "--incremental", "db"]
+        );
+        assert_eq!(CmdGtags::dbpath(&opt), Some(PathBuf::from("db")));
+    }
+
+    #[test]
+    fn test_command_fail() {
+        let args = vec!["ptags", "--format", "gtags", "--bin-gtags", "aaa"];
+        let opt = Opt::from_iter(args.iter());
+        let ret = CmdGtags::call(&opt, &[String::from("a.c")]);
+        assert_eq!(
+            &format!("{:?}", ret)[0..43],
+            "Err(failed to call gtags command (cd .; aaa"
+        );
+    }
+}
@@ -0,0 +1,140 @@
+use crate::bin::{path_from_bytes, shell_quote, Opt};
+use crate::signal;
+use anyhow::{bail, Context, Error};
+use std::process::{Command, Output};
+use std::str;
+use thiserror::Error;
+use tracing::debug;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Error
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Error)]
+enum HgError {
+    #[error("failed to execute hg command ({})\n{}", cmd, err)]
+    Exec { cmd: String, err: String },
+
+    #[error("failed to call hg command ({})", cmd)]
+    Call { cmd: String },
+
+    #[error("failed to convert to UTF-8 ({:?})", s)]
+    Conv { s: Vec<u8> },
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// CmdHg
+// ---------------------------------------------------------------------------------------------------------------------
+
+pub struct CmdHg;
+
+impl CmdHg {
+    pub fn get_files(opt: &Opt) -> Result<Vec<String>, Error> {
+        let mut list = CmdHg::files(opt)?;
+        if opt.include_untracked {
+            list.append(&mut CmdHg::status(opt, "--unknown")?);
+        }
+        if opt.include_ignored {
+            list.append(&mut CmdHg::status(opt, "--ignored")?);
+        }
+        list.sort();
+
+        if opt.verbose {
+            eprintln!("Files: {}", list.len());
+        }
+
+        Ok(list)
+    }
+
+    fn call(opt: &Opt, args: &[String]) -> Result<Output, Error> {
+        let cmd = CmdHg::get_cmd(opt, args);
+        debug!(cmd = %cmd, "call hg");
+        if opt.dry_run {
+            println!("hg: {}", cmd);
+        }
+
+        let output = signal::output(Command::new(&opt.bin_hg).args(args).current_dir(&opt.dir))
+            .context(HgError::Call { cmd: cmd.clone() })?;
+
+        if !output.status.success() {
+            bail!(HgError::Exec {
+                cmd,
+                err: String::from(str::from_utf8(&output.stderr).context(HgError::Conv {
+                    s: output.stderr.to_vec(),
+                })?)
+            });
+        }
+
+        Ok(output)
+    }
+
+    fn files(opt: &Opt) -> Result<Vec<String>, Error> {
+        let mut args = vec![String::from("files"), String::from("--print0")];
+        if opt.include_submodule {
+            args.push(String::from("--subrepos"));
+        }
+        args.append(&mut opt.opt_hg.clone());
+        // The pattern '.' makes hg print paths relative to the search directory.
+        args.push(String::from("."));
+
+        let output = CmdHg::call(opt, &args)?;
+        CmdHg::entries(opt, &output)
+    }
+
+    fn status(opt: &Opt, filter: &str) -> Result<Vec<String>, Error> {
+        let args = vec![
+            String::from("status"),
+            String::from(filter),
+            String::from("--no-status"),
+            String::from("--print0"),
+            String::from("."),
+        ];
+
+        let output = CmdHg::call(opt, &args)?;
+        CmdHg::entries(opt, &output)
+    }
+
+    /// Splits NUL-terminated output of `--print0` option.
+    fn entries(opt: &Opt, output: &Output) -> Result<Vec<String>, Error> {
+        output
+            .stdout
+            .split(|x| *x == 0)
+            .filter(|x| !x.is_empty())
+            .map(|x| path_from_bytes(opt, x))
+            .collect()
+    }
+
+    fn get_cmd(opt: &Opt, args: &[String]) -> String {
+        let mut cmd = format!(
+            "cd {}; {}",
+            shell_quote(&opt.dir.to_string_lossy()),
+            shell_quote(&opt.bin_hg.to_string_lossy())
+        );
+        for arg in args {
+            cmd = format!("{} {}", cmd, shell_quote(arg));
+        }
+        cmd
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::CmdHg;
+    use crate::bin::Opt;
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_command_fail() {
+        let args = vec!["ptags", "--vcs", "hg", "--bin-hg", "aaa"];
+        let opt = Opt::from_iter(args.iter());
+        let files = CmdHg::get_files(&opt);
+        assert_eq!(
+            &format!("{:?}", files)[0..41],
+            "Err(failed to call hg command (cd .; aaa "
+        );
+    }
+}
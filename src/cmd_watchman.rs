@@ -0,0 +1,201 @@
+use crate::bin::{shell_quote, Opt};
+use crate::signal;
+use anyhow::{bail, Context, Error};
+use serde_json::Value;
+use std::process::Command;
+use thiserror::Error;
+use tracing::debug;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Error
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Error)]
+enum WatchmanError {
+    #[error("failed to execute watchman command ({})\n{}", cmd, err)]
+    ExecFailed { cmd: String, err: String },
+
+    #[error("failed to call watchman command ({})", cmd)]
+    CallFailed { cmd: String },
+
+    #[error("unexpected response of watchman command ({})\n{}", cmd, response)]
+    InvalidResponse { cmd: String, response: String },
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// CmdWatchman
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Filename which marks the directory to be watched by Watchman.
+pub const WATCHMAN_CONFIG: &str = ".watchmanconfig";
+
+/// Files changed since a clock.
+#[derive(Debug, PartialEq)]
+pub struct Since {
+    /// Clock of the query to be passed to the next query
+    pub clock: String,
+    /// Changed files relative to `DIR` including removed files
+    pub files: Vec<String>,
+    /// True if Watchman can't tell changes since the clock because it was restarted
+    pub fresh: bool,
+}
+
+pub struct CmdWatchman;
+
+impl CmdWatchman {
+    /// Returns the current clock of the watch containing `DIR`.
+    pub fn clock(opt: &Opt) -> Result<String, Error> {
+        let (root, _) = CmdWatchman::watch_project(opt)?;
+        let args = vec![String::from("clock"), root];
+        let response = CmdWatchman::call(opt, &args)?;
+        match response["clock"].as_str() {
+            Some(x) => Ok(String::from(x)),
+            None => bail!(CmdWatchman::invalid(opt, &args, &response)),
+        }
+    }
+
+    /// Returns files changed since the clock.
+    pub fn since(opt: &Opt, clock: &str) -> Result<Since, Error> {
+        let (root, relative) = CmdWatchman::watch_project(opt)?;
+        let args = vec![String::from("since"), root, String::from(clock)];
+        let response = CmdWatchman::call(opt, &args)?;
+        match CmdWatchman::parse_since(&response, relative.as_deref()) {
+            Some(x) => Ok(x),
+            None => bail!(CmdWatchman::invalid(opt, &args, &response)),
+        }
+    }
+
+    /// Returns the root of the watch and the path of `DIR` relative to it.
+    fn watch_project(opt: &Opt) -> Result<(String, Option<String>), Error> {
+        let dir = opt.dir.canonicalize()?;
+        let args = vec![
+            String::from("watch-project"),
+            dir.to_string_lossy().into_owned(),
+        ];
+        let response = CmdWatchman::call(opt, &args)?;
+        let root = match response["watch"].as_str() {
+            Some(x) => String::from(x),
+            None => bail!(CmdWatchman::invalid(opt, &args, &response)),
+        };
+        let relative = response["relative_path"].as_str().map(String::from);
+        Ok((root, relative))
+    }
+
+    /// Parses the response of `since`. Names are relative to the root, so files out of `DIR` are dropped.
+    fn parse_since(response: &Value, relative: Option<&str>) -> Option<Since> {
+        let clock = String::from(response["clock"].as_str()?);
+        let fresh = response["is_fresh_instance"].as_bool().unwrap_or(false);
+        let prefix = relative.map(|x| format!("{}/", x.trim_end_matches('/')));
+        let mut files = Vec::new();
+        for file in response["files"].as_array()? {
+            let name = file["name"].as_str().or_else(|| file.as_str())?;
+            let name = match prefix {
+                Some(ref x) => match name.strip_prefix(x.as_str()) {
+                    Some(x) => x,
+                    None => continue,
+                },
+                None => name,
+            };
+            files.push(String::from(name));
+        }
+        Some(Since {
+            clock,
+            files,
+            fresh,
+        })
+    }
+
+    fn call(opt: &Opt, args: &[String]) -> Result<Value, Error> {
+        let cmd = CmdWatchman::get_cmd(opt, args);
+        debug!(cmd = %cmd, "call watchman");
+
+        let output = signal::output(
+            Command::new(&opt.bin_watchman)
+                .arg("--no-pretty")
+                .args(args)
+                .current_dir(&opt.dir),
+        )
+        .context(WatchmanError::CallFailed { cmd: cmd.clone() })?;
+
+        if !output.status.success() {
+            bail!(WatchmanError::ExecFailed {
+                cmd,
+                err: String::from_utf8_lossy(&output.stderr).into_owned(),
+            });
+        }
+
+        let response: Value =
+            serde_json::from_slice(&output.stdout).context(WatchmanError::InvalidResponse {
+                cmd: cmd.clone(),
+                response: String::from_utf8_lossy(&output.stdout).into_owned(),
+            })?;
+        if let Some(err) = response["error"].as_str() {
+            bail!(WatchmanError::ExecFailed {
+                cmd,
+                err: String::from(err),
+            });
+        }
+        Ok(response)
+    }
+
+    fn invalid(opt: &Opt, args: &[String], response: &Value) -> WatchmanError {
+        WatchmanError::InvalidResponse {
+            cmd: CmdWatchman::get_cmd(opt, args),
+            response: response.to_string(),
+        }
+    }
+
+    fn get_cmd(opt: &Opt, args: &[String]) -> String {
+        let mut cmd = format!(
+            "cd {}; {} --no-pretty",
+            shell_quote(&opt.dir.to_string_lossy()),
+            shell_quote(&opt.bin_watchman.to_string_lossy())
+        );
+        for arg in args {
+            cmd = format!("{} {}", cmd, shell_quote(arg));
+        }
+        cmd
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{CmdWatchman, Since};
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_since() {
+        let response = json!({
+            "clock": "c:1:2",
+            "is_fresh_instance": false,
+            "files": [
+                {"name": "src/a.rs", "exists": true},
+                {"name": "src/b.rs", "exists": false},
+                {"name": "doc/c.md", "exists": true},
+            ],
+        });
+        assert_eq!(
+            CmdWatchman::parse_since(&response, None),
+            Some(Since {
+                clock: String::from("c:1:2"),
+                files: vec![
+                    String::from("src/a.rs"),
+                    String::from("src/b.rs"),
+                    String::from("doc/c.md")
+                ],
+                fresh: false,
+            })
+        );
+        assert_eq!(
+            CmdWatchman::parse_since(&response, Some("src"))
+                .unwrap()
+                .files,
+            vec!["a.rs", "b.rs"]
+        );
+        assert_eq!(CmdWatchman::parse_since(&json!({}), None), None);
+    }
+}
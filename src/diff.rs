@@ -0,0 +1,273 @@
+use crate::bin::Opt;
+use crate::find::{Tag, TagsFile};
+use anyhow::Error;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::io::{stdout, BufWriter, Write};
+use std::path::Path;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Change
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Change of a symbol identified by the name and the kind.
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    Added,
+    Removed,
+    /// Moved from the file
+    Moved(String),
+}
+
+/// Changes of a file. Moved symbols belong to the file they are moved to.
+#[derive(Debug, Default, PartialEq)]
+pub struct FileChanges {
+    pub changes: Vec<(String, Option<String>, Change)>,
+}
+
+/// Symbols of a tags file as `(name, kind) -> files`.
+type Symbols = BTreeMap<(String, Option<String>), Vec<String>>;
+
+fn symbols(tags: impl Iterator<Item = Tag>) -> Symbols {
+    let mut ret = Symbols::new();
+    for tag in tags {
+        ret.entry((tag.name, tag.kind)).or_default().push(tag.path);
+    }
+    for files in ret.values_mut() {
+        files.sort();
+    }
+    ret
+}
+
+/// Compares symbols of two tags files and returns changes grouped by file.
+///
+/// Symbols are compared by files they are in, so changes of the line in the same file are not reported.
+/// If the number of files of a symbol is unchanged partially, the rest is paired as moves in order of the path.
+pub fn diff(
+    old: impl Iterator<Item = Tag>,
+    new: impl Iterator<Item = Tag>,
+) -> BTreeMap<String, FileChanges> {
+    let mut old = symbols(old);
+    let new = symbols(new);
+    let mut ret: BTreeMap<String, FileChanges> = BTreeMap::new();
+    let mut push = |path: &str, key: &(String, Option<String>), change: Change| {
+        ret.entry(String::from(path)).or_default().changes.push((
+            key.0.clone(),
+            key.1.clone(),
+            change,
+        ));
+    };
+
+    for (key, new_files) in &new {
+        let old_files = old.remove(key).unwrap_or_default();
+        let mut removed = Vec::new();
+        let mut added = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        while i < old_files.len() || j < new_files.len() {
+            if j == new_files.len() || (i < old_files.len() && old_files[i] < new_files[j]) {
+                removed.push(&old_files[i]);
+                i += 1;
+            } else if i == old_files.len() || new_files[j] < old_files[i] {
+                added.push(&new_files[j]);
+                j += 1;
+            } else {
+                i += 1;
+                j += 1;
+            }
+        }
+
+        let moved = removed.len().min(added.len());
+        for (from, to) in removed.iter().zip(added.iter()) {
+            push(to, key, Change::Moved(String::from(from.as_str())));
+        }
+        for path in &removed[moved..] {
+            push(path, key, Change::Removed);
+        }
+        for path in &added[moved..] {
+            push(path, key, Change::Added);
+        }
+    }
+    for (key, old_files) in &old {
+        for path in old_files {
+            push(path, key, Change::Removed);
+        }
+    }
+
+    for file in ret.values_mut() {
+        file.changes.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+    }
+    ret
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Run
+// ---------------------------------------------------------------------------------------------------------------------
+
+fn to_json(changes: &BTreeMap<String, FileChanges>) -> Value {
+    let mut count = [0, 0, 0];
+    let mut files = Vec::new();
+    for (path, file) in changes {
+        let mut added = Vec::new();
+        let mut removed = Vec::new();
+        let mut moved = Vec::new();
+        for (name, kind, change) in &file.changes {
+            match change {
+                Change::Added => added.push(json!({"name": name, "kind": kind})),
+                Change::Removed => removed.push(json!({"name": name, "kind": kind})),
+                Change::Moved(from) => {
+                    moved.push(json!({"name": name, "kind": kind, "from": from}))
+                }
+            }
+        }
+        count[0] += added.len();
+        count[1] += removed.len();
+        count[2] += moved.len();
+        files.push(json!({
+            "path": path,
+            "added": added,
+            "removed": removed,
+            "moved": moved,
+        }));
+    }
+    json!({
+        "added": count[0],
+        "removed": count[1],
+        "moved": count[2],
+        "files": files,
+    })
+}
+
+fn write_text(out: &mut dyn Write, changes: &BTreeMap<String, FileChanges>) -> Result<(), Error> {
+    let mut count = [0, 0, 0];
+    for (path, file) in changes {
+        writeln!(out, "{}", path)?;
+        for (name, kind, change) in &file.changes {
+            let kind = kind
+                .as_deref()
+                .map(|x| format!(" ({})", x))
+                .unwrap_or_default();
+            match change {
+                Change::Added => {
+                    count[0] += 1;
+                    writeln!(out, "  + {}{}", name, kind)?;
+                }
+                Change::Removed => {
+                    count[1] += 1;
+                    writeln!(out, "  - {}{}", name, kind)?;
+                }
+                Change::Moved(from) => {
+                    count[2] += 1;
+                    writeln!(out, "  > {}{} from {}", name, kind, from)?;
+                }
+            }
+        }
+    }
+    writeln!(
+        out,
+        "{} added, {} removed, {} moved",
+        count[0], count[1], count[2]
+    )?;
+    Ok(())
+}
+
+/// Prints changes of symbols from `old` to `new` in text or JSON.
+pub fn run(opt: &Opt, old: &Path, new: &Path, format: &str) -> Result<(), Error> {
+    let old = TagsFile::open(opt, old)?;
+    let new = TagsFile::open(opt, new)?;
+    let changes = diff(old.tags(), new.tags());
+
+    let out = stdout();
+    let mut out = BufWriter::new(out.lock());
+    if format == "json" {
+        writeln!(out, "{}", to_json(&changes))?;
+    } else {
+        write_text(&mut out, &changes)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, to_json, write_text, Change};
+    use crate::find::Tag;
+
+    fn tags(lines: &[&str]) -> Vec<Tag> {
+        lines
+            .iter()
+            .filter_map(|x| Tag::parse(x.as_bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = tags(&[
+            "!_TAG_FILE_SORTED\t1\t/0=unsorted, 1=sorted, 2=foldcase/",
+            "bar\ta.rs\t/^fn bar() {}$/;\"\tf\tline:3",
+            "baz\ta.rs\t/^fn baz() {}$/;\"\tf\tline:5",
+            "foo\ta.rs\t/^fn foo() {}$/;\"\tf\tline:1",
+            "new\ta.rs\t/^fn new() {}$/;\"\tf\tline:7",
+            "new\tb.rs\t/^fn new() {}$/;\"\tf\tline:7",
+        ]);
+        let new = tags(&[
+            "bar\ta.rs\t/^fn bar() {}$/;\"\tf\tline:1",
+            "baz\tc.rs\t/^fn baz() {}$/;\"\tf\tline:1",
+            "foo\ta.rs\t/^const foo: u32 = 0;$/;\"\tc\tline:2",
+            "new\ta.rs\t/^fn new() {}$/;\"\tf\tline:7",
+            "new\tb.rs\t/^fn new() {}$/;\"\tf\tline:7",
+            "new\tc.rs\t/^fn new() {}$/;\"\tf\tline:7",
+        ]);
+        let changes = diff(old.into_iter(), new.into_iter());
+        let changes: Vec<_> = changes
+            .iter()
+            .flat_map(|(path, x)| {
+                x.changes
+                    .iter()
+                    .map(move |(name, kind, change)| (path.as_str(), name, kind, change))
+            })
+            .map(|(path, name, kind, change)| {
+                let change = match change {
+                    Change::Added => String::from("+"),
+                    Change::Removed => String::from("-"),
+                    Change::Moved(x) => format!("<{}", x),
+                };
+                format!("{}:{}:{}:{}", path, name, kind.as_deref().unwrap(), change)
+            })
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                "a.rs:foo:c:+",
+                "a.rs:foo:f:-",
+                "c.rs:baz:f:<a.rs",
+                "c.rs:new:f:+"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_output() {
+        let old = tags(&["foo\ta.rs\t/^fn foo() {}$/;\"\tf", "bar\ta.rs\t/^bar$/"]);
+        let new = tags(&["foo\tb.rs\t/^fn foo() {}$/;\"\tf"]);
+        let changes = diff(old.into_iter(), new.into_iter());
+
+        let mut out = Vec::new();
+        write_text(&mut out, &changes).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "a.rs\n  - bar\nb.rs\n  > foo (f) from a.rs\n0 added, 1 removed, 1 moved\n"
+        );
+
+        let json = to_json(&changes);
+        assert_eq!(json["removed"], 1);
+        assert_eq!(json["moved"], 1);
+        assert_eq!(json["files"][0]["path"], "a.rs");
+        assert_eq!(json["files"][0]["removed"][0]["name"], "bar");
+        assert_eq!(json["files"][1]["moved"][0]["from"], "a.rs");
+        assert_eq!(json["files"][1]["moved"][0]["kind"], "f");
+    }
+}
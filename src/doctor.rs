@@ -0,0 +1,253 @@
+use crate::bin::{read_config, Opt};
+use crate::cmd_ctags::{CmdCtags, CtagsFlavor};
+use crate::cmd_git::CmdGit;
+use anyhow::{bail, Error};
+use std::io::{stdout, Write};
+use structopt::StructOpt;
+use thiserror::Error;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Error
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Error)]
+enum DoctorError {
+    #[error("ptags is not ready ({} problems)", count)]
+    NotReady { count: usize },
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Doctor
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    /// ptags works with limited features
+    Warn,
+    /// ptags doesn't work
+    Fail,
+}
+
+#[derive(Debug)]
+pub struct Item {
+    pub status: Status,
+    pub name: &'static str,
+    pub message: String,
+}
+
+impl Item {
+    fn new(status: Status, name: &'static str, message: impl Into<String>) -> Item {
+        Item {
+            status,
+            name,
+            message: message.into(),
+        }
+    }
+}
+
+/// Returns keys of the config which are not options of ptags, and are ignored silently.
+pub fn unknown_keys(config: &toml::Table) -> Vec<String> {
+    let known = match serde_json::to_value(Opt::from_iter(["ptags"].iter())) {
+        Ok(serde_json::Value::Object(x)) => x,
+        _ => return Vec::new(),
+    };
+    config
+        .keys()
+        .filter(|x| !known.contains_key(x.as_str()))
+        .cloned()
+        .collect()
+}
+
+fn diagnose_git(opt: &Opt, items: &mut Vec<Item>) {
+    if opt.list.is_some() || opt.no_vcs || opt.vcs != "git" {
+        return;
+    }
+
+    match CmdGit::version(opt) {
+        Ok(x) => items.push(Item::new(Status::Ok, "git", x)),
+        Err(_) => {
+            let message = format!("not found ({})", opt.bin_git.to_string_lossy());
+            items.push(Item::new(Status::Fail, "git", message));
+            return;
+        }
+    }
+
+    // git-lfs is required only by `--exclude-lfs`.
+    match CmdGit::lfs_version(opt) {
+        Ok(x) => items.push(Item::new(Status::Ok, "git-lfs", x)),
+        Err(_) if opt.exclude_lfs => {
+            items.push(Item::new(Status::Fail, "git-lfs", "not found"));
+        }
+        Err(_) => items.push(Item::new(Status::Warn, "git-lfs", "not found")),
+    }
+
+    match CmdGit::is_work_tree(opt) {
+        Ok(true) => {
+            let dir = opt.dir.canonicalize().unwrap_or_else(|_| opt.dir.clone());
+            items.push(Item::new(Status::Ok, "work tree", dir.to_string_lossy()));
+        }
+        _ => items.push(Item::new(
+            Status::Warn,
+            "work tree",
+            format!(
+                "not a git work tree, files are searched by directory walk ({})",
+                opt.dir.to_string_lossy()
+            ),
+        )),
+    }
+}
+
+fn diagnose_ctags(opt: &Opt, items: &mut Vec<Item>) {
+    let info = CmdCtags::info(opt);
+    match info.flavor {
+        _ if info.version.is_empty() => {
+            let message = format!("not found ({})", opt.bin_ctags.to_string_lossy());
+            items.push(Item::new(Status::Fail, "ctags", message));
+            return;
+        }
+        CtagsFlavor::Universal => items.push(Item::new(Status::Ok, "ctags", info.version)),
+        CtagsFlavor::Exuberant => items.push(Item::new(
+            Status::Warn,
+            "ctags",
+            format!(
+                "{}, Universal Ctags is required by some options",
+                info.version
+            ),
+        )),
+        CtagsFlavor::Unknown => items.push(Item::new(
+            Status::Warn,
+            "ctags",
+            format!("{}, unknown flavor", info.version),
+        )),
+    }
+
+    match CmdCtags::list_languages(opt) {
+        Ok(x) if !x.is_empty() => {
+            let message = format!("{} languages", x.len());
+            items.push(Item::new(Status::Ok, "languages", message));
+        }
+        _ => items.push(Item::new(
+            Status::Fail,
+            "languages",
+            "no language is listed",
+        )),
+    }
+}
+
+fn diagnose_config(opt: &Opt, items: &mut Vec<Item>) {
+    for path in &opt.config_sources {
+        let config = match read_config(path) {
+            Ok(x) => x,
+            Err(x) => {
+                items.push(Item::new(Status::Fail, "config", x.to_string()));
+                continue;
+            }
+        };
+        items.push(Item::new(Status::Ok, "config", path.to_string_lossy()));
+        for key in unknown_keys(&config) {
+            let message = format!("unknown key ({}) in {}", key, path.to_string_lossy());
+            items.push(Item::new(Status::Warn, "config", message));
+        }
+    }
+    if let Err(x) = opt.ctags.check() {
+        items.push(Item::new(Status::Fail, "config", x.to_string()));
+    }
+}
+
+/// Checks the environment which ptags depends on.
+pub fn diagnose(opt: &Opt) -> Vec<Item> {
+    let mut items = Vec::new();
+    diagnose_git(opt, &mut items);
+    diagnose_ctags(opt, &mut items);
+    diagnose_config(opt, &mut items);
+    items
+}
+
+fn write_report(out: &mut dyn Write, items: &[Item]) -> Result<(), Error> {
+    for item in items {
+        let status = match item.status {
+            Status::Ok => "ok",
+            Status::Warn => "warn",
+            Status::Fail => "fail",
+        };
+        writeln!(out, "{:<5} {:<10} {}", status, item.name, item.message)?;
+    }
+    let failed = items.iter().filter(|x| x.status == Status::Fail).count();
+    let warned = items.iter().filter(|x| x.status == Status::Warn).count();
+    if failed == 0 {
+        writeln!(out, "\nptags is ready ({} warnings)", warned)?;
+    }
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Run
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Prints the report, and fails if ptags doesn't work in the environment.
+pub fn run(opt: &Opt) -> Result<(), Error> {
+    let items = diagnose(opt);
+    let out = stdout();
+    let mut out = out.lock();
+    write_report(&mut out, &items)?;
+    out.flush()?;
+
+    let count = items.iter().filter(|x| x.status == Status::Fail).count();
+    if count > 0 {
+        bail!(DoctorError::NotReady { count });
+    }
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{diagnose, unknown_keys, write_report, Item, Status};
+    use crate::bin::Opt;
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_unknown_keys() {
+        let config: toml::Table =
+            "thread = 4\nthreads = 4\nbin_ctags = \"ctags\"\nlog_level = \"info\""
+                .parse()
+                .unwrap();
+        assert_eq!(unknown_keys(&config), vec!["threads"]);
+    }
+
+    #[test]
+    fn test_diagnose() {
+        let args = vec!["ptags", "--bin-ctags", "aaa", "--exclude-lfs"];
+        let opt = Opt::from_iter(args.iter());
+        let items = diagnose(&opt);
+        let ctags = items.iter().find(|x| x.name == "ctags").unwrap();
+        assert_eq!(ctags.status, Status::Fail);
+        assert_eq!(ctags.message, "not found (aaa)");
+        assert!(items.iter().all(|x| x.name != "languages"));
+
+        let args = vec!["ptags", "--no-vcs"];
+        let opt = Opt::from_iter(args.iter());
+        assert!(diagnose(&opt).iter().all(|x| x.name != "git"));
+    }
+
+    #[test]
+    fn test_write_report() {
+        let items = vec![
+            Item::new(Status::Ok, "git", "git version 2.43.0"),
+            Item::new(Status::Warn, "git-lfs", "not found"),
+        ];
+        let mut out = Vec::new();
+        write_report(&mut out, &items).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "ok    git        git version 2.43.0\n\
+             warn  git-lfs    not found\n\
+             \n\
+             ptags is ready (1 warnings)\n"
+        );
+    }
+}
@@ -0,0 +1,359 @@
+use crate::bin::Opt;
+use crate::find::{Tag, TagsFile};
+use anyhow::{bail, Context, Error};
+use rusqlite::{params, Connection};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+use thiserror::Error;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Error
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Error)]
+enum ExportError {
+    #[error("export requires --sqlite or --lsif")]
+    NoOutput,
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// SQLite
+// ---------------------------------------------------------------------------------------------------------------------
+
+const SCHEMA: &str = "
+CREATE TABLE files (
+    id       INTEGER PRIMARY KEY,
+    path     TEXT NOT NULL UNIQUE,
+    language TEXT
+);
+CREATE TABLE symbols (
+    id       INTEGER PRIMARY KEY,
+    name     TEXT NOT NULL,
+    file_id  INTEGER NOT NULL REFERENCES files(id),
+    kind     TEXT,
+    line     INTEGER,
+    pattern  TEXT,
+    language TEXT,
+    scope    TEXT
+);
+CREATE INDEX symbols_name ON symbols(name);
+CREATE INDEX symbols_file ON symbols(file_id);
+CREATE INDEX symbols_kind ON symbols(kind);
+";
+
+/// Trigram index for fuzzy search like `SELECT * FROM symbols_fts WHERE name LIKE '%tag%'`.
+const SCHEMA_FTS: &str = "
+CREATE VIRTUAL TABLE symbols_fts USING fts5(name, content='symbols', content_rowid='id', tokenize='trigram');
+INSERT INTO symbols_fts(symbols_fts) VALUES('rebuild');
+";
+
+/// Writes tags into the database. The database is replaced if it exists.
+pub fn write_sqlite(
+    path: &Path,
+    tags: impl Iterator<Item = Tag>,
+    fts: bool,
+) -> Result<usize, Error> {
+    let base = match path.parent() {
+        Some(x) if !x.as_os_str().is_empty() => x.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let tmp = NamedTempFile::new_in(&base)?;
+    let mut conn = Connection::open(tmp.path())?;
+    conn.execute_batch(SCHEMA)?;
+
+    let mut count = 0;
+    let tx = conn.transaction()?;
+    {
+        let mut files: HashMap<String, i64> = HashMap::new();
+        let mut insert_file = tx.prepare("INSERT INTO files (path, language) VALUES (?1, ?2)")?;
+        let mut insert_symbol = tx.prepare(
+            "INSERT INTO symbols (name, file_id, kind, line, pattern, language, scope) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )?;
+        for tag in tags {
+            let language = tag.field("language");
+            let file_id = match files.get(&tag.path) {
+                Some(x) => *x,
+                None => {
+                    let id = insert_file.insert(params![tag.path, language])?;
+                    files.insert(tag.path.clone(), id);
+                    id
+                }
+            };
+            insert_symbol.execute(params![
+                tag.name,
+                file_id,
+                tag.kind,
+                tag.line(),
+                tag.pattern,
+                language,
+                tag.scope(),
+            ])?;
+            count += 1;
+        }
+    }
+    tx.commit()?;
+    if fts {
+        conn.execute_batch(SCHEMA_FTS)
+            .context("failed to create FTS5 index")?;
+    }
+    conn.close().map_err(|x| x.1)?;
+
+    tmp.persist(path)
+        .context(format!("failed to write file ({:?})", path))?;
+    Ok(count)
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// LSIF
+// ---------------------------------------------------------------------------------------------------------------------
+
+const LSIF_VERSION: &str = "0.4.3";
+
+/// Returns `file://` URI of the path escaping characters which are not allowed in URI.
+pub fn file_uri(path: &Path) -> String {
+    let mut ret = String::from("file://");
+    for c in path.to_string_lossy().replace('\\', "/").bytes() {
+        if c.is_ascii_alphanumeric() || b"/-_.~:".contains(&c) {
+            ret.push(c as char);
+        } else {
+            ret.push_str(&format!("%{:02X}", c));
+        }
+    }
+    ret
+}
+
+/// Returns the column of the name in the search pattern like `/^    fn new() {}$/`, or 0 if not found.
+pub fn column(tag: &Tag) -> usize {
+    let pattern = tag.pattern.trim_start_matches('/').trim_start_matches('^');
+    pattern
+        .find(&tag.name)
+        .map(|x| pattern[..x].encode_utf16().count())
+        .unwrap_or(0)
+}
+
+/// Writes LSIF dump containing documents and ranges of tags linked to definition results.
+///
+/// Tags without line numbers are skipped. Call ctags with `--fields=+n` or use JSON output format which has them.
+pub fn write_lsif(
+    out: &mut dyn Write,
+    root: &Path,
+    tags: impl Iterator<Item = Tag>,
+) -> Result<usize, Error> {
+    let mut docs: BTreeMap<String, Vec<Tag>> = BTreeMap::new();
+    for tag in tags {
+        docs.entry(tag.path.clone()).or_default().push(tag);
+    }
+
+    let mut id = 0;
+    let mut emit = |out: &mut dyn Write, mut x: Value| -> Result<usize, Error> {
+        id += 1;
+        x["id"] = json!(id);
+        writeln!(out, "{}", x)?;
+        Ok(id)
+    };
+
+    emit(
+        out,
+        json!({
+            "type": "vertex",
+            "label": "metaData",
+            "version": LSIF_VERSION,
+            "projectRoot": file_uri(root),
+            "positionEncoding": "utf-16",
+            "toolInfo": {"name": "ptags", "version": env!("CARGO_PKG_VERSION")},
+        }),
+    )?;
+    let project = emit(
+        out,
+        json!({"type": "vertex", "label": "project", "kind": "ctags"}),
+    )?;
+
+    let mut count = 0;
+    let mut documents = Vec::new();
+    for (path, tags) in &docs {
+        let uri = file_uri(&root.join(path));
+        let mut doc = json!({"type": "vertex", "label": "document", "uri": uri});
+        if let Some(language) = tags.iter().find_map(|x| x.field("language")) {
+            doc["languageId"] = json!(language.to_lowercase());
+        }
+        let doc = emit(out, doc)?;
+        documents.push(doc);
+
+        let mut ranges = Vec::new();
+        for tag in tags {
+            let line = match tag.line() {
+                Some(x) if x > 0 => x - 1,
+                _ => continue,
+            };
+            let col = column(tag);
+            let end = col + tag.name.encode_utf16().count();
+            let range = emit(
+                out,
+                json!({
+                    "type": "vertex",
+                    "label": "range",
+                    "start": {"line": line, "character": col},
+                    "end": {"line": line, "character": end},
+                }),
+            )?;
+            let result_set = emit(out, json!({"type": "vertex", "label": "resultSet"}))?;
+            emit(
+                out,
+                json!({"type": "edge", "label": "next", "outV": range, "inV": result_set}),
+            )?;
+            let def = emit(out, json!({"type": "vertex", "label": "definitionResult"}))?;
+            emit(
+                out,
+                json!({"type": "edge", "label": "textDocument/definition", "outV": result_set, "inV": def}),
+            )?;
+            emit(
+                out,
+                json!({"type": "edge", "label": "item", "outV": def, "inVs": [range], "document": doc}),
+            )?;
+            ranges.push(range);
+            count += 1;
+        }
+        if !ranges.is_empty() {
+            emit(
+                out,
+                json!({"type": "edge", "label": "contains", "outV": doc, "inVs": ranges}),
+            )?;
+        }
+    }
+    if !documents.is_empty() {
+        emit(
+            out,
+            json!({"type": "edge", "label": "contains", "outV": project, "inVs": documents}),
+        )?;
+    }
+    Ok(count)
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Run
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Exports the tags file into the database or the index.
+pub fn run(opt: &Opt, sqlite: Option<&Path>, fts: bool, lsif: Option<&Path>) -> Result<(), Error> {
+    if sqlite.is_none() && lsif.is_none() {
+        bail!(ExportError::NoOutput);
+    }
+    let tags = TagsFile::open(opt, &opt.output)?;
+    if let Some(sqlite) = sqlite {
+        let count = write_sqlite(sqlite, tags.tags(), fts)?;
+        if opt.verbose {
+            eprintln!("Exported tags to SQLite: {}", count);
+        }
+    }
+    if let Some(lsif) = lsif {
+        let root = opt.dir.canonicalize()?;
+        let file = File::create(lsif).context(format!("failed to create file ({:?})", lsif))?;
+        let mut out = BufWriter::new(file);
+        let count = write_lsif(&mut out, &root, tags.tags())?;
+        out.flush()?;
+        if opt.verbose {
+            eprintln!("Exported tags to LSIF: {}", count);
+        }
+    }
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{file_uri, write_lsif, write_sqlite};
+    use crate::find::Tag;
+    use rusqlite::Connection;
+    use serde_json::Value;
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_write_sqlite() {
+        let lines = [
+            "Foo\ta.rs\t/^struct Foo;$/;\"\ts\tline:1\tlanguage:Rust",
+            "new\ta.rs\t/^    fn new() {}$/;\"\tf\tline:3\tlanguage:Rust\timplementation:Foo",
+            "main\tb.py\t5;\"\tf\tlanguage:Python",
+        ];
+        let tags = lines.iter().filter_map(|x| Tag::parse(x.as_bytes()));
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("tags.db");
+        assert_eq!(write_sqlite(&path, tags, true).unwrap(), 3);
+
+        let conn = Connection::open(&path).unwrap();
+        let files: i64 = conn
+            .query_row("SELECT COUNT(*) FROM files", [], |x| x.get(0))
+            .unwrap();
+        assert_eq!(files, 2);
+
+        let (path, kind, line, scope): (String, String, i64, String) = conn
+            .query_row(
+                "SELECT f.path, s.kind, s.line, s.scope FROM symbols s \
+                 JOIN files f ON s.file_id = f.id WHERE s.name = 'new'",
+                [],
+                |x| Ok((x.get(0)?, x.get(1)?, x.get(2)?, x.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            (path.as_str(), kind.as_str(), line, scope.as_str()),
+            ("a.rs", "f", 3, "Foo")
+        );
+
+        let (line, language): (i64, String) = conn
+            .query_row(
+                "SELECT line, language FROM symbols WHERE name = 'main'",
+                [],
+                |x| Ok((x.get(0)?, x.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((line, language.as_str()), (5, "Python"));
+
+        let name: String = conn
+            .query_row(
+                "SELECT name FROM symbols_fts WHERE name LIKE '%ai%'",
+                [],
+                |x| x.get(0),
+            )
+            .unwrap();
+        assert_eq!(name, "main");
+    }
+
+    #[test]
+    fn test_write_lsif() {
+        let lines = [
+            "new\ta b.rs\t/^    fn new() {}$/;\"\tf\tline:3\tlanguage:Rust",
+            "NONE\ta b.rs\t/^const NONE$/;\"\tC",
+        ];
+        let tags = lines.iter().filter_map(|x| Tag::parse(x.as_bytes()));
+        let mut out = Vec::new();
+        let count = write_lsif(&mut out, Path::new("/src"), tags).unwrap();
+        assert_eq!(count, 1);
+
+        let lines: Vec<Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|x| serde_json::from_str(x).unwrap())
+            .collect();
+        let label = |x: &str| lines.iter().find(|y| y["label"] == x).unwrap();
+        assert_eq!(label("metaData")["projectRoot"], "file:///src");
+        assert_eq!(label("document")["uri"], "file:///src/a%20b.rs");
+        assert_eq!(label("document")["languageId"], "rust");
+        assert_eq!(label("range")["start"]["line"], 2);
+        assert_eq!(label("range")["start"]["character"], 7);
+        assert_eq!(label("range")["end"]["character"], 10);
+        assert_eq!(label("item")["inVs"][0], label("range")["id"]);
+        assert_eq!(label("item")["document"], label("document")["id"]);
+        for (i, x) in lines.iter().enumerate() {
+            assert_eq!(x["id"], i + 1);
+        }
+        assert_eq!(file_uri(Path::new("/a#b")), "file:///a%23b");
+    }
+}
@@ -0,0 +1,358 @@
+use crate::bin::Opt;
+use anyhow::{bail, Context, Error};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use regex::RegexSet;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Error
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Error)]
+enum FilterError {
+    #[error("failed to read exclude file ({:?})\n{}", path, err)]
+    ExcludeFile { path: PathBuf, err: String },
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// FileFilter
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Filename of gitignore-style patterns loaded from `DIR` automatically.
+pub const IGNORE_FILE: &str = ".ptagsignore";
+
+/// Filter of the file list applied before distributing files to ctags.
+pub struct FileFilter {
+    ignore: Option<Gitignore>,
+    include: Option<GlobSet>,
+    include_regex: Option<RegexSet>,
+    exclude_regex: Option<RegexSet>,
+}
+
+impl FileFilter {
+    pub fn new(opt: &Opt) -> Result<FileFilter, Error> {
+        let mut files: Vec<PathBuf> = opt.exclude_from.clone();
+        let ignore_file = opt.dir.join(IGNORE_FILE);
+        if ignore_file.is_file() {
+            files.push(ignore_file);
+        }
+
+        let ignore = if files.is_empty() {
+            None
+        } else {
+            let mut builder = GitignoreBuilder::new(&opt.dir);
+            for path in &files {
+                if let Some(err) = builder.add(path) {
+                    bail!(FilterError::ExcludeFile {
+                        path: path.clone(),
+                        err: err.to_string(),
+                    });
+                }
+            }
+            Some(builder.build()?)
+        };
+
+        let include = if opt.include.is_empty() {
+            None
+        } else {
+            let mut builder = GlobSetBuilder::new();
+            for glob in &opt.include {
+                builder
+                    .add(Glob::new(glob).context(format!("failed to parse --include ({})", glob))?);
+            }
+            Some(builder.build()?)
+        };
+
+        let include_regex = regex_set(&opt.include_regex, "--include-regex")?;
+        let exclude_regex = regex_set(&opt.exclude_regex, "--exclude-regex")?;
+
+        Ok(FileFilter {
+            ignore,
+            include,
+            include_regex,
+            exclude_regex,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ignore.is_none()
+            && self.include.is_none()
+            && self.include_regex.is_none()
+            && self.exclude_regex.is_none()
+    }
+
+    /// Returns true if the file should be tagged.
+    pub fn matches(&self, file: &str) -> bool {
+        if let Some(ref include) = self.include {
+            if !include.is_match(file) {
+                return false;
+            }
+        }
+        if let Some(ref include) = self.include_regex {
+            if !include.is_match(file) {
+                return false;
+            }
+        }
+        if let Some(ref exclude) = self.exclude_regex {
+            if exclude.is_match(file) {
+                return false;
+            }
+        }
+        if let Some(ref ignore) = self.ignore {
+            if ignore
+                .matched_path_or_any_parents(Path::new(file), false)
+                .is_ignore()
+            {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub fn apply(&self, opt: &Opt, list: Vec<String>) -> Vec<String> {
+        if self.is_empty() {
+            return list;
+        }
+        let len = list.len();
+        let ret: Vec<String> = list.into_iter().filter(|x| self.matches(x)).collect();
+        if opt.verbose {
+            eprintln!("Filtered files: {}", len - ret.len());
+        }
+        ret
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Minified
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Suffixes of minified or bundled files.
+const MINIFIED_SUFFIXES: [&str; 4] = [".min.js", ".min.css", ".bundle.js", ".bundle.css"];
+
+/// Bytes read from the head of the file to measure the line length.
+const MINIFIED_SAMPLE: u64 = 64 * 1024;
+
+/// Files whose average line length in the sample is over this are regarded as minified.
+const MINIFIED_LINE_LENGTH: usize = 500;
+
+/// Returns true if the file looks minified by the name or the average line length.
+pub fn is_minified(dir: &Path, file: &str) -> bool {
+    if MINIFIED_SUFFIXES.iter().any(|x| file.ends_with(x)) {
+        return true;
+    }
+    let mut buf = Vec::new();
+    match File::open(dir.join(file)) {
+        Ok(f) => {
+            if f.take(MINIFIED_SAMPLE).read_to_end(&mut buf).is_err() {
+                return false;
+            }
+        }
+        Err(_) => return false,
+    }
+    let lines = buf.iter().filter(|x| **x == b'\n').count() + 1;
+    buf.len() / lines > MINIFIED_LINE_LENGTH
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Symlink
+// ---------------------------------------------------------------------------------------------------------------------
+
+pub fn is_symlink(dir: &Path, file: &str) -> bool {
+    fs::symlink_metadata(dir.join(file))
+        .map(|x| x.file_type().is_symlink())
+        .unwrap_or(false)
+}
+
+/// Resolves symbolic links in the list.
+///
+/// Dangling links and links to files already in the list are removed,
+/// and links to directories are expanded to files in them.
+pub fn follow_symlinks(dir: &Path, list: Vec<String>) -> Vec<String> {
+    let mut seen: HashSet<PathBuf> = list
+        .iter()
+        .filter(|x| !is_symlink(dir, x))
+        .filter_map(|x| dir.join(x).canonicalize().ok())
+        .collect();
+
+    let mut ret = Vec::new();
+    for f in list {
+        if !is_symlink(dir, &f) {
+            ret.push(f);
+            continue;
+        }
+        let target = match dir.join(&f).canonicalize() {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        if target.is_dir() {
+            let mut visited = HashSet::new();
+            walk_dir(&target, &f, &mut visited, &mut seen, &mut ret);
+        } else if seen.insert(target) {
+            ret.push(f);
+        }
+    }
+    ret
+}
+
+/// Lists files in the directory following links. A directory visited already is skipped to avoid loops.
+fn walk_dir(
+    path: &Path,
+    name: &str,
+    visited: &mut HashSet<PathBuf>,
+    seen: &mut HashSet<PathBuf>,
+    ret: &mut Vec<String>,
+) {
+    if !visited.insert(path.to_path_buf()) {
+        return;
+    }
+    let mut entries = match fs::read_dir(path) {
+        Ok(x) => x.filter_map(|x| x.ok()).collect::<Vec<_>>(),
+        Err(_) => return,
+    };
+    entries.sort_by_key(|x| x.file_name());
+    for entry in entries {
+        let child = format!("{}/{}", name, entry.file_name().to_string_lossy());
+        let target = match entry.path().canonicalize() {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        if target.is_dir() {
+            walk_dir(&target, &child, visited, seen, ret);
+        } else if seen.insert(target) {
+            ret.push(child);
+        }
+    }
+}
+
+fn regex_set(patterns: &[String], option: &str) -> Result<Option<RegexSet>, Error> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let ret = RegexSet::new(patterns).context(format!("failed to parse {}", option))?;
+    Ok(Some(ret))
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{follow_symlinks, is_minified, is_symlink, FileFilter, IGNORE_FILE};
+    use crate::bin::Opt;
+    use std::fs;
+    use structopt::StructOpt;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_exclude_from() {
+        let dir = TempDir::new().unwrap();
+        let exclude = dir.path().join("exclude.txt");
+        fs::write(
+            &exclude,
+            "# comment\nthird_party/\n*.min.js\n!keep.min.js\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join(IGNORE_FILE), "/gen\n").unwrap();
+
+        let args = vec![
+            "ptags",
+            "--exclude-from",
+            exclude.to_str().unwrap(),
+            dir.path().to_str().unwrap(),
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let filter = FileFilter::new(&opt).unwrap();
+        let list = vec![
+            "src/a.rs",
+            "third_party/b/c.rs",
+            "web/d.min.js",
+            "web/keep.min.js",
+            "gen/e.rs",
+            "src/gen/f.rs",
+        ];
+        let list = list.into_iter().map(String::from).collect();
+        assert_eq!(
+            filter.apply(&opt, list),
+            vec!["src/a.rs", "web/keep.min.js", "src/gen/f.rs"]
+        );
+
+        let args = vec!["ptags", "--exclude-from", "not_found.txt"];
+        let opt = Opt::from_iter(args.iter());
+        assert!(FileFilter::new(&opt).is_err());
+    }
+
+    #[test]
+    fn test_include() {
+        let args = vec!["ptags", "--include", "*.py", "--include", "*.pyx"];
+        let opt = Opt::from_iter(args.iter());
+        let filter = FileFilter::new(&opt).unwrap();
+        assert!(filter.matches("a.py"));
+        assert!(filter.matches("src/b.pyx"));
+        assert!(!filter.matches("src/c.rs"));
+
+        let args = vec!["ptags", "--include", "src/**/*.rs", "--include", "a[.rs"];
+        let opt = Opt::from_iter(args.iter());
+        assert!(FileFilter::new(&opt).is_err());
+    }
+
+    #[test]
+    fn test_is_minified() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("a.js"), "var a = 1;\n".repeat(100)).unwrap();
+        fs::write(dir.path().join("b.js"), "var b=1;".repeat(1000)).unwrap();
+        assert!(!is_minified(dir.path(), "a.js"));
+        assert!(is_minified(dir.path(), "b.js"));
+        assert!(is_minified(dir.path(), "c.min.js"));
+        assert!(!is_minified(dir.path(), "not_found.js"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_follow_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path();
+        fs::create_dir_all(path.join("vendor/lib")).unwrap();
+        fs::write(path.join("a.rs"), "").unwrap();
+        fs::write(path.join("vendor/lib/b.rs"), "").unwrap();
+        symlink("a.rs", path.join("c.rs")).unwrap();
+        symlink("not_found.rs", path.join("d.rs")).unwrap();
+        symlink("vendor", path.join("e")).unwrap();
+        symlink("..", path.join("vendor/lib/loop")).unwrap();
+
+        let list = vec!["a.rs", "c.rs", "d.rs", "e"];
+        let list: Vec<String> = list.into_iter().map(String::from).collect();
+        assert!(is_symlink(path, "c.rs"));
+        assert!(!is_symlink(path, "a.rs"));
+        assert_eq!(follow_symlinks(path, list), vec!["a.rs", "e/lib/b.rs"]);
+    }
+
+    #[test]
+    fn test_regex() {
+        let args = vec![
+            "ptags",
+            "--include-regex",
+            r"\.(c|h)$",
+            "--exclude-regex",
+            r"(^|/)gen/v[0-9]+/",
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let filter = FileFilter::new(&opt).unwrap();
+        assert!(filter.matches("src/a.c"));
+        assert!(filter.matches("gen/vx/b.h"));
+        assert!(!filter.matches("gen/v2/b.h"));
+        assert!(!filter.matches("src/gen/v10/c.c"));
+        assert!(!filter.matches("src/d.rs"));
+
+        let args = vec!["ptags", "--exclude-regex", "("];
+        let opt = Opt::from_iter(args.iter());
+        assert!(FileFilter::new(&opt).is_err());
+    }
+}
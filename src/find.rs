@@ -0,0 +1,388 @@
+use crate::bin::{tag_name, Opt};
+use anyhow::{Context, Error};
+use serde_json::{json, Map, Value};
+use std::fs;
+use std::io::{stdout, BufWriter, Write};
+use std::path::Path;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Query
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Default)]
+pub struct Query {
+    pub name: String,
+    pub prefix: bool,
+    pub icase: bool,
+    pub kind: Option<String>,
+}
+
+impl Query {
+    fn matches_name(&self, name: &[u8]) -> bool {
+        let key = self.name.as_bytes();
+        match (self.prefix, self.icase) {
+            (false, false) => name == key,
+            (true, false) => name.starts_with(key),
+            (false, true) => name.eq_ignore_ascii_case(key),
+            (true, true) => name.len() >= key.len() && name[..key.len()].eq_ignore_ascii_case(key),
+        }
+    }
+
+    fn matches_kind(&self, tag: &Tag) -> bool {
+        match self.kind {
+            Some(ref kind) => tag.kind.as_deref() == Some(kind.as_str()),
+            None => true,
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Tag
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Fields of a tag line in ctags format.
+#[derive(Debug, PartialEq)]
+pub struct Tag {
+    pub name: String,
+    pub path: String,
+    pub pattern: String,
+    pub kind: Option<String>,
+    pub fields: Vec<(String, String)>,
+}
+
+impl Tag {
+    /// Parses `<name> TAB <path> TAB <pattern>;" TAB <fields>...`, or returns `None` for pseudo-tags.
+    pub fn parse(line: &[u8]) -> Option<Tag> {
+        if line.starts_with(b"!_") {
+            return None;
+        }
+        let line = String::from_utf8_lossy(line);
+        let mut iter = line.splitn(3, '\t');
+        let name = String::from(iter.next()?);
+        let path = String::from(iter.next()?);
+        let rest = iter.next().unwrap_or("");
+        let (pattern, fields) = match rest.find(";\"\t") {
+            Some(x) => (&rest[..x], &rest[x + 3..]),
+            None => (rest.trim_end_matches(";\""), ""),
+        };
+
+        let mut kind = None;
+        let mut ret = Vec::new();
+        for field in fields.split('\t').filter(|x| !x.is_empty()) {
+            match field.split_once(':') {
+                Some(("kind", value)) => kind = Some(String::from(value)),
+                Some((key, value)) => ret.push((String::from(key), String::from(value))),
+                None => kind = Some(String::from(field)),
+            }
+        }
+
+        Some(Tag {
+            name,
+            path,
+            pattern: String::from(pattern),
+            kind,
+            fields: ret,
+        })
+    }
+
+    fn from_json(line: &[u8]) -> Option<Tag> {
+        let tag: Value = serde_json::from_slice(line).ok()?;
+        if tag["_type"] != "tag" {
+            return None;
+        }
+        let mut fields = Vec::new();
+        if let Some(map) = tag.as_object() {
+            for (key, value) in map {
+                if ["_type", "name", "path", "pattern", "kind"].contains(&key.as_str()) {
+                    continue;
+                }
+                match value {
+                    Value::String(x) => fields.push((key.clone(), x.clone())),
+                    Value::Number(x) => fields.push((key.clone(), x.to_string())),
+                    Value::Bool(x) => fields.push((key.clone(), x.to_string())),
+                    _ => (),
+                }
+            }
+        }
+        Some(Tag {
+            name: String::from(tag["name"].as_str()?),
+            path: String::from(tag["path"].as_str().unwrap_or("")),
+            pattern: String::from(tag["pattern"].as_str().unwrap_or("")),
+            kind: tag["kind"].as_str().map(String::from),
+            fields,
+        })
+    }
+
+    pub fn field(&self, key: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|x| x.0 == key)
+            .map(|x| x.1.as_str())
+    }
+
+    /// Returns the line number from `line:` field, or the pattern if it is a line number by `--excmd=number`.
+    pub fn line(&self) -> Option<i64> {
+        self.field("line")
+            .or(Some(self.pattern.as_str()))
+            .and_then(|x| x.parse().ok())
+    }
+
+    /// Returns the scope like `Foo` from fields like `class:Foo` or `scope:class:Foo`.
+    pub fn scope(&self) -> Option<&str> {
+        if let Some(x) = self.field("scope") {
+            return Some(x.split_once(':').map(|x| x.1).unwrap_or(x));
+        }
+        [
+            "class",
+            "struct",
+            "enum",
+            "union",
+            "namespace",
+            "module",
+            "interface",
+            "implementation",
+            "function",
+        ]
+        .iter()
+        .find_map(|x| self.field(x))
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut ret = Map::new();
+        ret.insert(String::from("name"), json!(self.name));
+        ret.insert(String::from("path"), json!(self.path));
+        ret.insert(String::from("pattern"), json!(self.pattern));
+        if let Some(ref kind) = self.kind {
+            ret.insert(String::from("kind"), json!(kind));
+        }
+        for (key, value) in &self.fields {
+            ret.insert(key.clone(), json!(value));
+        }
+        Value::Object(ret)
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// TagsFile
+// ---------------------------------------------------------------------------------------------------------------------
+
+pub struct TagsFile {
+    data: Vec<u8>,
+    json: bool,
+    sorted: bool,
+}
+
+impl TagsFile {
+    pub fn open(opt: &Opt, path: &Path) -> Result<TagsFile, Error> {
+        let data = fs::read(path).context(format!("failed to read file ({:?})", path))?;
+        let json = opt.format == "json";
+        // Binary search is available only if the file is sorted case-sensitively like ptags output.
+        let sorted = !json
+            && data
+                .split(|x| *x == b'\n')
+                .take_while(|x| x.starts_with(b"!_"))
+                .any(|x| x.starts_with(b"!_TAG_FILE_SORTED\t1\t"));
+        Ok(TagsFile { data, json, sorted })
+    }
+
+    /// Returns tag lines matched with the query.
+    pub fn find(&self, opt: &Opt, query: &Query) -> Vec<&[u8]> {
+        let lines: Box<dyn Iterator<Item = &[u8]>> = if self.sorted && !query.icase {
+            let beg = self.lower_bound(query.name.as_bytes());
+            Box::new(
+                self.data[beg..]
+                    .split(|x| *x == b'\n')
+                    .take_while(|x| query.matches_name(name(x))),
+            )
+        } else {
+            Box::new(self.data.split(|x| *x == b'\n').filter(|x| {
+                tag_name(opt, x)
+                    .map(|x| query.matches_name(x.as_bytes()))
+                    .unwrap_or(false)
+            }))
+        };
+
+        lines
+            .filter(|x| !x.is_empty())
+            .filter(|x| {
+                self.parse(x)
+                    .map(|x| query.matches_kind(&x))
+                    .unwrap_or(false)
+            })
+            .collect()
+    }
+
+    /// Returns all tags except pseudo-tags.
+    pub fn tags(&self) -> impl Iterator<Item = Tag> + '_ {
+        self.data
+            .split(|x| *x == b'\n')
+            .filter(|x| !x.is_empty())
+            .filter_map(move |x| self.parse(x))
+    }
+
+    pub fn parse(&self, line: &[u8]) -> Option<Tag> {
+        if self.json {
+            Tag::from_json(line)
+        } else {
+            Tag::parse(line)
+        }
+    }
+
+    /// Returns the offset of the first line whose name is not less than the key.
+    fn lower_bound(&self, key: &[u8]) -> usize {
+        let data = &self.data;
+        let mut lo = 0;
+        let mut hi = data.len();
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let start = line_start(data, mid);
+            if start >= hi {
+                hi = mid;
+                continue;
+            }
+            let end = data[start..]
+                .iter()
+                .position(|x| *x == b'\n')
+                .map(|x| start + x + 1)
+                .unwrap_or(data.len());
+            if name(&data[start..end]) < key {
+                lo = end;
+            } else {
+                hi = start;
+            }
+        }
+        lo
+    }
+}
+
+/// Returns the first line start at or after the position.
+fn line_start(data: &[u8], pos: usize) -> usize {
+    if pos == 0 {
+        return 0;
+    }
+    data[pos - 1..]
+        .iter()
+        .position(|x| *x == b'\n')
+        .map(|x| pos + x)
+        .unwrap_or(data.len())
+}
+
+fn name(line: &[u8]) -> &[u8] {
+    line.split(|x| *x == b'\t' || *x == b'\n')
+        .next()
+        .unwrap_or(b"")
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Run
+// ---------------------------------------------------------------------------------------------------------------------
+
+pub fn run(opt: &Opt, query: &Query, json: bool) -> Result<(), Error> {
+    let tags = TagsFile::open(opt, &opt.output)?;
+    let out = stdout();
+    let mut out = BufWriter::new(out.lock());
+    for line in tags.find(opt, query) {
+        if json {
+            if let Some(tag) = tags.parse(line) {
+                writeln!(out, "{}", tag.to_json())?;
+            }
+        } else {
+            out.write_all(line)?;
+            out.write_all(b"\n")?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{Query, Tag, TagsFile};
+    use crate::bin::Opt;
+    use std::fs;
+    use structopt::StructOpt;
+    use tempfile::TempDir;
+
+    static TAGS: &str = "!_TAG_FILE_FORMAT\t2\t/extended format/\n\
+                         !_TAG_FILE_SORTED\t1\t/0=unsorted, 1=sorted, 2=foldcase/\n\
+                         Bar\tb.rs\t/^struct Bar;$/;\"\ts\tline:1\n\
+                         bar\ta.rs\t/^fn bar() {}$/;\"\tf\tline:3\n\
+                         baz\ta.rs\t/^fn baz() {}$/;\"\tf\tline:5\n\
+                         baz\tc.rs\t/^const baz: u32 = 0;$/;\"\tkind:constant\tline:1\n\
+                         foo\ta.rs\t/^fn foo() {}$/;\"\tf\tline:1\n";
+
+    fn find(tags: &TagsFile, opt: &Opt, query: &Query) -> Vec<String> {
+        tags.find(opt, query)
+            .iter()
+            .map(|x| Tag::parse(x).unwrap())
+            .map(|x| format!("{}:{}", x.name, x.path))
+            .collect()
+    }
+
+    #[test]
+    fn test_find() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("tags");
+        fs::write(&path, TAGS).unwrap();
+        let args = vec!["ptags"];
+        let opt = Opt::from_iter(args.iter());
+        let tags = TagsFile::open(&opt, &path).unwrap();
+        assert!(tags.sorted);
+
+        let query = |name: &str| Query {
+            name: String::from(name),
+            ..Default::default()
+        };
+        assert_eq!(
+            find(&tags, &opt, &query("baz")),
+            vec!["baz:a.rs", "baz:c.rs"]
+        );
+        assert_eq!(find(&tags, &opt, &query("foo")), vec!["foo:a.rs"]);
+        assert_eq!(find(&tags, &opt, &query("Bar")), vec!["Bar:b.rs"]);
+        assert!(find(&tags, &opt, &query("ba")).is_empty());
+        assert!(find(&tags, &opt, &query("zzz")).is_empty());
+
+        let q = Query {
+            prefix: true,
+            ..query("ba")
+        };
+        assert_eq!(
+            find(&tags, &opt, &q),
+            vec!["bar:a.rs", "baz:a.rs", "baz:c.rs"]
+        );
+
+        let q = Query {
+            icase: true,
+            ..query("bar")
+        };
+        assert_eq!(find(&tags, &opt, &q), vec!["Bar:b.rs", "bar:a.rs"]);
+
+        let q = Query {
+            kind: Some(String::from("constant")),
+            ..query("baz")
+        };
+        assert_eq!(find(&tags, &opt, &q), vec!["baz:c.rs"]);
+    }
+
+    #[test]
+    fn test_parse() {
+        let tag = Tag::parse(b"baz\tc.rs\t/^const baz$/;\"\tkind:constant\tline:1").unwrap();
+        assert_eq!(tag.kind.as_deref(), Some("constant"));
+        assert_eq!(
+            tag.to_json().to_string(),
+            r#"{"kind":"constant","line":"1","name":"baz","path":"c.rs","pattern":"/^const baz$/"}"#
+        );
+        assert_eq!(Tag::parse(b"!_TAG_FILE_SORTED\t1\t//"), None);
+
+        let tag = Tag::from_json(
+            br#"{"_type":"tag","name":"baz","path":"c.rs","pattern":"/^const baz$/","line":1,"kind":"constant"}"#,
+        )
+        .unwrap();
+        assert_eq!(tag.kind.as_deref(), Some("constant"));
+        assert_eq!(tag.fields, vec![(String::from("line"), String::from("1"))]);
+    }
+}
@@ -0,0 +1,169 @@
+use crate::bin::{vcs_list, Opt};
+use crate::cmd_ctags::CmdCtags;
+use crate::filter::FileFilter;
+use anyhow::{Context, Error};
+use globset::{Glob, GlobSetBuilder};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::io::{stdout, BufWriter, Write};
+use std::path::Path;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Coverage
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Key of skipped files without extension.
+const NO_EXTENSION: &str = "(no extension)";
+
+/// Numbers of files parsed by each language, and skipped by ctags.
+#[derive(Debug, Default, PartialEq)]
+pub struct Coverage {
+    pub parsed: BTreeMap<String, usize>,
+    /// Skipped files by extension like `.md`
+    pub skipped: BTreeMap<String, usize>,
+}
+
+/// Maps files to languages by basename with patterns of `ctags --list-maps`.
+///
+/// A file matched by patterns of multiple languages is counted as the first language in the list.
+/// ctags may parse files skipped here by other heuristics like modelines or shebangs.
+pub fn coverage(maps: &[(String, Vec<String>)], files: &[String]) -> Result<Coverage, Error> {
+    let mut builder = GlobSetBuilder::new();
+    let mut languages = Vec::new();
+    for (lang, patterns) in maps {
+        for pattern in patterns {
+            // Patterns which globset can't parse are ignored because they are only for the report.
+            if let Ok(x) = Glob::new(pattern) {
+                builder.add(x);
+                languages.push(lang);
+            }
+        }
+    }
+    let set = builder
+        .build()
+        .context("failed to build patterns of languages")?;
+
+    let mut ret = Coverage::default();
+    for file in files {
+        let name = Path::new(file)
+            .file_name()
+            .map(|x| x.to_string_lossy())
+            .unwrap_or_default();
+        match set.matches(name.as_ref()).first() {
+            Some(x) => *ret.parsed.entry(languages[*x].clone()).or_default() += 1,
+            None => {
+                let key = match Path::new(name.as_ref()).extension() {
+                    Some(x) => format!(".{}", x.to_string_lossy()),
+                    None => String::from(NO_EXTENSION),
+                };
+                *ret.skipped.entry(key).or_default() += 1;
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// Returns entries in descending order of the number of files.
+fn by_count(map: &BTreeMap<String, usize>) -> Vec<(&String, &usize)> {
+    let mut ret: Vec<_> = map.iter().collect();
+    ret.sort_by_key(|x| std::cmp::Reverse(*x.1));
+    ret
+}
+
+fn write_text(out: &mut dyn Write, coverage: &Coverage) -> Result<(), Error> {
+    writeln!(out, "{:<20} {:>8}", "Language", "Files")?;
+    for (lang, count) in by_count(&coverage.parsed) {
+        writeln!(out, "{:<20} {:>8}", lang, count)?;
+    }
+    writeln!(out)?;
+    writeln!(out, "{:<20} {:>8}", "Skipped", "Files")?;
+    for (ext, count) in by_count(&coverage.skipped) {
+        writeln!(out, "{:<20} {:>8}", ext, count)?;
+    }
+    writeln!(out)?;
+
+    let parsed: usize = coverage.parsed.values().sum();
+    let skipped: usize = coverage.skipped.values().sum();
+    writeln!(
+        out,
+        "{} files: {} parsed, {} skipped",
+        parsed + skipped,
+        parsed,
+        skipped
+    )?;
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Run
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Prints how many files of `DIR` are parsed by each language, and skipped by ctags.
+///
+/// With `all`, languages which no file is parsed by are printed too.
+pub fn run(opt: &Opt, all: bool, format: &str) -> Result<(), Error> {
+    let list = vcs_list(opt).context("failed to get file list")?;
+    let list = FileFilter::new(opt)?.apply(opt, list);
+    let maps = CmdCtags::list_maps(opt).context("failed to get language maps")?;
+    let mut coverage = coverage(&maps, &list)?;
+    if all {
+        for lang in CmdCtags::list_languages(opt)? {
+            coverage.parsed.entry(lang).or_default();
+        }
+    }
+
+    let out = stdout();
+    let mut out = BufWriter::new(out.lock());
+    if format == "json" {
+        let json = json!({"parsed": coverage.parsed, "skipped": coverage.skipped});
+        writeln!(out, "{}", json)?;
+    } else {
+        write_text(&mut out, &coverage)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{coverage, write_text};
+
+    #[test]
+    fn test_coverage() {
+        let maps = vec![
+            (
+                String::from("C"),
+                vec![String::from("*.c"), String::from("*.h")],
+            ),
+            (String::from("C++"), vec![String::from("*.h")]),
+            (String::from("Make"), vec![String::from("[Mm]akefile")]),
+        ];
+        let files: Vec<String> = [
+            "src/a.c",
+            "src/a.h",
+            "Makefile",
+            "README.md",
+            "doc/b.md",
+            "LICENSE",
+        ]
+        .iter()
+        .map(|x| String::from(*x))
+        .collect();
+        let coverage = coverage(&maps, &files).unwrap();
+        assert_eq!(coverage.parsed["C"], 2);
+        assert_eq!(coverage.parsed["Make"], 1);
+        assert!(!coverage.parsed.contains_key("C++"));
+        assert_eq!(coverage.skipped[".md"], 2);
+        assert_eq!(coverage.skipped["(no extension)"], 1);
+
+        let mut out = Vec::new();
+        write_text(&mut out, &coverage).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("Language                Files\nC                           2\n"));
+        assert!(out.ends_with("6 files: 3 parsed, 3 skipped\n"));
+    }
+}
@@ -1,3 +1,32 @@
+pub mod api;
+pub mod audit;
 pub mod bin;
+pub mod cache;
+pub mod check;
+pub mod cmd_cscope;
 pub mod cmd_ctags;
 pub mod cmd_git;
+pub mod cmd_gtags;
+pub mod cmd_hg;
+pub mod cmd_watchman;
+pub mod diff;
+pub mod doctor;
+pub mod export;
+pub mod filter;
+pub mod find;
+pub mod languages;
+pub mod lock;
+pub mod log;
+pub mod lsp;
+pub mod merge;
+pub mod outline;
+pub mod pick;
+pub mod pipeline;
+pub mod regenerate;
+pub mod sandbox;
+pub mod serve;
+pub mod signal;
+pub mod state;
+pub mod trace;
+pub mod walk;
+pub mod watch;
@@ -0,0 +1,145 @@
+use crate::bin::Opt;
+use anyhow::{bail, Context, Error};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Error
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Error)]
+enum LockError {
+    #[error("tags file is locked by another ptags ({:?})", path)]
+    Locked { path: PathBuf },
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// TagsLock
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Returns the lock filename of the output like `tags.lock`.
+pub fn lock_path(output: &Path) -> PathBuf {
+    let mut path = OsString::from(output.as_os_str());
+    path.push(".lock");
+    PathBuf::from(path)
+}
+
+/// Advisory lock of the output held while generating tags.
+///
+/// The lock file is removed when the lock is released.
+/// A process waiting for the lock may get the lock of the removed file,
+/// so the lock is taken again if the file is not the same as the path.
+pub struct TagsLock {
+    path: PathBuf,
+    #[cfg(unix)]
+    _lock: nix::fcntl::Flock<std::fs::File>,
+}
+
+impl TagsLock {
+    /// Locks the output, or returns `None` if the output is not a file or not written by `--dry-run`.
+    ///
+    /// If another process has the lock, this waits for it unless `--no-wait`.
+    pub fn acquire(opt: &Opt) -> Result<Option<TagsLock>, Error> {
+        if opt.output.to_str().unwrap_or("") == "-" || opt.output_cmd.is_some() || opt.dry_run {
+            return Ok(None);
+        }
+        let path = lock_path(&opt.output);
+        let wait = opt.wait || !opt.no_wait;
+        TagsLock::lock(opt, path, wait).map(Some)
+    }
+
+    #[cfg(unix)]
+    fn lock(opt: &Opt, path: PathBuf, wait: bool) -> Result<TagsLock, Error> {
+        use nix::errno::Errno;
+        use nix::fcntl::{Flock, FlockArg};
+        use std::fs::{self, OpenOptions};
+        use std::os::unix::fs::MetadataExt;
+
+        loop {
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)
+                .context(format!("failed to open file ({:?})", path))?;
+            let lock = match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+                Ok(x) => x,
+                Err((file, Errno::EWOULDBLOCK)) => {
+                    if !wait {
+                        bail!(LockError::Locked { path });
+                    }
+                    if opt.verbose {
+                        eprintln!("Wait for lock: {:?}", path);
+                    }
+                    Flock::lock(file, FlockArg::LockExclusive)
+                        .map_err(|(_, x)| x)
+                        .context(format!("failed to lock file ({:?})", path))?
+                }
+                Err((_, x)) => {
+                    return Err(x).context(format!("failed to lock file ({:?})", path));
+                }
+            };
+
+            let locked = lock.metadata()?;
+            match fs::metadata(&path) {
+                Ok(x) if x.dev() == locked.dev() && x.ino() == locked.ino() => {
+                    return Ok(TagsLock { path, _lock: lock });
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn lock(_opt: &Opt, path: PathBuf, _wait: bool) -> Result<TagsLock, Error> {
+        Ok(TagsLock { path })
+    }
+}
+
+impl Drop for TagsLock {
+    /// The file is removed before the lock is released by dropping the fields.
+    fn drop(&mut self) {
+        if cfg!(unix) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{lock_path, TagsLock};
+    use crate::bin::Opt;
+    use std::path::Path;
+    use structopt::StructOpt;
+    use tempfile::TempDir;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_lock() {
+        let dir = TempDir::new().unwrap();
+        let output = dir.path().join("tags");
+        let output = output.to_str().unwrap();
+        assert_eq!(lock_path(Path::new("tags")), Path::new("tags.lock"));
+
+        let opt = Opt::from_iter(vec!["ptags", "-f", output, "--no-wait"].iter());
+        let lock = TagsLock::acquire(&opt).unwrap();
+        assert!(lock.is_some());
+        assert!(lock_path(&opt.output).exists());
+        assert!(TagsLock::acquire(&opt).is_err());
+
+        let opt = Opt::from_iter(vec!["ptags", "-f", output].iter());
+        let handle = std::thread::spawn(move || TagsLock::acquire(&opt).unwrap().is_some());
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        drop(lock);
+        assert!(handle.join().unwrap());
+        assert!(!lock_path(Path::new(output)).exists());
+
+        let opt = Opt::from_iter(vec!["ptags", "-f", "-"].iter());
+        assert!(TagsLock::acquire(&opt).unwrap().is_none());
+    }
+}
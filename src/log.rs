@@ -0,0 +1,115 @@
+use crate::bin::Opt;
+use anyhow::{anyhow, Context, Error};
+use std::fs::OpenOptions;
+use std::io::{stderr, IsTerminal};
+use std::sync::Mutex;
+use tracing::level_filters::LevelFilter;
+use tracing::Subscriber;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Log
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Returns the maximum level of logs.
+///
+/// `--quiet` limits logs to errors, and `--verbose` enables debug logs unless `--log-level` is given.
+pub fn level(opt: &Opt) -> LevelFilter {
+    match opt.log_level.as_deref() {
+        Some("off") => LevelFilter::OFF,
+        Some("error") => LevelFilter::ERROR,
+        Some("warn") => LevelFilter::WARN,
+        Some("info") => LevelFilter::INFO,
+        Some("debug") => LevelFilter::DEBUG,
+        Some("trace") => LevelFilter::TRACE,
+        _ if opt.quiet => LevelFilter::ERROR,
+        _ if opt.verbose => LevelFilter::DEBUG,
+        _ => LevelFilter::WARN,
+    }
+}
+
+/// Builds the subscriber writing logs to `--log-file` or stderr in the format of `--log-format`.
+///
+/// JSON logs are written one object per line with fields flattened into the object.
+pub fn subscriber(opt: &Opt) -> Result<Box<dyn Subscriber + Send + Sync>, Error> {
+    let (writer, ansi) = match opt.log_file {
+        Some(ref path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .context(format!("failed to open file ({:?})", path))?;
+            (BoxMakeWriter::new(Mutex::new(file)), false)
+        }
+        None => (BoxMakeWriter::new(stderr), stderr().is_terminal()),
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level(opt))
+        .with_writer(writer)
+        .with_target(false);
+    if opt.log_format == "json" {
+        Ok(Box::new(builder.json().flatten_event(true).finish()))
+    } else {
+        Ok(Box::new(builder.with_ansi(ansi).finish()))
+    }
+}
+
+/// Installs the subscriber for the process.
+pub fn init(opt: &Opt) -> Result<(), Error> {
+    tracing::subscriber::set_global_default(subscriber(opt)?).map_err(|x| anyhow!(x))
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{level, subscriber};
+    use crate::bin::Opt;
+    use serde_json::Value;
+    use std::fs;
+    use structopt::StructOpt;
+    use tempfile::TempDir;
+    use tracing::level_filters::LevelFilter;
+
+    #[test]
+    fn test_level() {
+        let opt = Opt::from_iter(vec!["ptags"].iter());
+        assert_eq!(level(&opt), LevelFilter::WARN);
+        let opt = Opt::from_iter(vec!["ptags", "-v"].iter());
+        assert_eq!(level(&opt), LevelFilter::DEBUG);
+        let opt = Opt::from_iter(vec!["ptags", "-v", "--log-level", "error"].iter());
+        assert_eq!(level(&opt), LevelFilter::ERROR);
+        let opt = Opt::from_iter(vec!["ptags", "-q"].iter());
+        assert_eq!(level(&opt), LevelFilter::ERROR);
+    }
+
+    #[test]
+    fn test_json_log_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("ptags.log");
+        let args = vec![
+            "ptags",
+            "--log-level",
+            "info",
+            "--log-format",
+            "json",
+            "--log-file",
+            path.to_str().unwrap(),
+        ];
+        let opt = Opt::from_iter(args.iter());
+        tracing::subscriber::with_default(subscriber(&opt).unwrap(), || {
+            tracing::info!(chunk = 1, "chunk finished");
+            tracing::debug!("dropped");
+        });
+
+        let log = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let json: Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(json["level"], "INFO");
+        assert_eq!(json["message"], "chunk finished");
+        assert_eq!(json["chunk"], 1);
+    }
+}
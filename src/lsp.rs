@@ -0,0 +1,287 @@
+use crate::bin::{dir_watcher, Opt};
+use crate::export::{column, file_uri};
+use crate::find::Tag;
+use crate::serve::Server;
+use anyhow::{Context, Error};
+use fuzzy_matcher::skim::SkimMatcherV2;
+use fuzzy_matcher::FuzzyMatcher;
+use serde_json::{json, Value};
+use std::io::{stdin, stdout, BufRead, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Message
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Reads a JSON-RPC message framed by `Content-Length` header, or returns `None` at EOF.
+pub fn read_message(input: &mut dyn BufRead) -> Result<Option<Value>, Error> {
+    let mut len = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(x) = line.strip_prefix("Content-Length:") {
+            len = Some(x.trim().parse::<usize>()?);
+        }
+    }
+    let len = len.context("missing Content-Length header")?;
+    let mut buf = vec![0; len];
+    input.read_exact(&mut buf)?;
+    Ok(Some(serde_json::from_slice(&buf)?))
+}
+
+pub fn write_message(output: &mut dyn Write, message: &Value) -> Result<(), Error> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()?;
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Symbol
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Maximum number of symbols responded to `workspace/symbol`.
+const MAX_SYMBOLS: usize = 1000;
+
+/// Returns `SymbolKind` of LSP from the full kind name of ctags.
+fn symbol_kind(kind: Option<&str>) -> u32 {
+    match kind.unwrap_or("") {
+        "file" => 1,
+        "module" | "package" => 2,
+        "namespace" => 3,
+        "class" | "implementation" => 5,
+        "method" | "member" => 6,
+        "property" => 7,
+        "field" => 8,
+        "constructor" => 9,
+        "enum" => 10,
+        "interface" | "trait" => 11,
+        "function" | "subroutine" | "procedure" => 12,
+        "constant" | "macro" | "define" => 14,
+        "enumerator" => 22,
+        "struct" | "union" => 23,
+        "typedef" | "type" | "alias" => 26,
+        _ => 13,
+    }
+}
+
+/// Returns `SymbolInformation` of the tag, or `None` if the tag doesn't have the line number.
+pub fn symbol_information(root: &Path, tag: &Tag) -> Option<Value> {
+    let line = tag.line().filter(|x| *x > 0)? - 1;
+    let col = column(tag);
+    let end = col + tag.name.encode_utf16().count();
+    let mut ret = json!({
+        "name": tag.name,
+        "kind": symbol_kind(tag.kind.as_deref()),
+        "location": {
+            "uri": file_uri(&root.join(&tag.path)),
+            "range": {
+                "start": {"line": line, "character": col},
+                "end": {"line": line, "character": end},
+            },
+        },
+    });
+    if let Some(scope) = tag.scope() {
+        ret["containerName"] = json!(scope);
+    }
+    Some(ret)
+}
+
+/// Returns symbols matched with the query by fuzzy matching in order of the score.
+pub fn workspace_symbols<'a>(
+    root: &Path,
+    lines: impl Iterator<Item = &'a str>,
+    query: &str,
+) -> Vec<Value> {
+    let matcher = SkimMatcherV2::default();
+    let mut tags: Vec<(i64, Tag)> = lines
+        .filter_map(|x| Tag::parse(x.as_bytes()))
+        .filter_map(|x| matcher.fuzzy_match(&x.name, query).map(|s| (s, x)))
+        .collect();
+    tags.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.name.cmp(&b.1.name)));
+    tags.iter()
+        .filter_map(|x| symbol_information(root, &x.1))
+        .take(MAX_SYMBOLS)
+        .collect()
+}
+
+/// Returns the path relative to the root from `file://` URI, or `None` if it is out of the root.
+pub fn uri_to_file(root: &Path, uri: &str) -> Option<String> {
+    let path = uri.strip_prefix("file://")?;
+    let mut bytes = Vec::new();
+    let mut iter = path.bytes();
+    while let Some(c) = iter.next() {
+        if c == b'%' {
+            let hex: Vec<u8> = iter.by_ref().take(2).collect();
+            let hex = std::str::from_utf8(&hex).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            bytes.push(c);
+        }
+    }
+    let path = String::from_utf8(bytes).ok()?;
+    let path = Path::new(&path).strip_prefix(root).ok()?;
+    Some(path.to_string_lossy().replace('\\', "/"))
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Run
+// ---------------------------------------------------------------------------------------------------------------------
+
+fn handle(root: &Path, server: &Mutex<Server>, message: &Value) -> Option<Value> {
+    let id = message.get("id")?.clone();
+    let params = &message["params"];
+    let result = match message["method"].as_str().unwrap_or("") {
+        "initialize" => json!({
+            "capabilities": {
+                "workspaceSymbolProvider": true,
+                "documentSymbolProvider": true,
+            },
+            "serverInfo": {"name": "ptags", "version": env!("CARGO_PKG_VERSION")},
+        }),
+        "shutdown" => Value::Null,
+        "workspace/symbol" => {
+            let query = params["query"].as_str().unwrap_or("");
+            let server = server.lock().unwrap();
+            json!(workspace_symbols(root, server.lines(), query))
+        }
+        "textDocument/documentSymbol" => {
+            let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+            let server = server.lock().unwrap();
+            let symbols: Vec<Value> = match uri_to_file(root, uri) {
+                Some(file) => server
+                    .file_lines(&file)
+                    .filter_map(|x| Tag::parse(x.as_bytes()))
+                    .filter_map(|x| symbol_information(root, &x))
+                    .collect(),
+                None => Vec::new(),
+            };
+            json!(symbols)
+        }
+        method => {
+            return Some(json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {"code": -32601, "message": format!("method not found ({})", method)},
+            }))
+        }
+    };
+    Some(json!({"jsonrpc": "2.0", "id": id, "result": result}))
+}
+
+/// Retags changed files whenever `DIR` is changed.
+fn regenerate_loop(opt: &Opt, server: &Mutex<Server>) {
+    let watcher = match dir_watcher(opt) {
+        Ok(x) => x,
+        Err(x) => {
+            eprintln!("{}", x);
+            return;
+        }
+    };
+    while watcher.wait().is_ok() {
+        match server.lock().unwrap().regenerate() {
+            Ok(x) if opt.verbose => eprintln!("Regenerate tags: {}", x),
+            Err(x) => eprintln!("{}", x),
+            _ => (),
+        }
+    }
+}
+
+/// Runs the language server through stdin and stdout.
+///
+/// Tags need line numbers, so ctags is called in ctags format with `--fields=+nKZ` after `--opt-ctags`.
+pub fn run(opt: &Opt) -> Result<(), Error> {
+    let mut opt = opt.clone();
+    opt.format = String::from("ctags");
+    opt.opt_ctags.push(String::from("--fields=+nKZ"));
+    // The server runs until the process exits, so the option is shared with the background thread.
+    let opt: &'static Opt = Box::leak(Box::new(opt));
+    let root = opt.dir.canonicalize()?;
+
+    let mut server = Server::new(opt)?;
+    server.regenerate()?;
+    let server = Arc::new(Mutex::new(server));
+    {
+        let server = server.clone();
+        thread::spawn(move || regenerate_loop(opt, &server));
+    }
+
+    let input = stdin();
+    let mut input = input.lock();
+    let output = stdout();
+    let mut output = output.lock();
+    while let Some(message) = read_message(&mut input)? {
+        if message["method"] == "exit" {
+            break;
+        }
+        if let Some(response) = handle(&root, &server, &message) {
+            write_message(&mut output, &response)?;
+        }
+    }
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{read_message, uri_to_file, workspace_symbols, write_message};
+    use serde_json::json;
+    use std::path::Path;
+
+    #[test]
+    fn test_message() {
+        let mut buf = Vec::new();
+        write_message(&mut buf, &json!({"id": 1, "method": "shutdown"})).unwrap();
+        write_message(&mut buf, &json!({"method": "exit"})).unwrap();
+        assert!(buf.starts_with(b"Content-Length: 28\r\n\r\n{"));
+
+        let mut input = buf.as_slice();
+        let message = read_message(&mut input).unwrap().unwrap();
+        assert_eq!(message["method"], "shutdown");
+        let message = read_message(&mut input).unwrap().unwrap();
+        assert_eq!(message["method"], "exit");
+        assert!(read_message(&mut input).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_workspace_symbols() {
+        let lines = [
+            "get_files\tsrc/cmd_git.rs\t/^    pub fn get_files() {$/;\"\tkind:function\tline:10\tscope:implementation:CmdGit",
+            "get_tags_header\tsrc/cmd_ctags.rs\t/^fn get_tags_header() {$/;\"\tkind:function\tline:20",
+            "Opt\tsrc/bin.rs\t/^pub struct Opt {$/;\"\tkind:struct",
+        ];
+        let root = Path::new("/src/ptags");
+        let symbols = workspace_symbols(root, lines.iter().copied(), "gfiles");
+        assert_eq!(symbols.len(), 1);
+        assert_eq!(symbols[0]["name"], "get_files");
+        assert_eq!(symbols[0]["kind"], 12);
+        assert_eq!(symbols[0]["containerName"], "CmdGit");
+        assert_eq!(
+            symbols[0]["location"]["uri"],
+            "file:///src/ptags/src/cmd_git.rs"
+        );
+        assert_eq!(symbols[0]["location"]["range"]["start"]["line"], 9);
+        assert_eq!(symbols[0]["location"]["range"]["start"]["character"], 11);
+
+        // Tags without line numbers are skipped.
+        let symbols = workspace_symbols(root, lines.iter().copied(), "");
+        assert_eq!(symbols.len(), 2);
+
+        assert_eq!(
+            uri_to_file(root, "file:///src/ptags/src/a%20b.rs").as_deref(),
+            Some("src/a b.rs")
+        );
+        assert_eq!(uri_to_file(root, "file:///tmp/a.rs"), None);
+    }
+}
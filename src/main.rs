@@ -1,4 +1,4 @@
-use ptagslib::bin::run;
+use ptagslib::bin::{exit_code, run};
 
 // ---------------------------------------------------------------------------------------------------------------------
 // Main
@@ -11,6 +11,7 @@ fn main() {
             for x in x.chain() {
                 println!("{}", x);
             }
+            std::process::exit(exit_code(&x));
         }
         _ => (),
     }
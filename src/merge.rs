@@ -0,0 +1,166 @@
+use crate::bin::{tag_name, write_tags, Opt};
+use anyhow::{Context, Error};
+use std::collections::HashSet;
+use std::fs;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Merge
+// ---------------------------------------------------------------------------------------------------------------------
+
+const TAG_FILE_SORTED: &str = "!_TAG_FILE_SORTED";
+const TAG_FILE_UNSORTED: &str = "!_TAG_FILE_SORTED\t0\t/0=unsorted, 1=sorted, 2=foldcase/";
+
+struct TagsData {
+    header: Vec<String>,
+    body: Vec<u8>,
+    sorted: bool,
+}
+
+fn read(opt: &Opt, path: &Path) -> Result<TagsData, Error> {
+    let data = fs::read(path).context(format!("failed to read file ({:?})", path))?;
+    let mut header = Vec::new();
+    let mut pos = 0;
+    for line in data.split(|x| *x == b'\n') {
+        if line.is_empty() || tag_name(opt, line).is_some() {
+            break;
+        }
+        header.push(String::from_utf8_lossy(line).into_owned());
+        pos += line.len() + 1;
+    }
+    let sorted = opt.format != "json"
+        && header
+            .iter()
+            .any(|x| x.starts_with(&format!("{}\t1\t", TAG_FILE_SORTED)));
+    Ok(TagsData {
+        header,
+        body: data[pos.min(data.len())..].to_vec(),
+        sorted,
+    })
+}
+
+/// Returns the key to dedupe pseudo-tags.
+///
+/// Pseudo-tags about the file, the program and ptags appear once, and others like kind descriptions appear per value.
+fn header_key(line: &str) -> String {
+    let mut fields = line.splitn(3, '\t');
+    let name = fields.next().unwrap_or("");
+    let value = fields.next().unwrap_or("");
+    if [
+        "!_TAG_FILE_",
+        "!_TAG_PROGRAM_",
+        "!_TAG_OUTPUT_",
+        "!_TAG_PTAGS_",
+    ]
+    .iter()
+    .any(|x| name.starts_with(x))
+    {
+        String::from(name)
+    } else {
+        format!("{}\t{}", name, value)
+    }
+}
+
+/// Merges pseudo-tags of the files in order. `!_TAG_FILE_SORTED` is kept only if all files are sorted.
+fn merge_header(tags: &[TagsData], sorted: bool) -> String {
+    let mut keys = HashSet::new();
+    let mut ret = String::new();
+    for line in tags.iter().flat_map(|x| x.header.iter()) {
+        if !keys.insert(header_key(line)) {
+            continue;
+        }
+        if line.starts_with(TAG_FILE_SORTED) && !sorted {
+            ret.push_str(TAG_FILE_UNSORTED);
+        } else {
+            ret.push_str(line);
+        }
+        ret.push('\n');
+    }
+    ret
+}
+
+/// Merges tags files into the output.
+///
+/// Sorted files are merged keeping the order, otherwise tags are concatenated.
+pub fn run(opt: &Opt, output: &Path, files: &[PathBuf]) -> Result<usize, Error> {
+    let tags = files
+        .iter()
+        .map(|x| read(opt, x))
+        .collect::<Result<Vec<_>, _>>()?;
+    let sorted = !opt.unsorted && tags.iter().all(|x| x.sorted);
+    let header = merge_header(&tags, sorted);
+
+    let mut opt = opt.clone();
+    opt.output = output.to_path_buf();
+    opt.unsorted = !sorted;
+    let inputs: Vec<Box<dyn BufRead>> = tags
+        .iter()
+        .map(|x| Box::new(x.body.as_slice()) as Box<dyn BufRead>)
+        .collect();
+    write_tags(&opt, &header, inputs)
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::run;
+    use crate::bin::Opt;
+    use std::fs;
+    use structopt::StructOpt;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_merge() {
+        let dir = TempDir::new().unwrap();
+        let a = dir.path().join("a.tags");
+        let b = dir.path().join("b.tags");
+        let c = dir.path().join("c.tags");
+        let out = dir.path().join("tags");
+        fs::write(
+            &a,
+            "!_TAG_FILE_SORTED\t1\t/0=unsorted, 1=sorted, 2=foldcase/\n\
+             !_TAG_KIND_DESCRIPTION!C\tf,function\t/functions/\n\
+             a\ta.c\t/^a$/;\"\tf\n\
+             c\ta.c\t/^c$/;\"\tf\n",
+        )
+        .unwrap();
+        fs::write(
+            &b,
+            "!_TAG_FILE_SORTED\t1\t/0=unsorted, 1=sorted, 2=foldcase/\n\
+             !_TAG_KIND_DESCRIPTION!C\tf,function\t/functions/\n\
+             !_TAG_KIND_DESCRIPTION!C\tv,variable\t/variables/\n\
+             b\tb.c\t/^b$/;\"\tv\n",
+        )
+        .unwrap();
+        fs::write(&c, "z\tc.c\t/^z$/;\"\tf\n").unwrap();
+
+        let args = vec!["ptags"];
+        let opt = Opt::from_iter(args.iter());
+        let count = run(&opt, &out, &[a.clone(), b.clone()]).unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(
+            fs::read_to_string(&out).unwrap(),
+            "!_TAG_FILE_SORTED\t1\t/0=unsorted, 1=sorted, 2=foldcase/\n\
+             !_TAG_KIND_DESCRIPTION!C\tf,function\t/functions/\n\
+             !_TAG_KIND_DESCRIPTION!C\tv,variable\t/variables/\n\
+             a\ta.c\t/^a$/;\"\tf\n\
+             b\tb.c\t/^b$/;\"\tv\n\
+             c\ta.c\t/^c$/;\"\tf\n"
+        );
+
+        let count = run(&opt, &out, &[c, a]).unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(
+            fs::read_to_string(&out).unwrap(),
+            "!_TAG_FILE_SORTED\t0\t/0=unsorted, 1=sorted, 2=foldcase/\n\
+             !_TAG_KIND_DESCRIPTION!C\tf,function\t/functions/\n\
+             z\tc.c\t/^z$/;\"\tf\n\
+             a\ta.c\t/^a$/;\"\tf\n\
+             c\ta.c\t/^c$/;\"\tf\n"
+        );
+    }
+}
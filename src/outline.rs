@@ -0,0 +1,190 @@
+use crate::bin::Opt;
+use crate::cmd_ctags::{CmdCtags, CtagsFlavor};
+use crate::find::Tag;
+use anyhow::{Context, Error};
+use serde_json::{json, Value};
+use std::io::{stdout, BufWriter, Write};
+use std::path::Path;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Outline
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Separators between names in scope fields like `Foo::bar` of C++ and Rust or `Foo.bar` of Python and Java.
+const SCOPE_SEPARATORS: [&str; 3] = ["::", ".", "/"];
+
+#[derive(Debug)]
+pub struct Node {
+    pub tag: Tag,
+    pub children: Vec<Node>,
+}
+
+/// Returns true if the scope points to the tag.
+fn is_scope_of(scope: &str, tag: &Tag) -> bool {
+    let prefix = match scope.strip_suffix(tag.name.as_str()) {
+        Some(x) => x,
+        None => return false,
+    };
+    match tag.scope() {
+        None => prefix.is_empty(),
+        Some(parent) => SCOPE_SEPARATORS
+            .iter()
+            .any(|x| prefix.strip_suffix(x) == Some(parent)),
+    }
+}
+
+/// Builds the tree of tags in the order of lines by scope fields.
+///
+/// The parent of a tag is the last tag before it whose full name is the scope of the tag.
+/// Tags whose scope is not found are placed at the top level.
+pub fn outline(mut tags: Vec<Tag>) -> Vec<Node> {
+    tags.sort_by_key(|x| x.line().unwrap_or(0));
+    let mut parents: Vec<Option<usize>> = Vec::new();
+    for (i, tag) in tags.iter().enumerate() {
+        let parent = tag
+            .scope()
+            .and_then(|scope| (0..i).rev().find(|x| is_scope_of(scope, &tags[*x])));
+        parents.push(parent);
+    }
+
+    // Children always follow the parent, so nodes are attached from the last.
+    let mut nodes: Vec<Option<Node>> = tags
+        .into_iter()
+        .map(|tag| {
+            Some(Node {
+                tag,
+                children: Vec::new(),
+            })
+        })
+        .collect();
+    let mut ret = Vec::new();
+    for i in (0..nodes.len()).rev() {
+        let node = nodes[i].take().unwrap();
+        match parents[i] {
+            Some(x) => nodes[x].as_mut().unwrap().children.insert(0, node),
+            None => ret.insert(0, node),
+        }
+    }
+    ret
+}
+
+fn write_text(out: &mut dyn Write, nodes: &[Node], depth: usize) -> Result<(), Error> {
+    for node in nodes {
+        let tag = &node.tag;
+        write!(out, "{}{}", "  ".repeat(depth), tag.name)?;
+        if let Some(ref kind) = tag.kind {
+            write!(out, " ({})", kind)?;
+        }
+        if let Some(line) = tag.line() {
+            write!(out, " :{}", line)?;
+        }
+        writeln!(out)?;
+        write_text(out, &node.children, depth + 1)?;
+    }
+    Ok(())
+}
+
+fn to_json(nodes: &[Node]) -> Value {
+    let nodes: Vec<Value> = nodes
+        .iter()
+        .map(|x| {
+            json!({
+                "name": x.tag.name,
+                "kind": x.tag.kind,
+                "line": x.tag.line(),
+                "children": to_json(&x.children),
+            })
+        })
+        .collect();
+    Value::Array(nodes)
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Run
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Calls ctags for the file and prints the outline.
+///
+/// Line numbers, full kind names and scopes are always enabled after `--opt-ctags`.
+pub fn run(opt: &Opt, file: &Path, format: &str) -> Result<(), Error> {
+    let path = file
+        .canonicalize()
+        .context(format!("failed to find file ({:?})", file))?;
+    let dir = opt.dir.canonicalize()?;
+    let file = match path.strip_prefix(&dir) {
+        Ok(x) => x.to_string_lossy().into_owned(),
+        Err(_) => path.to_string_lossy().into_owned(),
+    };
+
+    let mut opt = opt.clone();
+    opt.format = String::from("ctags");
+    opt.unsorted = true;
+    opt.split_by_lang = false;
+    if CmdCtags::info(&opt).flavor == CtagsFlavor::Exuberant {
+        opt.opt_ctags.push(String::from("--fields=+nKs"));
+    } else {
+        opt.opt_ctags.push(String::from("--fields=+nKZ"));
+    }
+
+    let mut tags = Vec::new();
+    for output in CmdCtags::call(&opt, &[file])? {
+        let data = output.read()?;
+        tags.extend(data.split(|x| *x == b'\n').filter_map(Tag::parse));
+    }
+    let nodes = outline(tags);
+
+    let out = stdout();
+    let mut out = BufWriter::new(out.lock());
+    if format == "json" {
+        writeln!(out, "{}", to_json(&nodes))?;
+    } else {
+        write_text(&mut out, &nodes, 0)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{outline, to_json, write_text};
+    use crate::find::Tag;
+
+    #[test]
+    fn test_outline() {
+        let lines = [
+            "bar\ta.py\t/^    def bar(self):$/;\"\tkind:member\tline:3\tscope:class:Foo",
+            "Foo\ta.py\t/^class Foo:$/;\"\tkind:class\tline:1",
+            "Inner\ta.py\t/^    class Inner:$/;\"\tkind:class\tline:5\tscope:class:Foo",
+            "baz\ta.py\t/^        def baz(self):$/;\"\tkind:member\tline:6\tscope:class:Foo.Inner",
+            "main\ta.py\t/^def main():$/;\"\tkind:function\tline:9",
+            "lost\ta.py\t/^    lost = 0$/;\"\tkind:variable\tline:10\tscope:function:missing",
+        ];
+        let tags = lines
+            .iter()
+            .filter_map(|x| Tag::parse(x.as_bytes()))
+            .collect();
+        let nodes = outline(tags);
+
+        let mut out = Vec::new();
+        write_text(&mut out, &nodes, 0).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Foo (class) :1\n  \
+               bar (member) :3\n  \
+               Inner (class) :5\n    \
+                 baz (member) :6\n\
+             main (function) :9\n\
+             lost (variable) :10\n"
+        );
+
+        let json = to_json(&nodes);
+        assert_eq!(json[0]["name"], "Foo");
+        assert_eq!(json[0]["children"][1]["children"][0]["name"], "baz");
+        assert_eq!(json[0]["children"][1]["children"][0]["line"], 6);
+        assert_eq!(json[1]["children"].as_array().unwrap().len(), 0);
+    }
+}
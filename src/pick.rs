@@ -0,0 +1,314 @@
+use crate::bin::{shell, shell_quote, Opt};
+use crate::find::{Tag, TagsFile};
+use anyhow::{bail, Error};
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::style::{Attribute, Print, SetAttribute};
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+use fuzzy_matcher::skim::SkimMatcherV2;
+use fuzzy_matcher::FuzzyMatcher;
+use std::env;
+use std::io::{stderr, Stderr, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Error
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Error)]
+enum PickError {
+    #[error("editor failed ({})", cmd)]
+    EditorFailed { cmd: String },
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Picker
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// State of the picker separated from the terminal.
+pub struct Picker {
+    tags: Vec<Tag>,
+    /// Text of each tag matched with the query
+    keys: Vec<String>,
+    matcher: SkimMatcherV2,
+    pub query: String,
+    /// Indices of tags matched with the query in order of the score
+    pub matches: Vec<usize>,
+    pub selected: usize,
+}
+
+impl Picker {
+    pub fn new(tags: Vec<Tag>, query: &str) -> Picker {
+        let keys = tags
+            .iter()
+            .map(|x| format!("{} {}", x.name, x.path))
+            .collect();
+        let mut ret = Picker {
+            tags,
+            keys,
+            matcher: SkimMatcherV2::default(),
+            query: String::from(query),
+            matches: Vec::new(),
+            selected: 0,
+        };
+        ret.update();
+        ret
+    }
+
+    /// Matches tags with the query. Tags of the same score keep the order of the tags file.
+    pub fn update(&mut self) {
+        let mut scores: Vec<(i64, usize)> = self
+            .keys
+            .iter()
+            .enumerate()
+            .filter_map(|(i, x)| self.matcher.fuzzy_match(x, &self.query).map(|x| (x, i)))
+            .collect();
+        scores.sort_by_key(|x| (-x.0, x.1));
+        self.matches = scores.into_iter().map(|x| x.1).collect();
+        self.selected = 0;
+    }
+
+    pub fn push(&mut self, c: char) {
+        self.query.push(c);
+        self.update();
+    }
+
+    pub fn pop(&mut self) {
+        self.query.pop();
+        self.update();
+    }
+
+    pub fn up(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    pub fn down(&mut self) {
+        if self.selected + 1 < self.matches.len() {
+            self.selected += 1;
+        }
+    }
+
+    pub fn tag(&self, index: usize) -> &Tag {
+        &self.tags[self.matches[index]]
+    }
+
+    pub fn current(&self) -> Option<&Tag> {
+        if self.matches.is_empty() {
+            None
+        } else {
+            Some(self.tag(self.selected))
+        }
+    }
+}
+
+/// Returns the line shown in the list like `name  kind  path:line`.
+fn label(tag: &Tag) -> String {
+    let mut ret = tag.name.clone();
+    if let Some(ref kind) = tag.kind {
+        ret.push_str(&format!("  {}", kind));
+    }
+    ret.push_str(&format!("  {}", tag.path));
+    if let Some(line) = tag.line() {
+        ret.push_str(&format!(":{}", line));
+    }
+    ret
+}
+
+/// Returns the path of the tag from the current directory.
+fn tag_path(opt: &Opt, tag: &Tag) -> PathBuf {
+    let path = opt.dir.join(&tag.path);
+    match path.strip_prefix(".") {
+        Ok(x) => x.to_path_buf(),
+        Err(_) => path,
+    }
+}
+
+/// Returns `file:line`, or `file` if the tag doesn't have the line number.
+pub fn location(opt: &Opt, tag: &Tag) -> String {
+    let path = tag_path(opt, tag);
+    match tag.line() {
+        Some(line) => format!("{}:{}", path.to_string_lossy(), line),
+        None => path.to_string_lossy().into_owned(),
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Terminal
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Returns the size of the terminal as `(width, height)`, or 80x24 if unknown.
+fn size() -> (usize, usize) {
+    match terminal::size() {
+        Ok((w, h)) if w > 0 && h > 0 => (w as usize, h as usize),
+        _ => (80, 24),
+    }
+}
+
+/// Draws the picker to stderr so that stdout can be captured like `vim $(ptags pick)`.
+fn draw(out: &mut Stderr, picker: &Picker, offset: usize) -> Result<(), Error> {
+    let (width, height) = size();
+    queue!(out, MoveTo(0, 0), Clear(ClearType::All))?;
+    queue!(out, Print(format!("> {}", picker.query)))?;
+    queue!(
+        out,
+        MoveTo(0, 1),
+        Print(format!("  {}/{}", picker.matches.len(), picker.tags.len()))
+    )?;
+    let rows = height.saturating_sub(2);
+    for row in 0..rows {
+        let i = offset + row;
+        if i >= picker.matches.len() {
+            break;
+        }
+        let text: String = label(picker.tag(i)).chars().take(width).collect();
+        queue!(out, MoveTo(0, (row + 2) as u16))?;
+        if i == picker.selected {
+            queue!(
+                out,
+                SetAttribute(Attribute::Reverse),
+                Print(text),
+                SetAttribute(Attribute::Reset)
+            )?;
+        } else {
+            queue!(out, Print(text))?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Runs the picker until a tag is selected or canceled by Esc or Ctrl-C.
+fn event_loop(out: &mut Stderr, picker: &mut Picker) -> Result<Option<usize>, Error> {
+    let mut offset = 0;
+    loop {
+        let rows = size().1.saturating_sub(2).max(1);
+        if picker.selected < offset {
+            offset = picker.selected;
+        } else if picker.selected >= offset + rows {
+            offset = picker.selected + 1 - rows;
+        }
+        draw(out, picker, offset)?;
+
+        let key = match event::read()? {
+            Event::Key(x) if x.kind != KeyEventKind::Release => x,
+            _ => continue,
+        };
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match (key.code, ctrl) {
+            (KeyCode::Enter, _) if !picker.matches.is_empty() => {
+                return Ok(Some(picker.matches[picker.selected]));
+            }
+            (KeyCode::Esc, _) | (KeyCode::Char('c'), true) => return Ok(None),
+            (KeyCode::Up, _) | (KeyCode::Char('p'), true) => picker.up(),
+            (KeyCode::Down, _) | (KeyCode::Char('n'), true) => picker.down(),
+            (KeyCode::Backspace, _) => picker.pop(),
+            (KeyCode::Char(c), false) => picker.push(c),
+            _ => (),
+        }
+    }
+}
+
+fn edit(opt: &Opt, tag: &Tag) -> Result<(), Error> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| String::from("vi"));
+    let path = tag_path(opt, tag);
+    let cmd = match tag.line() {
+        Some(line) => format!(
+            "{} +{} {}",
+            editor,
+            line,
+            shell_quote(&path.to_string_lossy())
+        ),
+        None => format!("{} {}", editor, shell_quote(&path.to_string_lossy())),
+    };
+    let status = shell(&cmd).status()?;
+    if !status.success() {
+        bail!(PickError::EditorFailed { cmd });
+    }
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Run
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Picks a tag from the tags file interactively, and prints the location or opens the editor.
+///
+/// Nothing is printed if canceled.
+pub fn run(opt: &Opt, tags_file: &Path, query: &str, edit_tag: bool) -> Result<(), Error> {
+    let tags: Vec<Tag> = TagsFile::open(opt, tags_file)?.tags().collect();
+    let mut picker = Picker::new(tags, query);
+
+    let mut out = stderr();
+    terminal::enable_raw_mode()?;
+    execute!(out, EnterAlternateScreen, Hide)?;
+    let selected = event_loop(&mut out, &mut picker);
+    execute!(out, Show, LeaveAlternateScreen)?;
+    terminal::disable_raw_mode()?;
+
+    let tag = match selected? {
+        Some(x) => &picker.tags[x],
+        None => return Ok(()),
+    };
+    if edit_tag {
+        edit(opt, tag)
+    } else {
+        println!("{}", location(opt, tag));
+        Ok(())
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{label, location, Picker};
+    use crate::bin::Opt;
+    use crate::find::Tag;
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_picker() {
+        let lines = [
+            "get_files\tsrc/cmd_git.rs\t/^fn get_files$/;\"\tf\tline:10",
+            "get_tags_header\tsrc/cmd_ctags.rs\t/^fn get_tags_header$/;\"\tf\tline:20",
+            "Opt\tsrc/bin.rs\t/^struct Opt$/;\"\ts",
+        ];
+        let tags = lines
+            .iter()
+            .filter_map(|x| Tag::parse(x.as_bytes()))
+            .collect();
+        let mut picker = Picker::new(tags, "");
+        assert_eq!(picker.matches.len(), 3);
+
+        for c in "gth".chars() {
+            picker.push(c);
+        }
+        assert_eq!(picker.matches, vec![1]);
+        picker.pop();
+        picker.pop();
+        assert_eq!(picker.matches.len(), 2);
+        picker.down();
+        picker.down();
+        assert_eq!(picker.selected, 1);
+        picker.up();
+        assert_eq!(picker.current().unwrap().name, "get_files");
+
+        picker.push('z');
+        assert!(picker.current().is_none());
+
+        let opt = Opt::from_iter(vec!["ptags"].iter());
+        let tag = &picker.tags[1];
+        assert_eq!(label(tag), "get_tags_header  f  src/cmd_ctags.rs:20");
+        assert_eq!(location(&opt, tag), "src/cmd_ctags.rs:20");
+        assert_eq!(location(&opt, &picker.tags[2]), "src/bin.rs");
+
+        let opt = Opt::from_iter(vec!["ptags", "lib"].iter());
+        assert_eq!(location(&opt, tag), "lib/src/cmd_ctags.rs:20");
+    }
+}
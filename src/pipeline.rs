@@ -0,0 +1,294 @@
+use crate::bin::{is_large_file, is_minified_file, merge_raw_tags, Opt};
+use crate::cmd_ctags::{CmdCtags, CtagsOutput, CtagsRun};
+use crate::cmd_git::CmdGit;
+use crate::filter::{self, FileFilter};
+use anyhow::Error;
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Seek, SeekFrom, Write};
+use std::thread::{self, JoinHandle};
+use tracing::debug;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Pipeline
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Number of files of the first chunk, which is doubled for each chunk up to `--batch-size`.
+///
+/// Small chunks are sent first so that ctags starts soon after git prints the first files.
+const FIRST_CHUNK: usize = 16;
+
+/// Number of finished outputs merged into a temporary file at once while other chunks are running.
+const MERGE_FAN_IN: usize = 16;
+
+type ChunkResult = (usize, Result<Vec<CtagsOutput>, Error>);
+
+type Merged = (CtagsRun, Vec<ChunkResult>, Vec<File>);
+
+/// Files listed by git and sent to ctags.
+#[derive(Debug, Default)]
+pub struct Listed {
+    pub list: Vec<String>,
+    /// File lists of chunks in the order sent to ctags
+    pub chunks: Vec<String>,
+    /// Number of files skipped by `--max-file-size`
+    pub large_files: usize,
+}
+
+/// Tags generated by the pipeline.
+#[derive(Debug)]
+pub struct Generated {
+    /// Outputs in the order of chunks. Tags of outputs marked as `merged` are in `merged`.
+    pub outputs: Vec<CtagsOutput>,
+    /// Temporary files of tags merged while ctags was running
+    pub merged: Vec<File>,
+}
+
+/// `--pipeline` overlaps `git ls-files`, ctags and merging of tags.
+///
+/// Files are sent to ctags in chunks as soon as git prints them,
+/// and finished outputs are merged into temporary files while other chunks are running,
+/// so that the final merge has fewer inputs.
+pub struct Pipeline {
+    /// `None` after `wait_list`
+    producer: Option<JoinHandle<Result<Listed, Error>>>,
+    merger: JoinHandle<Result<Merged, Error>>,
+}
+
+impl Pipeline {
+    /// Returns the option which needs the whole file list before ctags, or `None` if the pipeline is available.
+    pub fn conflict(opt: &Opt) -> Option<&'static str> {
+        let conflicts = [
+            (opt.list.is_some(), "--list"),
+            (
+                !opt.extra_dirs.is_empty() || !opt.repos.is_empty() || opt.recurse_repos,
+                "multiple directories",
+            ),
+            (opt.rev.is_some(), "--rev"),
+            (opt.staged, "--staged"),
+            (opt.no_vcs || opt.vcs != "git", "--no-vcs or --vcs"),
+            (opt.format == "cscope" || opt.format == "gtags", "--format"),
+            (opt.incremental || opt.watch, "--incremental"),
+            (opt.diff_against.is_some(), "--diff-against"),
+            (opt.append, "--append"),
+            (opt.cache, "--cache"),
+            (opt.max_memory.is_some(), "--max-memory"),
+            (opt.balance_by_size, "--balance-by-size"),
+            (opt.profile, "--profile"),
+            (opt.dry_run, "--dry-run"),
+            (opt.exclude_lfs, "--exclude-lfs"),
+            (opt.exclude_vendored, "--exclude-vendored"),
+            (opt.sparse, "--sparse"),
+            (opt.follow_symlinks, "--follow-symlinks"),
+            (!opt.encoding_for.is_empty(), "--encoding-for"),
+        ];
+        conflicts.iter().find(|x| x.0).map(|x| x.1)
+    }
+
+    pub fn start(opt: &Opt) -> Result<Pipeline, Error> {
+        let (mut sender, run) = CmdCtags::start(opt)?;
+        let filter = FileFilter::new(opt)?;
+
+        let producer_opt = opt.clone();
+        let producer = thread::spawn(move || {
+            let opt = producer_opt;
+            let mut listed = Listed::default();
+            let mut chunk = String::new();
+            let mut chunk_files = 0;
+            let mut chunk_size = FIRST_CHUNK.min(opt.batch_size).max(1);
+            let mut filtered = 0;
+            CmdGit::stream_files(&opt, |file| {
+                if !filter.matches(&file) {
+                    filtered += 1;
+                    return Ok(());
+                }
+                if opt.skip_symlinks && filter::is_symlink(&opt.dir, &file) {
+                    return Ok(());
+                }
+                if is_large_file(&opt, &file) {
+                    listed.large_files += 1;
+                    return Ok(());
+                }
+                if opt.skip_minified && is_minified_file(&opt, &file) {
+                    return Ok(());
+                }
+                // ctags reads the file list line by line, so a path including newline can't be passed.
+                if file.contains('\n') {
+                    if !opt.quiet {
+                        eprintln!("skipped a file whose name contains newline ({:?})", file);
+                    }
+                    return Ok(());
+                }
+
+                chunk.push_str(&file);
+                chunk.push('\n');
+                listed.list.push(file);
+                chunk_files += 1;
+                if chunk_files == chunk_size {
+                    debug!(files = chunk_files, "send chunk");
+                    listed.chunks.push(chunk.clone());
+                    sender.send(std::mem::take(&mut chunk));
+                    chunk_files = 0;
+                    chunk_size = (chunk_size * 2).min(opt.batch_size).max(1);
+                }
+                Ok(())
+            })?;
+            if chunk_files > 0 {
+                listed.chunks.push(chunk.clone());
+                sender.send(chunk);
+            }
+            if opt.verbose && !filter.is_empty() {
+                eprintln!("Filtered files: {}", filtered);
+            }
+            Ok(listed)
+        });
+
+        let merger_opt = opt.clone();
+        let merger = thread::spawn(move || {
+            let opt = merger_opt;
+            let mut results: Vec<ChunkResult> = Vec::new();
+            let mut merged = Vec::new();
+            // Indices of results and outputs which are finished and not merged yet
+            let mut pending = Vec::new();
+            while let Some((i, result)) = run.recv() {
+                if let Ok(ref outputs) = result {
+                    for (j, o) in outputs.iter().enumerate() {
+                        if o.status.success() {
+                            pending.push((results.len(), j));
+                        }
+                    }
+                }
+                results.push((i, result));
+
+                // Unsorted tags are concatenated in the order of chunks, so they are not merged in advance.
+                if !opt.unsorted && pending.len() >= MERGE_FAN_IN {
+                    merged.push(Pipeline::merge(&opt, &mut results, &pending)?);
+                    pending.clear();
+                }
+            }
+            Ok((run, results, merged))
+        });
+
+        Ok(Pipeline {
+            producer: Some(producer),
+            merger,
+        })
+    }
+
+    /// Waits until git finishes listing files, while ctags keeps running.
+    pub fn wait_list(&mut self) -> Result<Listed, Error> {
+        match self.producer.take() {
+            Some(x) => x.join().unwrap(),
+            None => Ok(Listed::default()),
+        }
+    }
+
+    /// Waits until all chunks are finished.
+    pub fn finish(mut self) -> Result<Generated, Error> {
+        // A failure of git is returned first because the rest of files are not sent to ctags.
+        self.wait_list()?;
+        let (run, results, merged) = self.merger.join().unwrap()?;
+        let outputs = run.collect(results)?;
+        Ok(Generated { outputs, merged })
+    }
+
+    /// Merges the pending outputs into a temporary file, and marks them as merged.
+    fn merge(
+        opt: &Opt,
+        results: &mut [ChunkResult],
+        pending: &[(usize, usize)],
+    ) -> Result<File, Error> {
+        let mut inputs: Vec<Box<dyn BufRead>> = Vec::new();
+        for &(i, j) in pending {
+            if let (_, Ok(ref outputs)) = results[i] {
+                inputs.push(Box::new(outputs[j].reader()?));
+            }
+        }
+        debug!(inputs = inputs.len(), "merge finished chunks");
+
+        let mut file = tempfile::tempfile()?;
+        {
+            let mut f = BufWriter::new(&mut file);
+            merge_raw_tags(opt, &mut f, inputs)?;
+            f.flush()?;
+        }
+        file.seek(SeekFrom::Start(0))?;
+
+        for &(i, j) in pending {
+            if let (_, Ok(ref mut outputs)) = results[i] {
+                outputs[j].merged = true;
+            }
+        }
+        Ok(file)
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::Pipeline;
+    use crate::bin::Opt;
+    #[cfg(unix)]
+    use crate::cmd_ctags::fake_ctags;
+    use std::io::{BufRead, BufReader};
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_conflict() {
+        let opt = Opt::from_iter(vec!["ptags", "--pipeline"].iter());
+        assert_eq!(Pipeline::conflict(&opt), None);
+        let opt = Opt::from_iter(vec!["ptags", "--pipeline", "--incremental"].iter());
+        assert_eq!(Pipeline::conflict(&opt), Some("--incremental"));
+        let opt = Opt::from_iter(vec!["ptags", "--pipeline", "--exclude-lfs"].iter());
+        assert_eq!(Pipeline::conflict(&opt), Some("--exclude-lfs"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_pipeline() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let bin = fake_ctags(
+            &dir,
+            "while read -r f; do printf '%s\\t%s\\t1;\"\\tf\\n' \"$f\" \"$f\"; done\n",
+        );
+
+        let args = vec![
+            "ptags",
+            "--bin-ctags",
+            &bin,
+            "--pipeline",
+            "--batch-size",
+            "2",
+            "--include",
+            "src/*.rs",
+        ];
+        let opt = Opt::from_iter(args.iter());
+        let mut pipeline = Pipeline::start(&opt).unwrap();
+        let listed = pipeline.wait_list().unwrap();
+        assert!(listed.list.len() > 32);
+        assert!(listed.list.iter().all(|x| x.ends_with(".rs")));
+        assert_eq!(listed.chunks[0].lines().count(), 2);
+
+        // 16 outputs are merged into a file in advance.
+        let generated = pipeline.finish().unwrap();
+        assert_eq!(generated.outputs.len(), listed.chunks.len());
+        assert_eq!(generated.merged.len(), 1);
+        assert_eq!(generated.outputs.iter().filter(|x| x.merged).count(), 16);
+
+        let merged: Vec<String> = BufReader::new(&generated.merged[0])
+            .lines()
+            .map(|x| x.unwrap())
+            .collect();
+        assert_eq!(merged.len(), 32);
+        assert!(merged.windows(2).all(|x| x[0] <= x[1]));
+        let rest: usize = generated
+            .outputs
+            .iter()
+            .filter(|x| !x.merged)
+            .map(|x| x.read().unwrap().split(|x| *x == b'\n').count() - 1)
+            .sum();
+        assert_eq!(merged.len() + rest, listed.list.len());
+    }
+}
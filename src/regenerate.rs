@@ -0,0 +1,190 @@
+use crate::bin::{run_opt, Opt, TAG_PTAGS_OPTIONS, TAG_PTAGS_VERSION};
+use anyhow::{bail, Context, Error};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+use thiserror::Error;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Error
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Error)]
+enum RegenerateError {
+    #[error("tags file has no {} pseudo-tag ({:?})", TAG_PTAGS_OPTIONS, path)]
+    NoProvenance { path: PathBuf },
+
+    #[error("invalid {} pseudo-tag ({:?})", TAG_PTAGS_OPTIONS, path)]
+    InvalidOptions { path: PathBuf },
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Provenance
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Pseudo-tags of ptags recorded in the tags file.
+#[derive(Debug, Default, PartialEq)]
+pub struct Provenance {
+    /// Options different from the default as JSON
+    pub options: Option<String>,
+    pub version: Option<String>,
+}
+
+/// Reverts the escape of tabs, newlines and backslashes in the value of a pseudo-tag.
+fn unescape(value: &str) -> String {
+    let mut ret = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            ret.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => ret.push('\t'),
+            Some('n') => ret.push('\n'),
+            Some(x) => ret.push(x),
+            None => ret.push('\\'),
+        }
+    }
+    ret
+}
+
+/// Reads pseudo-tags of ptags from the header of the tags file of ctags format or JSON format.
+pub fn read_provenance(path: &Path) -> Result<Provenance, Error> {
+    let file = File::open(path).context(format!("failed to open file ({:?})", path))?;
+    let mut ret = Provenance::default();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let (name, value) = if let Some(rest) = line.strip_prefix("!_") {
+            let mut fields = rest.splitn(3, '\t');
+            let name = String::from(fields.next().unwrap_or(""));
+            (name, unescape(fields.next().unwrap_or("")))
+        } else {
+            match serde_json::from_str::<serde_json::Value>(&line) {
+                Ok(ref x) if x["_type"] == "ptag" => (
+                    String::from(x["name"].as_str().unwrap_or("")),
+                    String::from(x["path"].as_str().unwrap_or("")),
+                ),
+                // Pseudo-tags precede tags.
+                _ => break,
+            }
+        };
+        if name == TAG_PTAGS_OPTIONS {
+            ret.options = Some(value);
+        } else if name == TAG_PTAGS_VERSION {
+            ret.version = Some(value);
+        }
+    }
+    Ok(ret)
+}
+
+/// Returns options which generate the same tags file from the recorded options.
+///
+/// The recorded options are applied to the default options, so that the current command line doesn't affect them.
+pub fn recorded_opt(options: &str) -> Option<Opt> {
+    let options: serde_json::Map<String, serde_json::Value> = serde_json::from_str(options).ok()?;
+    let mut value = serde_json::to_value(Opt::from_iter(["ptags"].iter())).ok()?;
+    for (key, x) in options {
+        value[key] = x;
+    }
+    let mut opt: Opt = serde_json::from_value(value).ok()?;
+    // Options which are not recorded are given by `Default` from the current command line.
+    opt.config = false;
+    opt.config_file = None;
+    opt.config_sources = Vec::new();
+    opt.cmd = None;
+    Some(opt)
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Run
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Generates the tags file again with the options recorded in it.
+///
+/// Options to control the run like `--verbose`, `--quiet` and `--dry-run` are taken from the command line.
+pub fn run(opt: &Opt) -> Result<(), Error> {
+    let path = &opt.output;
+    let provenance = read_provenance(path)?;
+    let options = match provenance.options {
+        Some(x) => x,
+        None => bail!(RegenerateError::NoProvenance { path: path.clone() }),
+    };
+    let mut recorded = match recorded_opt(&options) {
+        Some(x) => x,
+        None => bail!(RegenerateError::InvalidOptions { path: path.clone() }),
+    };
+
+    let version = env!("CARGO_PKG_VERSION");
+    if !opt.quiet && provenance.version.as_deref().is_some_and(|x| x != version) {
+        eprintln!(
+            "{:?} was generated by ptags {}, and is regenerated by ptags {}",
+            path,
+            provenance.version.unwrap_or_default(),
+            version
+        );
+    }
+    if opt.verbose {
+        eprintln!("Regenerate with options: {}", options);
+    }
+
+    recorded.verbose = opt.verbose;
+    recorded.quiet = opt.quiet;
+    recorded.dry_run = opt.dry_run;
+    run_opt(&recorded).context(format!("failed to regenerate ({:?})", path))
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{read_provenance, recorded_opt, unescape, Provenance};
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_read_provenance() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("tags");
+        std::fs::write(
+            &path,
+            "!_TAG_FILE_SORTED\t1\t//\n\
+             !_TAG_PTAGS_VERSION\t0.1.0\t//\n\
+             !_TAG_PTAGS_OPTIONS\t{\"opt_ctags\":[\"--regex-c=/a\\\\\\\\tb/\"],\"thread\":2}\t/effective options/\n\
+             a\ta.rs\t1;\"\tf\n",
+        )
+        .unwrap();
+        let provenance = read_provenance(&path).unwrap();
+        assert_eq!(provenance.version.as_deref(), Some("0.1.0"));
+        let opt = recorded_opt(&provenance.options.unwrap()).unwrap();
+        assert_eq!(opt.thread, 2);
+        assert_eq!(opt.opt_ctags, vec!["--regex-c=/a\\tb/"]);
+        assert_eq!(opt.output, PathBuf::from("tags"));
+
+        std::fs::write(
+            &path,
+            "{\"_type\":\"ptag\",\"name\":\"TAG_PTAGS_OPTIONS\",\"path\":\"{\\\"format\\\":\\\"json\\\"}\"}\n\
+             {\"_type\":\"tag\",\"name\":\"a\"}\n\
+             {\"_type\":\"ptag\",\"name\":\"TAG_PTAGS_VERSION\",\"path\":\"0.1.0\"}\n",
+        )
+        .unwrap();
+        let provenance = read_provenance(&path).unwrap();
+        assert_eq!(provenance.version, None);
+        assert_eq!(
+            recorded_opt(&provenance.options.unwrap()).unwrap().format,
+            "json"
+        );
+
+        std::fs::write(&path, "a\ta.rs\t1;\"\tf\n").unwrap();
+        assert_eq!(read_provenance(&path).unwrap(), Provenance::default());
+        assert!(recorded_opt("{\"thread\":\"a\"}").is_none());
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(unescape("a\\tb\\nc\\\\t\\"), "a\tb\nc\\t\\");
+    }
+}
@@ -0,0 +1,270 @@
+use crate::bin::Opt;
+use crate::cmd_ctags::CmdCtags;
+use anyhow::{bail, Error};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempfile::TempDir;
+use thiserror::Error;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Error
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug, Error)]
+enum SandboxError {
+    #[error("--sandbox is not available with {}", option)]
+    Unavailable { option: String },
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Sandbox
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Directories of the system which ctags reads to be executed.
+const SYSTEM_DIRS: &[&str] = &[
+    "/usr", "/lib", "/lib32", "/lib64", "/bin", "/sbin", "/etc", "/opt", "/nix",
+];
+
+/// Confinement of ctags processes by `--sandbox`.
+///
+/// ctags can read `DIR`, option files and the system, and can write only its temporary directory.
+/// Tags are written to stdout, which is a file opened by ptags before the confinement.
+#[derive(Debug)]
+pub struct Sandbox {
+    /// Directory given to ctags by `TMPDIR`, where file lists of `--files-via tempfile` are placed too
+    dir: TempDir,
+    /// Ruleset of Landlock, or `None` if the kernel doesn't support it
+    #[cfg(target_os = "linux")]
+    ruleset: Option<std::os::fd::OwnedFd>,
+}
+
+impl Sandbox {
+    /// Prepares the sandbox, or returns `None` without `--sandbox`.
+    ///
+    /// If the system doesn't support Landlock, ctags runs without the confinement after the warning.
+    pub fn new(opt: &Opt) -> Result<Option<Sandbox>, Error> {
+        if !opt.sandbox {
+            return Ok(None);
+        }
+        if opt.remote.is_some() {
+            bail!(SandboxError::Unavailable {
+                option: String::from("--remote"),
+            });
+        }
+        if opt.ctags_container.is_some() {
+            bail!(SandboxError::Unavailable {
+                option: String::from("--ctags-container"),
+            });
+        }
+
+        let dir = TempDir::new()?;
+        let mut read = vec![opt.dir.clone()];
+        read.extend(SYSTEM_DIRS.iter().map(PathBuf::from));
+        read.extend(CmdCtags::user_option_dirs());
+        if let Some(path) = std::env::var_os("PATH") {
+            read.extend(std::env::split_paths(&path));
+        }
+        if let Some(parent) = opt.bin_ctags.parent() {
+            read.push(parent.to_path_buf());
+        }
+        let write = vec![dir.path().to_path_buf(), PathBuf::from("/dev/null")];
+
+        #[cfg(target_os = "linux")]
+        {
+            let ruleset = landlock::ruleset(&read, &write);
+            if ruleset.is_none() && !opt.quiet {
+                eprintln!(
+                    "Landlock is not supported by the kernel, ctags runs without the sandbox"
+                );
+            }
+            Ok(Some(Sandbox { dir, ruleset }))
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (read, write);
+            if !opt.quiet {
+                eprintln!("--sandbox is supported only on Linux, ctags runs without the sandbox");
+            }
+            Ok(Some(Sandbox { dir }))
+        }
+    }
+
+    /// Returns the temporary directory which ctags can write.
+    pub fn dir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Confines the process between fork and exec.
+    pub fn apply(&self, cmd: &mut Command) {
+        cmd.env("TMPDIR", self.dir.path());
+        #[cfg(target_os = "linux")]
+        if let Some(ref ruleset) = self.ruleset {
+            use std::os::fd::AsRawFd;
+            use std::os::unix::process::CommandExt;
+
+            let fd = ruleset.as_raw_fd();
+            // The closure runs between fork and exec, so it only calls async-signal-safe functions.
+            unsafe {
+                cmd.pre_exec(move || landlock::restrict_self(fd));
+            }
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Landlock
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Raw system calls of Landlock ABI v1, which isn't wrapped by nix.
+#[cfg(target_os = "linux")]
+mod landlock {
+    use nix::fcntl::{open, OFlag};
+    use nix::libc;
+    use nix::sys::stat::Mode;
+    use std::io;
+    use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+    use std::path::{Path, PathBuf};
+
+    const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+    const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+    const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+    const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+    /// All rights of ABI v1 from EXECUTE to MAKE_SYM
+    const ACCESS_FS_ALL: u64 = (1 << 13) - 1;
+    /// Rights which can be given to a file instead of a directory
+    const ACCESS_FS_FILE: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE;
+    const ACCESS_FS_READ: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+
+    const RULE_PATH_BENEATH: libc::c_int = 1;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    /// Creates the ruleset which allows to read `read` and to write `write`, or returns `None` if unsupported.
+    ///
+    /// Paths which don't exist are skipped.
+    pub fn ruleset(read: &[PathBuf], write: &[PathBuf]) -> Option<OwnedFd> {
+        let attr = RulesetAttr {
+            handled_access_fs: ACCESS_FS_ALL,
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        if fd < 0 {
+            return None;
+        }
+        let ruleset = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+        let rules = read
+            .iter()
+            .map(|x| (x, ACCESS_FS_READ))
+            .chain(write.iter().map(|x| (x, ACCESS_FS_ALL)));
+        for (path, access) in rules {
+            add_rule(&ruleset, path, access);
+        }
+        Some(ruleset)
+    }
+
+    fn add_rule(ruleset: &OwnedFd, path: &Path, access: u64) {
+        use std::os::fd::AsRawFd;
+
+        let access = if path.is_dir() {
+            access
+        } else if path.exists() {
+            access & ACCESS_FS_FILE
+        } else {
+            return;
+        };
+        let fd = match open(path, OFlag::O_PATH | OFlag::O_CLOEXEC, Mode::empty()) {
+            Ok(x) => unsafe { OwnedFd::from_raw_fd(x) },
+            Err(_) => return,
+        };
+        let attr = PathBeneathAttr {
+            allowed_access: access,
+            parent_fd: fd.as_raw_fd(),
+        };
+        unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                RULE_PATH_BENEATH,
+                &attr as *const PathBeneathAttr,
+                0,
+            );
+        }
+    }
+
+    /// Enforces the ruleset to the current process.
+    pub fn restrict_self(ruleset: RawFd) -> io::Result<()> {
+        unsafe {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::syscall(libc::SYS_landlock_restrict_self, ruleset, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::Sandbox;
+    use crate::bin::Opt;
+    use std::process::{Command, Stdio};
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_new() {
+        let opt = Opt::from_iter(vec!["ptags"].iter());
+        assert!(Sandbox::new(&opt).unwrap().is_none());
+
+        let opt = Opt::from_iter(vec!["ptags", "--sandbox", "--remote", "build1"].iter());
+        assert_eq!(
+            Sandbox::new(&opt).err().unwrap().to_string(),
+            "--sandbox is not available with --remote"
+        );
+
+        let opt = Opt::from_iter(vec!["ptags", "--sandbox", "-q"].iter());
+        let sandbox = Sandbox::new(&opt).unwrap().unwrap();
+        assert!(sandbox.dir().is_dir());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_apply() {
+        let opt = Opt::from_iter(vec!["ptags", "--sandbox", "-q"].iter());
+        let sandbox = Sandbox::new(&opt).unwrap().unwrap();
+        if sandbox.ruleset.is_none() {
+            return;
+        }
+        let outside = tempfile::TempDir::new().unwrap();
+        let script = format!(
+            "touch \"$TMPDIR/a\" && ! touch {}",
+            outside.path().join("a").to_string_lossy()
+        );
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(script).stderr(Stdio::null());
+        sandbox.apply(&mut cmd);
+        assert!(cmd.status().unwrap().success());
+        assert!(sandbox.dir().join("a").exists());
+        assert!(!outside.path().join("a").exists());
+    }
+}
@@ -0,0 +1,218 @@
+use crate::bin::{
+    call_ctags, get_tags_header, split_files, tag_file, tag_name, vcs_list, write_tags, Opt,
+};
+use crate::state::State;
+use anyhow::{Context, Error};
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Server
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Keeps the file list, the tags header and the tags of each file in memory.
+///
+/// Requests are line-based and one request is accepted per connection:
+///
+/// - `regenerate` : retag changed files, write the tags file and respond `ok <number of tags>`
+/// - `query <name>` : respond tag lines whose name is `<name>`
+pub struct Server<'a> {
+    opt: &'a Opt,
+    header: String,
+    state: State,
+    tags: BTreeMap<String, Vec<String>>,
+}
+
+impl<'a> Server<'a> {
+    pub fn new(opt: &'a Opt) -> Result<Server<'a>, Error> {
+        let header = get_tags_header(opt)?;
+        Ok(Server {
+            opt,
+            header,
+            state: State::default(),
+            tags: BTreeMap::new(),
+        })
+    }
+
+    pub fn regenerate(&mut self) -> Result<usize, Error> {
+        let list = vcs_list(self.opt).context("failed to get file list")?;
+        let state = State::from_files(&self.opt.dir, &list);
+        let (changed, removed) = self.state.diff(&state);
+        if self.opt.verbose {
+            eprintln!("Changed: {}, Removed: {}", changed.len(), removed.len());
+        }
+
+        for f in changed.iter().chain(removed.iter()) {
+            self.tags.remove(f);
+        }
+
+        if !changed.is_empty() {
+            let files = split_files(&changed, self.opt);
+            let outputs = call_ctags(self.opt, &files).context("failed to call ctags")?;
+            for o in outputs.iter().filter(|x| x.status.success()) {
+                for line in String::from_utf8_lossy(&o.read()?).lines() {
+                    if let Some(file) = tag_file(self.opt, line.as_bytes()) {
+                        self.tags.entry(file).or_default().push(String::from(line));
+                    }
+                }
+            }
+        }
+        self.state = state;
+
+        let mut lines: Vec<&str> = self
+            .tags
+            .values()
+            .flat_map(|x| x.iter().map(|x| x.as_str()))
+            .collect();
+        if !self.opt.unsorted {
+            lines.sort_unstable();
+        }
+        let mut buf = String::new();
+        for line in &lines {
+            buf.push_str(line);
+            buf.push('\n');
+        }
+        write_tags(self.opt, &self.header, vec![Box::new(buf.as_bytes())])
+            .context(format!("failed to write file ({:?})", &self.opt.output))?;
+
+        Ok(lines.len())
+    }
+
+    pub fn query(&self, name: &str) -> Vec<&str> {
+        self.tags
+            .values()
+            .flat_map(|x| x.iter())
+            .filter(|x| tag_name(self.opt, x.as_bytes()).as_deref() == Some(name))
+            .map(|x| x.as_str())
+            .collect()
+    }
+
+    /// Returns all tag lines.
+    pub fn lines(&self) -> impl Iterator<Item = &str> {
+        self.tags
+            .values()
+            .flat_map(|x| x.iter().map(|x| x.as_str()))
+    }
+
+    /// Returns tag lines of the file.
+    pub fn file_lines(&self, file: &str) -> impl Iterator<Item = &str> {
+        self.tags
+            .get(file)
+            .into_iter()
+            .flat_map(|x| x.iter().map(|x| x.as_str()))
+    }
+
+    pub fn handle(&mut self, request: &str) -> String {
+        let mut iter = request.trim().splitn(2, ' ');
+        match (iter.next(), iter.next()) {
+            (Some("regenerate"), None) => match self.regenerate() {
+                Ok(x) => format!("ok {}\n", x),
+                Err(x) => format!("error {}\n", x),
+            },
+            (Some("query"), Some(name)) => {
+                let mut ret = String::new();
+                for line in self.query(name) {
+                    ret.push_str(line);
+                    ret.push('\n');
+                }
+                ret
+            }
+            _ => format!("error unknown request ({})\n", request.trim()),
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn serve(&mut self, socket: &Path) -> Result<(), Error> {
+        use std::os::unix::net::{UnixListener, UnixStream};
+
+        // Remove the socket left by a server which is not running.
+        if socket.exists() && UnixStream::connect(socket).is_err() {
+            std::fs::remove_file(socket)?;
+        }
+        let listener =
+            UnixListener::bind(socket).context(format!("failed to bind socket ({:?})", socket))?;
+
+        for stream in listener.incoming() {
+            // A broken connection is reported, and the server keeps serving others.
+            if let Err(x) = self.accept(stream) {
+                if !self.opt.quiet {
+                    eprintln!("failed to handle request ({})", x);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn accept(
+        &mut self,
+        stream: std::io::Result<std::os::unix::net::UnixStream>,
+    ) -> Result<(), Error> {
+        let mut stream = stream?;
+        let mut request = String::new();
+        BufReader::new(&stream).read_line(&mut request)?;
+        if self.opt.verbose {
+            eprintln!("Request: {}", request.trim());
+        }
+        let response = self.handle(&request);
+        let _ = stream.write_all(response.as_bytes());
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn serve(&mut self, _socket: &Path) -> Result<(), Error> {
+        anyhow::bail!("serve is not supported on this platform")
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::Server;
+    use crate::bin::Opt;
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_handle() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let output = dir.path().join("tags");
+        let args = vec!["ptags", "-f", output.to_str().unwrap()];
+        let opt = Opt::from_iter(args.iter());
+        let mut server = Server::new(&opt).unwrap();
+        assert_eq!(&server.handle("regenerate\n")[0..3], "ok ");
+        assert_eq!(
+            server.handle("query BIN_NAME\n"),
+            "BIN_NAME\tMakefile\t/^BIN_NAME = ptags$/;\"\tm\n"
+        );
+        assert_eq!(server.handle("aaa\n"), "error unknown request (aaa)\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_accept() {
+        use std::io::{Read, Write};
+        use std::os::unix::net::UnixStream;
+
+        let args = vec!["ptags", "--bin-ctags", "true"];
+        let opt = Opt::from_iter(args.iter());
+        let mut server = Server::new(&opt).unwrap();
+
+        let (mut client, stream) = UnixStream::pair().unwrap();
+        client.write_all(b"query \xff\n").unwrap();
+        assert!(server.accept(Ok(stream)).is_err());
+        assert!(server
+            .accept(Err(std::io::ErrorKind::Other.into()))
+            .is_err());
+
+        let (mut client, stream) = UnixStream::pair().unwrap();
+        client.write_all(b"aaa\n").unwrap();
+        server.accept(Ok(stream)).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "error unknown request (aaa)\n");
+    }
+}
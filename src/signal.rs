@@ -0,0 +1,127 @@
+use crate::audit;
+use anyhow::{Context, Error};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Output};
+use std::sync::Mutex;
+use std::time::Instant;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Signal
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Exit code when ptags is interrupted by SIGINT/SIGTERM.
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+static CHILDREN: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+static FILES: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Installs the handler of SIGINT/SIGTERM.
+///
+/// The handler kills running children and removes files being written so that an interrupt never leaves a truncated tags file.
+pub fn install() -> Result<(), Error> {
+    ctrlc::set_handler(|| {
+        for pid in CHILDREN.lock().unwrap_or_else(|x| x.into_inner()).iter() {
+            kill_group(*pid);
+        }
+        for path in FILES.lock().unwrap_or_else(|x| x.into_inner()).iter() {
+            let _ = std::fs::remove_file(path);
+        }
+        eprintln!("interrupted");
+        process::exit(EXIT_INTERRUPTED);
+    })
+    .context("failed to install signal handler")
+}
+
+pub fn register_child(pid: u32) {
+    CHILDREN.lock().unwrap().push(pid);
+}
+
+pub fn unregister_child(pid: u32) {
+    CHILDREN.lock().unwrap().retain(|x| *x != pid);
+}
+
+pub fn register_file(path: &Path) {
+    FILES.lock().unwrap().push(path.to_path_buf());
+}
+
+pub fn unregister_file(path: &Path) {
+    FILES.lock().unwrap().retain(|x| x != path);
+}
+
+/// Puts the child into its own process group so that the handler can kill its descendants too.
+pub fn isolate(cmd: &mut Command) -> &mut Command {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(0);
+    }
+    cmd
+}
+
+/// Same as `Command::output` but the child is killed by the handler on interrupt, and recorded by the audit log.
+pub fn output(cmd: &mut Command) -> io::Result<Output> {
+    let started = Instant::now();
+    let child = isolate(cmd)
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn();
+    let child = match child {
+        Ok(x) => x,
+        Err(x) => {
+            audit::record(cmd, started, None, 0, 0);
+            return Err(x);
+        }
+    };
+    let pid = child.id();
+    register_child(pid);
+    let output = child.wait_with_output();
+    unregister_child(pid);
+    match output {
+        Ok(ref x) => audit::record(cmd, started, Some(x.status), 0, x.stdout.len() as u64),
+        Err(_) => audit::record(cmd, started, None, 0, 0),
+    }
+    output
+}
+
+/// Kills the process group created by `isolate`.
+#[cfg(unix)]
+pub fn kill_group(pid: u32) {
+    use nix::sys::signal::{self, Signal};
+    use nix::unistd::Pid;
+    let _ = signal::kill(Pid::from_raw(-(pid as i32)), Signal::SIGTERM);
+}
+
+// Console interrupts are delivered to children too on Windows.
+#[cfg(not(unix))]
+pub fn kill_group(_pid: u32) {}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{output, register_file, unregister_file, FILES};
+    use std::path::Path;
+    use std::process::Command;
+
+    #[test]
+    fn test_output() {
+        let out = output(Command::new("git").arg("--version")).unwrap();
+        assert!(out.status.success());
+        assert!(String::from_utf8_lossy(&out.stdout).starts_with("git version"));
+
+        register_file(Path::new("test_signal.tags"));
+        assert!(FILES
+            .lock()
+            .unwrap()
+            .contains(&Path::new("test_signal.tags").to_path_buf()));
+        unregister_file(Path::new("test_signal.tags"));
+        assert!(!FILES
+            .lock()
+            .unwrap()
+            .contains(&Path::new("test_signal.tags").to_path_buf()));
+    }
+}
@@ -0,0 +1,190 @@
+use anyhow::{Context, Error};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// State
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct FileState {
+    pub mtime: u64,
+    pub size: u64,
+}
+
+/// Statistics of the last run which `--stat` shows deltas against.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct RunStats {
+    pub files: u64,
+    pub tags: u64,
+    pub git_files_ms: u64,
+    pub call_ctags_ms: u64,
+    pub write_tags_ms: u64,
+    #[serde(default)]
+    pub warnings: u64,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct State {
+    /// Clock of Watchman at the run by `--use-watchman`
+    #[serde(default)]
+    pub watchman_clock: Option<String>,
+    #[serde(default)]
+    pub files: BTreeMap<String, FileState>,
+    #[serde(default)]
+    pub stats: Option<RunStats>,
+}
+
+impl State {
+    pub fn load(path: &Path) -> Result<Option<State>, Error> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let s = fs::read_to_string(path).context(format!("failed to open file ({:?})", path))?;
+        let state = toml::from_str(&s).context(format!("failed to parse toml ({:?})", path))?;
+        Ok(Some(state))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let s = toml::to_string(&self)?;
+        fs::write(path, s).context(format!("failed to write file ({:?})", path))?;
+        Ok(())
+    }
+
+    pub fn from_files(dir: &Path, files: &[String]) -> State {
+        let mut state = State::default();
+        for f in files {
+            if let Ok(meta) = fs::metadata(dir.join(f)) {
+                let mtime = meta
+                    .modified()
+                    .ok()
+                    .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+                    .map(|x| x.as_nanos() as u64)
+                    .unwrap_or(0);
+                let size = meta.len();
+                state.files.insert(f.clone(), FileState { mtime, size });
+            }
+        }
+        state
+    }
+
+    /// Returns the state of files checking only files which are changed or not in `old`.
+    pub fn from_files_since(
+        dir: &Path,
+        files: &[String],
+        old: &State,
+        changed: &HashSet<String>,
+    ) -> State {
+        let mut state = State::default();
+        let mut check = Vec::new();
+        for f in files {
+            match old.files.get(f) {
+                Some(x) if !changed.contains(f) => {
+                    state.files.insert(f.clone(), *x);
+                }
+                _ => check.push(f.clone()),
+            }
+        }
+        state
+            .files
+            .append(&mut State::from_files(dir, &check).files);
+        state
+    }
+
+    /// Returns files which are added or modified in `new`, and files which are removed from `new`.
+    pub fn diff(&self, new: &State) -> (Vec<String>, Vec<String>) {
+        let mut changed = Vec::new();
+        let mut removed = Vec::new();
+        for (f, s) in &new.files {
+            if self.files.get(f) != Some(s) {
+                changed.push(f.clone());
+            }
+        }
+        for f in self.files.keys() {
+            if !new.files.contains_key(f) {
+                removed.push(f.clone());
+            }
+        }
+        (changed, removed)
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{FileState, RunStats, State};
+    use std::collections::HashSet;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_diff() {
+        let mut old = State::default();
+        old.files
+            .insert(String::from("a"), FileState { mtime: 1, size: 1 });
+        old.files
+            .insert(String::from("b"), FileState { mtime: 1, size: 1 });
+        old.files
+            .insert(String::from("c"), FileState { mtime: 1, size: 1 });
+        let mut new = State::default();
+        new.files
+            .insert(String::from("a"), FileState { mtime: 1, size: 1 });
+        new.files
+            .insert(String::from("b"), FileState { mtime: 2, size: 1 });
+        new.files
+            .insert(String::from("d"), FileState { mtime: 1, size: 1 });
+        let (changed, removed) = old.diff(&new);
+        assert_eq!(changed, vec!["b", "d"]);
+        assert_eq!(removed, vec!["c"]);
+    }
+
+    #[test]
+    fn test_from_files_since() {
+        let dir = TempDir::new().unwrap();
+        for f in &["a", "b", "d"] {
+            fs::write(dir.path().join(f), "new").unwrap();
+        }
+        let mut old = State::default();
+        for f in &["a", "b", "c"] {
+            old.files
+                .insert(String::from(*f), FileState { mtime: 1, size: 1 });
+        }
+        let files = vec![String::from("a"), String::from("b"), String::from("d")];
+        let changed: HashSet<String> = vec![String::from("b"), String::from("c")]
+            .into_iter()
+            .collect();
+        let new = State::from_files_since(dir.path(), &files, &old, &changed);
+        assert_eq!(new.files["a"], FileState { mtime: 1, size: 1 });
+        assert_eq!(new.files["b"].size, 3);
+        assert_eq!(new.files["d"].size, 3);
+        let (changed, removed) = old.diff(&new);
+        assert_eq!(changed, vec!["b", "d"]);
+        assert_eq!(removed, vec!["c"]);
+    }
+
+    #[test]
+    fn test_save_stats() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("state");
+        std::fs::write(&path, "[files.a]\nmtime = 1\nsize = 2\n").unwrap();
+        let mut state = State::load(&path).unwrap().unwrap();
+        assert_eq!(state.stats, None);
+
+        let stats = RunStats {
+            files: 3,
+            tags: 10,
+            ..Default::default()
+        };
+        state.stats = Some(stats.clone());
+        state.save(&path).unwrap();
+        let state = State::load(&path).unwrap().unwrap();
+        assert_eq!(state.stats, Some(stats));
+        assert_eq!(state.files["a"], FileState { mtime: 1, size: 2 });
+    }
+}
@@ -0,0 +1,88 @@
+use anyhow::{Context, Error};
+use serde_json::{json, Value};
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Trace
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Recorder of spans written in Chrome tracing JSON format by `--trace`.
+///
+/// The file can be opened by `chrome://tracing` or Perfetto.
+/// Phases of ptags are on thread 0, and ctags processes are on the thread of each worker from 1.
+pub struct Trace {
+    begin: Instant,
+    events: Vec<Value>,
+}
+
+impl Trace {
+    pub fn new(begin: Instant) -> Trace {
+        Trace {
+            begin,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn span(&mut self, name: &str, tid: usize, start: Instant, dur: Duration, args: Value) {
+        let ts = start.saturating_duration_since(self.begin);
+        self.events.push(json!({
+            "name": name,
+            "ph": "X",
+            "pid": 1,
+            "tid": tid,
+            "ts": ts.as_micros() as u64,
+            "dur": dur.as_micros() as u64,
+            "args": args,
+        }));
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "traceEvents": self.events,
+            "displayTimeUnit": "ms",
+        })
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Error> {
+        fs::write(path, self.to_json().to_string())
+            .context(format!("failed to write file ({:?})", path))
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::Trace;
+    use serde_json::json;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_trace() {
+        let begin = Instant::now();
+        let mut trace = Trace::new(begin);
+        trace.span("git_files", 0, begin, Duration::from_millis(2), json!({}));
+        trace.span(
+            "ctags",
+            1,
+            begin + Duration::from_millis(2),
+            Duration::from_micros(1500),
+            json!({"files": 3}),
+        );
+        let json = trace.to_json();
+        let events = json["traceEvents"].as_array().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["ts"], 0);
+        assert_eq!(events[0]["dur"], 2000);
+        assert_eq!(events[1]["name"], "ctags");
+        assert_eq!(events[1]["ph"], "X");
+        assert_eq!(events[1]["tid"], 1);
+        assert_eq!(events[1]["ts"], 2000);
+        assert_eq!(events[1]["dur"], 1500);
+        assert_eq!(events[1]["args"]["files"], 3);
+    }
+}
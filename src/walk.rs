@@ -0,0 +1,81 @@
+use crate::bin::{path_from_os_str, Opt};
+use anyhow::{Context, Error};
+use ignore::overrides::OverrideBuilder;
+use ignore::WalkBuilder;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Walk
+// ---------------------------------------------------------------------------------------------------------------------
+
+pub struct Walk;
+
+impl Walk {
+    pub fn get_files(opt: &Opt) -> Result<Vec<String>, Error> {
+        let mut overrides = OverrideBuilder::new(&opt.dir);
+        for e in &opt.exclude {
+            overrides
+                .add(&format!("!{}", e))
+                .context(format!("failed to parse glob ({})", e))?;
+        }
+        let overrides = overrides.build()?;
+
+        let walker = WalkBuilder::new(&opt.dir)
+            .overrides(overrides)
+            .require_git(false)
+            .git_ignore(!opt.include_ignored)
+            .git_exclude(!opt.include_ignored)
+            .ignore(!opt.include_ignored)
+            .build();
+
+        let mut ret = Vec::new();
+        for entry in walker {
+            let entry = entry?;
+            if !entry.file_type().map(|x| x.is_file()).unwrap_or(false) {
+                continue;
+            }
+            let path = entry.path().strip_prefix(&opt.dir).unwrap_or(entry.path());
+            let path: Vec<_> = path
+                .components()
+                .map(|x| path_from_os_str(opt, x.as_os_str()))
+                .collect::<Result<_, _>>()?;
+            ret.push(path.join("/"));
+        }
+        ret.sort();
+
+        if opt.verbose {
+            eprintln!("Files: {}", ret.len());
+        }
+
+        Ok(ret)
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::Walk;
+    use crate::bin::Opt;
+    use std::fs;
+    use structopt::StructOpt;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_get_files() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join(".gitignore"), "*.o\n").unwrap();
+        fs::write(dir.path().join("src/a.rs"), "").unwrap();
+        fs::write(dir.path().join("src/a.o"), "").unwrap();
+        fs::write(dir.path().join("b.c"), "").unwrap();
+        fs::write(dir.path().join("c.md"), "").unwrap();
+
+        let dir = dir.path().to_string_lossy();
+        let args = vec!["ptags", "--no-vcs", "--exclude=*.md", &dir];
+        let opt = Opt::from_iter(args.iter());
+        let files = Walk::get_files(&opt).unwrap();
+        assert_eq!(files, vec!["b.c", "src/a.rs"]);
+    }
+}
@@ -0,0 +1,134 @@
+use crate::bin::TEMP_PREFIX;
+use anyhow::{Context, Error};
+use notify::{Event, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// DirWatcher
+// ---------------------------------------------------------------------------------------------------------------------
+
+pub struct DirWatcher {
+    _watcher: notify::RecommendedWatcher,
+    rx: mpsc::Receiver<notify::Result<Event>>,
+    ignore: Vec<PathBuf>,
+    /// Directories where temporary files of ptags like `.ptagsXXXXXX` are ignored
+    temp_dirs: Vec<PathBuf>,
+    delay: Duration,
+}
+
+/// Resolves the path for comparison, even if the file is already removed like the lock file.
+fn normalize(path: &Path) -> PathBuf {
+    if let Ok(x) = path.canonicalize() {
+        return x;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => {
+            let parent = if parent.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                parent
+            };
+            match parent.canonicalize() {
+                Ok(x) => x.join(name),
+                Err(_) => path.to_path_buf(),
+            }
+        }
+        _ => path.to_path_buf(),
+    }
+}
+
+impl DirWatcher {
+    pub fn new(
+        dir: &Path,
+        ignore: &[PathBuf],
+        temp_dirs: &[PathBuf],
+        delay: Duration,
+    ) -> Result<DirWatcher, Error> {
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx).context("failed to create watcher")?;
+        watcher
+            .watch(dir, RecursiveMode::Recursive)
+            .context(format!("failed to watch directory ({:?})", dir))?;
+
+        // Files written by ptags itself must not trigger the next run.
+        let ignore = ignore.iter().map(|x| normalize(x)).collect();
+        let temp_dirs = temp_dirs.iter().map(|x| normalize(x)).collect();
+
+        Ok(DirWatcher {
+            _watcher: watcher,
+            rx,
+            ignore,
+            temp_dirs,
+            delay,
+        })
+    }
+
+    /// Blocks until a change is detected and no further change arrives within the delay.
+    pub fn wait(&self) -> Result<(), Error> {
+        loop {
+            let event = self.rx.recv().context("watcher disconnected")?;
+            if self.is_relevant(&event?) {
+                break;
+            }
+        }
+        while let Ok(event) = self.rx.recv_timeout(self.delay) {
+            let _ = event?;
+        }
+        Ok(())
+    }
+
+    fn is_relevant(&self, event: &Event) -> bool {
+        if event.kind.is_access() {
+            return false;
+        }
+        event.paths.iter().any(|p| {
+            let p = normalize(p);
+            let temp = p
+                .file_name()
+                .is_some_and(|x| x.to_string_lossy().starts_with(TEMP_PREFIX))
+                && p.parent()
+                    .is_some_and(|x| self.temp_dirs.iter().any(|y| y == x));
+            !temp && !self.ignore.iter().any(|x| x == &p)
+        })
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::DirWatcher;
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_wait() {
+        let dir = TempDir::new().unwrap();
+        let ignored = dir.path().join("tags");
+        fs::write(&ignored, "").unwrap();
+        let watcher = DirWatcher::new(
+            dir.path(),
+            &[ignored.clone()],
+            &[dir.path().to_path_buf()],
+            Duration::from_millis(50),
+        )
+        .unwrap();
+
+        let path = dir.path().join("a.rs");
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            fs::write(&ignored, "x").unwrap();
+            fs::write(ignored.with_file_name(".ptags123"), "x").unwrap();
+            thread::sleep(Duration::from_millis(100));
+            fs::write(&path, "fn a() {}").unwrap();
+        });
+        assert!(watcher.wait().is_ok());
+        assert!(dir.path().join("a.rs").exists());
+    }
+}